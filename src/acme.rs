@@ -0,0 +1,602 @@
+//! Certificates for TLS control connections from an ACME certificate authority, such as Let's
+//! Encrypt.
+//!
+//! The server proves that it controls each domain with the HTTP-01 challenge, answering the
+//! authority on a plain HTTP port that must be reachable as port 80 of every domain. The account
+//! key, certificate, and private key are kept in a directory so that restarts reuse them, and the
+//! certificate is renewed in the background a month before it expires, without restarting.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use dashmap::DashMap;
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+use reqwest::header::CONTENT_TYPE;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout};
+use tracing::{debug, info, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::health::read_head;
+use crate::shared::NETWORK_TIMEOUT;
+use crate::tls;
+
+/// Directory URL of the Let's Encrypt certificate authority.
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Directory URL of the Let's Encrypt staging environment, whose certificates are not trusted
+/// but whose rate limits are much higher, for trying out a setup.
+pub const LETS_ENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+/// Port answering HTTP-01 challenges by default, where authorities send them.
+pub const ACME_HTTP_PORT: u16 = 80;
+
+/// How long before it expires a certificate is renewed.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// Longest time between checks of whether the certificate is due for renewal.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+/// Delay before trying again after failing to obtain a certificate, which keeps well within the
+/// rate limits of authorities.
+const RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// Interval between polls of a pending authorization or order.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Number of polls before giving up on a pending authorization or order.
+const POLL_ATTEMPTS: u32 = 60;
+
+/// Path under which authorities fetch HTTP-01 challenge responses.
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+const ACCOUNT_FILE: &str = "account.pem";
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+const DOMAINS_FILE: &str = "domains";
+
+/// Obtains and renews the certificate for TLS control connections from an ACME authority.
+pub struct Acme {
+    domains: Vec<String>,
+    dir: PathBuf,
+    directory: String,
+    contact: Option<String>,
+    challenge_port: u16,
+    resolver: Arc<Resolver>,
+
+    /// Expiry of the current certificate, if there is one.
+    expires: Mutex<Option<SystemTime>>,
+
+    /// Key authorizations of pending HTTP-01 challenges, by token.
+    challenges: Arc<DashMap<String, String>>,
+
+    http: reqwest::Client,
+}
+
+impl Acme {
+    /// Manage a certificate for some domains, keeping it and the account key in a directory.
+    ///
+    /// A certificate that an earlier run left in the directory is served right away, as long as
+    /// it was issued for the same domains.
+    pub fn new(domains: Vec<String>, dir: impl Into<PathBuf>) -> Result<Self> {
+        if domains.is_empty() {
+            bail!("ACME needs at least one domain");
+        }
+        let dir = dir.into();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("could not create ACME directory {}", dir.display()))?;
+        let acme = Acme {
+            domains,
+            dir,
+            directory: LETS_ENCRYPT.into(),
+            contact: None,
+            challenge_port: ACME_HTTP_PORT,
+            resolver: Arc::default(),
+            expires: Mutex::new(None),
+            challenges: Arc::default(),
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()?,
+        };
+        if let Some((key, expires)) = acme.load()? {
+            acme.install(key, expires);
+        }
+        Ok(acme)
+    }
+
+    /// Use another certificate authority, by the URL of its directory, instead of Let's Encrypt.
+    pub fn set_directory(&mut self, url: impl Into<String>) {
+        self.directory = url.into();
+    }
+
+    /// Register the account with a contact email, where authorities send notices about it.
+    pub fn set_contact(&mut self, email: impl Into<String>) {
+        self.contact = Some(email.into());
+    }
+
+    /// Answer challenges on this port instead of port 80, such as when port 80 is forwarded to
+    /// it.
+    pub fn set_challenge_port(&mut self, port: u16) {
+        self.challenge_port = port;
+    }
+
+    /// Returns the port that answers challenges.
+    pub fn challenge_port(&self) -> u16 {
+        self.challenge_port
+    }
+
+    /// Returns when the current certificate expires, if there is one.
+    pub fn expires(&self) -> Option<SystemTime> {
+        *self.expires.lock().unwrap()
+    }
+
+    /// Build a TLS server configuration that presents the latest certificate.
+    ///
+    /// Handshakes fail until the first certificate is obtained.
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>> {
+        tls::resolver_config(Arc::clone(&self.resolver) as Arc<dyn ResolvesServerCert>)
+    }
+
+    /// Answer challenges on a listener, and obtain or renew the certificate whenever it is due.
+    pub(crate) async fn run(self: Arc<Self>, listener: TcpListener) {
+        tokio::join!(self.serve_challenges(listener), self.keep_renewed());
+    }
+
+    async fn keep_renewed(&self) {
+        loop {
+            let due = match self.expires() {
+                Some(expires) => expires.checked_sub(RENEW_BEFORE).unwrap_or(UNIX_EPOCH),
+                None => UNIX_EPOCH,
+            };
+            if let Ok(wait) = due.duration_since(SystemTime::now()) {
+                sleep(wait.min(CHECK_INTERVAL)).await;
+                continue;
+            }
+            info!(domains = ?self.domains, "requesting certificate");
+            match self.issue().await {
+                Ok(expires) => {
+                    let days = expires
+                        .duration_since(SystemTime::now())
+                        .unwrap_or_default()
+                        .as_secs()
+                        / 86400;
+                    info!(domains = ?self.domains, days, "obtained certificate");
+                }
+                Err(err) => {
+                    warn!(domains = ?self.domains, "failed to obtain certificate: {err:#}");
+                    sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// Serve the certificate and its expiry to new handshakes.
+    fn install(&self, key: CertifiedKey, expires: SystemTime) {
+        *self.resolver.0.write().unwrap() = Some(Arc::new(key));
+        *self.expires.lock().unwrap() = Some(expires);
+    }
+
+    /// Load the certificate kept in the directory, if it was issued for the same domains.
+    fn load(&self) -> Result<Option<(CertifiedKey, SystemTime)>> {
+        let (Some(domains), Some(cert), Some(key)) = (
+            self.read(DOMAINS_FILE)?,
+            self.read(CERT_FILE)?,
+            self.read(KEY_FILE)?,
+        ) else {
+            return Ok(None);
+        };
+        if !String::from_utf8_lossy(&domains)
+            .lines()
+            .eq(self.domains.iter().map(String::as_str))
+        {
+            info!("domains changed since the certificate was issued");
+            return Ok(None);
+        }
+        certified_key(&cert, &key).map(Some)
+    }
+
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.dir.join(name);
+        match fs::read(&path) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("could not read {}", path.display())),
+        }
+    }
+
+    /// Load the account key, or generate and save one for a new account.
+    fn account_key(&self) -> Result<KeyPair> {
+        if let Some(pem) = self.read(ACCOUNT_FILE)? {
+            return Ok(KeyPair::from_pem(&String::from_utf8_lossy(&pem))?);
+        }
+        let key = KeyPair::generate()?;
+        write_private(&self.dir.join(ACCOUNT_FILE), key.serialize_pem().as_bytes())?;
+        Ok(key)
+    }
+
+    /// Order a certificate for the domains, then save and serve it, returning its expiry.
+    async fn issue(&self) -> Result<SystemTime> {
+        let mut account = Account::new(&self.http, &self.directory, &self.account_key()?).await?;
+        account.register(self.contact.as_deref()).await?;
+        let identifiers: Vec<_> = (self.domains.iter())
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let response = (account.post(&account.directory.new_order.clone()))
+            .send(Some(json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = location(&response)?;
+        let order: Resource = parse(response).await?;
+        for url in &order.authorizations {
+            self.authorize(&mut account, url).await?;
+        }
+
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(self.domains.clone())?;
+        // Authorities reject names in the subject that are not among the domains.
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, &self.domains[0]);
+        let csr = params.serialize_request(&key)?;
+        let finalize = order.finalize.context("order has no finalize URL")?;
+        let csr = json!({ "csr": URL_SAFE_NO_PAD.encode(csr.der()) });
+        account.post(&finalize).send(Some(csr)).await?;
+        let order = account.poll(&order_url).await?;
+        let url = match &order.certificate {
+            Some(url) if order.status == "valid" => url.clone(),
+            _ => bail!("order is {}: {}", order.status, order.problem()),
+        };
+        let chain = account.post(&url).send(None).await?.bytes().await?;
+        let key = key.serialize_pem();
+        let (certified, expires) = certified_key(&chain, key.as_bytes())?;
+
+        write_private(&self.dir.join(KEY_FILE), key.as_bytes())?;
+        write_private(&self.dir.join(CERT_FILE), &chain)?;
+        write_private(
+            &self.dir.join(DOMAINS_FILE),
+            self.domains.join("\n").as_bytes(),
+        )?;
+        self.install(certified, expires);
+        Ok(expires)
+    }
+
+    /// Complete the HTTP-01 challenge of an authorization, unless it is already valid.
+    async fn authorize(&self, account: &mut Account<'_>, url: &str) -> Result<()> {
+        let authorization: Resource = parse(account.post(url).send(None).await?).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let domain = authorization
+            .identifier
+            .map(|id| id.value)
+            .unwrap_or_default();
+        let challenge = (authorization.challenges.into_iter())
+            .find(|challenge| challenge.kind == "http-01")
+            .with_context(|| format!("authority offers no HTTP-01 challenge for {domain}"))?;
+        let key_authorization = format!("{}.{}", challenge.token, account.thumbprint);
+        self.challenges
+            .insert(challenge.token.clone(), key_authorization);
+        let result = async {
+            account.post(&challenge.url).send(Some(json!({}))).await?;
+            account.poll(url).await
+        }
+        .await;
+        self.challenges.remove(&challenge.token);
+        match result? {
+            authorization if authorization.status == "valid" => Ok(()),
+            authorization => bail!(
+                "authorization for {domain} is {}: {}",
+                authorization.status,
+                authorization.problem()
+            ),
+        }
+    }
+
+    /// Answer HTTP-01 challenges with the key authorizations of pending ones.
+    async fn serve_challenges(&self, listener: TcpListener) {
+        loop {
+            let (mut stream, addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    warn!(%err, "failed to accept ACME challenge");
+                    continue;
+                }
+            };
+            let challenges = Arc::clone(&self.challenges);
+            tokio::spawn(async move {
+                if let Err(err) = answer(&mut stream, &challenges).await {
+                    debug!(%err, %addr, "ACME challenge failed");
+                }
+            });
+        }
+    }
+}
+
+async fn answer(stream: &mut TcpStream, challenges: &DashMap<String, String>) -> Result<()> {
+    let head = timeout(NETWORK_TIMEOUT, read_head(stream)).await??;
+    let path = head.split(' ').nth(1).unwrap_or_default();
+    let key_authorization = (path.strip_prefix(CHALLENGE_PATH))
+        .and_then(|token| challenges.get(token))
+        .map(|entry| entry.clone());
+    let response = match key_authorization {
+        Some(body) => format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        ),
+        None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Picks the current certificate for every handshake.
+#[derive(Debug, Default)]
+struct Resolver(RwLock<Option<Arc<CertifiedKey>>>);
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+/// URLs of the operations of an authority.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// An order or authorization, which share the fields used here.
+#[derive(Deserialize)]
+struct Resource {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: Option<String>,
+    certificate: Option<String>,
+    identifier: Option<Identifier>,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+    error: Option<Problem>,
+}
+
+impl Resource {
+    /// Describe why the resource failed, from its own error or that of its challenges.
+    fn problem(&self) -> String {
+        let challenge = self.challenges.iter().find_map(|c| c.error.as_ref());
+        match self.error.as_ref().or(challenge) {
+            Some(problem) => problem.detail.clone(),
+            None => "no reason given".into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+    error: Option<Problem>,
+}
+
+/// Error document of an authority.
+#[derive(Default, Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+/// An account with an authority, which signs each request with its key.
+struct Account<'a> {
+    http: &'a reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    jwk: Value,
+    thumbprint: String,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl<'a> Account<'a> {
+    async fn new(http: &'a reqwest::Client, url: &str, key: &KeyPair) -> Result<Self> {
+        let response = http.get(url).send().await?.error_for_status()?;
+        let directory = parse(response).await?;
+        let rng = SystemRandom::new();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key.serialize_der(), &rng)
+                .map_err(|err| anyhow!("invalid ACME account key: {err}"))?;
+        // The public key is an uncompressed point, with the coordinates after a tag byte.
+        let (x, y) = key.public_key().as_ref()[1..].split_at(32);
+        // Members are in lexicographic order without whitespace, as the thumbprint requires.
+        let jwk = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            URL_SAFE_NO_PAD.encode(x),
+            URL_SAFE_NO_PAD.encode(y)
+        );
+        let thumbprint = URL_SAFE_NO_PAD.encode(Sha256::digest(jwk.as_bytes()));
+        Ok(Account {
+            http,
+            directory,
+            key,
+            rng,
+            jwk: serde_json::from_str(&jwk)?,
+            thumbprint,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    /// Create the account, or look up the existing account of the key.
+    async fn register(&mut self, contact: Option<&str>) -> Result<()> {
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = contact {
+            payload["contact"] = json!([format!("mailto:{email}")]);
+        }
+        let url = self.directory.new_account.clone();
+        let response = self.post(&url).send(Some(payload)).await?;
+        self.kid = Some(location(&response)?);
+        Ok(())
+    }
+
+    /// Fetch an order or authorization until it is no longer pending.
+    async fn poll(&mut self, url: &str) -> Result<Resource> {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource: Resource = parse(self.post(url).send(None).await?).await?;
+            if resource.status != "pending" && resource.status != "processing" {
+                return Ok(resource);
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+        bail!("timed out waiting for the authority to validate the order")
+    }
+
+    fn post<'b>(&'b mut self, url: &'b str) -> SignedPost<'b, 'a> {
+        SignedPost { account: self, url }
+    }
+
+    async fn nonce(&mut self) -> Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response =
+            (self.http.head(&self.directory.new_nonce).send().await?).error_for_status()?;
+        replay_nonce(&response).context("authority sent no nonce")
+    }
+}
+
+/// A request signed with the account key, as every request after the directory must be.
+struct SignedPost<'b, 'a> {
+    account: &'b mut Account<'a>,
+    url: &'b str,
+}
+
+impl SignedPost<'_, '_> {
+    /// Send the request with a JSON payload, or as a POST-as-GET without one.
+    async fn send(self, payload: Option<Value>) -> Result<reqwest::Response> {
+        let account = self.account;
+        let payload = match payload {
+            Some(payload) => URL_SAFE_NO_PAD.encode(payload.to_string()),
+            None => String::new(),
+        };
+        let mut retried = false;
+        loop {
+            let mut protected =
+                json!({ "alg": "ES256", "nonce": account.nonce().await?, "url": self.url });
+            match &account.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = account.jwk.clone(),
+            }
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let signature = (account.key)
+                .sign(&account.rng, format!("{protected}.{payload}").as_bytes())
+                .map_err(|_| anyhow!("could not sign ACME request"))?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": URL_SAFE_NO_PAD.encode(signature),
+            });
+            let response = (account.http.post(self.url))
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await?;
+            account.nonce = replay_nonce(&response);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let status = response.status();
+            let problem: Problem =
+                serde_json::from_slice(&response.bytes().await?).unwrap_or_default();
+            // Nonces expire, so the authority sends a fresh one along with this error.
+            if problem.kind == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            bail!(
+                "authority refused request with {status}: {}",
+                problem.detail
+            );
+        }
+    }
+}
+
+fn replay_nonce(response: &reqwest::Response) -> Option<String> {
+    let nonce = response.headers().get("replay-nonce")?;
+    nonce.to_str().ok().map(String::from)
+}
+
+fn location(response: &reqwest::Response) -> Result<String> {
+    let location = (response.headers().get(reqwest::header::LOCATION))
+        .and_then(|location| location.to_str().ok())
+        .context("authority sent no location")?;
+    Ok(location.into())
+}
+
+async fn parse<T: DeserializeOwned>(response: reqwest::Response) -> Result<T> {
+    let body = response.bytes().await?;
+    serde_json::from_slice(&body).context("invalid response from the authority")
+}
+
+/// Load a certificate chain and private key in PEM format, returning the expiry of the
+/// certificate.
+fn certified_key(cert: &[u8], key: &[u8]) -> Result<(CertifiedKey, SystemTime)> {
+    let certs = CertificateDer::pem_slice_iter(cert)
+        .collect::<Result<Vec<_>, _>>()
+        .context("invalid certificate chain")?;
+    let leaf = certs.first().context("certificate chain is empty")?;
+    let expires = not_after(leaf).context("could not read the expiry of the certificate")?;
+    let key = PrivateKeyDer::from_pem_slice(key).context("invalid private key")?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&key)?;
+    Ok((CertifiedKey::new(certs, key), expires))
+}
+
+/// Write a file that only its owner may read, since it holds a private key or the certificate
+/// that goes with one.
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file =
+        (options.open(path)).with_context(|| format!("could not write {}", path.display()))?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+/// Read the end of the validity period of a DER-encoded X.509 certificate.
+fn not_after(cert: &[u8]) -> Option<SystemTime> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let secs = u64::try_from(cert.validity().not_after.timestamp()).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
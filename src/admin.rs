@@ -0,0 +1,53 @@
+//! Client for the administrative protocol of a `bore` server.
+
+use anyhow::{bail, Result};
+
+use crate::auth::Authenticator;
+use crate::client::ClientOptions;
+use crate::shared::{
+    AdminRequest, AdminResponse, BoxedStream, ClientMessage, Delimited, ServerMessage,
+};
+
+/// Authenticated connection used to send an administrative request to a server.
+///
+/// Administration is only available on servers that require a secret.
+pub struct AdminClient {
+    stream: Delimited<BoxedStream>,
+}
+
+impl AdminClient {
+    /// Connect to a server and authenticate with its secret.
+    pub async fn connect(to: &str, secret: &str) -> Result<Self> {
+        Self::connect_with_options(to, secret, &ClientOptions::default()).await
+    }
+
+    /// Connect to a server using the control port and transport settings of client options.
+    pub async fn connect_with_options(
+        to: &str,
+        secret: &str,
+        options: &ClientOptions,
+    ) -> Result<Self> {
+        let port = options.control_port();
+        let stream = options.connector().connect(to, port).await?;
+        let mut stream = Delimited::new(stream);
+        Authenticator::new(secret)
+            .client_handshake(&mut stream)
+            .await?;
+        Ok(AdminClient { stream })
+    }
+
+    /// Send a request, returning every entry of the reply.
+    pub async fn request(mut self, request: AdminRequest) -> Result<Vec<AdminResponse>> {
+        self.stream.send(ClientMessage::Admin(request)).await?;
+        let mut responses = Vec::new();
+        loop {
+            match self.stream.recv_timeout().await? {
+                Some(ServerMessage::Admin(AdminResponse::Done)) => return Ok(responses),
+                Some(ServerMessage::Admin(response)) => responses.push(response),
+                Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+                Some(_) => bail!("unexpected message from server"),
+                None => bail!("unexpected EOF"),
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Append-only audit log of tunnel sessions, kept apart from the operational logs.
+//!
+//! Each event is written as one line of JSON with a `timestamp` in seconds since the Unix
+//! epoch, so the log can be shipped to other systems or searched with standard tools.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tracing::warn;
+
+/// An event recorded in the audit log.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A client failed to authenticate.
+    AuthFailed {
+        /// Address of the client.
+        client: SocketAddr,
+        /// Why authentication failed.
+        reason: String,
+    },
+
+    /// A client opened a tunnel, or resumed one after reconnecting.
+    Opened {
+        /// Public port of the tunnel.
+        port: u16,
+        /// Name of the tunnel, if the client provided one.
+        name: Option<String>,
+        /// Address of the client's control connection.
+        client: SocketAddr,
+        /// Identity of the client, if it authenticated.
+        identity: Option<String>,
+        /// Whether the client took over a tunnel that was held for it to reconnect.
+        resumed: bool,
+    },
+
+    /// A client joined a shared tunnel that another client opened.
+    Joined {
+        /// Public port of the tunnel.
+        port: u16,
+        /// Name of the tunnel.
+        name: String,
+        /// Address of the client's control connection.
+        client: SocketAddr,
+        /// Identity of the client, if it authenticated.
+        identity: Option<String>,
+    },
+
+    /// A client left a shared tunnel that it had joined.
+    Left {
+        /// Public port of the tunnel.
+        port: u16,
+        /// Name of the tunnel.
+        name: String,
+        /// Address of the client's control connection.
+        client: SocketAddr,
+    },
+
+    /// A tunnel was closed and its public port released.
+    Closed {
+        /// Public port of the tunnel.
+        port: u16,
+        /// Name of the tunnel, if the client provided one.
+        name: Option<String>,
+        /// Address of the client's control connection.
+        client: SocketAddr,
+        /// Identity of the client, if it authenticated.
+        identity: Option<String>,
+        /// How long the tunnel was open since it was last opened or resumed, in seconds.
+        duration_secs: u64,
+        /// Number of visitor connections forwarded over the life of the tunnel.
+        connections: u64,
+        /// Bytes received from visitors over the life of the tunnel.
+        received: u64,
+        /// Bytes sent to visitors over the life of the tunnel.
+        sent: u64,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+    timestamp: u64,
+}
+
+/// Audit log file, which events are appended to.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Open an audit log, creating the file if it does not exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("could not open audit log {}", path.display()))?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append an event to the log, warning if it cannot be written.
+    pub fn record(&self, event: AuditEvent) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let record = Record {
+            event: &event,
+            timestamp,
+        };
+        let mut line = serde_json::to_vec(&record).expect("audit event is serializable");
+        line.push(b'\n');
+        // Each line is written at once, so lines from concurrent tunnels never interleave.
+        if let Err(err) = self.file.lock().unwrap().write_all(&line) {
+            warn!(%err, "failed to write audit log");
+        }
+    }
+}
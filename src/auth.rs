@@ -1,13 +1,49 @@
 //! Auth implementation for bore client and server.
 
-use anyhow::{bail, ensure, Result};
+use futures_util::future::BoxFuture;
 use hmac::{Hmac, Mac};
 use sha2::{Digest, Sha256};
 use tokio::io::{AsyncRead, AsyncWrite};
 use uuid::Uuid;
 
+use crate::error::{Error, Result};
 use crate::shared::{ClientMessage, Delimited, ServerMessage};
 
+/// Identity of an authenticated client, such as a user name.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Identity(pub String);
+
+/// A scheme for authenticating clients on the server.
+///
+/// The server sends each client a random challenge, and passes the client's answer to
+/// [`Authenticate::verify`]. The default scheme is [`Authenticator`], where the answer is an
+/// HMAC of the challenge keyed with a shared secret.
+pub trait Authenticate: Send + Sync {
+    /// Check a client's answer to a challenge, returning the identity of the client.
+    ///
+    /// Rejected answers should return [`Error::Handshake`].
+    fn verify<'a>(
+        &'a self,
+        challenge: &'a Uuid,
+        answer: &'a str,
+    ) -> BoxFuture<'a, Result<Identity>>;
+}
+
+/// As the server, send a challenge to the client and verify their response.
+pub async fn server_handshake<T: AsyncRead + AsyncWrite + Unpin>(
+    auth: &dyn Authenticate,
+    stream: &mut Delimited<T>,
+) -> Result<Identity> {
+    let challenge = Uuid::new_v4();
+    stream.send(ServerMessage::Challenge(challenge)).await?;
+    match stream.recv_timeout().await? {
+        Some(ClientMessage::Authenticate(answer)) => auth.verify(&challenge, &answer).await,
+        _ => Err(Error::Handshake(
+            "server requires secret, but no secret was provided".into(),
+        )),
+    }
+}
+
 /// Wrapper around a MAC used for authenticating clients that have a secret.
 pub struct Authenticator(Hmac<Sha256>);
 
@@ -52,15 +88,8 @@ impl Authenticator {
         &self,
         stream: &mut Delimited<T>,
     ) -> Result<()> {
-        let challenge = Uuid::new_v4();
-        stream.send(ServerMessage::Challenge(challenge)).await?;
-        match stream.recv_timeout().await? {
-            Some(ClientMessage::Authenticate(tag)) => {
-                ensure!(self.validate(&challenge, &tag), "invalid secret");
-                Ok(())
-            }
-            _ => bail!("server requires secret, but no secret was provided"),
-        }
+        server_handshake(self, stream).await?;
+        Ok(())
     }
 
     /// As the client, answer a challenge to attempt to authenticate with the server.
@@ -70,10 +99,30 @@ impl Authenticator {
     ) -> Result<()> {
         let challenge = match stream.recv_timeout().await? {
             Some(ServerMessage::Challenge(challenge)) => challenge,
-            _ => bail!("expected authentication challenge, but no secret was required"),
+            _ => {
+                return Err(Error::Handshake(
+                    "expected authentication challenge, but no secret was required".into(),
+                ))
+            }
         };
         let tag = self.answer(&challenge);
         stream.send(ClientMessage::Authenticate(tag)).await?;
         Ok(())
     }
 }
+
+impl Authenticate for Authenticator {
+    /// Validate the answer, identifying every client that knows the secret as `secret`.
+    fn verify<'a>(
+        &'a self,
+        challenge: &'a Uuid,
+        answer: &'a str,
+    ) -> BoxFuture<'a, Result<Identity>> {
+        Box::pin(async move {
+            match self.validate(challenge, answer) {
+                true => Ok(Identity("secret".into())),
+                false => Err(Error::Handshake("invalid secret".into())),
+            }
+        })
+    }
+}
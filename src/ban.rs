@@ -0,0 +1,185 @@
+//! Temporary bans of source IPs that repeatedly fail authentication, or that an operator bans.
+//!
+//! Banned IPs can neither open control connections nor connect to tunnels as visitors. Bans may
+//! be persisted in a JSON file that maps each IP to the Unix time at which its ban expires.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use tracing::warn;
+
+/// Policy for banning IPs after repeated authentication failures.
+#[derive(Clone, Copy, Debug)]
+pub struct BanPolicy {
+    /// Number of failures within the window that triggers a ban.
+    pub threshold: u32,
+
+    /// Length of the window in which failures are counted.
+    pub window: Duration,
+
+    /// How long a ban lasts.
+    pub duration: Duration,
+}
+
+/// Tracks authentication failures and active bans per source IP.
+#[derive(Debug, Default)]
+pub struct BanList {
+    policy: Option<BanPolicy>,
+    failures: DashMap<IpAddr, VecDeque<Instant>>,
+    bans: DashMap<IpAddr, Instant>,
+    path: Option<PathBuf>,
+    /// Held while the ban file is written, so that concurrent saves do not mix.
+    saving: Mutex<()>,
+}
+
+impl BanList {
+    /// Create a ban list that bans IPs according to a policy.
+    pub fn new(policy: BanPolicy) -> Self {
+        BanList {
+            policy: Some(policy),
+            ..Default::default()
+        }
+    }
+
+    /// Ban IPs that repeatedly fail to authenticate according to a policy.
+    pub fn set_policy(&mut self, policy: BanPolicy) {
+        self.policy = Some(policy);
+    }
+
+    /// Persist bans in a JSON file, loading the bans saved there that have not expired.
+    pub fn open(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref().to_path_buf();
+        match fs::read(&path) {
+            Ok(data) => {
+                let saved: BTreeMap<IpAddr, u64> = serde_json::from_slice(&data)
+                    .with_context(|| format!("invalid ban file {}", path.display()))?;
+                let (now, unix_now) = (Instant::now(), unix_time());
+                for (ip, expires) in saved {
+                    if expires > unix_now {
+                        self.bans
+                            .insert(ip, now + Duration::from_secs(expires - unix_now));
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("could not read {}", path.display()))
+            }
+        }
+        self.path = Some(path);
+        Ok(())
+    }
+
+    /// Returns whether an IP is currently banned.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        self.bans.remove_if(&ip, |_, until| *until <= now);
+        self.bans.contains_key(&ip)
+    }
+
+    /// Record an authentication failure, returning true if it caused a new ban.
+    pub fn record_failure(&self, ip: IpAddr) -> bool {
+        let Some(policy) = self.policy else {
+            return false;
+        };
+        let now = Instant::now();
+        let mut failures = self.failures.entry(ip).or_default();
+        while failures
+            .front()
+            .is_some_and(|time| now.duration_since(*time) > policy.window)
+        {
+            failures.pop_front();
+        }
+        failures.push_back(now);
+        if failures.len() < policy.threshold as usize {
+            return false;
+        }
+        drop(failures);
+        self.ban(ip, policy.duration);
+        true
+    }
+
+    /// Ban an IP for a duration, replacing any ban it already has.
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        self.failures.remove(&ip);
+        self.bans.insert(ip, Instant::now() + duration);
+        self.save();
+    }
+
+    /// Returns the currently banned IPs with the time remaining on each ban.
+    pub fn bans(&self) -> Vec<(IpAddr, Duration)> {
+        let now = Instant::now();
+        let mut bans: Vec<_> = self
+            .bans
+            .iter()
+            .filter(|entry| *entry.value() > now)
+            .map(|entry| (*entry.key(), entry.value().duration_since(now)))
+            .collect();
+        bans.sort();
+        bans
+    }
+
+    /// Lift the ban on an IP, returning whether it was banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.failures.remove(&ip);
+        let banned = self.bans.remove(&ip).is_some();
+        self.save();
+        banned
+    }
+
+    /// Lift all bans and forget all recorded failures.
+    pub fn clear(&self) {
+        self.failures.clear();
+        self.bans.clear();
+        self.save();
+    }
+
+    /// Write the current bans to the ban file, if there is one.
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let _saving = self.saving.lock().unwrap();
+        let unix_now = unix_time();
+        let saved: BTreeMap<IpAddr, u64> = self
+            .bans()
+            .into_iter()
+            .map(|(ip, remaining)| (ip, unix_now + remaining.as_secs().max(1)))
+            .collect();
+        // Write to a temporary file first, so that a crash never leaves a truncated file.
+        let tmp = path.with_extension("tmp");
+        let result = fs::write(&tmp, serde_json::to_vec_pretty(&saved).unwrap())
+            .and_then(|()| fs::rename(&tmp, path));
+        if let Err(err) = result {
+            warn!(%err, "failed to save bans");
+        }
+    }
+
+    /// Forget expired bans and failures outside the window, bounding memory use.
+    pub fn purge(&self) {
+        let now = Instant::now();
+        self.bans.retain(|_, until| *until > now);
+        if let Some(policy) = self.policy {
+            self.failures.retain(|_, failures| {
+                failures
+                    .back()
+                    .is_some_and(|time| now.duration_since(*time) <= policy.window)
+            });
+        }
+    }
+}
+
+/// Returns the number of seconds since the Unix epoch.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,139 @@
+//! HTTP basic authentication of visitors, checked by the client before the local service.
+//!
+//! The first request on each visitor connection must carry the username and password in an
+//! `Authorization` header, or the visitor gets a `401 Unauthorized` response and the connection
+//! is closed without reaching the local service. Browsers prompt for the credentials once and
+//! send them with every request after that.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+use crate::shared::NETWORK_TIMEOUT;
+
+/// Largest request head that is read while looking for credentials.
+const MAX_HEAD_LENGTH: usize = 64 * 1024;
+
+/// Maximum number of headers parsed in a request.
+const MAX_HEADERS: usize = 100;
+
+const UNAUTHORIZED: &str = "HTTP/1.1 401 Unauthorized\r\n\
+    WWW-Authenticate: Basic realm=\"bore\", charset=\"UTF-8\"\r\n\
+    Content-Length: 0\r\nConnection: close\r\n\r\n";
+
+const BAD_REQUEST: &str =
+    "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// A username and password that visitors must send.
+#[derive(Clone, PartialEq, Eq)]
+pub struct BasicAuth {
+    username: String,
+    password: String,
+}
+
+impl BasicAuth {
+    /// Require visitors to send a username and password.
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        BasicAuth {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// Check whether the value of an `Authorization` header carries the credentials.
+    ///
+    /// ```
+    /// use bore_cli::basic_auth::BasicAuth;
+    ///
+    /// let auth = BasicAuth::new("admin", "hunter2");
+    /// assert!(auth.accepts("Basic YWRtaW46aHVudGVyMg=="));
+    /// assert!(auth.accepts("basic   YWRtaW46aHVudGVyMg=="));
+    /// assert!(!auth.accepts("Basic YWRtaW46aHVudGVyMw=="));
+    /// assert!(!auth.accepts("Bearer YWRtaW46aHVudGVyMg=="));
+    /// ```
+    pub fn accepts(&self, header: &str) -> bool {
+        let Some((scheme, credentials)) = header.trim().split_once(' ') else {
+            return false;
+        };
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return false;
+        }
+        let Ok(credentials) = STANDARD.decode(credentials.trim()) else {
+            return false;
+        };
+        let expected = format!("{}:{}", self.username, self.password);
+        // Comparing digests keeps the time taken independent of where the credentials differ.
+        Sha256::digest(credentials) == Sha256::digest(expected)
+    }
+
+    /// Read the head of the first request from a visitor, and check its credentials.
+    ///
+    /// Returns the bytes read, to be forwarded to the local service, if the visitor is
+    /// authorized. Otherwise, responds with an error and returns `None`.
+    pub async fn check<S>(&self, stream: &mut S) -> io::Result<Option<Vec<u8>>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut buf = Vec::new();
+        let authorized = loop {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            match httparse::Request::new(&mut headers).parse(&buf) {
+                Ok(httparse::Status::Complete(_)) => {
+                    break headers.iter().any(|header| {
+                        header.name.eq_ignore_ascii_case("authorization")
+                            && std::str::from_utf8(header.value).is_ok_and(|v| self.accepts(v))
+                    });
+                }
+                Ok(httparse::Status::Partial) if buf.len() <= MAX_HEAD_LENGTH => (),
+                _ => {
+                    stream.write_all(BAD_REQUEST.as_bytes()).await?;
+                    stream.shutdown().await?;
+                    return Ok(None);
+                }
+            }
+            let mut chunk = [0u8; 4096];
+            let n = match timeout(NETWORK_TIMEOUT, stream.read(&mut chunk)).await {
+                Ok(result) => result?,
+                Err(_) => return Ok(None),
+            };
+            if n == 0 {
+                return Ok(None);
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        };
+        if !authorized {
+            stream.write_all(UNAUTHORIZED.as_bytes()).await?;
+            stream.shutdown().await?;
+            return Ok(None);
+        }
+        Ok(Some(buf))
+    }
+}
+
+impl FromStr for BasicAuth {
+    type Err = anyhow::Error;
+
+    /// Parse credentials given as `username:password`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some((username, password)) if !username.is_empty() => {
+                Ok(BasicAuth::new(username, password))
+            }
+            _ => bail!("expected credentials like `username:password`"),
+        }
+    }
+}
+
+impl fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
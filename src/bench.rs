@@ -0,0 +1,227 @@
+//! Benchmark of the throughput and latency of a tunnel, as run by `bore bench`.
+//!
+//! The benchmark opens a tunnel to a small service of its own, and then connects to the public
+//! port like a visitor would. The first byte that a visitor sends picks what the service does
+//! with the connection, so a single tunnel serves every measurement.
+
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tracing::debug;
+
+use crate::client::{Client, ClientOptions};
+use crate::error::{Error, Result};
+use crate::shared::NETWORK_TIMEOUT;
+
+/// Asks the service to send the byte back, for measuring latency.
+const ECHO: u8 = b'e';
+
+/// Asks the service to read until the end of the stream, and reply with the number of bytes.
+const SINK: u8 = b'u';
+
+/// Asks the service to send as many bytes as the number that follows.
+const SOURCE: u8 = b'd';
+
+/// What to measure in a benchmark.
+#[derive(Clone, Debug)]
+pub struct BenchOptions {
+    /// Number of connections opened to measure latency.
+    pub connections: usize,
+
+    /// Number of bytes sent in each direction to measure throughput.
+    pub size: u64,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            connections: 20,
+            size: 64 << 20,
+        }
+    }
+}
+
+/// Results of a benchmark.
+#[derive(Clone, Debug, Serialize)]
+pub struct BenchReport {
+    /// Public port of the tunnel that was measured.
+    pub port: u16,
+
+    /// Time to open a connection to the public port.
+    pub connect: Percentiles,
+
+    /// Time from starting to connect until the first byte came back through the tunnel.
+    pub first_byte: Percentiles,
+
+    /// Number of bytes sent in each direction.
+    pub bytes: u64,
+
+    /// Seconds taken to send the bytes from a visitor to the local service.
+    pub upload_secs: f64,
+
+    /// Seconds taken to send the bytes from the local service to a visitor.
+    pub download_secs: f64,
+}
+
+impl BenchReport {
+    /// Returns the throughput from visitors to the local service, in bytes per second.
+    pub fn upload_rate(&self) -> f64 {
+        self.bytes as f64 / self.upload_secs
+    }
+
+    /// Returns the throughput from the local service to visitors, in bytes per second.
+    pub fn download_rate(&self) -> f64 {
+        self.bytes as f64 / self.download_secs
+    }
+}
+
+/// Summary of latency samples, in microseconds.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Percentiles {
+    /// Median sample.
+    pub p50_us: u64,
+
+    /// Sample that 90% of the others do not exceed.
+    pub p90_us: u64,
+
+    /// Slowest sample.
+    pub max_us: u64,
+}
+
+impl Percentiles {
+    fn new(mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        let rank = |percent: usize| {
+            let index = (samples.len() * percent).div_ceil(100).max(1) - 1;
+            samples
+                .get(index)
+                .map_or(0, |sample| sample.as_micros() as u64)
+        };
+        Percentiles {
+            p50_us: rank(50),
+            p90_us: rank(90),
+            max_us: rank(100),
+        }
+    }
+}
+
+/// Open a tunnel through a server and measure its latency and throughput.
+pub async fn run(
+    to: &str,
+    secret: Option<&str>,
+    client_options: ClientOptions,
+    options: &BenchOptions,
+) -> Result<BenchReport> {
+    let service = TcpListener::bind("127.0.0.1:0").await?;
+    let local_port = service.local_addr()?.port();
+    let client =
+        Client::with_options("127.0.0.1", local_port, to, 0, secret, client_options).await?;
+    let port = client.remote_port();
+    // Both tasks are aborted when the set is dropped, closing the tunnel.
+    let mut tasks = JoinSet::new();
+    tasks.spawn(serve(service));
+    tasks.spawn(async move {
+        if let Err(err) = client.listen().await {
+            debug!(%err, "benchmark tunnel closed");
+        }
+    });
+
+    let mut connect = Vec::with_capacity(options.connections);
+    let mut first_byte = Vec::with_capacity(options.connections);
+    for _ in 0..options.connections {
+        let start = Instant::now();
+        let mut stream = visit(to, port).await?;
+        connect.push(start.elapsed());
+        stream.write_u8(ECHO).await?;
+        match timeout(NETWORK_TIMEOUT, stream.read_u8()).await {
+            Ok(Ok(ECHO)) => first_byte.push(start.elapsed()),
+            Ok(Ok(_)) => return Err(Error::Protocol("unexpected reply from the tunnel".into())),
+            Ok(Err(err)) => return Err(err.into()),
+            Err(_) => return Err(Error::Timeout("no reply through the tunnel".into())),
+        }
+    }
+
+    let start = Instant::now();
+    let mut stream = visit(to, port).await?;
+    stream.write_u8(SINK).await?;
+    io::copy(&mut io::repeat(0).take(options.size), &mut stream).await?;
+    stream.shutdown().await?;
+    let received = stream.read_u64().await?;
+    let upload = start.elapsed();
+    check_size(received, options.size)?;
+
+    let start = Instant::now();
+    let mut stream = visit(to, port).await?;
+    stream.write_u8(SOURCE).await?;
+    stream.write_u64(options.size).await?;
+    let received = io::copy(&mut stream, &mut io::sink()).await?;
+    let download = start.elapsed();
+    check_size(received, options.size)?;
+
+    Ok(BenchReport {
+        port,
+        connect: Percentiles::new(connect),
+        first_byte: Percentiles::new(first_byte),
+        bytes: options.size,
+        upload_secs: upload.as_secs_f64(),
+        download_secs: download.as_secs_f64(),
+    })
+}
+
+/// Connect to the public port of the tunnel.
+async fn visit(to: &str, port: u16) -> Result<TcpStream> {
+    match timeout(NETWORK_TIMEOUT, TcpStream::connect((to, port))).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(source)) => Err(Error::Connect {
+            host: to.into(),
+            port,
+            source,
+        }),
+        Err(_) => Err(Error::Timeout(format!(
+            "timed out connecting to {to}:{port}"
+        ))),
+    }
+}
+
+fn check_size(received: u64, size: u64) -> Result<()> {
+    match received == size {
+        true => Ok(()),
+        false => Err(Error::Protocol(format!(
+            "tunnel delivered {received} of {size} bytes"
+        ))),
+    }
+}
+
+/// Serve benchmark connections from the tunnel.
+async fn serve(listener: TcpListener) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream).await {
+                debug!(%err, "benchmark connection failed");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> io::Result<()> {
+    match stream.read_u8().await? {
+        ECHO => stream.write_u8(ECHO).await?,
+        SINK => {
+            let received = io::copy(&mut stream, &mut io::sink()).await?;
+            stream.write_u64(received).await?;
+        }
+        SOURCE => {
+            let size = stream.read_u64().await?;
+            io::copy(&mut io::repeat(0).take(size), &mut stream).await?;
+        }
+        _ => {}
+    }
+    stream.shutdown().await
+}
@@ -1,25 +1,408 @@
 //! Client implementation for the `bore` service.
 
+use std::collections::HashSet;
+use std::future::pending;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::{bail, Context, Result};
-use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
-use tracing::{error, info, info_span, warn, Instrument};
+use anyhow::{anyhow, bail};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use ipnet::IpNet;
+use rustls::{ClientConfig, ServerConfig};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, sleep_until, timeout};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 use uuid::Uuid;
 
 use crate::auth::Authenticator;
+use crate::basic_auth::BasicAuth;
+use crate::error::{Error, ErrorCode, Result};
+use crate::event::{self, ClientEvent};
+use crate::hook::EnvFile;
+use crate::inspect::Inspector;
+use crate::ports::PortList;
+use crate::rewrite::{Edits, Forwarded, HostHeader, RewriteRequests};
 use crate::shared::{
-    proxy, ClientMessage, Delimited, ServerMessage, CONTROL_PORT, NETWORK_TIMEOUT,
+    proxy, BoxedStream, ClientMessage, Delimited, Prefixed, ServerInfo, ServerMessage,
+    TunnelRequest, AUTH_FAILED, CONTROL_PORT, DEFAULT_BUFFER_SIZE, MAX_METADATA_LENGTH,
+    MAX_NAME_LENGTH, MAX_POOL_SIZE, NETWORK_TIMEOUT, TLS_CONTROL_PORT, VERSION_REQUIRED,
 };
+use crate::stats::Stats;
+use crate::status::{ClientStatus, ConnectionState};
+use crate::throttle::{Bandwidth, ByteRate, Throttled};
+use crate::tls;
+use crate::transport::{Connector, TcpConnector};
+
+/// Delay before replacing a pooled data connection that failed.
+const POOL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Interval at which a client updates its status file.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time to wait on a connection attempt before also trying the server's next address.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Optional settings for a client, beyond the required connection parameters.
+#[derive(Clone, Debug, Default)]
+pub struct ClientOptions {
+    /// Stable name for the tunnel, so the server can reassign the same port on reconnect.
+    pub name: Option<String>,
+
+    /// Ask the server for a token to resume an unnamed tunnel after a disconnect, available
+    /// from [`Client::resume_token`].
+    pub resumable: bool,
+
+    /// Token of an earlier client, to take over the port of its tunnel if the server still
+    /// holds it.
+    pub resume_token: Option<Uuid>,
+
+    /// Share the named tunnel with other clients that set this too, so that the server hands
+    /// each visitor to one of them in turn, and keeps the tunnel open while any is connected.
+    ///
+    /// This has no effect on unnamed tunnels.
+    pub shared: bool,
+
+    /// Description of the tunnel, shown to the server's operators in listings and logs.
+    pub description: Option<String>,
+
+    /// Host name of this machine, shown to the server's operators in listings and logs.
+    pub client_host: Option<String>,
+
+    /// Show the tunnel's name, port, and description in the public listing of servers that
+    /// publish one.
+    pub public_listing: bool,
+
+    /// Ask for the tunnel's subdomain, which servers with a domain assign, available from
+    /// [`Client::hostname`].
+    ///
+    /// Any request beyond a plain port asks for it anyway, so this only matters to tunnels with
+    /// no other options.
+    pub subdomain: bool,
+
+    /// Lifetime of the tunnel, after which the client closes it and servers that support this
+    /// release its port, even if the client is still connected.
+    pub expires: Option<Duration>,
+
+    /// Bytes that visitors may transfer in both directions, after which servers that support
+    /// this close the tunnel.
+    pub transfer_limit: Option<u64>,
+
+    /// TLS configuration, if control connections to the server are encrypted.
+    pub tls: Option<Arc<ClientConfig>>,
+
+    /// Control port of the server, defaulting to [`CONTROL_PORT`], or
+    /// [`TLS_CONTROL_PORT`] when TLS is enabled.
+    pub control_port: Option<u16>,
+
+    /// Inspector recording the HTTP traffic of the tunnel.
+    pub inspector: Option<Arc<Inspector>>,
+
+    /// TLS configuration for terminating visitor connections before the local service.
+    pub terminate_tls: Option<Arc<ServerConfig>>,
+
+    /// TLS configuration, if the local service expects encrypted connections.
+    pub local_tls: Option<Arc<ClientConfig>>,
+
+    /// Credentials that visitors of an HTTP service must send, checked before the local
+    /// service is reached.
+    pub basic_auth: Option<BasicAuth>,
+
+    /// Add `X-Forwarded-For`, `X-Forwarded-Proto`, and `Forwarded` headers describing the
+    /// visitor to each HTTP request.
+    ///
+    /// The visitor's address is left out if the server is too old to send it.
+    pub forwarded_headers: bool,
+
+    /// What the `Host` header of HTTP requests is set to before they reach the local service.
+    pub host_header: HostHeader,
+
+    /// Send a PROXY protocol (version 1) header describing the visitor to the local service
+    /// before any data, for services like HAProxy or nginx that read it.
+    ///
+    /// The header says `UNKNOWN` if the server is too old to send the visitor's address.
+    pub proxy_protocol: bool,
+
+    /// Filter on the addresses of visitors, which requires the server to send them.
+    pub ip_filter: IpFilter,
+
+    /// Local source address for connections to the server.
+    pub bind_addr: Option<IpAddr>,
+
+    /// Accept a random port from the server if the requested one is unavailable.
+    pub fallback_random: bool,
+
+    /// Number of idle data connections kept open for new visitors, up to [`MAX_POOL_SIZE`].
+    pub pool_size: usize,
+
+    /// Interval at which to log a summary of transfer statistics.
+    pub stats_interval: Option<Duration>,
+
+    /// Ports to accept from the server when any available port is requested.
+    pub port_range: Option<PortList>,
+
+    /// Time without messages from the server after which the connection is considered dead.
+    ///
+    /// The server sends heartbeats about twice a second, so this can be a few seconds.
+    pub heartbeat_timeout: Option<Duration>,
+
+    /// Interval at which to resolve the server's host name again while connected.
+    ///
+    /// The host name is always resolved afresh when connecting. With this set, the client also
+    /// ends a connection once none of the addresses the name resolved to when it connected are
+    /// among its current ones, so that reconnecting follows the server to its new address.
+    pub resolve_interval: Option<Duration>,
+
+    /// Server address to bind the public port on, such as loopback for a private tunnel.
+    pub remote_bind: Option<IpAddr>,
+
+    /// Answer heartbeats from the server, so both sides measure the round-trip time of the
+    /// control connection, available from [`Client::stats`].
+    ///
+    /// Servers that do not support this keep sending plain heartbeats, and nothing is measured.
+    pub measure_rtt: bool,
+
+    /// Size of the buffer copying each direction of a visitor connection, defaulting to
+    /// [`DEFAULT_BUFFER_SIZE`].
+    pub proxy_buffer_size: Option<usize>,
+
+    /// Maximum bandwidth sent to visitors, across all connections.
+    pub max_up: Option<ByteRate>,
+
+    /// Maximum bandwidth received from visitors, across all connections.
+    pub max_down: Option<ByteRate>,
+
+    /// Token that stops the client when cancelled, closing its control connection.
+    ///
+    /// The client says goodbye first, so the server releases the port at once instead of holding
+    /// it for a reconnect. Visitor connections in progress are unaffected.
+    pub shutdown: CancellationToken,
+
+    /// Channel receiving lifecycle events, such as one created by [`event::channel`].
+    pub events: Option<broadcast::Sender<ClientEvent>>,
+
+    /// File in which to keep the address of the tunnel, written each time the client connects.
+    pub env_file: Option<EnvFile>,
+
+    /// File in which to keep a [`ClientStatus`] document while listening, updated every second.
+    pub status_file: Option<PathBuf>,
+
+    /// Custom transport for connections to the server, replacing TCP with the `tls` and
+    /// `bind_addr` settings.
+    pub connector: Option<Arc<dyn Connector>>,
+}
+
+impl ClientOptions {
+    /// Returns the transport for connections to the server.
+    pub fn connector(&self) -> Arc<dyn Connector> {
+        match &self.connector {
+            Some(connector) => Arc::clone(connector),
+            None => Arc::new(TcpConnector {
+                tls: self.tls.clone(),
+                bind_addr: self.bind_addr,
+            }),
+        }
+    }
+
+    /// Returns the control port of the server to connect to.
+    pub fn control_port(&self) -> u16 {
+        self.control_port.unwrap_or(match self.tls {
+            Some(_) => TLS_CONTROL_PORT,
+            None => CONTROL_PORT,
+        })
+    }
+}
+
+/// Rules for accepting visitors by IP address.
+///
+/// A visitor is rejected if it matches any denied network, or if there are allowed networks
+/// and it matches none of them.
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    /// Networks to accept visitors from, or empty to accept any address.
+    pub allow: Vec<IpNet>,
+
+    /// Networks to reject visitors from.
+    pub deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// Returns whether the filter has no rules and accepts every visitor.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Check whether a visitor's address is accepted by the filter.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+    }
+}
+
+/// Scheme of the service behind a tunnel, used to print its public URL.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    /// Plain HTTP.
+    Http,
+
+    /// HTTP over TLS.
+    Https,
+
+    /// Raw TCP.
+    Tcp,
+}
+
+impl Scheme {
+    /// Returns the URL of a service at a host and port, omitting the default port.
+    ///
+    /// ```
+    /// use bore_cli::client::Scheme;
+    ///
+    /// assert_eq!(Scheme::Http.url("bore.pub", 80), "http://bore.pub");
+    /// assert_eq!(Scheme::Https.url("bore.pub", 35261), "https://bore.pub:35261");
+    /// assert_eq!(Scheme::Tcp.url("::1", 5432), "tcp://[::1]:5432");
+    /// ```
+    pub fn url(self, host: &str, port: u16) -> String {
+        let (scheme, default_port) = match self {
+            Scheme::Http => ("http", Some(80)),
+            Scheme::Https => ("https", Some(443)),
+            Scheme::Tcp => ("tcp", None),
+        };
+        let host = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{ip}]"),
+            _ => host.to_string(),
+        };
+        match default_port == Some(port) {
+            true => format!("{scheme}://{host}"),
+            false => format!("{scheme}://{host}:{port}"),
+        }
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "http" => Ok(Scheme::Http),
+            "https" => Ok(Scheme::Https),
+            "tcp" => Ok(Scheme::Tcp),
+            _ => bail!("unknown scheme `{s}`, expected `http`, `https`, or `tcp`"),
+        }
+    }
+}
+
+/// Configuration of a client that connects only when asked, made by [`Client::lazy`].
+///
+/// It can connect any number of times, such as to retry after a failure, and each successful
+/// connection is a new [`Client`].
+#[derive(Clone, Debug)]
+pub struct LazyClient {
+    local_host: String,
+    local_port: u16,
+    to: String,
+    port: u16,
+    secret: Option<String>,
+    options: ClientOptions,
+}
+
+impl LazyClient {
+    /// Connect to the server and request the tunnel.
+    pub async fn connect(&self) -> Result<Client> {
+        Client::with_options(
+            &self.local_host,
+            self.local_port,
+            &self.to,
+            self.port,
+            self.secret.as_deref(),
+            self.options.clone(),
+        )
+        .await
+    }
+
+    /// Returns the options of the next connection, to change them between connections.
+    pub(crate) fn options_mut(&mut self) -> &mut ClientOptions {
+        &mut self.options
+    }
+}
 
 /// State structure for the client.
 pub struct Client {
     /// Control connection to the server.
-    conn: Option<Delimited<TcpStream>>,
+    conn: Option<Delimited<BoxedStream>>,
 
     /// Destination address of the server.
     to: String,
 
+    /// Control port of the server.
+    control_port: u16,
+
+    /// Transport for connections to the server.
+    connector: Arc<dyn Connector>,
+
+    /// Optional inspector recording HTTP traffic.
+    inspector: Option<Arc<Inspector>>,
+
+    /// TLS configuration, if visitor connections are decrypted by the client.
+    terminate_tls: Option<Arc<ServerConfig>>,
+
+    /// TLS configuration, if connections to the local service are encrypted.
+    local_tls: Option<Arc<ClientConfig>>,
+
+    /// Credentials that visitors must send, if any.
+    basic_auth: Option<BasicAuth>,
+
+    /// Whether to describe visitors in forwarding headers of their HTTP requests.
+    forwarded_headers: bool,
+
+    /// Value replacing the `Host` header of HTTP requests, if it is changed.
+    host_header: Option<String>,
+
+    /// Whether to describe visitors in a PROXY protocol header to the local service.
+    proxy_protocol: bool,
+
+    /// Filter on the addresses of visitors.
+    ip_filter: IpFilter,
+
+    /// Token and size of the pool of idle data connections, if the server supports it.
+    pool: Option<(Uuid, usize)>,
+
+    /// Transfer statistics of forwarded connections.
+    stats: Arc<Stats>,
+
+    /// Interval at which to log transfer statistics, if enabled.
+    stats_interval: Option<Duration>,
+
+    /// Time without messages after which the server is considered dead, if enabled.
+    heartbeat_timeout: Option<Duration>,
+
+    /// Interval at which to check the server's host name for new addresses, if enabled.
+    resolve_interval: Option<Duration>,
+
+    /// Size of the buffer copying each direction of a visitor connection.
+    buffer_size: usize,
+
+    /// Bandwidth limit on traffic sent to visitors.
+    max_up: Option<Arc<Bandwidth>>,
+
+    /// Bandwidth limit on traffic received from visitors.
+    max_down: Option<Arc<Bandwidth>>,
+
+    /// Token that stops the client when cancelled.
+    shutdown: CancellationToken,
+
+    /// Optional channel receiving lifecycle events.
+    events: Option<broadcast::Sender<ClientEvent>>,
+
     // Local host that is forwarded.
     local_host: String,
 
@@ -29,8 +412,27 @@ pub struct Client {
     /// Port that is publicly available on the remote.
     remote_port: u16,
 
+    /// Secret token for resuming the tunnel after a disconnect, if the server issued one.
+    resume_token: Option<Uuid>,
+
+    /// Host name of the tunnel, if the server assigned one.
+    hostname: Option<String>,
+
+    /// Time at which the tunnel expires, if it has a lifetime.
+    expires_at: Option<Instant>,
+
+    /// Description of the server, if it sent one.
+    info: Option<ServerInfo>,
+
     /// Optional secret used to authenticate clients.
     auth: Option<Authenticator>,
+
+    /// File in which to keep the status of the tunnel, if any.
+    status_file: Option<PathBuf>,
+
+    /// Whether the server challenges connections of this client although it has no secret,
+    /// which servers that leave some ports open do.
+    challenged: bool,
 }
 
 impl Client {
@@ -42,32 +444,294 @@ impl Client {
         port: u16,
         secret: Option<&str>,
     ) -> Result<Self> {
-        let mut stream = Delimited::new(connect_with_timeout(to, CONTROL_PORT).await?);
+        let options = ClientOptions::default();
+        Self::with_options(local_host, local_port, to, port, secret, options).await
+    }
+
+    /// Store the configuration of a client without connecting, to connect later with
+    /// [`LazyClient::connect`].
+    pub fn lazy(
+        local_host: &str,
+        local_port: u16,
+        to: &str,
+        port: u16,
+        secret: Option<&str>,
+        options: ClientOptions,
+    ) -> LazyClient {
+        LazyClient {
+            local_host: local_host.into(),
+            local_port,
+            to: to.into(),
+            port,
+            secret: secret.map(String::from),
+            options,
+        }
+    }
+
+    /// Create a new client with additional options.
+    pub async fn with_options(
+        local_host: &str,
+        local_port: u16,
+        to: &str,
+        port: u16,
+        secret: Option<&str>,
+        options: ClientOptions,
+    ) -> Result<Self> {
+        if let Some(name) = &options.name {
+            if name.len() > MAX_NAME_LENGTH {
+                return Err(anyhow!("tunnel name is too long").into());
+            }
+        }
+        let metadata = [
+            ("description", &options.description),
+            ("client host name", &options.client_host),
+        ];
+        for (field, value) in metadata {
+            if value
+                .as_ref()
+                .is_some_and(|value| value.len() > MAX_METADATA_LENGTH)
+            {
+                return Err(anyhow!("{field} is too long").into());
+            }
+        }
+        if options.pool_size > MAX_POOL_SIZE {
+            return Err(anyhow!("connection pool is too large").into());
+        }
+        let control_port = options.control_port();
+        let connector = options.connector();
+        let stream = connector.connect(to, control_port).await?;
+        let mut stream = Delimited::new(stream);
         let auth = secret.map(Authenticator::new);
         if let Some(auth) = &auth {
-            auth.client_handshake(&mut stream).await?;
+            let handshake = auth.client_handshake(&mut stream);
+            handshake.instrument(info_span!("handshake")).await?;
         }
 
-        stream.send(ClientMessage::Hello(port)).await?;
-        let remote_port = match stream.recv_timeout().await? {
-            Some(ServerMessage::Hello(remote_port)) => remote_port,
-            Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
-            Some(ServerMessage::Challenge(_)) => {
-                bail!("server requires authentication, but no client secret was provided");
+        // The lifetime starts before the request is sent, so the client closes the tunnel no
+        // later than the server does.
+        let expires_at = options.expires.map(|expires| Instant::now() + expires);
+        let mut request = TunnelRequest {
+            port,
+            name: options.name,
+            visitor_addr: !options.ip_filter.is_empty()
+                || options.forwarded_headers
+                || options.proxy_protocol,
+            fallback_random: options.fallback_random,
+            pool: options.pool_size > 0,
+            port_range: options.port_range,
+            bind_addr: options.remote_bind,
+            measure_rtt: options.measure_rtt,
+            resumable: options.resumable,
+            resume_token: options.resume_token,
+            shared: options.shared,
+            server_info: false,
+            error_codes: false,
+            shutdown_notice: false,
+            hostname: options.subdomain,
+            expires_secs: options.expires.map(|expires| expires.as_secs().max(1)),
+            transfer_limit: options.transfer_limit,
+            close_notice: false,
+            description: options.description,
+            client_host: options.client_host,
+            public_listing: options.public_listing,
+            client_version: None,
+        };
+        let mut info = None;
+        let mut challenged = false;
+        let mut plain = request.is_plain();
+        let assign = async {
+            loop {
+                if plain {
+                    stream.send(ClientMessage::Hello(port)).await?;
+                } else {
+                    // Servers that understand extended requests describe themselves first, refuse
+                    // with error codes, name the tunnel, and give notice before shutting down,
+                    // which older ones skip.
+                    request.server_info = true;
+                    request.error_codes = true;
+                    request.shutdown_notice = true;
+                    request.close_notice = true;
+                    request.hostname = true;
+                    request.client_version = Some(env!("CARGO_PKG_VERSION").into());
+                    stream
+                        .send(ClientMessage::Open(mem::take(&mut request)))
+                        .await?;
+                }
+                let mut message = stream.recv_timeout().await?;
+                // Servers that leave some ports open challenge clients without a secret all the
+                // same, and take their request in place of an answer.
+                if let (None, Some(ServerMessage::Challenge(_))) = (&auth, &message) {
+                    challenged = true;
+                    message = stream.recv_timeout().await?;
+                }
+                if let Some(ServerMessage::Info(server)) = message {
+                    debug!(version = server.version, features = ?server.features, "server info");
+                    info = Some(server);
+                    message = stream.recv_timeout().await?;
+                }
+                return match message {
+                    Some(ServerMessage::Hello(remote_port)) => Ok(remote_port),
+                    // Plain requests carry no version, so ask again on a new connection with one.
+                    Some(ServerMessage::Error(message))
+                        if plain && message.starts_with(VERSION_REQUIRED) =>
+                    {
+                        debug!("server requires client version, sending extended request");
+                        stream = Delimited::new(connector.connect(to, control_port).await?);
+                        if let Some(auth) = &auth {
+                            auth.client_handshake(&mut stream).await?;
+                        }
+                        challenged = false;
+                        plain = false;
+                        continue;
+                    }
+                    Some(ServerMessage::Error(message)) => {
+                        let mut err = server_error(message);
+                        if challenged && matches!(err, Error::Handshake(_)) {
+                            return Err(missing_secret());
+                        }
+                        if let (Error::Bind(message), Some(info)) = (&mut err, &info) {
+                            if port != 0 && !info.ports.contains(port) {
+                                message.push_str(&format!(" (server ports are {})", info.ports));
+                            }
+                        }
+                        Err(err)
+                    }
+                    Some(ServerMessage::Failure { code, message }) => {
+                        let mut message = format!("server error: {message}");
+                        if let (ErrorCode::PortOutOfRange, Some(info)) = (code, &info) {
+                            message.push_str(&format!(" (server ports are {})", info.ports));
+                        }
+                        Err(Error::Refused { code, message })
+                    }
+                    Some(ServerMessage::Challenge(_)) => Err(missing_secret()),
+                    Some(_) => Err(Error::Protocol(
+                        "unexpected initial non-hello message".into(),
+                    )),
+                    None => Err(Error::Protocol("unexpected EOF".into())),
+                };
             }
-            Some(_) => bail!("unexpected initial non-hello message"),
-            None => bail!("unexpected EOF"),
         };
-        info!(remote_port, "connected to server");
-        info!("listening at {to}:{remote_port}");
+        let remote_port = assign
+            .instrument(info_span!("assign_port", requested = port))
+            .await?;
+        if port != 0 && remote_port != port {
+            warn!(
+                port,
+                remote_port, "requested port unavailable, assigned a random port"
+            );
+        }
+        let hostname = match info.as_ref().is_some_and(|info| info.supports("hostname")) {
+            true => match stream.recv_timeout().await? {
+                Some(ServerMessage::Hostname(hostname)) => hostname,
+                _ => return Err(Error::Protocol("expected tunnel host name".into())),
+            },
+            false => None,
+        };
+        let pool = match options.pool_size > 0 {
+            true => match stream.recv_timeout().await? {
+                Some(ServerMessage::Pool(token)) => Some((token, options.pool_size)),
+                _ => {
+                    warn!("server does not support connection pooling");
+                    None
+                }
+            },
+            false => None,
+        };
+        if let Some(ip) = options.remote_bind {
+            // Older servers ignore the address and bind publicly, so fail rather than expose.
+            match stream.recv_timeout().await? {
+                Some(ServerMessage::Bound(bound)) if bound == ip => {
+                    info!(%ip, "public port bound on server address");
+                }
+                Some(ServerMessage::Bound(bound)) => {
+                    return Err(Error::Bind(format!("server bound the tunnel on {bound}")));
+                }
+                _ => {
+                    return Err(Error::Bind(
+                        "server does not support binding to a specific address".into(),
+                    ))
+                }
+            }
+        }
+        if options.shared && !info.as_ref().is_some_and(|info| info.supports("shared")) {
+            warn!("server does not support sharing tunnels");
+        }
+        if expires_at.is_some() && !info.as_ref().is_some_and(|info| info.supports("expires")) {
+            warn!("server does not support expiring tunnels, so only the client will close it");
+        }
+        let limits_transfer = info
+            .as_ref()
+            .is_some_and(|info| info.supports("transfer-limit"));
+        if options.transfer_limit.is_some() && !limits_transfer {
+            warn!("server does not support transfer limits, so the tunnel stays open");
+        }
+        let lists_tunnels = info
+            .as_ref()
+            .is_some_and(|info| info.supports("public-listing"));
+        if options.public_listing && !lists_tunnels {
+            warn!("server does not publish a listing of tunnels");
+        }
+        // Servers too old to describe themselves cannot resume tunnels either.
+        let resume_token = match options.resumable && info.is_some() {
+            true => match stream.recv_timeout().await? {
+                Some(ServerMessage::Resume(token)) => token,
+                _ => {
+                    warn!("server does not support resuming tunnels");
+                    None
+                }
+            },
+            false => None,
+        };
+        info!(port = remote_port, "connected to server");
+        info!(
+            "listening at {}:{remote_port}",
+            hostname.as_deref().unwrap_or(to)
+        );
+        let connected = ClientEvent::Connected {
+            remote_host: hostname.as_deref().unwrap_or(to).into(),
+            remote_port,
+        };
+        event::emit(options.events.as_ref(), connected);
+        if let Some(env_file) = &options.env_file {
+            let host = hostname.as_deref().unwrap_or(to);
+            if let Err(err) = env_file.write(host, remote_port) {
+                warn!(path = %env_file.path.display(), %err, "failed to write env file");
+            }
+        }
 
         Ok(Client {
             conn: Some(stream),
             to: to.to_string(),
+            control_port,
+            connector,
+            inspector: options.inspector,
+            terminate_tls: options.terminate_tls,
+            local_tls: options.local_tls,
+            basic_auth: options.basic_auth,
+            forwarded_headers: options.forwarded_headers,
+            proxy_protocol: options.proxy_protocol,
+            host_header: options.host_header.value(local_host, local_port),
+            ip_filter: options.ip_filter,
+            pool,
+            stats: Arc::default(),
+            stats_interval: options.stats_interval,
+            heartbeat_timeout: options.heartbeat_timeout,
+            resolve_interval: options.resolve_interval,
+            buffer_size: options.proxy_buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            max_up: options.max_up.map(|rate| Arc::new(Bandwidth::new(rate))),
+            max_down: options.max_down.map(|rate| Arc::new(Bandwidth::new(rate))),
+            shutdown: options.shutdown,
+            events: options.events,
             local_host: local_host.to_string(),
             local_port,
             remote_port,
+            resume_token,
+            hostname,
+            expires_at,
+            info,
             auth,
+            challenged,
+            status_file: options.status_file,
         })
     }
 
@@ -76,54 +740,683 @@ impl Client {
         self.remote_port
     }
 
+    /// Returns the description of the server, if it sent one.
+    ///
+    /// Servers only describe themselves in reply to extended tunnel requests, such as those with
+    /// a name or a connection pool, and older servers not at all.
+    pub fn server_info(&self) -> Option<&ServerInfo> {
+        self.info.as_ref()
+    }
+
+    /// Returns the token that a new client can present to take over this tunnel's port after a
+    /// disconnect, if the server issued one.
+    ///
+    /// The server only holds the port for its reconnect grace period.
+    pub fn resume_token(&self) -> Option<Uuid> {
+        self.resume_token
+    }
+
+    /// Returns the host name under which visitors reach the tunnel, if the server assigned one.
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_deref()
+    }
+
+    /// Returns the host that visitors connect to, which is the tunnel's host name if it has
+    /// one, or else the server's address.
+    pub fn public_host(&self) -> &str {
+        self.hostname().unwrap_or(&self.to)
+    }
+
+    /// Returns the time left until the tunnel expires, if it has a lifetime.
+    pub fn expires_in(&self) -> Option<Duration> {
+        self.expires_at
+            .map(|expires_at| expires_at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns the public URL of the tunnel, for a service with the given scheme.
+    pub fn public_url(&self, scheme: Scheme) -> String {
+        scheme.url(self.public_host(), self.remote_port)
+    }
+
+    /// Returns the transfer statistics of forwarded connections, and the time of the last
+    /// heartbeat from the server.
+    ///
+    /// The statistics are shared, so the handle stays up to date while the client listens.
+    pub fn stats(&self) -> Arc<Stats> {
+        Arc::clone(&self.stats)
+    }
+
     /// Start the client, listening for new connections.
+    ///
+    /// Runs until the server closes the control connection, the tunnel expires, or the shutdown
+    /// token in [`ClientOptions`] is cancelled.
     pub async fn listen(mut self) -> Result<()> {
-        let mut conn = self.conn.take().unwrap();
+        let conn = self.conn.take().unwrap();
+        let _summary = TunnelSummary {
+            port: self.remote_port,
+            opened: Instant::now(),
+            stats: Arc::clone(&self.stats),
+        };
         let this = Arc::new(self);
+        // Background tasks end along with the control connection, by dropping the set.
+        let mut tasks = JoinSet::new();
+        if let Some((token, size)) = this.pool {
+            for _ in 0..size {
+                tasks.spawn(Arc::clone(&this).keep_pooled(token));
+            }
+        }
+        if let Some(period) = this.stats_interval {
+            tasks.spawn(Arc::clone(&this.stats).report(period));
+        }
+        if this.status_file.is_some() {
+            tasks.spawn(Arc::clone(&this).keep_status());
+        }
+        let result = match this.resolve_interval {
+            Some(period) => tokio::select! {
+                result = this.handle_control(conn) => result,
+                err = this.follow_dns(period) => Err(err),
+            },
+            None => this.handle_control(conn).await,
+        };
+        // The last status written says how the connection ended.
+        tasks.shutdown().await;
+        let error = result.as_ref().err().map(|err| format!("{err:#}"));
+        this.write_status(ConnectionState::Disconnected, error);
+        result
+    }
+
+    /// Write the status file every so often while connected.
+    async fn keep_status(self: Arc<Self>) {
         loop {
-            match conn.recv().await? {
+            self.write_status(ConnectionState::Connected, None);
+            sleep(STATUS_INTERVAL).await;
+        }
+    }
+
+    /// Write the status of the tunnel, if the client keeps a status file.
+    fn write_status(&self, state: ConnectionState, error: Option<String>) {
+        let Some(path) = &self.status_file else {
+            return;
+        };
+        let status = ClientStatus {
+            state,
+            remote_host: self.public_host().into(),
+            remote_port: self.remote_port,
+            local_port: self.local_port,
+            last_heartbeat_secs: self.stats.last_heartbeat().map(|at| at.elapsed().as_secs()),
+            connections: self.stats.connections(),
+            active: self.stats.active(),
+            received: self.stats.received(),
+            sent: self.stats.sent(),
+            error,
+            updated_at: ClientStatus::now(),
+        };
+        if let Err(err) = status.write(path) {
+            warn!(path = %path.display(), %err, "failed to write status file");
+        }
+    }
+
+    /// Resolve the server's host name periodically, returning once it has moved to addresses
+    /// that are all different from those it had when connecting.
+    ///
+    /// Failed lookups are ignored, so a resolver outage never closes a working connection.
+    async fn follow_dns(&self, period: Duration) -> Error {
+        let mut known = self.resolve_server().await;
+        loop {
+            sleep(period).await;
+            let current = self.resolve_server().await;
+            if current.is_empty() {
+                continue;
+            }
+            if !known.is_empty() && known.is_disjoint(&current) {
+                info!(addrs = ?current, "server moved to new addresses");
+                let message = format!("{} now resolves to different addresses", self.to);
+                return io::Error::new(io::ErrorKind::ConnectionAborted, message).into();
+            }
+            // Addresses that have dropped out may be the one the connection is using, so only
+            // those still listed count as known.
+            known = match known.is_empty() {
+                true => current,
+                false => &known & &current,
+            };
+        }
+    }
+
+    /// Returns the current addresses of the server's host name, or none if it cannot be resolved.
+    async fn resolve_server(&self) -> HashSet<IpAddr> {
+        match lookup_host((self.to.as_str(), self.control_port)).await {
+            Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+            Err(err) => {
+                debug!(%err, host = self.to, "could not resolve server");
+                HashSet::new()
+            }
+        }
+    }
+
+    /// Handle messages on the control connection until the server closes it.
+    async fn handle_control(self: &Arc<Self>, mut conn: Delimited<BoxedStream>) -> Result<()> {
+        loop {
+            let recv = async {
+                match self.heartbeat_timeout {
+                    Some(limit) => match timeout(limit, conn.recv()).await {
+                        Ok(message) => message,
+                        Err(_) => {
+                            event::emit(self.events.as_ref(), ClientEvent::HeartbeatMissed);
+                            let message = format!("no heartbeat from server in {limit:?}");
+                            Err(Error::Timeout(message))
+                        }
+                    },
+                    None => conn.recv().await,
+                }
+            };
+            let expiry = async {
+                match self.expires_at {
+                    Some(expires_at) => sleep_until(expires_at.into()).await,
+                    None => pending().await,
+                }
+            };
+            let message = tokio::select! {
+                message = recv => message?,
+                _ = self.shutdown.cancelled() => {
+                    say_goodbye(&mut conn).await;
+                    info!("client shut down");
+                    return Ok(());
+                }
+                _ = expiry => {
+                    say_goodbye(&mut conn).await;
+                    info!("tunnel expired");
+                    return Ok(());
+                }
+            };
+            match message {
                 Some(ServerMessage::Hello(_)) => warn!("unexpected hello"),
+                Some(ServerMessage::Hostname(_)) => warn!("unexpected host name"),
+                Some(ServerMessage::Pool(_)) => warn!("unexpected pool token"),
+                Some(ServerMessage::Bound(_)) => warn!("unexpected bound address"),
+                Some(ServerMessage::Resume(_)) => warn!("unexpected resumption token"),
                 Some(ServerMessage::Challenge(_)) => warn!("unexpected challenge"),
-                Some(ServerMessage::Heartbeat) => (),
+                Some(ServerMessage::Admin(_)) => warn!("unexpected admin response"),
+                Some(ServerMessage::Pong(_)) => warn!("unexpected pong"),
+                Some(ServerMessage::Info(_)) => warn!("unexpected server info"),
+                Some(ServerMessage::Heartbeat) => self.stats.record_heartbeat(),
+                Some(ServerMessage::Probe { seq, rtt_us }) => {
+                    self.stats.record_heartbeat();
+                    if let Some(rtt_us) = rtt_us {
+                        self.stats.record_rtt(Duration::from_micros(rtt_us));
+                    }
+                    conn.send(ClientMessage::Echo(seq)).await?;
+                }
                 Some(ServerMessage::Connection(id)) => {
-                    let this = Arc::clone(&this);
-                    tokio::spawn(
-                        async move {
-                            info!("new connection");
-                            match this.handle_connection(id).await {
-                                Ok(_) => info!("connection exited"),
-                                Err(err) => warn!(%err, "connection exited with error"),
-                            }
-                        }
-                        .instrument(info_span!("proxy", %id)),
-                    );
+                    let visitor = self.visitor(id, None, None);
+                    Arc::clone(self).spawn_connection(visitor, None)
+                }
+                Some(ServerMessage::Visitor { id, addr, port }) => {
+                    let visitor = self.visitor(id, Some(addr), port);
+                    Arc::clone(self).spawn_connection(visitor, None)
                 }
                 Some(ServerMessage::Error(err)) => error!(%err, "server error"),
+                Some(ServerMessage::Failure { code, message }) => {
+                    error!(?code, err = message, "server error")
+                }
+                Some(ServerMessage::Shutdown {
+                    reason,
+                    retry_after,
+                }) => {
+                    let retry_after = retry_after.map(Duration::from_secs);
+                    return Err(Error::ServerShutdown {
+                        reason,
+                        retry_after,
+                    });
+                }
+                Some(ServerMessage::Closed { code, message }) => {
+                    let message = format!("server closed the tunnel: {message}");
+                    return Err(Error::Closed { code, message });
+                }
                 None => return Ok(()),
             }
         }
     }
 
-    async fn handle_connection(&self, id: Uuid) -> Result<()> {
-        let mut remote_conn =
-            Delimited::new(connect_with_timeout(&self.to[..], CONTROL_PORT).await?);
+    /// Describe a visitor announced by the server, on the tunnel's port unless it says otherwise.
+    fn visitor(&self, id: Uuid, addr: Option<SocketAddr>, port: Option<u16>) -> Visitor {
+        Visitor {
+            id,
+            addr,
+            port: port.unwrap_or(self.remote_port),
+        }
+    }
+
+    /// Handle a visitor in the background, on a pooled connection if one was used.
+    fn spawn_connection(self: Arc<Self>, visitor: Visitor, pooled: Option<Delimited<BoxedStream>>) {
+        let span = info_span!(
+            "proxy",
+            id = %visitor.id,
+            port = visitor.port,
+            visitor_addr = visitor.addr.map(field::display),
+            received = field::Empty,
+            sent = field::Empty,
+        );
+        tokio::spawn(
+            async move {
+                info!("new connection");
+                let result = match pooled {
+                    Some(remote_conn) => self.serve(visitor, remote_conn).await,
+                    None => self.handle_connection(visitor).await,
+                };
+                match result {
+                    Ok((received, sent)) => {
+                        info!(received, sent, "connection exited");
+                        let span = Span::current();
+                        span.record("received", received).record("sent", sent);
+                    }
+                    Err(err) => warn!(%err, "connection exited with error"),
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Keep one idle data connection in the server's pool, replacing it after each visitor.
+    async fn keep_pooled(self: Arc<Self>, token: Uuid) {
+        loop {
+            match self.wait_pooled(token).await {
+                Ok((visitor, remote_conn)) => {
+                    Arc::clone(&self).spawn_connection(visitor, Some(remote_conn))
+                }
+                Err(err) => {
+                    warn!(%err, "pooled connection failed");
+                    sleep(POOL_RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// Offer a data connection to the server's pool, and wait until a visitor arrives on it.
+    async fn wait_pooled(&self, token: Uuid) -> Result<(Visitor, Delimited<BoxedStream>)> {
+        let mut remote_conn = self.connect_server().await?;
+        remote_conn.send(ClientMessage::Pool(token)).await?;
+        match remote_conn.recv().await? {
+            Some(ServerMessage::Connection(id)) => Ok((self.visitor(id, None, None), remote_conn)),
+            Some(ServerMessage::Visitor { id, addr, port }) => {
+                Ok((self.visitor(id, Some(addr), port), remote_conn))
+            }
+            Some(_) => Err(Error::Protocol(
+                "unexpected message on pooled connection".into(),
+            )),
+            None => Err(Error::Protocol("pooled connection closed by server".into())),
+        }
+    }
+
+    /// Open an authenticated connection to the server.
+    async fn connect_server(&self) -> Result<Delimited<BoxedStream>> {
+        let stream = self.connector.connect(&self.to, self.control_port).await?;
+        let mut stream = Delimited::new(stream);
         if let Some(auth) = &self.auth {
-            auth.client_handshake(&mut remote_conn).await?;
+            auth.client_handshake(&mut stream).await?;
+        } else if self.challenged {
+            match stream.recv_timeout().await? {
+                Some(ServerMessage::Challenge(_)) => {}
+                _ => return Err(Error::Protocol("expected challenge from server".into())),
+            }
+        }
+        Ok(stream)
+    }
+
+    async fn handle_connection(&self, visitor: Visitor) -> Result<(u64, u64)> {
+        let mut remote_conn = self.connect_server().await?;
+        remote_conn.send(ClientMessage::Accept(visitor.id)).await?;
+        self.serve(visitor, remote_conn).await
+    }
+
+    /// Forward a visitor's connection to the local service, once the server has handed it over.
+    ///
+    /// Returns the number of bytes received from and sent to the visitor.
+    async fn serve(
+        &self,
+        visitor: Visitor,
+        remote_conn: Delimited<BoxedStream>,
+    ) -> Result<(u64, u64)> {
+        let Visitor { id, addr, port } = visitor;
+        // Visitors of unknown address are rejected too, if the server is too old to send them.
+        let allowed = match addr {
+            Some(addr) => self.ip_filter.allows(addr.ip()),
+            None => self.ip_filter.is_empty(),
+        };
+        if !allowed {
+            // Dropping the accepted stream closes the visitor's connection immediately.
+            info!("rejected visitor by IP filter");
+            return Ok((0, 0));
         }
-        remote_conn.send(ClientMessage::Accept(id)).await?;
-        let mut local_conn = connect_with_timeout(&self.local_host, self.local_port).await?;
         let parts = remote_conn.into_parts();
         debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
-        local_conn.write_all(&parts.read_buf).await?; // mostly of the cases, this will be empty
-        proxy(local_conn, parts.io).await?;
-        Ok(())
+        let mut remote = self.stats.track(parts.io);
+        if let Some(events) = &self.events {
+            event::emit(Some(events), ClientEvent::VisitorOpened { id, addr, port });
+            remote = remote.notify_closed(id, events.clone());
+        }
+        let remote = Throttled::new(remote, self.max_down.clone(), self.max_up.clone());
+        // Data read along with the last frame, which is empty in most cases.
+        let remote = Prefixed::new(parts.read_buf.to_vec(), remote);
+        if let Some(config) = &self.terminate_tls {
+            let remote = timeout(
+                NETWORK_TIMEOUT,
+                TlsAcceptor::from(Arc::clone(config)).accept(remote),
+            )
+            .await
+            .map_err(|_| Error::Timeout("timed out waiting for visitor TLS handshake".into()))??;
+            return self.admit(remote, visitor).await;
+        }
+        self.admit(remote, visitor).await
+    }
+
+    /// Check the credentials of a visitor, if required, and forward it to the local service.
+    ///
+    /// Returns the number of bytes received from and sent to the visitor.
+    async fn admit<S>(&self, mut remote: S, visitor: Visitor) -> Result<(u64, u64)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let addr = visitor.addr;
+        let mut head = Vec::new();
+        if let Some(auth) = &self.basic_auth {
+            match auth.check(&mut remote).await? {
+                Some(data) => head = data,
+                None => {
+                    info!("rejected visitor without valid credentials");
+                    return Ok((0, 0));
+                }
+            }
+        }
+        let mut local_conn = connect_stream(
+            &self.local_host,
+            self.local_port,
+            self.local_tls.as_ref(),
+            None,
+        )
+        .await?;
+        if self.proxy_protocol {
+            let header = proxy_header(addr, visitor.port);
+            local_conn.write_all(header.as_bytes()).await?;
+        }
+        // The request head read while checking credentials is rewritten like the rest.
+        let remote = Prefixed::new(head, remote);
+        let proto = match self.terminate_tls {
+            Some(_) => "https",
+            None => "http",
+        };
+        let edits = Edits {
+            forwarded: self.forwarded_headers.then_some(Forwarded { addr, proto }),
+            host: self.host_header.clone(),
+        };
+        let (sent, received) = match edits.is_empty() {
+            true => self.forward(local_conn, remote).await?,
+            false => {
+                let remote = RewriteRequests::new(remote, edits);
+                self.forward(local_conn, remote).await?
+            }
+        };
+        Ok((received, sent))
+    }
+
+    /// Copy data between the local service and a visitor, inspecting it if enabled.
+    ///
+    /// Returns the number of bytes sent to and received from the visitor.
+    async fn forward<S>(&self, local_conn: BoxedStream, remote: S) -> io::Result<(u64, u64)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match &self.inspector {
+            Some(inspector) => {
+                let proxy = inspector.proxy(local_conn, remote, self.buffer_size);
+                proxy.await
+            }
+            None => proxy(local_conn, remote, self.buffer_size).await,
+        }
     }
 }
 
-async fn connect_with_timeout(to: &str, port: u16) -> Result<TcpStream> {
-    match timeout(NETWORK_TIMEOUT, TcpStream::connect((to, port))).await {
+/// Logs the summary of a tunnel when dropped, so it is logged however the client stops.
+struct TunnelSummary {
+    port: u16,
+    opened: Instant,
+    stats: Arc<Stats>,
+}
+
+impl Drop for TunnelSummary {
+    fn drop(&mut self) {
+        let uptime = self.opened.elapsed();
+        self.stats.log_closed(self.port, None, uptime);
+    }
+}
+
+/// Connect to a host, optionally from a specific local source address.
+pub(crate) async fn connect_with_timeout(
+    to: &str,
+    port: u16,
+    bind_addr: Option<IpAddr>,
+) -> Result<TcpStream> {
+    match timeout(NETWORK_TIMEOUT, connect_any(to, port, bind_addr)).await {
         Ok(res) => res,
         Err(err) => Err(err.into()),
     }
-    .with_context(|| format!("could not connect to {to}:{port}"))
+    .map_err(|source| Error::Connect {
+        host: to.to_string(),
+        port,
+        source,
+    })
+}
+
+/// Response times and description of a server, measured by [`ping`].
+#[derive(Clone, Debug)]
+pub struct PingReport {
+    /// Time taken to open the control connection, including any TLS handshake.
+    pub connect: Duration,
+
+    /// Round-trip time of the ping, after authenticating.
+    pub rtt: Duration,
+
+    /// Description the server sent in reply.
+    pub info: ServerInfo,
+}
+
+/// Check that a server is reachable and accepts the secret, measuring its response time.
+pub async fn ping(to: &str, secret: Option<&str>, options: &ClientOptions) -> Result<PingReport> {
+    let start = Instant::now();
+    let stream = options
+        .connector()
+        .connect(to, options.control_port())
+        .await?;
+    let connect = start.elapsed();
+    let mut stream = Delimited::new(stream);
+    if let Some(secret) = secret {
+        Authenticator::new(secret)
+            .client_handshake(&mut stream)
+            .await?;
+    }
+
+    let start = Instant::now();
+    stream.send(ClientMessage::Ping).await?;
+    let mut reply = stream.recv_timeout().await?;
+    let challenged = matches!(reply, Some(ServerMessage::Challenge(_)));
+    if secret.is_none() && challenged {
+        reply = stream.recv_timeout().await?;
+    }
+    match reply {
+        Some(ServerMessage::Pong(info)) => Ok(PingReport {
+            connect,
+            rtt: start.elapsed(),
+            info,
+        }),
+        Some(ServerMessage::Error(message)) => match server_error(message) {
+            Error::Handshake(_) if challenged => Err(missing_secret()),
+            err => Err(err),
+        },
+        Some(ServerMessage::Challenge(_)) => Err(missing_secret()),
+        Some(_) => Err(Error::Protocol("unexpected reply to ping".into())),
+        None => Err(Error::Protocol(
+            "server closed the connection, it may be too old to answer pings".into(),
+        )),
+    }
+}
+
+/// A visitor connection announced by the server.
+#[derive(Clone, Copy, Debug)]
+struct Visitor {
+    /// Identifier of the connection.
+    id: Uuid,
+    /// Address of the visitor, if the server sent it.
+    addr: Option<SocketAddr>,
+    /// Public port the visitor connected to.
+    port: u16,
+}
+
+/// Format a PROXY protocol (version 1) header for a visitor of a public port.
+///
+/// The server's public address is not known to the client, so the destination is the
+/// unspecified address of the visitor's family.
+fn proxy_header(addr: Option<SocketAddr>, port: u16) -> String {
+    match addr {
+        Some(SocketAddr::V4(addr)) => format!(
+            "PROXY TCP4 {} {} {} {port}\r\n",
+            addr.ip(),
+            Ipv4Addr::UNSPECIFIED,
+            addr.port()
+        ),
+        Some(SocketAddr::V6(addr)) => format!(
+            "PROXY TCP6 {} {} {} {port}\r\n",
+            addr.ip(),
+            Ipv6Addr::UNSPECIFIED,
+            addr.port()
+        ),
+        None => "PROXY UNKNOWN\r\n".into(),
+    }
+}
+
+/// Error of a client without a secret that the server did not let through its challenge.
+fn missing_secret() -> Error {
+    Error::Handshake("server requires authentication, but no client secret was provided".into())
+}
+
+/// Classify an error sent by the server in reply to a tunnel request.
+fn server_error(message: String) -> Error {
+    // Older servers send the reason of a failed handshake without a prefix.
+    let message = format!("server error: {message}");
+    match message.contains(AUTH_FAILED) || message.ends_with("invalid secret") {
+        true => Error::Handshake(message),
+        false => Error::Bind(message),
+    }
+}
+
+/// Tell the server that the client is leaving on purpose, so it releases the port at once.
+///
+/// Servers too old to understand this close the connection all the same.
+async fn say_goodbye(conn: &mut Delimited<BoxedStream>) {
+    if let Err(err) = conn.send(ClientMessage::Goodbye).await {
+        debug!(%err, "could not say goodbye to server");
+    }
+}
+
+/// Connect to any resolved address of a host, racing them as in Happy Eyeballs (RFC 8305).
+///
+/// With a source address, only addresses of its family are tried.
+async fn connect_any(to: &str, port: u16, bind_addr: Option<IpAddr>) -> io::Result<TcpStream> {
+    let addrs: Vec<_> = lookup_host((to, port))
+        .await?
+        .filter(|addr| bind_addr.is_none_or(|ip| ip.is_ipv4() == addr.is_ipv4()))
+        .collect();
+    if addrs.is_empty() {
+        let message = match bind_addr {
+            Some(ip) => format!("no address of {to} matches the family of {ip}"),
+            None => format!("no addresses found for {to}"),
+        };
+        return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, message));
+    }
+    connect_addrs(addrs, bind_addr).await
+}
+
+/// Connect to the first of several addresses that accepts, racing them as in Happy Eyeballs
+/// (RFC 8305).
+///
+/// Addresses are tried in the order of [`interleave_families`], and each attempt that has not
+/// finished after 250 milliseconds gets company from the next one, so an unreachable address of
+/// one family does not hold up the other. The error of the last attempt is returned if all fail.
+pub async fn connect_addrs(
+    addrs: Vec<SocketAddr>,
+    bind_addr: Option<IpAddr>,
+) -> io::Result<TcpStream> {
+    let mut pending = interleave_families(addrs).into_iter();
+    let Some(first) = pending.next() else {
+        let message = "no addresses to connect to";
+        return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, message));
+    };
+    let mut attempts = FuturesUnordered::new();
+    attempts.push(connect_addr(first, bind_addr));
+    loop {
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                // A failed attempt is replaced right away, without waiting out the delay.
+                Err(err) => match pending.next() {
+                    Some(addr) => attempts.push(connect_addr(addr, bind_addr)),
+                    None if attempts.is_empty() => return Err(err),
+                    None => {}
+                },
+            },
+            _ = sleep(ATTEMPT_DELAY), if pending.len() > 0 => {
+                attempts.push(connect_addr(pending.next().unwrap(), bind_addr));
+            }
+        }
+    }
+}
+
+/// Connect to one address, from a local source address if given.
+async fn connect_addr(addr: SocketAddr, bind_addr: Option<IpAddr>) -> io::Result<TcpStream> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(ip) = bind_addr {
+        socket.bind(SocketAddr::new(ip, 0))?;
+    }
+    socket.connect(addr).await
+}
+
+/// Reorder addresses to alternate between families, starting with that of the first address.
+///
+/// ```
+/// use std::net::SocketAddr;
+/// use bore_cli::client::interleave_families;
+///
+/// let addrs = ["[::1]:80", "[::2]:80", "[::3]:80", "10.0.0.1:80"];
+/// let addrs: Vec<SocketAddr> = addrs.iter().map(|addr| addr.parse().unwrap()).collect();
+/// let ordered: Vec<_> = interleave_families(addrs).iter().map(|a| a.to_string()).collect();
+/// assert_eq!(ordered, ["[::1]:80", "10.0.0.1:80", "[::2]:80", "[::3]:80"]);
+/// ```
+pub fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(|addr| addr.is_ipv6());
+    let (first, second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connect to a host, starting a TLS session if configured.
+pub(crate) async fn connect_stream(
+    host: &str,
+    port: u16,
+    tls: Option<&Arc<ClientConfig>>,
+    bind_addr: Option<IpAddr>,
+) -> Result<BoxedStream> {
+    let stream = connect_with_timeout(host, port, bind_addr).await?;
+    Ok(match tls {
+        Some(config) => Box::new(tls::connect(Arc::clone(config), host, stream).await?),
+        None => Box::new(stream),
+    })
 }
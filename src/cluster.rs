@@ -0,0 +1,324 @@
+//! Clustering of several server nodes that share tunnel state.
+//!
+//! Each node periodically announces the public ports of the tunnels it hosts to its peers
+//! over the control port. When a peer learns about a tunnel held by another node, it binds
+//! the same public port itself and relays visitors to the owning node over its control port,
+//! along with their addresses, so a load balancer can send both clients and visitors to any
+//! node in the cluster.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use dashmap::{DashMap, DashSet};
+use futures_util::future::join_all;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::auth::Authenticator;
+use crate::shared::{
+    proxy, BoxedStream, ClientMessage, ClusterMessage, Delimited, Prefixed, SocketOptions,
+    ACCEPT_RETRY_DELAY, CONTROL_PORT, DEFAULT_BUFFER_SIZE, NETWORK_TIMEOUT,
+};
+
+/// Interval between announcements of this node's tunnels to its peers.
+pub const GOSSIP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time after which a peer's tunnel is forgotten unless it is announced again.
+pub const CLAIM_TTL: Duration = Duration::from_secs(20);
+
+/// Tunnel hosted by another node, forwarded through a local listener.
+struct RemoteClaim {
+    node: String,
+    expires: Instant,
+    forwarder: JoinHandle<()>,
+}
+
+impl Drop for RemoteClaim {
+    fn drop(&mut self) {
+        self.forwarder.abort();
+    }
+}
+
+/// Shared tunnel state for a node in a cluster.
+pub struct Cluster {
+    /// Address at which peers can reach this node.
+    node: String,
+
+    /// Addresses of the other nodes in the cluster.
+    peers: Vec<String>,
+
+    /// Secret shared by all nodes, used to authenticate announcements and relayed visitors.
+    auth: Arc<Authenticator>,
+
+    /// Address and options with which the public ports of peer tunnels are bound.
+    binding: OnceLock<(IpAddr, SocketOptions)>,
+
+    /// Public ports of tunnels hosted by this node.
+    local: DashSet<u16>,
+
+    /// Public ports of tunnels hosted by other nodes.
+    remote: DashMap<u16, RemoteClaim>,
+}
+
+impl Cluster {
+    /// Create the cluster state for a node reachable at `node`.
+    ///
+    /// Addresses of the node and its peers are host names or IP addresses, optionally with a
+    /// control port other than [`CONTROL_PORT`], as in `10.0.0.1:7000`.
+    pub fn new(node: &str, peers: Vec<String>, secret: &str) -> Self {
+        Cluster {
+            node: node.to_string(),
+            peers,
+            auth: Arc::new(Authenticator::new(secret)),
+            binding: OnceLock::new(),
+            local: DashSet::new(),
+            remote: DashMap::new(),
+        }
+    }
+
+    /// Returns the node hosting the tunnel on a port, if it is known.
+    pub fn owner(&self, port: u16) -> Option<String> {
+        if self.local.contains(&port) {
+            return Some(self.node.clone());
+        }
+        self.remote.get(&port).map(|claim| claim.node.clone())
+    }
+
+    /// Bind the public ports of peer tunnels on this address, with these options, as the
+    /// server does for its own tunnels.
+    ///
+    /// The server calls this when it starts listening. Peer tunnels are not forwarded before.
+    pub(crate) fn bind_on(&self, ip: IpAddr, options: SocketOptions) {
+        let _ = self.binding.set((ip, options));
+    }
+
+    /// Announce a tunnel hosted by this node, releasing it when the guard is dropped.
+    pub fn claim(self: &Arc<Self>, port: u16) -> ClaimGuard {
+        self.local.insert(port);
+        self.spawn_broadcast(vec![ClusterMessage::Claim {
+            node: self.node.clone(),
+            port,
+        }]);
+        ClaimGuard {
+            cluster: Arc::clone(self),
+            port,
+        }
+    }
+
+    /// Periodically re-announce local tunnels and expire stale remote ones.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            sleep(GOSSIP_INTERVAL).await;
+            let now = Instant::now();
+            self.remote.retain(|port, claim| {
+                let alive = claim.expires > now;
+                if !alive {
+                    info!(port, node = %claim.node, "peer tunnel expired");
+                }
+                alive
+            });
+            let claims = self
+                .local
+                .iter()
+                .map(|port| ClusterMessage::Claim {
+                    node: self.node.clone(),
+                    port: *port,
+                })
+                .collect();
+            self.broadcast(claims).await;
+        }
+    }
+
+    /// Process announcements received from a peer on a control connection.
+    pub async fn handle(
+        &self,
+        stream: &mut Delimited<BoxedStream>,
+        first: ClusterMessage,
+    ) -> Result<()> {
+        self.apply(first);
+        while let Some(message) = stream.recv().await? {
+            match message {
+                ClientMessage::Cluster(message) => self.apply(message),
+                _ => warn!("unexpected message from peer"),
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&self, message: ClusterMessage) {
+        match message {
+            ClusterMessage::Claim { node, port } => {
+                if node == self.node {
+                    return;
+                }
+                if self.local.contains(&port) {
+                    warn!(port, %node, "peer claims a port hosted by this node");
+                    return;
+                }
+                let expires = Instant::now() + CLAIM_TTL;
+                if let Some(mut claim) = self.remote.get_mut(&port) {
+                    if claim.node == node {
+                        claim.expires = expires;
+                        return;
+                    }
+                }
+                self.remote.remove(&port);
+                let Some((ip, options)) = self.binding.get() else {
+                    return;
+                };
+                // The claim is only kept once the port is bound, so the next announcement
+                // tries again, such as after the previous owner's forwarder let go of it.
+                let listener = match options.bind(SocketAddr::new(*ip, port)) {
+                    Ok(listener) => listener,
+                    Err(err) => {
+                        warn!(%err, port, %node, "could not bind port for peer tunnel");
+                        return;
+                    }
+                };
+                info!(port, %node, "forwarding peer tunnel");
+                let auth = Arc::clone(&self.auth);
+                let forwarder =
+                    tokio::spawn(forward(listener, options.clone(), auth, node.clone()));
+                self.remote.insert(
+                    port,
+                    RemoteClaim {
+                        node,
+                        expires,
+                        forwarder,
+                    },
+                );
+            }
+            ClusterMessage::Release { node, port } => {
+                if self
+                    .remote
+                    .remove_if(&port, |_, claim| claim.node == node)
+                    .is_some()
+                {
+                    info!(port, %node, "peer tunnel released");
+                }
+            }
+            ClusterMessage::Visitor { .. } => warn!("unexpected relayed visitor from peer"),
+        }
+    }
+
+    fn spawn_broadcast(self: &Arc<Self>, messages: Vec<ClusterMessage>) {
+        let this = Arc::clone(self);
+        tokio::spawn(async move { this.broadcast(messages).await });
+    }
+
+    async fn broadcast(&self, messages: Vec<ClusterMessage>) {
+        if messages.is_empty() {
+            return;
+        }
+        let messages = &messages;
+        join_all(self.peers.iter().map(|peer| async move {
+            if let Err(err) = self.send_to(peer, messages).await {
+                debug!(%err, %peer, "failed to reach peer");
+            }
+        }))
+        .await;
+    }
+
+    async fn send_to(&self, peer: &str, messages: &[ClusterMessage]) -> Result<()> {
+        let mut stream = connect(peer, &self.auth).await?;
+        for message in messages {
+            stream.send(ClientMessage::Cluster(message.clone())).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Guard that releases a tunnel claimed by this node when dropped.
+pub struct ClaimGuard {
+    cluster: Arc<Cluster>,
+    port: u16,
+}
+
+impl Drop for ClaimGuard {
+    fn drop(&mut self) {
+        self.cluster.local.remove(&self.port);
+        self.cluster.spawn_broadcast(vec![ClusterMessage::Release {
+            node: self.cluster.node.clone(),
+            port: self.port,
+        }]);
+    }
+}
+
+/// Accept visitors on a port and relay them to the node hosting its tunnel.
+async fn forward(
+    listener: TcpListener,
+    options: SocketOptions,
+    auth: Arc<Authenticator>,
+    node: String,
+) {
+    let port = listener.local_addr().map_or(0, |addr| addr.port());
+    loop {
+        let (visitor, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!(%err, port, "failed to accept visitor for peer tunnel");
+                sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        if let Err(err) = options.apply(&visitor) {
+            warn!(%err, visitor_addr = %addr, "failed to apply socket options");
+        }
+        let span = info_span!("forward", visitor_addr = %addr, port, %node);
+        let (auth, node) = (Arc::clone(&auth), node.clone());
+        tokio::spawn(
+            async move {
+                let relay = async {
+                    let mut upstream = connect(&node, &auth).await?;
+                    let message = ClusterMessage::Visitor { port, addr };
+                    upstream.send(ClientMessage::Cluster(message)).await?;
+                    anyhow::Ok(upstream.into_parts())
+                };
+                let upstream = match timeout(NETWORK_TIMEOUT, relay).await {
+                    Ok(Ok(parts)) => Prefixed::new(parts.read_buf.to_vec(), parts.io),
+                    Ok(Err(err)) => {
+                        warn!(%err, "could not relay visitor to peer");
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("timed out relaying visitor to peer");
+                        return;
+                    }
+                };
+                match proxy(visitor, upstream, DEFAULT_BUFFER_SIZE).await {
+                    Ok((received, sent)) => debug!(received, sent, "forwarded connection exited"),
+                    Err(err) => debug!(%err, "forwarded connection exited with error"),
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Open an authenticated control connection to a node.
+async fn connect(node: &str, auth: &Authenticator) -> Result<Delimited<TcpStream>> {
+    let stream = timeout(NETWORK_TIMEOUT, TcpStream::connect(control_addr(node))).await??;
+    let mut stream = Delimited::new(stream);
+    auth.client_handshake(&mut stream).await?;
+    Ok(stream)
+}
+
+/// Split the address of a node into its host and control port, which is [`CONTROL_PORT`]
+/// unless the address names another, as in `10.0.0.1:7000` or `[::1]:7000`.
+fn control_addr(node: &str) -> (&str, u16) {
+    if let Some((host, port)) = node.rsplit_once(':') {
+        let bracketed = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'));
+        match (port.parse(), bracketed) {
+            (Ok(port), Some(host)) => return (host, port),
+            (Ok(port), None) if !host.contains(':') => return (host, port),
+            _ => {}
+        }
+    }
+    (node, CONTROL_PORT)
+}
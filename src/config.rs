@@ -0,0 +1,116 @@
+//! Client configuration file with named profiles.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// Name of the profile used when none is selected explicitly.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Defaults for client options, which command-line arguments take precedence over.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Address of the remote server.
+    pub to: Option<String>,
+
+    /// Secret for authentication.
+    pub secret: Option<String>,
+
+    /// Port on the remote server to select.
+    pub port: Option<u16>,
+
+    /// Local host to expose.
+    pub local_host: Option<String>,
+
+    /// Stable name for the tunnel.
+    pub name: Option<String>,
+
+    /// Whether to encrypt control connections with TLS.
+    pub tls: Option<bool>,
+
+    /// PEM file with extra certificates to trust.
+    pub tls_ca: Option<PathBuf>,
+
+    /// Control port of the server.
+    pub control_port: Option<u16>,
+
+    /// Local source address for connections to the server.
+    pub bind_addr: Option<IpAddr>,
+
+    /// Seconds without a heartbeat after which the server is considered dead.
+    pub heartbeat_timeout: Option<u64>,
+
+    /// Whether to measure the round-trip time to the server.
+    pub measure_rtt: Option<bool>,
+}
+
+/// Contents of the client configuration file, in TOML.
+///
+/// ```toml
+/// [profiles.default]
+/// to = "bore.example.com"
+///
+/// [profiles.work]
+/// to = "bore.work.internal"
+/// secret = "my_secret_string"
+/// port = 30000
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Profiles by name.
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+impl Config {
+    /// Returns the path of the configuration file, which is `bore/config.toml` in the user's
+    /// configuration directory unless overridden by the `BORE_CONFIG` environment variable.
+    pub fn default_path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("BORE_CONFIG") {
+            return Some(path.into());
+        }
+        let dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None if cfg!(windows) => PathBuf::from(env::var_os("APPDATA")?),
+            None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("bore").join("config.toml"))
+    }
+
+    /// Read a configuration file, treating a missing file as empty.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("could not read {}", path.display()))
+            }
+        };
+        toml::from_str(&data).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Select a profile by name, or the default profile if it exists when no name is given.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        match name {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .cloned()
+                .with_context(|| format!("no profile named {name:?} in config file")),
+            None => Ok(self
+                .profiles
+                .get(DEFAULT_PROFILE)
+                .cloned()
+                .unwrap_or_default()),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Long-running client daemon that manages tunnels on request over a Unix socket.
+
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{info, warn};
+
+use crate::client::ClientOptions;
+use crate::shared::Delimited;
+use crate::supervisor::{Supervisor, TunnelConfig, TunnelState, TunnelStatus};
+use crate::tls;
+use crate::transport::bind_unix;
+
+/// Maximum byte length of a message on the daemon socket, which can list many tunnels.
+pub const MAX_DAEMON_FRAME_LENGTH: usize = 64 * 1024;
+
+/// A tunnel to add to the daemon, with options already resolved by the caller.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NewTunnel {
+    /// Local host to expose.
+    pub local_host: String,
+
+    /// Local port to expose.
+    pub local_port: u16,
+
+    /// Address of the remote server.
+    pub to: String,
+
+    /// Port on the remote server to select, or 0 for any available port.
+    pub port: u16,
+
+    /// Optional secret for authentication.
+    pub secret: Option<String>,
+
+    /// Stable name for the tunnel, also used to remove it.
+    pub name: Option<String>,
+
+    /// Whether to encrypt control connections with TLS.
+    pub tls: bool,
+
+    /// Absolute path of a PEM file with extra certificates to trust.
+    pub tls_ca: Option<PathBuf>,
+
+    /// Control port of the server, if it is not the default.
+    pub control_port: Option<u16>,
+
+    /// Local source address for connections to the server.
+    pub bind_addr: Option<IpAddr>,
+
+    /// Seconds without a heartbeat after which the server is considered dead.
+    pub heartbeat_timeout: Option<u64>,
+
+    /// Seconds between lookups of the server's host name while connected.
+    pub resolve_interval: Option<u64>,
+
+    /// Whether to measure the round-trip time to the server.
+    pub measure_rtt: bool,
+}
+
+impl NewTunnel {
+    /// Build the configuration of the supervised tunnel.
+    pub fn config(self) -> Result<TunnelConfig> {
+        let tls = match self.tls {
+            true => Some(tls::client_config(self.tls_ca.as_deref())?),
+            false => None,
+        };
+        Ok(TunnelConfig {
+            local_host: self.local_host,
+            local_port: self.local_port,
+            to: self.to,
+            port: self.port,
+            secret: self.secret,
+            options: ClientOptions {
+                name: self.name,
+                tls,
+                control_port: self.control_port,
+                bind_addr: self.bind_addr,
+                heartbeat_timeout: self.heartbeat_timeout.map(Duration::from_secs),
+                resolve_interval: self.resolve_interval.map(Duration::from_secs),
+                measure_rtt: self.measure_rtt,
+                ..Default::default()
+            },
+        })
+    }
+}
+
+/// A request to the daemon, sent as the only message on a connection.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum DaemonRequest {
+    /// Start supervising a new tunnel.
+    Add(NewTunnel),
+
+    /// List the tunnels and their states.
+    List,
+
+    /// Report the state, uptime, and recent errors of each tunnel.
+    Status,
+
+    /// Stop a tunnel by label.
+    Remove(String),
+}
+
+/// A reply from the daemon.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum DaemonResponse {
+    /// The request succeeded.
+    Done,
+
+    /// Label and state of each tunnel.
+    Tunnels(Vec<(String, TunnelState)>),
+
+    /// Detailed report on each tunnel.
+    Status(Vec<TunnelStatus>),
+
+    /// The request failed with this message.
+    Error(String),
+}
+
+/// Returns the default path of the daemon socket, in the user's runtime directory.
+pub fn default_socket_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("bore.sock"),
+        None => {
+            let user = env::var("USER").unwrap_or_default();
+            env::temp_dir().join(format!("bore-{user}.sock"))
+        }
+    }
+}
+
+/// Daemon listening for requests on a Unix socket.
+pub struct Daemon {
+    listener: UnixListener,
+    supervisor: Arc<Supervisor>,
+}
+
+impl Daemon {
+    /// Listen on a Unix socket, replacing a stale socket file left by an earlier daemon.
+    pub async fn bind(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let listener = bind_unix(path).await?;
+        // Requests may carry secrets, so only the owner can connect.
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        info!(path = %path.display(), "daemon listening");
+        Ok(Daemon {
+            listener,
+            supervisor: Arc::new(Supervisor::new(Vec::new())),
+        })
+    }
+
+    /// Start the daemon, handling requests until the socket fails.
+    pub async fn listen(self) -> Result<()> {
+        tokio::spawn(Arc::clone(&self.supervisor).run());
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            let supervisor = Arc::clone(&self.supervisor);
+            tokio::spawn(async move {
+                if let Err(err) = handle_request(&supervisor, stream).await {
+                    warn!(%err, "daemon request exited with error");
+                }
+            });
+        }
+    }
+}
+
+async fn handle_request(supervisor: &Supervisor, stream: UnixStream) -> Result<()> {
+    let mut stream = Delimited::with_max_length(stream, MAX_DAEMON_FRAME_LENGTH);
+    let response = match stream.recv_timeout().await? {
+        Some(DaemonRequest::Add(tunnel)) => match tunnel.config().and_then(|c| supervisor.add(c)) {
+            Ok(()) => DaemonResponse::Done,
+            Err(err) => DaemonResponse::Error(format!("{err:#}")),
+        },
+        Some(DaemonRequest::List) => DaemonResponse::Tunnels(supervisor.states()),
+        Some(DaemonRequest::Status) => DaemonResponse::Status(supervisor.status()),
+        Some(DaemonRequest::Remove(label)) => match supervisor.remove(&label) {
+            true => DaemonResponse::Done,
+            false => DaemonResponse::Error(format!("no tunnel named {label}")),
+        },
+        None => return Ok(()),
+    };
+    stream.send(response).await?;
+    Ok(())
+}
+
+/// Send a request to the daemon listening on a socket, returning its reply.
+pub async fn request(path: impl AsRef<Path>, request: DaemonRequest) -> Result<DaemonResponse> {
+    let path = path.as_ref();
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("could not connect to a daemon at {}", path.display()))?;
+    let mut stream = Delimited::with_max_length(stream, MAX_DAEMON_FRAME_LENGTH);
+    stream.send(request).await?;
+    match stream.recv_timeout().await? {
+        Some(DaemonResponse::Error(message)) => bail!("daemon error: {message}"),
+        Some(response) => Ok(response),
+        None => bail!("unexpected EOF"),
+    }
+}
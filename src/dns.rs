@@ -0,0 +1,321 @@
+//! Dynamic DNS records for named tunnels, created when they open and removed when they close.
+//!
+//! Each named tunnel gets a record like `web.tunnels.example` pointing at the server. Records are
+//! kept through a [`DnsProvider`]: the Cloudflare API, a shell command that can run `nsupdate`
+//! for RFC 2136 servers or the AWS CLI for Route 53, or an implementation of the trait.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use futures_util::future::BoxFuture;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// Time to live of the records, in seconds, short so that stale records expire quickly.
+pub const DNS_TTL: u32 = 60;
+
+/// Base URL of the Cloudflare API.
+const CLOUDFLARE_API: &str = "https://api.cloudflare.com/client/v4";
+
+/// What the records of tunnels point at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DnsTarget {
+    /// An address of the server, in an `A` or `AAAA` record.
+    Address(IpAddr),
+
+    /// A host name of the server, in a `CNAME` record.
+    Alias(String),
+}
+
+impl DnsTarget {
+    /// Returns the type of the records pointing at this target.
+    pub fn record_type(&self) -> &'static str {
+        match self {
+            DnsTarget::Address(IpAddr::V4(_)) => "A",
+            DnsTarget::Address(IpAddr::V6(_)) => "AAAA",
+            DnsTarget::Alias(_) => "CNAME",
+        }
+    }
+}
+
+impl fmt::Display for DnsTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsTarget::Address(ip) => write!(f, "{ip}"),
+            DnsTarget::Alias(host) => write!(f, "{host}"),
+        }
+    }
+}
+
+impl FromStr for DnsTarget {
+    type Err = anyhow::Error;
+
+    /// Parse an IP address, or else a host name.
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(ip) = s.parse() {
+            return Ok(DnsTarget::Address(ip));
+        }
+        let host = s.trim_end_matches('.');
+        if host.is_empty() || !host.split('.').all(is_label) {
+            bail!("expected an IP address or host name, found `{s}`");
+        }
+        Ok(DnsTarget::Alias(host.to_ascii_lowercase()))
+    }
+}
+
+/// A DNS record of a tunnel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DnsRecord {
+    /// Fully qualified name of the record, without a trailing dot.
+    pub name: String,
+
+    /// What the record points at.
+    pub target: DnsTarget,
+
+    /// Time to live of the record, in seconds.
+    pub ttl: u32,
+}
+
+/// A DNS service in which the records of tunnels are kept.
+pub trait DnsProvider: Send + Sync {
+    /// Create a record, or replace the target of an existing one with the same name and type.
+    fn upsert<'a>(&'a self, record: &'a DnsRecord) -> BoxFuture<'a, Result<()>>;
+
+    /// Remove a record, succeeding if it does not exist.
+    fn remove<'a>(&'a self, record: &'a DnsRecord) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Keeps a record for each named tunnel under a domain.
+pub struct DnsUpdater {
+    provider: Arc<dyn DnsProvider>,
+    domain: String,
+    target: DnsTarget,
+    /// Held during each change, so a tunnel that closes and reopens quickly ends up with a record.
+    lock: Arc<Mutex<()>>,
+}
+
+impl DnsUpdater {
+    /// Create an updater that keeps records under a domain, pointing at a target.
+    pub fn new(provider: Arc<dyn DnsProvider>, domain: &str, target: DnsTarget) -> Self {
+        DnsUpdater {
+            provider,
+            domain: domain.trim_matches('.').to_ascii_lowercase(),
+            target,
+            lock: Arc::default(),
+        }
+    }
+
+    /// Returns the record of a tunnel name, or `None` if the name is not a valid DNS label.
+    pub fn record(&self, name: &str) -> Option<DnsRecord> {
+        let label = name.to_ascii_lowercase();
+        is_label(&label).then(|| DnsRecord {
+            name: format!("{label}.{}", self.domain),
+            target: self.target.clone(),
+            ttl: DNS_TTL,
+        })
+    }
+
+    /// Create or update the record of a tunnel in the background.
+    pub fn publish(&self, name: &str) {
+        self.spawn(name, true);
+    }
+
+    /// Remove the record of a tunnel in the background.
+    pub fn withdraw(&self, name: &str) {
+        self.spawn(name, false);
+    }
+
+    fn spawn(&self, name: &str, publish: bool) {
+        let Some(record) = self.record(name) else {
+            warn!(
+                name,
+                "tunnel name is not a valid DNS label, so it has no record"
+            );
+            return;
+        };
+        let provider = Arc::clone(&self.provider);
+        let lock = Arc::clone(&self.lock);
+        tokio::spawn(async move {
+            let _guard = lock.lock().await;
+            let result = match publish {
+                true => provider.upsert(&record).await,
+                false => provider.remove(&record).await,
+            };
+            match (result, publish) {
+                (Ok(()), true) => {
+                    info!(record = record.name, target = %record.target, "published DNS record")
+                }
+                (Ok(()), false) => info!(record = record.name, "removed DNS record"),
+                (Err(err), _) => {
+                    warn!(record = record.name, "failed to update DNS record: {err:#}")
+                }
+            }
+        });
+    }
+}
+
+/// Returns whether a string is a valid label of a host name.
+fn is_label(s: &str) -> bool {
+    (1..=63).contains(&s.len())
+        && s.bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !s.starts_with('-')
+        && !s.ends_with('-')
+}
+
+/// Records kept in a zone on Cloudflare, through its API.
+pub struct Cloudflare {
+    zone_id: String,
+    token: String,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct CloudflareResponse {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<CloudflareError>,
+    #[serde(default)]
+    result: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct CloudflareError {
+    message: String,
+}
+
+impl Cloudflare {
+    /// Keep records in a zone, with an API token allowed to edit its DNS records.
+    pub fn new(zone_id: impl Into<String>, token: impl Into<String>) -> Self {
+        Cloudflare {
+            zone_id: zone_id.into(),
+            token: token.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Send a request to the API, returning the result of a successful response.
+    async fn call(&self, request: reqwest::RequestBuilder) -> Result<serde_json::Value> {
+        let body = request
+            .bearer_auth(&self.token)
+            .send()
+            .await
+            .context("Cloudflare API request failed")?
+            .bytes()
+            .await?;
+        let response: CloudflareResponse =
+            serde_json::from_slice(&body).context("invalid response from Cloudflare API")?;
+        if !response.success {
+            let errors: Vec<_> = response.errors.into_iter().map(|e| e.message).collect();
+            bail!("Cloudflare API error: {}", errors.join(", "));
+        }
+        Ok(response.result)
+    }
+
+    /// Returns the IDs of the records with the name and type of a record.
+    async fn find(&self, record: &DnsRecord) -> Result<Vec<String>> {
+        let url = format!("{CLOUDFLARE_API}/zones/{}/dns_records", self.zone_id);
+        let query = [
+            ("type", record.target.record_type()),
+            ("name", &record.name),
+        ];
+        let result = self.call(self.client.get(url).query(&query)).await?;
+        let ids = result
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|record| record["id"].as_str().map(String::from))
+            .collect();
+        Ok(ids)
+    }
+}
+
+impl DnsProvider for Cloudflare {
+    fn upsert<'a>(&'a self, record: &'a DnsRecord) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let body = json!({
+                "type": record.target.record_type(),
+                "name": record.name,
+                "content": record.target.to_string(),
+                "ttl": record.ttl,
+                "proxied": false,
+            });
+            let records = format!("{CLOUDFLARE_API}/zones/{}/dns_records", self.zone_id);
+            let request = match self.find(record).await?.first() {
+                Some(id) => self.client.put(format!("{records}/{id}")),
+                None => self.client.post(records),
+            };
+            let request = request
+                .header("content-type", "application/json")
+                .body(body.to_string());
+            self.call(request).await?;
+            Ok(())
+        })
+    }
+
+    fn remove<'a>(&'a self, record: &'a DnsRecord) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            for id in self.find(record).await? {
+                let url = format!("{CLOUDFLARE_API}/zones/{}/dns_records/{id}", self.zone_id);
+                self.call(self.client.delete(url)).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Records kept by a shell command, such as a script around `nsupdate` or the AWS CLI.
+///
+/// The command runs with the record in its environment: `BORE_DNS_ACTION` is `upsert` or
+/// `remove`, and `BORE_DNS_NAME`, `BORE_DNS_TYPE`, `BORE_DNS_CONTENT`, and `BORE_DNS_TTL`
+/// describe the record. It should exit with a nonzero status if the change failed.
+pub struct DnsCommand {
+    command: String,
+}
+
+impl DnsCommand {
+    /// Keep records by running a command through the system shell.
+    pub fn new(command: impl Into<String>) -> Self {
+        DnsCommand {
+            command: command.into(),
+        }
+    }
+
+    async fn run(&self, action: &str, record: &DnsRecord) -> Result<()> {
+        let (program, flag) = match cfg!(windows) {
+            true => ("cmd", "/C"),
+            false => ("sh", "-c"),
+        };
+        let status = Command::new(program)
+            .arg(flag)
+            .arg(&self.command)
+            .env("BORE_DNS_ACTION", action)
+            .env("BORE_DNS_NAME", &record.name)
+            .env("BORE_DNS_TYPE", record.target.record_type())
+            .env("BORE_DNS_CONTENT", record.target.to_string())
+            .env("BORE_DNS_TTL", record.ttl.to_string())
+            .status()
+            .await
+            .context("could not run DNS command")?;
+        if !status.success() {
+            bail!("DNS command failed with {status}");
+        }
+        Ok(())
+    }
+}
+
+impl DnsProvider for DnsCommand {
+    fn upsert<'a>(&'a self, record: &'a DnsRecord) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.run("upsert", record))
+    }
+
+    fn remove<'a>(&'a self, record: &'a DnsRecord) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.run("remove", record))
+    }
+}
@@ -0,0 +1,155 @@
+//! Errors returned by the client, server, and protocol.
+
+use std::io;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Result type of the client, server, and protocol.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// An error from a tunnel, classified so that callers can react without matching on messages.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// Authentication failed, or the client and server disagree on whether it is required.
+    #[error("{0}")]
+    Handshake(String),
+
+    /// A peer sent a malformed or unexpected message, or closed the connection early.
+    #[error("{0}")]
+    Protocol(String),
+
+    /// The server could not open a tunnel on the requested port or address.
+    #[error("{0}")]
+    Bind(String),
+
+    /// The server refused a tunnel request, for the reason given by the code.
+    #[error("{message}")]
+    Refused {
+        /// Reason for the refusal.
+        code: ErrorCode,
+        /// Description of the reason, for people.
+        message: String,
+    },
+
+    /// A peer did not respond in time.
+    #[error("{0}")]
+    Timeout(String),
+
+    /// Could not open a connection to a host.
+    #[error("could not connect to {host}:{port}")]
+    Connect {
+        /// Host that was dialed.
+        host: String,
+        /// Port that was dialed.
+        port: u16,
+        /// Cause of the failure.
+        #[source]
+        source: io::Error,
+    },
+
+    /// The server closed the tunnel because it is shutting down.
+    #[error("server shut down: {reason}")]
+    ServerShutdown {
+        /// Why the server shut down, as it described it.
+        reason: String,
+        /// How long to wait before reconnecting, if the server said.
+        retry_after: Option<Duration>,
+    },
+
+    /// The server closed an open tunnel, for the reason given by the code.
+    #[error("{message}")]
+    Closed {
+        /// Reason for closing the tunnel.
+        code: ErrorCode,
+        /// Description of the reason, for people.
+        message: String,
+    },
+
+    /// An I/O error on an open connection.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// Any other failure, such as invalid options.
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl Error {
+    /// Returns the code the server sends clients for this error, if it refused a request or closed
+    /// a tunnel.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::Refused { code, .. } | Error::Closed { code, .. } => Some(*code),
+            Error::Bind(_) => Some(ErrorCode::BindFailed),
+            Error::Protocol(_) => Some(ErrorCode::InvalidRequest),
+            _ => None,
+        }
+    }
+
+    /// Returns whether trying again later may succeed without changing the request.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Refused { code, .. } | Error::Closed { code, .. } => code.is_retryable(),
+            Error::Handshake(_) | Error::Bind(_) | Error::Other(_) => false,
+            _ => true,
+        }
+    }
+}
+
+/// Reason for refusing a tunnel request, sent to clients that understand codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// The requested port is outside the ports the server allows.
+    PortOutOfRange,
+
+    /// The requested port is excluded by the server.
+    PortExcluded,
+
+    /// The requested port is already in use.
+    PortInUse,
+
+    /// No port in the allowed range is available.
+    NoPortAvailable,
+
+    /// The server does not allow binding the tunnel on the requested address.
+    AddressNotAllowed,
+
+    /// The server lacks permission to bind the port.
+    PermissionDenied,
+
+    /// Binding the port failed for another reason.
+    BindFailed,
+
+    /// The client has used up its transfer quota for the month.
+    QuotaExceeded,
+
+    /// The tunnel transferred as many bytes as it may.
+    TransferLimit,
+
+    /// The request was malformed, such as a tunnel name that is too long.
+    InvalidRequest,
+
+    /// The client is older than the minimum version that the server accepts.
+    ClientOutdated,
+
+    /// A code added in a newer version of the protocol.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// Returns whether trying again later may succeed without changing the request.
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::PortInUse
+                | ErrorCode::NoPortAvailable
+                | ErrorCode::BindFailed
+                | ErrorCode::Unknown
+        )
+    }
+}
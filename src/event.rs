@@ -0,0 +1,124 @@
+//! Typed events for following the lifecycle of tunnels from library code.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::auth::Identity;
+use crate::stats::Stats;
+
+/// Number of events buffered for each receiver of a channel created with [`channel`].
+pub const EVENT_CAPACITY: usize = 256;
+
+/// Create a broadcast channel for events, with a capacity of [`EVENT_CAPACITY`].
+pub fn channel<E: Clone>() -> (broadcast::Sender<E>, broadcast::Receiver<E>) {
+    broadcast::channel(EVENT_CAPACITY)
+}
+
+/// An event in the lifecycle of a client's tunnel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// Connected to the server, which assigned a public port.
+    Connected {
+        /// Host at which visitors reach the tunnel, which is its host name if the server
+        /// assigned one.
+        remote_host: String,
+        /// Port publicly available on the remote.
+        remote_port: u16,
+    },
+
+    /// A visitor connection was opened and is being forwarded.
+    VisitorOpened {
+        /// Identifier of the connection.
+        id: Uuid,
+        /// Address of the visitor, if the server sent it.
+        addr: Option<SocketAddr>,
+        /// Public port the visitor connected to.
+        port: u16,
+    },
+
+    /// A visitor connection was closed.
+    VisitorClosed {
+        /// Identifier of the connection.
+        id: Uuid,
+        /// Bytes received from the visitor.
+        received: u64,
+        /// Bytes sent to the visitor.
+        sent: u64,
+    },
+
+    /// No message arrived from the server within the heartbeat timeout.
+    HeartbeatMissed,
+
+    /// The tunnel was disconnected, and a supervisor will reconnect it after a delay.
+    Reconnecting {
+        /// Delay before the next attempt.
+        delay: Duration,
+        /// Reason that the tunnel was disconnected.
+        error: String,
+    },
+}
+
+/// An event in the lifecycle of the tunnels on a server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerEvent {
+    /// A client opened a tunnel on a public port.
+    TunnelOpened {
+        /// Public port of the tunnel.
+        port: u16,
+        /// Name of the tunnel, if the client provided one.
+        name: Option<String>,
+        /// Address of the client's control connection.
+        client: SocketAddr,
+    },
+
+    /// A tunnel was closed and its public port released.
+    TunnelClosed {
+        /// Public port of the tunnel.
+        port: u16,
+        /// Name of the tunnel, if the client provided one.
+        name: Option<String>,
+        /// Address of the client's control connection.
+        client: SocketAddr,
+    },
+
+    /// A visitor connected to the public port of a tunnel.
+    VisitorOpened {
+        /// Public port of the tunnel.
+        port: u16,
+        /// Address of the visitor.
+        addr: SocketAddr,
+    },
+
+    /// A visitor connection that was forwarded to a client was closed.
+    VisitorClosed {
+        /// Public port of the tunnel.
+        port: u16,
+        /// Address of the visitor.
+        addr: SocketAddr,
+        /// Bytes received from the visitor.
+        received: u64,
+        /// Bytes sent to the visitor.
+        sent: u64,
+    },
+}
+
+/// Callbacks run by the server when tunnels open and close, such as to register DNS records.
+///
+/// Callbacks run on the task of the tunnel, so slow work should be spawned.
+pub trait TunnelObserver: Send + Sync {
+    /// Called when a client opens a tunnel, with its identity if it authenticated.
+    fn on_tunnel_open(&self, identity: Option<&Identity>, port: u16);
+
+    /// Called when a tunnel is closed and its port released, with the traffic of its visitors.
+    fn on_tunnel_close(&self, port: u16, stats: &Stats);
+}
+
+/// Send an event if there is a channel, ignoring whether anyone is listening.
+pub(crate) fn emit<E>(events: Option<&broadcast::Sender<E>>, event: E) {
+    if let Some(events) = events {
+        let _ = events.send(event);
+    }
+}
@@ -0,0 +1,148 @@
+//! GeoIP-based filtering of visitor connections on public ports.
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use dashmap::DashMap;
+use maxminddb::{geoip2, Reader};
+
+/// Label used in counters for addresses that are missing from the database.
+const UNKNOWN_COUNTRY: &str = "unknown";
+
+/// Which countries are permitted to connect to a tunnel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CountryPolicy {
+    /// Only visitors from these ISO country codes are accepted.
+    Allow(HashSet<String>),
+
+    /// Visitors from these ISO country codes are rejected.
+    Deny(HashSet<String>),
+}
+
+impl CountryPolicy {
+    /// Check whether a visitor with the given country code may connect.
+    ///
+    /// Visitors whose country is unknown only pass deny-lists.
+    pub fn permits(&self, country: Option<&str>) -> bool {
+        match (self, country) {
+            (CountryPolicy::Allow(codes), Some(code)) => codes.contains(code),
+            (CountryPolicy::Allow(_), None) => false,
+            (CountryPolicy::Deny(codes), Some(code)) => !codes.contains(code),
+            (CountryPolicy::Deny(_), None) => true,
+        }
+    }
+}
+
+impl FromStr for CountryPolicy {
+    type Err = anyhow::Error;
+
+    /// Parse a policy of the form `allow:US,DE` or `deny:CN`.
+    ///
+    /// ```
+    /// use bore_cli::geoip::CountryPolicy;
+    ///
+    /// let policy: CountryPolicy = "allow:us,DE".parse().unwrap();
+    /// assert!(policy.permits(Some("US")));
+    /// assert!(!policy.permits(Some("FR")));
+    /// assert!(!policy.permits(None));
+    /// ```
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, codes) = s
+            .split_once(':')
+            .context("expected a policy like `allow:US,DE` or `deny:CN`")?;
+        let codes: HashSet<String> = codes
+            .split(',')
+            .map(|code| code.trim().to_ascii_uppercase())
+            .filter(|code| !code.is_empty())
+            .collect();
+        if codes.is_empty() {
+            bail!("policy must list at least one country code");
+        }
+        match kind {
+            "allow" => Ok(CountryPolicy::Allow(codes)),
+            "deny" => Ok(CountryPolicy::Deny(codes)),
+            _ => bail!("unknown policy kind `{kind}`, expected `allow` or `deny`"),
+        }
+    }
+}
+
+/// Filter that looks up visitor addresses in a MaxMind database.
+pub struct GeoFilter {
+    /// Country database loaded into memory.
+    reader: Reader<Vec<u8>>,
+
+    /// Policy applied to tunnels without an override.
+    policy: Option<CountryPolicy>,
+
+    /// Per-port policies that take precedence over the default.
+    overrides: HashMap<u16, CountryPolicy>,
+
+    /// Number of blocked connection attempts, keyed by country code.
+    blocked: DashMap<String, u64>,
+}
+
+impl GeoFilter {
+    /// Load a filter from a MaxMind (GeoLite2/GeoIP2) country or city database.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let reader = Reader::open_readfile(path)
+            .with_context(|| format!("could not open GeoIP database {}", path.display()))?;
+        Ok(GeoFilter {
+            reader,
+            policy: None,
+            overrides: HashMap::new(),
+            blocked: DashMap::new(),
+        })
+    }
+
+    /// Set the policy applied to every tunnel without a per-port override.
+    pub fn set_policy(&mut self, policy: CountryPolicy) {
+        self.policy = Some(policy);
+    }
+
+    /// Override the policy for the tunnel on a specific public port.
+    pub fn set_port_policy(&mut self, port: u16, policy: CountryPolicy) {
+        self.overrides.insert(port, policy);
+    }
+
+    /// Look up the ISO country code of an address, if present in the database.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.reader.lookup(ip).ok()?;
+        Some(record.country?.iso_code?.to_string())
+    }
+
+    /// Check a visitor connecting to a public port, counting it if blocked.
+    ///
+    /// Returns the country code of the blocked visitor on rejection.
+    pub fn check(&self, port: u16, ip: IpAddr) -> Result<(), String> {
+        let Some(policy) = self.overrides.get(&port).or(self.policy.as_ref()) else {
+            return Ok(());
+        };
+        let country = self.country(ip);
+        if policy.permits(country.as_deref()) {
+            return Ok(());
+        }
+        let country = country.unwrap_or_else(|| UNKNOWN_COUNTRY.into());
+        *self.blocked.entry(country.clone()).or_insert(0) += 1;
+        Err(country)
+    }
+
+    /// Returns the number of blocked attempts for each country code.
+    pub fn blocked_counts(&self) -> Vec<(String, u64)> {
+        let mut counts: Vec<_> = self
+            .blocked
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    /// Returns the total number of blocked attempts.
+    pub fn blocked_total(&self) -> u64 {
+        self.blocked.iter().map(|entry| *entry.value()).sum()
+    }
+}
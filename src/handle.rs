@@ -0,0 +1,168 @@
+//! Handle on a client that outlives its connections to the server.
+
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+
+use tokio::sync::{watch, Mutex as AsyncMutex};
+use tokio::task::AbortHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::client::LazyClient;
+use crate::error::Result;
+
+/// State of the connection behind a [`ClientHandle`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientState {
+    /// Not connected, with the error that ended the last connection if there was one.
+    Disconnected(Option<String>),
+
+    /// Connecting to the server.
+    Connecting,
+
+    /// Connected, with the port assigned on the remote server.
+    Connected(u16),
+}
+
+impl fmt::Display for ClientState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientState::Disconnected(None) => write!(f, "disconnected"),
+            ClientState::Disconnected(Some(err)) => write!(f, "disconnected after error: {err}"),
+            ClientState::Connecting => write!(f, "connecting"),
+            ClientState::Connected(port) => write!(f, "connected on port {port}"),
+        }
+    }
+}
+
+/// Handle on a client that stays usable when its connection to the server drops.
+///
+/// [`Client::listen`](crate::client::Client::listen) consumes the client, while the handle keeps
+/// its configuration, so [`ClientHandle::reconnect`] can connect again as often as needed. New
+/// connections take over the tunnel's port if the server still holds it. Clones of a handle share
+/// its connection, and dropping the last one closes it.
+#[derive(Clone)]
+pub struct ClientHandle(Arc<Inner>);
+
+struct Inner {
+    /// Configuration of the next connection, locked while connecting so reconnects take turns.
+    lazy: AsyncMutex<LazyClient>,
+
+    /// Token from the configuration, which stops every connection when cancelled.
+    shutdown: CancellationToken,
+
+    state: watch::Sender<ClientState>,
+    current: Mutex<Connection>,
+}
+
+/// The task listening on the current connection.
+#[derive(Default)]
+struct Connection {
+    /// Incremented for each connection, so that one that was replaced does not report its end.
+    generation: u64,
+    task: Option<AbortHandle>,
+    shutdown: Option<CancellationToken>,
+}
+
+impl Connection {
+    /// Drop the connection at once, without saying goodbye, so the server may hold the port.
+    fn abort(&mut self) {
+        self.generation += 1;
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        self.shutdown = None;
+    }
+}
+
+impl ClientHandle {
+    /// Create a handle that is not connected yet, from the configuration of a client.
+    pub fn new(mut lazy: LazyClient) -> Self {
+        lazy.options_mut().resumable = true;
+        let shutdown = lazy.options_mut().shutdown.clone();
+        let (state, _) = watch::channel(ClientState::Disconnected(None));
+        ClientHandle(Arc::new(Inner {
+            lazy: AsyncMutex::new(lazy),
+            shutdown,
+            state,
+            current: Mutex::default(),
+        }))
+    }
+
+    /// Returns the current state of the connection.
+    pub fn state(&self) -> ClientState {
+        self.0.state.borrow().clone()
+    }
+
+    /// Returns a receiver that sees each change of state, to wait for the connection to drop.
+    pub fn subscribe(&self) -> watch::Receiver<ClientState> {
+        self.0.state.subscribe()
+    }
+
+    /// Returns the port publicly available on the remote, while connected.
+    pub fn remote_port(&self) -> Option<u16> {
+        match *self.0.state.borrow() {
+            ClientState::Connected(port) => Some(port),
+            _ => None,
+        }
+    }
+
+    /// Connect to the server and listen in the background, returning the assigned port.
+    ///
+    /// A connection that is still open is dropped first, so the server holds its port for this
+    /// one to take over if it keeps tunnels for reconnecting clients.
+    pub async fn reconnect(&self) -> Result<u16> {
+        let mut lazy = self.0.lazy.lock().await;
+        self.0.current.lock().unwrap().abort();
+        self.0.state.send_replace(ClientState::Connecting);
+        let shutdown = self.0.shutdown.child_token();
+        lazy.options_mut().shutdown = shutdown.clone();
+        let client = match lazy.connect().await {
+            Ok(client) => client,
+            Err(err) => {
+                let error = format!("{err:#}");
+                self.0
+                    .state
+                    .send_replace(ClientState::Disconnected(Some(error)));
+                return Err(err);
+            }
+        };
+        lazy.options_mut().resume_token = client.resume_token();
+        let port = client.remote_port();
+
+        let mut current = self.0.current.lock().unwrap();
+        current.generation += 1;
+        let generation = current.generation;
+        let inner = Arc::downgrade(&self.0);
+        let task = tokio::spawn(async move {
+            let result = client.listen().await;
+            let Some(inner) = Weak::upgrade(&inner) else {
+                return;
+            };
+            let current = inner.current.lock().unwrap();
+            if current.generation == generation {
+                let error = result.err().map(|err| format!("{err:#}"));
+                inner.state.send_replace(ClientState::Disconnected(error));
+            }
+        });
+        current.task = Some(task.abort_handle());
+        current.shutdown = Some(shutdown);
+        // The state changes while the connection is locked, so its task reports its end after.
+        self.0.state.send_replace(ClientState::Connected(port));
+        Ok(port)
+    }
+
+    /// Close the connection, saying goodbye so the server releases the port at once.
+    pub fn disconnect(&self) {
+        if let Some(shutdown) = &self.0.current.lock().unwrap().shutdown {
+            shutdown.cancel();
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some(shutdown) = &self.current.get_mut().unwrap().shutdown {
+            shutdown.cancel();
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! HTTP health endpoint of the server, for container orchestrators, load balancers, and
+//! operators.
+//!
+//! The endpoint answers `GET /healthz` with a small JSON report, and `GET /status` with the same
+//! report as plain text for people. `GET /tunnels` lists the tunnels whose clients opted into a
+//! public listing, as JSON. It speaks just enough HTTP/1.1 for probes like those of
+//! Kubernetes and Docker, closing the connection after each response.
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout};
+use tracing::{debug, warn};
+
+use crate::shared::{ACCEPT_RETRY_DELAY, NETWORK_TIMEOUT};
+
+/// Maximum size of a request head, which probes keep far smaller.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Readiness and load of a server, reported by its health endpoint.
+#[derive(Clone, Debug, Serialize)]
+pub struct HealthReport {
+    /// Whether the server accepts new tunnels, which is false once it starts shutting down.
+    pub ready: bool,
+
+    /// Seconds since the server started.
+    pub uptime_secs: u64,
+
+    /// Number of tunnels currently open.
+    pub tunnels: usize,
+
+    /// Number of visitor connections currently open.
+    pub active_connections: u64,
+
+    /// Number of visitor connections waiting for their client to accept them.
+    pub pending_connections: usize,
+
+    /// Number of ports in the server's range that are neither excluded nor used by open tunnels.
+    pub ports_available: u32,
+
+    /// Number of ports in the server's range that are not excluded.
+    pub ports_total: u32,
+}
+
+/// Tunnel shown in the server's public listing, at the request of its client.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ListedTunnel {
+    /// Public port of the tunnel.
+    pub port: u16,
+
+    /// Name of the tunnel, if the client provided one.
+    pub name: Option<String>,
+
+    /// Description of the tunnel, if the client provided one.
+    pub description: Option<String>,
+
+    /// Host name under which visitors reach the tunnel, if the server assigned one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+}
+
+impl HealthReport {
+    /// Render the report as a plain text status page.
+    pub fn status_page(&self) -> String {
+        let secs = self.uptime_secs;
+        let used = self.ports_total.saturating_sub(self.ports_available);
+        let percent = match self.ports_total {
+            0 => 0.0,
+            total => 100.0 * used as f64 / total as f64,
+        };
+        let status = match self.ready {
+            true => "ready",
+            false => "shutting down",
+        };
+        format!(
+            "bore server {version}\n\n\
+             status:       {status}\n\
+             uptime:       {hours}h{minutes:02}m{seconds:02}s\n\
+             tunnels:      {tunnels}\n\
+             connections:  {active} active, {pending} pending\n\
+             ports:        {used} of {total} in use ({percent:.1}%)\n",
+            version = env!("CARGO_PKG_VERSION"),
+            hours = secs / 3600,
+            minutes = secs / 60 % 60,
+            seconds = secs % 60,
+            tunnels = self.tunnels,
+            active = self.active_connections,
+            pending = self.pending_connections,
+            total = self.ports_total,
+        )
+    }
+}
+
+/// Answer health checks on a listener, building a fresh report and listing for each request.
+pub async fn serve(
+    listener: TcpListener,
+    report: impl Fn() -> HealthReport,
+    listing: impl Fn() -> Vec<ListedTunnel>,
+) {
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!(%err, "failed to accept health check");
+                sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        // Reports and listings are cheap, so building both before reading the request keeps this
+        // simple.
+        let (report, listing) = (report(), listing());
+        tokio::spawn(async move {
+            if let Err(err) = respond(&mut stream, report, listing).await {
+                debug!(%err, %addr, "health check failed");
+            }
+        });
+    }
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    report: HealthReport,
+    listing: Vec<ListedTunnel>,
+) -> Result<()> {
+    let head = timeout(NETWORK_TIMEOUT, read_head(stream)).await??;
+    let mut parts = head.split(' ');
+    const JSON: &str = "application/json";
+    const TEXT: &str = "text/plain; charset=utf-8";
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET" | "HEAD"), Some("/healthz")) => match report.ready {
+            true => ("200 OK", JSON, serde_json::to_string(&report)?),
+            false => (
+                "503 Service Unavailable",
+                JSON,
+                serde_json::to_string(&report)?,
+            ),
+        },
+        // The status page is for people, so it is served even while shutting down.
+        (Some("GET" | "HEAD"), Some("/" | "/status")) => ("200 OK", TEXT, report.status_page()),
+        (Some("GET" | "HEAD"), Some("/tunnels")) => {
+            ("200 OK", JSON, serde_json::to_string(&listing)?)
+        }
+        (Some("GET" | "HEAD"), Some(_)) => ("404 Not Found", TEXT, "not found\n".into()),
+        _ => (
+            "405 Method Not Allowed",
+            TEXT,
+            "method not allowed\n".into(),
+        ),
+    };
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    );
+    if !head.starts_with("HEAD ") {
+        response.push_str(&body);
+    }
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Read the head of an HTTP request, returning its request line.
+pub(crate) async fn read_head(stream: &mut TcpStream) -> Result<String> {
+    let mut buf = Vec::new();
+    // Probes send no body, so the request ends at the first empty line.
+    while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+        if buf.len() >= MAX_REQUEST_SIZE {
+            bail!("request head too large");
+        }
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("connection closed before the end of the request");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let head = String::from_utf8_lossy(&buf);
+    Ok(head.lines().next().unwrap_or_default().to_string())
+}
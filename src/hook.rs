@@ -0,0 +1,67 @@
+//! Hook commands run when a tunnel connects or disconnects, and env files describing it.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::client::Scheme;
+use crate::status::write_atomically;
+
+/// Run a command through the system shell with extra environment variables, waiting for it
+/// to exit.
+///
+/// Failures are logged rather than returned, since hooks should not bring down a tunnel.
+pub async fn run_hook(command: &str, vars: &[(&str, String)]) {
+    let (program, flag) = match cfg!(windows) {
+        true => ("cmd", "/C"),
+        false => ("sh", "-c"),
+    };
+    let mut shell = Command::new(program);
+    shell.arg(flag).arg(command);
+    shell.envs(vars.iter().map(|(key, value)| (key, value)));
+    match shell.status().await {
+        Ok(status) if status.success() => info!(command, "hook finished"),
+        Ok(status) => warn!(command, %status, "hook failed"),
+        Err(err) => warn!(command, %err, "could not run hook"),
+    }
+}
+
+/// File in which a client keeps the address of its tunnel, as `KEY=value` lines that shells can
+/// `source` and dotenv loaders can read.
+///
+/// The file sets `BORE_REMOTE_HOST`, `BORE_REMOTE_PORT`, and `BORE_URL`, and is written again
+/// whenever the client connects with a different address.
+#[derive(Clone, Debug)]
+pub struct EnvFile {
+    /// Path of the file.
+    pub path: PathBuf,
+
+    /// Scheme of the URL in `BORE_URL`.
+    pub scheme: Scheme,
+}
+
+impl EnvFile {
+    /// Keep the address in a file, with a `tcp://` URL.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        EnvFile {
+            path: path.into(),
+            scheme: Scheme::Tcp,
+        }
+    }
+
+    /// Write the address of a tunnel, unless the file already has it.
+    ///
+    /// The file is replaced in one step, so that readers never see it half written.
+    pub fn write(&self, host: &str, port: u16) -> io::Result<()> {
+        let url = self.scheme.url(host, port);
+        let contents =
+            format!("BORE_REMOTE_HOST={host}\nBORE_REMOTE_PORT={port}\nBORE_URL={url}\n");
+        if fs::read_to_string(&self.path).is_ok_and(|current| current == contents) {
+            return Ok(());
+        }
+        write_atomically(&self.path, contents.as_bytes())
+    }
+}
@@ -0,0 +1,783 @@
+//! Inspection of HTTP traffic passing through a tunnel, with a local web UI.
+//!
+//! When enabled, the client parses the HTTP/1.x requests of visitors and the responses of
+//! the local service as they are proxied, keeping the most recent exchanges in memory.
+//! They can be browsed and replayed against the local service through a small web server.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use rustls::ClientConfig;
+use serde::Serialize;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+
+use crate::client::connect_stream;
+
+/// Default number of exchanges kept in memory.
+pub const DEFAULT_CAPACITY: usize = 100;
+
+/// Default number of bytes recorded from each message body.
+pub const DEFAULT_BODY_LIMIT: usize = 64 * 1024;
+
+/// Largest message head that is parsed, beyond which inspection of a stream stops.
+const MAX_HEAD_LENGTH: usize = 64 * 1024;
+
+/// Maximum number of headers parsed in a message.
+const MAX_HEADERS: usize = 100;
+
+/// How long a replayed request waits for the local service to respond.
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An HTTP request or response, with its body truncated to the recording limit.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct HttpMessage {
+    /// Request line or status line, such as `GET / HTTP/1.1`.
+    pub start_line: String,
+
+    /// Header names and values, in order.
+    pub headers: Vec<(String, String)>,
+
+    /// Recorded part of the body, decoded lossily as UTF-8.
+    pub body: String,
+
+    /// Size of the whole body, in bytes.
+    pub body_size: u64,
+
+    /// Whether the body was longer than the recording limit.
+    pub truncated: bool,
+
+    /// Raw bytes of the message as sent, if they fit within the recording limit.
+    #[serde(skip)]
+    raw: Option<Vec<u8>>,
+}
+
+/// A request and, once it arrives, its response.
+#[derive(Clone, Debug, Serialize)]
+pub struct Exchange {
+    /// Identifier of the exchange, increasing over time.
+    pub id: u64,
+
+    /// Time at which the request completed, in seconds since the Unix epoch.
+    pub timestamp: u64,
+
+    /// Request method.
+    pub method: String,
+
+    /// Request path.
+    pub path: String,
+
+    /// Response status code, if a response has been received.
+    pub status: Option<u16>,
+
+    /// Time between the request and its response, in milliseconds.
+    pub duration_ms: Option<u64>,
+
+    /// Identifier of the exchange this one replayed, if any.
+    pub replay_of: Option<u64>,
+
+    /// The request.
+    pub request: HttpMessage,
+
+    /// The response, if one has been received.
+    pub response: Option<HttpMessage>,
+}
+
+/// Records HTTP exchanges of a tunnel and serves them to a local web UI.
+#[derive(Debug)]
+pub struct Inspector {
+    exchanges: Mutex<VecDeque<Exchange>>,
+    next_id: AtomicU64,
+    capacity: usize,
+    body_limit: usize,
+}
+
+impl Default for Inspector {
+    fn default() -> Self {
+        Inspector::new(DEFAULT_CAPACITY, DEFAULT_BODY_LIMIT)
+    }
+}
+
+impl Inspector {
+    /// Create an inspector keeping `capacity` exchanges with bodies of up to `body_limit` bytes.
+    pub fn new(capacity: usize, body_limit: usize) -> Self {
+        Inspector {
+            exchanges: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(1),
+            capacity,
+            body_limit,
+        }
+    }
+
+    /// Returns the recorded exchanges, newest first.
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        self.exchanges
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns a recorded exchange by its identifier.
+    pub fn get(&self, id: u64) -> Option<Exchange> {
+        let exchanges = self.exchanges.lock().unwrap();
+        exchanges.iter().find(|exchange| exchange.id == id).cloned()
+    }
+
+    /// Copy data between a local and a remote stream, recording the HTTP exchanges.
+    ///
+    /// Streams that do not look like HTTP are passed through unchanged. Each direction is copied
+    /// through a buffer of `buffer_size` bytes. Returns the number of bytes copied from `local`
+    /// to `remote`, and from `remote` to `local`.
+    pub async fn proxy<L, R>(
+        &self,
+        local: L,
+        remote: R,
+        buffer_size: usize,
+    ) -> io::Result<(u64, u64)>
+    where
+        L: AsyncRead + AsyncWrite + Unpin,
+        R: AsyncRead + AsyncWrite + Unpin,
+    {
+        let pending = Mutex::new(VecDeque::new());
+        let mut requests = Parser::new(self.body_limit);
+        let mut responses = Parser::new(self.body_limit);
+        let (mut local_read, mut local_write) = io::split(local);
+        let (mut remote_read, mut remote_write) = io::split(remote);
+        let (mut sent, mut received) = (0, 0);
+        let upstream = copy_with(&mut remote_read, &mut local_write, buffer_size, |data| {
+            received += data.len() as u64;
+            for message in requests.feed_requests(data) {
+                self.record_request(&pending, message);
+            }
+        });
+        let downstream = copy_with(&mut local_read, &mut remote_write, buffer_size, |data| {
+            sent += data.len() as u64;
+            let head = || pending_is_head(&pending);
+            for message in responses.feed_responses(data, head) {
+                self.record_response(&pending, message);
+            }
+        });
+        let result = tokio::try_join!(upstream, downstream);
+        if let Some(message) = responses.finish() {
+            self.record_response(&pending, message);
+        }
+        result?;
+        Ok((sent, received))
+    }
+
+    fn record_request(
+        &self,
+        pending: &Mutex<VecDeque<(u64, String, Instant)>>,
+        (request, method, path): (HttpMessage, String, String),
+    ) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        debug!(id, %method, %path, "recorded request");
+        pending
+            .lock()
+            .unwrap()
+            .push_back((id, method.clone(), Instant::now()));
+        self.push(Exchange {
+            id,
+            timestamp: unix_time(),
+            method,
+            path,
+            status: None,
+            duration_ms: None,
+            replay_of: None,
+            request,
+            response: None,
+        });
+    }
+
+    fn record_response(
+        &self,
+        pending: &Mutex<VecDeque<(u64, String, Instant)>>,
+        (response, status): (HttpMessage, u16),
+    ) {
+        let Some((id, _, started)) = pending.lock().unwrap().pop_front() else {
+            return;
+        };
+        let mut exchanges = self.exchanges.lock().unwrap();
+        if let Some(exchange) = exchanges.iter_mut().find(|exchange| exchange.id == id) {
+            exchange.status = Some(status);
+            exchange.duration_ms = Some(started.elapsed().as_millis() as u64);
+            exchange.response = Some(response);
+        }
+    }
+
+    fn push(&self, exchange: Exchange) {
+        let mut exchanges = self.exchanges.lock().unwrap();
+        exchanges.push_back(exchange);
+        while exchanges.len() > self.capacity {
+            exchanges.pop_front();
+        }
+    }
+
+    /// Send a recorded request to the local service again, recording it as a new exchange.
+    pub async fn replay(
+        &self,
+        id: u64,
+        local_host: &str,
+        local_port: u16,
+        local_tls: Option<&Arc<ClientConfig>>,
+    ) -> Result<Exchange> {
+        let original = self.get(id).context("no such request")?;
+        let Some(raw) = &original.request.raw else {
+            bail!("request was too large to be recorded in full");
+        };
+        let mut stream = connect_stream(local_host, local_port, local_tls, None).await?;
+        let started = Instant::now();
+        stream.write_all(raw).await?;
+
+        let mut parser = Parser::new(self.body_limit);
+        let is_head = original.method.eq_ignore_ascii_case("HEAD");
+        let mut buf = vec![0; 8192];
+        let response = timeout(REPLAY_TIMEOUT, async {
+            loop {
+                let n = stream.read(&mut buf).await?;
+                if n == 0 {
+                    return Ok::<_, io::Error>(parser.finish());
+                }
+                if let Some(response) = parser.feed_responses(&buf[..n], || is_head).pop() {
+                    return Ok(Some(response));
+                }
+            }
+        })
+        .await
+        .context("timed out waiting for the local service")??;
+        let (response, status) = response.context("local service closed the connection")?;
+
+        let exchange = Exchange {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp: unix_time(),
+            status: Some(status),
+            duration_ms: Some(started.elapsed().as_millis() as u64),
+            replay_of: Some(id),
+            response: Some(response),
+            ..original
+        };
+        self.push(exchange.clone());
+        Ok(exchange)
+    }
+
+    /// Serve the web UI and JSON API on a listener, replaying requests to a local service.
+    pub async fn serve(
+        self: Arc<Self>,
+        listener: TcpListener,
+        local_host: String,
+        local_port: u16,
+        local_tls: Option<Arc<ClientConfig>>,
+    ) -> Result<()> {
+        info!(addr = ?listener.local_addr()?, "inspector listening");
+        let local = Arc::new((local_host, local_port, local_tls));
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let this = Arc::clone(&self);
+            let local = Arc::clone(&local);
+            tokio::spawn(async move {
+                let (local_host, local_port, local_tls) = &*local;
+                let result = this
+                    .handle_http(stream, local_host, *local_port, local_tls.as_ref())
+                    .await;
+                if let Err(err) = result {
+                    warn!(%err, ?addr, "inspector request failed");
+                }
+            });
+        }
+    }
+
+    async fn handle_http(
+        &self,
+        mut stream: TcpStream,
+        local_host: &str,
+        local_port: u16,
+        local_tls: Option<&Arc<ClientConfig>>,
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        let (method, path) = loop {
+            let mut chunk = [0; 4096];
+            let n = timeout(REPLAY_TIMEOUT, stream.read(&mut chunk)).await??;
+            if n == 0 {
+                return Ok(());
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut request = httparse::Request::new(&mut headers);
+            if request.parse(&buf)?.is_complete() {
+                let method = request.method.unwrap_or_default().to_string();
+                break (method, request.path.unwrap_or_default().to_string());
+            }
+            if buf.len() > MAX_HEAD_LENGTH {
+                bail!("request head too large");
+            }
+        };
+
+        let segments: Vec<_> = path.trim_matches('/').split('/').collect();
+        let (status, content_type, body) = match (&method[..], &segments[..]) {
+            ("GET", [""]) => (200, "text/html; charset=utf-8", INDEX_HTML.into()),
+            ("GET", ["api", "requests"]) => json(&self.exchanges()),
+            ("GET", ["api", "requests", id]) => match id.parse().ok().and_then(|id| self.get(id)) {
+                Some(exchange) => json(&exchange),
+                None => (404, "text/plain", "no such request".into()),
+            },
+            ("POST", ["api", "requests", id, "replay"]) => {
+                let id = id.parse().unwrap_or_default();
+                match self.replay(id, local_host, local_port, local_tls).await {
+                    Ok(exchange) => json(&exchange),
+                    Err(err) => (502, "text/plain", format!("{err:#}").into_bytes()),
+                }
+            }
+            _ => (404, "text/plain", "not found".into()),
+        };
+        let head = format!(
+            "HTTP/1.1 {status} {}\r\ncontent-type: {content_type}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+            if status == 200 { "OK" } else { "Error" },
+            body.len(),
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&body).await?;
+        Ok(())
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> (u16, &'static str, Vec<u8>) {
+    let body = serde_json::to_vec(value).expect("exchanges are serializable");
+    (200, "application/json", body)
+}
+
+fn pending_is_head(pending: &Mutex<VecDeque<(u64, String, Instant)>>) -> bool {
+    let pending = pending.lock().unwrap();
+    pending
+        .front()
+        .is_some_and(|(_, method, _)| method.eq_ignore_ascii_case("HEAD"))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Copy data from a reader to a writer, passing each chunk to a callback after writing it, and
+/// shut the writer down at EOF.
+async fn copy_with<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    buffer_size: usize,
+    mut inspect: impl FnMut(&[u8]),
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; buffer_size];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return writer.shutdown().await;
+        }
+        writer.write_all(&buf[..n]).await?;
+        inspect(&buf[..n]);
+    }
+}
+
+/// Position of an incremental parser within a stream of HTTP messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// Waiting for a complete message head.
+    Head,
+    /// Reading a body with a known number of bytes remaining.
+    Body(u64),
+    /// Waiting for the size line of a chunk.
+    ChunkSize,
+    /// Reading chunk data with a number of bytes remaining.
+    ChunkData(u64),
+    /// Waiting for the line break after chunk data.
+    ChunkEnd,
+    /// Skipping trailer lines after the last chunk.
+    Trailer,
+    /// Reading a body that ends when the connection closes.
+    UntilEof,
+    /// The stream is not HTTP, or has switched protocols, and is no longer parsed.
+    Stopped,
+}
+
+/// A message completed by a parser, with the fields needed to pair it with others.
+struct Parsed {
+    message: HttpMessage,
+    method: String,
+    path: String,
+    status: u16,
+}
+
+/// Incremental parser for one direction of an HTTP/1.x connection.
+struct Parser {
+    buf: Vec<u8>,
+    state: State,
+    body_limit: usize,
+    message: HttpMessage,
+    body: Vec<u8>,
+    /// Request method and path, or response status, of the message being parsed.
+    method: String,
+    path: String,
+    status: u16,
+}
+
+impl Parser {
+    fn new(body_limit: usize) -> Self {
+        Parser {
+            buf: Vec::new(),
+            state: State::Head,
+            body_limit,
+            message: HttpMessage::default(),
+            body: Vec::new(),
+            method: String::new(),
+            path: String::new(),
+            status: 0,
+        }
+    }
+
+    /// Parse request data, returning each request completed by it.
+    fn feed_requests(&mut self, data: &[u8]) -> Vec<(HttpMessage, String, String)> {
+        self.feed(data, true, || false)
+            .into_iter()
+            .map(|parsed| (parsed.message, parsed.method, parsed.path))
+            .collect()
+    }
+
+    /// Parse response data, returning each response completed by it.
+    ///
+    /// The `head` callback tells whether the matching request used the `HEAD` method, in
+    /// which case the response has no body.
+    fn feed_responses(&mut self, data: &[u8], head: impl Fn() -> bool) -> Vec<(HttpMessage, u16)> {
+        self.feed(data, false, head)
+            .into_iter()
+            .map(|parsed| (parsed.message, parsed.status))
+            .collect()
+    }
+
+    /// Complete a message whose body is delimited by the end of the stream.
+    fn finish(&mut self) -> Option<(HttpMessage, u16)> {
+        if self.state != State::UntilEof {
+            return None;
+        }
+        self.state = State::Stopped;
+        let parsed = self.complete();
+        Some((parsed.message, parsed.status))
+    }
+
+    fn feed(&mut self, data: &[u8], request: bool, head: impl Fn() -> bool) -> Vec<Parsed> {
+        let mut messages = Vec::new();
+        if self.state == State::Stopped {
+            return messages;
+        }
+        self.buf.extend_from_slice(data);
+        loop {
+            match self.state {
+                State::Head => {
+                    let parsed = if request {
+                        self.parse_request_head()
+                    } else {
+                        self.parse_response_head(head())
+                    };
+                    match parsed {
+                        Some(true) => messages.push(self.complete()),
+                        Some(false) => (),
+                        None => break,
+                    }
+                }
+                State::Body(remaining) => match self.read_body(remaining) {
+                    Some(0) => messages.push(self.complete()),
+                    Some(remaining) => self.state = State::Body(remaining),
+                    None => break,
+                },
+                State::ChunkData(remaining) => match self.read_body(remaining) {
+                    Some(0) => self.state = State::ChunkEnd,
+                    Some(remaining) => self.state = State::ChunkData(remaining),
+                    None => break,
+                },
+                State::ChunkSize => {
+                    let Some(line) = self.take_line() else { break };
+                    let size = line.split(';').next().unwrap_or_default().trim();
+                    match u64::from_str_radix(size, 16) {
+                        Ok(0) => self.state = State::Trailer,
+                        Ok(size) => self.state = State::ChunkData(size),
+                        Err(_) => self.state = State::Stopped,
+                    }
+                }
+                State::ChunkEnd => {
+                    if self.buf.len() < 2 {
+                        break;
+                    }
+                    self.take(2);
+                    self.state = State::ChunkSize;
+                }
+                State::Trailer => {
+                    let Some(line) = self.take_line() else { break };
+                    if line.is_empty() {
+                        messages.push(self.complete());
+                    }
+                }
+                State::UntilEof => {
+                    let data = std::mem::take(&mut self.buf);
+                    self.message.raw = None;
+                    self.record_body(&data);
+                    break;
+                }
+                State::Stopped => {
+                    self.buf.clear();
+                    break;
+                }
+            }
+            if self.state == State::Stopped {
+                self.buf.clear();
+                break;
+            }
+        }
+        messages
+    }
+
+    /// Parse a request head, returning whether the request is already complete, or `None`
+    /// if more data is needed.
+    fn parse_request_head(&mut self) -> Option<bool> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        let len = match request.parse(&self.buf) {
+            Ok(httparse::Status::Complete(len)) => len,
+            Ok(httparse::Status::Partial) if self.buf.len() <= MAX_HEAD_LENGTH => return None,
+            _ => {
+                self.state = State::Stopped;
+                return None;
+            }
+        };
+        self.method = request.method.unwrap_or_default().to_string();
+        self.path = request.path.unwrap_or_default().to_string();
+        let start_line = format!(
+            "{} {} HTTP/1.{}",
+            self.method,
+            self.path,
+            request.version.unwrap_or(1)
+        );
+        let framing = Framing::of(request.headers);
+        let headers = owned_headers(request.headers);
+        self.start(start_line, headers, len);
+        Some(self.begin_body(framing.unwrap_or(Framing::Length(0))))
+    }
+
+    /// Parse a response head, like [`Parser::parse_request_head`].
+    fn parse_response_head(&mut self, head: bool) -> Option<bool> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        let len = match response.parse(&self.buf) {
+            Ok(httparse::Status::Complete(len)) => len,
+            Ok(httparse::Status::Partial) if self.buf.len() <= MAX_HEAD_LENGTH => return None,
+            _ => {
+                self.state = State::Stopped;
+                return None;
+            }
+        };
+        let code = response.code.unwrap_or_default();
+        self.status = code;
+        let start_line = format!(
+            "HTTP/1.{} {} {}",
+            response.version.unwrap_or(1),
+            code,
+            response.reason.unwrap_or_default()
+        );
+        let framing = match code {
+            100..=199 | 204 | 304 => Framing::Length(0),
+            _ if head => Framing::Length(0),
+            _ => Framing::of(response.headers).unwrap_or(Framing::UntilEof),
+        };
+        let headers = owned_headers(response.headers);
+        self.start(start_line, headers, len);
+        if (100..200).contains(&code) && code != 101 {
+            // Informational responses precede the final response to the same request.
+            self.message = HttpMessage::default();
+            return Some(false);
+        }
+        let complete = self.begin_body(framing);
+        if code == 101 {
+            // The connection has switched to another protocol.
+            self.state = State::Stopped;
+        }
+        Some(complete)
+    }
+
+    fn start(&mut self, start_line: String, headers: Vec<(String, String)>, len: usize) {
+        self.message = HttpMessage {
+            start_line,
+            headers,
+            raw: Some(Vec::new()),
+            ..Default::default()
+        };
+        self.body.clear();
+        self.take(len);
+    }
+
+    /// Move into the state for reading a body, returning whether the message is complete.
+    fn begin_body(&mut self, framing: Framing) -> bool {
+        self.state = match framing {
+            Framing::Length(0) => return true,
+            Framing::Length(len) => State::Body(len),
+            Framing::Chunked => State::ChunkSize,
+            Framing::UntilEof => State::UntilEof,
+        };
+        false
+    }
+
+    /// Remove bytes from the front of the buffer, keeping them as raw message data.
+    fn take(&mut self, len: usize) -> Vec<u8> {
+        let data: Vec<u8> = self.buf.drain(..len).collect();
+        let limit = self.body_limit + MAX_HEAD_LENGTH;
+        if let Some(raw) = &mut self.message.raw {
+            if raw.len() + data.len() <= limit {
+                raw.extend_from_slice(&data);
+            } else {
+                self.message.raw = None;
+            }
+        }
+        data
+    }
+
+    /// Remove a line from the front of the buffer, without its line break.
+    fn take_line(&mut self) -> Option<String> {
+        let Some(end) = self.buf.windows(2).position(|w| w == b"\r\n") else {
+            if self.buf.len() > MAX_HEAD_LENGTH {
+                self.state = State::Stopped;
+            }
+            return None;
+        };
+        let line = self.take(end + 2);
+        Some(String::from_utf8_lossy(&line[..end]).into())
+    }
+
+    /// Consume up to `remaining` bytes of body, returning how many remain, or `None` if
+    /// the buffer is empty.
+    fn read_body(&mut self, remaining: u64) -> Option<u64> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let len = remaining.min(self.buf.len() as u64);
+        let data = self.take(len as usize);
+        self.record_body(&data);
+        Some(remaining - len)
+    }
+
+    fn record_body(&mut self, data: &[u8]) {
+        self.message.body_size += data.len() as u64;
+        let room = self.body_limit.saturating_sub(self.body.len());
+        if data.len() > room {
+            self.message.truncated = true;
+        }
+        self.body.extend_from_slice(&data[..data.len().min(room)]);
+    }
+
+    /// Finish the current message and prepare for the next one on the stream.
+    fn complete(&mut self) -> Parsed {
+        if self.state != State::Stopped {
+            self.state = State::Head;
+        }
+        let mut message = std::mem::take(&mut self.message);
+        message.body = String::from_utf8_lossy(&self.body).into();
+        self.body.clear();
+        Parsed {
+            message,
+            method: self.method.clone(),
+            path: self.path.clone(),
+            status: self.status,
+        }
+    }
+}
+
+fn owned_headers(headers: &[httparse::Header]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|h| (h.name.to_string(), String::from_utf8_lossy(h.value).into()))
+        .collect()
+}
+
+/// How the end of a message body is determined.
+#[derive(Clone, Copy, Debug)]
+enum Framing {
+    Length(u64),
+    Chunked,
+    UntilEof,
+}
+
+impl Framing {
+    /// Read the framing from message headers, if either header that sets it is present.
+    fn of(headers: &[httparse::Header]) -> Option<Self> {
+        let mut framing = None;
+        for header in headers {
+            let value = String::from_utf8_lossy(header.value);
+            if header.name.eq_ignore_ascii_case("transfer-encoding")
+                && value.to_ascii_lowercase().contains("chunked")
+            {
+                return Some(Framing::Chunked);
+            }
+            if header.name.eq_ignore_ascii_case("content-length") {
+                framing = value.trim().parse().ok().map(Framing::Length);
+            }
+        }
+        framing
+    }
+}
+
+const INDEX_HTML: &str = r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>bore inspector</title>
+<style>
+  body { font-family: sans-serif; margin: 0; display: flex; height: 100vh; }
+  #list { width: 40%; overflow-y: auto; border-right: 1px solid #ccc; }
+  #detail { flex: 1; overflow-y: auto; padding: 1em; }
+  table { width: 100%; border-collapse: collapse; font-size: 14px; }
+  td { padding: 4px 8px; border-bottom: 1px solid #eee; cursor: pointer; }
+  tr:hover { background: #f4f4f4; }
+  pre { background: #f8f8f8; padding: 8px; white-space: pre-wrap; word-break: break-all; }
+</style>
+</head>
+<body>
+<div id="list"><table id="requests"></table></div>
+<div id="detail"><p>Select a request to inspect it.</p></div>
+<script>
+let selected = null;
+const esc = s => String(s).replace(/[&<>"]/g, c => ({"&": "&amp;", "<": "&lt;", ">": "&gt;", '"': "&quot;"}[c]));
+const message = m => m ? `<pre>${esc(m.start_line)}\n${m.headers.map(([k, v]) => esc(k + ": " + v)).join("\n")}\n\n${esc(m.body)}${m.truncated ? "\n[truncated]" : ""}</pre>` : "<p>No response yet.</p>";
+async function refresh() {
+  const exchanges = await (await fetch("/api/requests")).json();
+  document.getElementById("requests").innerHTML = exchanges.map(e =>
+    `<tr onclick="show(${e.id})"><td>${e.id}</td><td>${esc(e.method)}</td><td>${esc(e.path)}</td>` +
+    `<td>${e.status ?? ""}</td><td>${e.duration_ms ?? ""}${e.duration_ms != null ? " ms" : ""}</td></tr>`).join("");
+}
+async function show(id) {
+  selected = id;
+  const e = await (await fetch(`/api/requests/${id}`)).json();
+  document.getElementById("detail").innerHTML =
+    `<h3>${esc(e.method)} ${esc(e.path)}</h3><button onclick="replay(${e.id})">Replay</button>` +
+    `<h4>Request</h4>${message(e.request)}<h4>Response</h4>${message(e.response)}`;
+}
+async function replay(id) {
+  const resp = await fetch(`/api/requests/${id}/replay`, { method: "POST" });
+  if (!resp.ok) { alert(await resp.text()); return; }
+  const e = await resp.json();
+  await refresh();
+  show(e.id);
+}
+refresh();
+setInterval(refresh, 2000);
+</script>
+</body>
+</html>
+"#;
@@ -11,11 +11,87 @@
 //! There are two components to the crate, offering implementations of the
 //! server network daemon and client local forwarding proxy. Both are public
 //! members and can be run programmatically with a Tokio 1.0 runtime.
+//!
+//! The client and server are behind the `client` and `server` cargo features,
+//! which are both enabled by default along with the `cli` feature for the
+//! binary. Applications that embed only one of them can disable the default
+//! features to leave out the other and its dependencies.
 
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+#[cfg(feature = "server")]
+pub mod acme;
+#[cfg(feature = "client")]
+pub mod admin;
+#[cfg(feature = "server")]
+pub mod allocator;
+#[cfg(feature = "server")]
+pub mod audit;
 pub mod auth;
+#[cfg(feature = "server")]
+pub mod ban;
+#[cfg(feature = "client")]
+pub mod basic_auth;
+#[cfg(feature = "client")]
+pub mod bench;
+#[cfg(feature = "client")]
 pub mod client;
+#[cfg(feature = "server")]
+pub mod cluster;
+#[cfg(feature = "client")]
+pub mod config;
+#[cfg(all(unix, feature = "client"))]
+pub mod daemon;
+#[cfg(feature = "server")]
+pub mod dns;
+pub mod error;
+pub mod event;
+#[cfg(feature = "server")]
+pub mod geoip;
+#[cfg(feature = "client")]
+pub mod handle;
+#[cfg(feature = "server")]
+pub mod health;
+#[cfg(feature = "client")]
+pub mod hook;
+#[cfg(feature = "client")]
+pub mod inspect;
+#[cfg(feature = "server")]
+pub mod limit;
+#[cfg(feature = "server")]
+pub mod middleware;
+pub mod ports;
+#[cfg(feature = "server")]
+pub mod quota;
+#[cfg(feature = "client")]
+pub mod relay;
+#[cfg(feature = "client")]
+pub mod rewrite;
+#[cfg(feature = "server")]
+pub mod schedule;
+#[cfg(feature = "server")]
+pub mod secrets;
+#[cfg(feature = "server")]
 pub mod server;
 pub mod shared;
+pub mod stats;
+#[cfg(feature = "client")]
+pub mod status;
+#[cfg(feature = "server")]
+pub mod store;
+#[cfg(feature = "server")]
+pub mod subdomain;
+#[cfg(feature = "client")]
+pub mod supervisor;
+#[cfg(all(unix, feature = "server"))]
+pub mod systemd;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod throttle;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod tls;
+pub mod transport;
+#[cfg(feature = "server")]
+pub mod version;
+#[cfg(feature = "server")]
+pub mod webhook;
@@ -0,0 +1,140 @@
+//! Token-bucket rate limiting for incoming connections.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use dashmap::DashMap;
+
+/// A rate expressed as a number of events per time period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rate {
+    /// Number of events allowed per period, also used as the burst size.
+    pub count: u32,
+
+    /// Length of the period.
+    pub period: Duration,
+}
+
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+
+    /// Parse a rate like `20/s`, `300/m`, or `1000/h`.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use bore_cli::limit::Rate;
+    ///
+    /// let rate: Rate = "300/m".parse().unwrap();
+    /// assert_eq!(rate.count, 300);
+    /// assert_eq!(rate.period, Duration::from_secs(60));
+    /// ```
+    fn from_str(s: &str) -> Result<Self> {
+        let (count, unit) = s.split_once('/').context("expected a rate like `20/s`")?;
+        let count: u32 = count.trim().parse().context("invalid count in rate")?;
+        let period = match unit.trim() {
+            "s" | "sec" => Duration::from_secs(1),
+            "m" | "min" => Duration::from_secs(60),
+            "h" | "hour" => Duration::from_secs(3600),
+            unit => bail!("unknown rate unit `{unit}`, expected `s`, `m`, or `h`"),
+        };
+        if count == 0 {
+            bail!("rate must allow at least one event per period");
+        }
+        Ok(Rate { count, period })
+    }
+}
+
+/// Token bucket that refills continuously at a fixed rate.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket for the given rate.
+    pub fn new(rate: Rate) -> Self {
+        Self {
+            rate,
+            tokens: rate.count as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        let per_sec = self.rate.count as f64 / self.rate.period.as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(self.rate.count as f64);
+        self.last = now;
+    }
+
+    /// Take a single token from the bucket, returning false if none are left.
+    pub fn try_acquire(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns whether the bucket has refilled completely.
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.rate.count as f64
+    }
+}
+
+/// Policy limiting how quickly new visitor connections are accepted.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AcceptPolicy {
+    /// Maximum rate of new connections on each public port.
+    pub per_port: Option<Rate>,
+
+    /// Maximum rate of new connections from each source IP, across all ports.
+    pub per_ip: Option<Rate>,
+}
+
+/// Shared limiter enforcing an [`AcceptPolicy`] on visitor connections.
+#[derive(Debug, Default)]
+pub struct AcceptLimiter {
+    policy: AcceptPolicy,
+    ips: DashMap<IpAddr, TokenBucket>,
+}
+
+impl AcceptLimiter {
+    /// Create a limiter for a policy.
+    pub fn new(policy: AcceptPolicy) -> Self {
+        Self {
+            policy,
+            ips: DashMap::new(),
+        }
+    }
+
+    /// Create the bucket for a single public port, if the policy limits ports.
+    pub fn port_bucket(&self) -> Option<TokenBucket> {
+        self.policy.per_port.map(TokenBucket::new)
+    }
+
+    /// Check whether a new connection from this address may be accepted.
+    pub fn check_ip(&self, ip: IpAddr) -> bool {
+        match self.policy.per_ip {
+            Some(rate) => self
+                .ips
+                .entry(ip)
+                .or_insert_with(|| TokenBucket::new(rate))
+                .try_acquire(),
+            None => true,
+        }
+    }
+
+    /// Forget source IPs whose buckets have refilled, bounding memory use.
+    pub fn purge(&self) {
+        self.ips.retain(|_, bucket| !bucket.is_full());
+    }
+}
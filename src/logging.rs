@@ -0,0 +1,330 @@
+//! Log output for long-running commands, as text or JSON, optionally to files rotated by time
+//! or size, and export of their spans to OpenTelemetry.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use clap::{ArgAction, Args as ClapArgs};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use tracing::level_filters::LevelFilter;
+use tracing::Subscriber;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriterExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Crates whose logs get more detailed with `-v`, while dependencies stay quieter.
+const OWN_TARGETS: [&str; 2] = ["bore", "bore_cli"];
+
+/// Options for how much to log, accepted by every command.
+#[derive(ClapArgs, Debug)]
+pub struct Verbosity {
+    /// Log debug messages, or trace messages too with `-vv`.
+    #[clap(
+        short,
+        long,
+        action = ArgAction::Count,
+        global = true,
+        conflicts_with = "quiet",
+        display_order = 900
+    )]
+    verbose: u8,
+
+    /// Log only warnings, or only errors with `-qq`.
+    #[clap(
+        short,
+        long,
+        action = ArgAction::Count,
+        global = true,
+        display_order = 901
+    )]
+    quiet: u8,
+}
+
+impl Verbosity {
+    /// Build the log filter, letting directives from `RUST_LOG` override it for their targets.
+    ///
+    /// Without flags, the filter logs at the command's default level. A bare level in
+    /// `RUST_LOG`, such as `debug`, only applies when no flag was given.
+    pub fn targets(&self, rust_log: Option<&str>, default: LevelFilter) -> Targets {
+        let overrides = match rust_log {
+            Some(var) => var.parse().unwrap_or_default(),
+            None => Targets::new(),
+        };
+        let (level, own_level) = match (self.verbose, self.quiet) {
+            (0, 0) => {
+                let level = match rust_log {
+                    Some(_) => overrides.default_level().unwrap_or(LevelFilter::OFF),
+                    None => default,
+                };
+                (level, level)
+            }
+            (0, 1) => (LevelFilter::WARN, LevelFilter::WARN),
+            (0, _) => (LevelFilter::ERROR, LevelFilter::ERROR),
+            (1, _) => (LevelFilter::INFO, LevelFilter::DEBUG),
+            _ => (LevelFilter::DEBUG, LevelFilter::TRACE),
+        };
+        let mut targets = Targets::new().with_default(level);
+        if own_level != level {
+            targets = targets.with_targets(OWN_TARGETS.map(|target| (target, own_level)));
+        }
+        targets.with_targets(overrides)
+    }
+}
+
+/// Options for the format and destination of logs.
+#[derive(ClapArgs, Debug)]
+pub struct Logging {
+    /// Format of log lines: `text`, or `json` for log collectors.
+    #[clap(
+        long,
+        value_name = "FORMAT",
+        default_value = "text",
+        env = "BORE_LOG_FORMAT"
+    )]
+    log_format: LogFormat,
+
+    /// File to write logs to, instead of stderr.
+    #[clap(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// When to start a new log file: `hourly`, `daily`, `never`, or a size such as `10M`.
+    #[clap(
+        long,
+        value_name = "WHEN",
+        default_value = "never",
+        requires = "log_file"
+    )]
+    log_rotation: LogRotation,
+
+    /// Number of rotated log files to keep, deleting older ones [default: keep all].
+    #[clap(long, value_name = "N", requires = "log_file")]
+    log_keep: Option<usize>,
+
+    /// Also write logs to stderr when writing them to a file.
+    #[clap(long, requires = "log_file")]
+    log_stderr: bool,
+
+    /// Export traces to an OpenTelemetry collector at this OTLP/HTTP URL.
+    #[clap(long, value_name = "URL", env = "BORE_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+}
+
+/// Format of log lines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable text, colored when written to a terminal.
+    #[default]
+    Text,
+
+    /// One JSON object per line, with the fields of the event and its spans.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("expected `text` or `json`"),
+        }
+    }
+}
+
+impl Logging {
+    /// Returns the format of log lines.
+    pub fn format(&self) -> LogFormat {
+        self.log_format
+    }
+
+    /// Returns whether logs go to a file.
+    pub fn is_file(&self) -> bool {
+        self.log_file.is_some()
+    }
+
+    /// Build the writer for log lines, which is stderr unless a log file was given.
+    pub fn writer(&self) -> Result<BoxMakeWriter> {
+        let Some(path) = &self.log_file else {
+            return Ok(BoxMakeWriter::new(io::stderr));
+        };
+        let file = match &self.log_rotation {
+            LogRotation::Size(max_size) => {
+                let file = SizeRotating::open(path, *max_size, self.log_keep)?;
+                BoxMakeWriter::new(Mutex::new(file))
+            }
+            LogRotation::Time(rotation) => {
+                let (dir, name) = split_path(path)?;
+                let mut builder = RollingFileAppender::builder()
+                    .rotation(rotation.clone())
+                    .filename_prefix(name);
+                if let Some(keep) = self.log_keep {
+                    builder = builder.max_log_files(keep.max(1));
+                }
+                BoxMakeWriter::new(builder.build(dir)?)
+            }
+        };
+        Ok(match self.log_stderr {
+            true => BoxMakeWriter::new(file.and(io::stderr)),
+            false => file,
+        })
+    }
+
+    /// Build the provider that exports spans over OTLP, if an endpoint was given.
+    ///
+    /// Spans are batched and sent from a background thread. The service name defaults to `bore`,
+    /// and can be changed with `OTEL_SERVICE_NAME`.
+    pub fn tracer_provider(&self) -> Result<Option<SdkTracerProvider>> {
+        let Some(endpoint) = &self.otlp_endpoint else {
+            return Ok(None);
+        };
+        // An endpoint set in code is used as is, so add the path that collectors serve traces on.
+        let endpoint = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .context("could not create the OTLP exporter")?;
+        let service_name = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "bore".into());
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(service_name).build())
+            .build();
+        Ok(Some(provider))
+    }
+}
+
+/// Build the layer that turns spans into OpenTelemetry spans, if traces are exported.
+pub fn otel_layer<S>(
+    provider: Option<&SdkTracerProvider>,
+) -> Option<OpenTelemetryLayer<S, SdkTracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let tracer = provider?.tracer("bore");
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// When to move on to a new log file.
+#[derive(Clone, Debug)]
+enum LogRotation {
+    /// At fixed intervals, with the date and time appended to each file name.
+    Time(Rotation),
+
+    /// Once the file reaches a size in bytes, renaming older files with a numeric suffix.
+    Size(u64),
+}
+
+impl FromStr for LogRotation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "hourly" => LogRotation::Time(Rotation::HOURLY),
+            "daily" => LogRotation::Time(Rotation::DAILY),
+            "never" => LogRotation::Time(Rotation::NEVER),
+            _ => {
+                let (number, multiplier) = match s.char_indices().last() {
+                    Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+                    Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+                    Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+                    _ => (s, 1),
+                };
+                match number
+                    .parse::<u64>()
+                    .ok()
+                    .and_then(|n| n.checked_mul(multiplier))
+                {
+                    Some(size) if size > 0 => LogRotation::Size(size),
+                    _ => bail!("expected `hourly`, `daily`, `never`, or a size like `10M`"),
+                }
+            }
+        })
+    }
+}
+
+/// Split a log file path into its directory and file name.
+fn split_path(path: &Path) -> Result<(&Path, &str)> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("invalid log file path {}", path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    Ok((dir, name))
+}
+
+/// A log file that is renamed to `<path>.1` once it grows past a size, shifting older files up.
+struct SizeRotating {
+    path: PathBuf,
+    max_size: u64,
+    keep: Option<usize>,
+    file: File,
+    size: u64,
+}
+
+impl SizeRotating {
+    fn open(path: &Path, max_size: u64, keep: Option<usize>) -> Result<Self> {
+        let file = append(path)?;
+        let size = file.metadata()?.len();
+        Ok(SizeRotating {
+            path: path.to_owned(),
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let keep = self.keep.unwrap_or(usize::MAX).max(1);
+        let mut last = 1;
+        while last < keep && self.rotated(last).exists() {
+            last += 1;
+        }
+        for index in (1..last).rev() {
+            fs::rename(self.rotated(index), self.rotated(index + 1))?;
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotating {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
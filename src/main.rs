@@ -1,40 +1,381 @@
-use anyhow::Result;
-use bore_cli::{client::Client, server::Server};
-use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use std::collections::BTreeMap;
+use std::env;
+#[cfg(windows)]
+use std::ffi::OsString;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{io, iter};
+
+use anyhow::{bail, Context, Result};
+use arboard::Clipboard;
+use bore_cli::acme::{self, Acme};
+use bore_cli::admin::AdminClient;
+use bore_cli::audit::AuditLog;
+use bore_cli::ban::BanPolicy;
+use bore_cli::basic_auth::BasicAuth;
+use bore_cli::bench::{self, BenchOptions, BenchReport, Percentiles};
+use bore_cli::client::{self, Client, ClientOptions, IpFilter, Scheme};
+use bore_cli::cluster::Cluster;
+use bore_cli::config::{Config, Profile};
+#[cfg(unix)]
+use bore_cli::daemon::{self, Daemon, DaemonRequest, DaemonResponse, NewTunnel};
+use bore_cli::dns::{Cloudflare, DnsCommand, DnsProvider, DnsTarget, DnsUpdater};
+use bore_cli::error::{Error, ErrorCode};
+use bore_cli::event::{self, ClientEvent};
+use bore_cli::geoip::{CountryPolicy, GeoFilter};
+use bore_cli::hook::{run_hook, EnvFile};
+use bore_cli::inspect::{Inspector, DEFAULT_BODY_LIMIT, DEFAULT_CAPACITY};
+use bore_cli::limit::{AcceptPolicy, Rate};
+use bore_cli::ports::PortList;
+use bore_cli::relay::Relay;
+use bore_cli::rewrite::HostHeader;
+use bore_cli::schedule::{Schedules, Window};
+use bore_cli::secrets::{ScopedSecrets, SecretScope};
+use bore_cli::server::{BindPolicy, ControlListener, Server};
+use bore_cli::shared::{
+    AdminRequest, AdminResponse, AdminStats, AdminTunnel, AdminUsage, SocketOptions, TunnelTarget,
+    CONTROL_PORT, TLS_CONTROL_PORT,
+};
+use bore_cli::supervisor::{Supervisor, TunnelConfig, TunnelSpec, TunnelsFile};
+#[cfg(unix)]
+use bore_cli::systemd;
+use bore_cli::throttle::ByteRate;
+#[cfg(unix)]
+use bore_cli::transport::UnixAcceptor;
+use bore_cli::version::Version;
+use bore_cli::{quota::Quotas, store::PortStore, tls, webhook::Webhooks};
+use clap::{error::ErrorKind, ArgGroup, Args as ClapArgs, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use futures_util::future::join_all;
+use ipnet::IpNet;
+use logging::{LogFormat, Logging, Verbosity};
+use tokio::net::TcpListener;
+use tokio::signal;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::level_filters::LevelFilter;
+use tracing::{field, info, info_span, warn, Instrument};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+
+mod logging;
+#[cfg(windows)]
+mod service;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 struct Args {
     #[clap(subcommand)]
     command: Command,
+
+    #[clap(flatten)]
+    verbosity: Verbosity,
 }
 
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
 enum Command {
     /// Starts a local proxy to the remote server.
     Local {
+        /// The local ports to expose, each through its own tunnel.
+        #[clap(env = "BORE_LOCAL_PORT", value_delimiter = ',', required = true)]
+        local_ports: Vec<u16>,
+
+        /// The local host to expose [default: localhost].
+        #[clap(short, long, value_name = "HOST")]
+        local_host: Option<String>,
+
+        /// Address of the remote server to expose local ports to.
+        #[clap(short, long, env = "BORE_SERVER")]
+        to: Option<String>,
+
+        /// Optional port on the remote server to select [default: 0].
+        #[clap(short, long)]
+        port: Option<u16>,
+
+        /// Optional secret for authentication.
+        #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
+        secret: Option<String>,
+
+        /// Stable name for the tunnel, so the server can reassign the same port.
+        #[clap(short, long, env = "BORE_NAME")]
+        name: Option<String>,
+
+        /// Share the named tunnel with other clients passing `--shared`, taking turns with
+        /// visitors.
+        #[clap(long)]
+        shared: bool,
+
+        /// Description of the tunnel, shown to the server's operators.
+        #[clap(long, value_name = "TEXT", env = "BORE_DESCRIPTION")]
+        description: Option<String>,
+
+        /// Host name of this machine, shown to the server's operators.
+        #[clap(long, value_name = "HOST", env = "BORE_CLIENT_HOST")]
+        client_host: Option<String>,
+
+        /// Show the tunnel's name, port, and description in the server's public listing.
+        #[clap(long)]
+        public_listing: bool,
+
+        /// Ask the server for a subdomain, to show in place of its address if it assigns one.
+        #[clap(long)]
+        subdomain: bool,
+
+        /// Close the tunnel and release its port after this long, such as `2h`.
+        #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+        expires: Option<Duration>,
+
+        /// Close the tunnel once visitors transferred this many bytes, such as `1G`.
+        #[clap(long, value_name = "SIZE", value_parser = parse_transfer_size)]
+        max_transfer: Option<u64>,
+
+        /// Profile in the config file providing defaults for these options.
+        #[clap(long, value_name = "NAME", env = "BORE_PROFILE")]
+        profile: Option<String>,
+
+        /// Record HTTP traffic and serve an inspector web UI at this address.
+        #[clap(long, value_name = "ADDR")]
+        inspect: Option<SocketAddr>,
+
+        /// Maximum number of bytes recorded from each HTTP body by the inspector.
+        #[clap(long, value_name = "BYTES", default_value_t = DEFAULT_BODY_LIMIT, requires = "inspect")]
+        inspect_body_limit: usize,
+
+        /// Decrypt TLS from visitors before forwarding plaintext to the local service.
+        #[clap(long)]
+        terminate_tls: bool,
+
+        /// PEM certificate chain presented to visitors, instead of a self-signed certificate.
+        #[clap(long, value_name = "PATH", requires_all = ["terminate_tls", "terminate_tls_key"])]
+        terminate_tls_cert: Option<PathBuf>,
+
+        /// PEM private key for the certificate presented to visitors.
+        #[clap(long, value_name = "PATH", requires = "terminate_tls_cert")]
+        terminate_tls_key: Option<PathBuf>,
+
+        /// Connect to the local service over TLS.
+        #[clap(long)]
+        local_tls: bool,
+
+        /// Accept any certificate from the local service, such as a self-signed one.
+        #[clap(long, requires = "local_tls")]
+        local_tls_insecure: bool,
+
+        /// Only accept visitors from this network, given as CIDR or a single address.
+        #[clap(long, value_name = "CIDR", value_parser = parse_ip_net)]
+        allow_ip: Vec<IpNet>,
+
+        /// Reject visitors from this network, given as CIDR or a single address.
+        #[clap(long, value_name = "CIDR", value_parser = parse_ip_net)]
+        deny_ip: Vec<IpNet>,
+
+        /// Require visitors of an HTTP service to log in with this `username:password`.
+        #[clap(
+            long,
+            value_name = "USER:PASS",
+            env = "BORE_BASIC_AUTH",
+            hide_env_values = true
+        )]
+        basic_auth: Option<BasicAuth>,
+
+        /// Add `X-Forwarded-For`, `X-Forwarded-Proto`, and `Forwarded` headers to HTTP requests.
+        #[clap(long)]
+        forwarded_headers: bool,
+
+        /// Host header of HTTP requests: `preserve`, `rewrite` to the local address, or
+        /// `custom:<value>`.
+        #[clap(long, value_name = "MODE", default_value = "preserve")]
+        host_header: HostHeader,
+
+        /// Send a PROXY protocol header with the visitor's address to the local service.
+        #[clap(long)]
+        proxy_protocol: bool,
+
+        /// Ports the server may assign when no port is selected, such as `30000-30100`.
+        #[clap(long, value_name = "PORTS", conflicts_with = "port")]
+        port_range: Option<PortList>,
+
+        /// Bind the remote port on this server address, such as `127.0.0.1` for a private tunnel.
+        #[clap(long, value_name = "IP")]
+        remote_bind: Option<IpAddr>,
+
+        /// Use a random remote port if the one selected with --port is unavailable.
+        #[clap(long)]
+        fallback_random: bool,
+
+        /// Number of idle connections to the server kept open, so new visitors connect faster.
+        #[clap(long, value_name = "N", default_value_t = 0)]
+        pool_size: usize,
+
+        /// Log a summary of transferred bytes and connections at this interval, and on exit.
+        #[clap(long, value_name = "SECS")]
+        stats_interval: Option<u64>,
+
+        /// Size of the buffer copying each direction of a visitor connection, such as `256K` [default: 8K].
+        #[clap(long, value_name = "SIZE", value_parser = parse_buffer_size)]
+        proxy_buffer_size: Option<usize>,
+
+        /// Maximum bandwidth sent to visitors, in bytes per second with an optional K, M, or G suffix.
+        #[clap(long, value_name = "RATE")]
+        max_up: Option<ByteRate>,
+
+        /// Maximum bandwidth received from visitors, in bytes per second with an optional K, M, or G suffix.
+        #[clap(long, value_name = "RATE")]
+        max_down: Option<ByteRate>,
+
+        /// Shell command to run once connected, with the remote host and port in its environment.
+        #[clap(long, value_name = "CMD")]
+        on_connect: Option<String>,
+
+        /// Shell command to run when the connection to the server ends.
+        #[clap(long, value_name = "CMD")]
+        on_disconnect: Option<String>,
+
+        /// Print the remote host and port to stdout as JSON once connected.
+        #[clap(long)]
+        json: bool,
+
+        /// Print the public URL of the tunnel with this scheme: `http`, `https`, or `tcp`.
+        #[clap(long)]
+        scheme: Option<Scheme>,
+
+        /// Copy the public URL to the clipboard once connected.
+        #[clap(long, requires = "scheme")]
+        copy: bool,
+
+        /// Keep the remote host, port, and URL in this file, as `KEY=value` lines to `source`.
+        #[clap(long, value_name = "PATH")]
+        write_env_to: Option<PathBuf>,
+
+        /// Keep the state, remote port, and traffic of the tunnel in this file as JSON.
+        #[clap(long, value_name = "PATH")]
+        status_file: Option<PathBuf>,
+
+        #[clap(flatten)]
+        transport: Transport,
+
+        #[clap(flatten)]
+        logging: Logging,
+
+        #[clap(flatten)]
+        detach: Detach,
+    },
+
+    /// Starts several local proxies from one process, reconnecting them as needed.
+    ///
+    /// This is the entry point for tunnels kept in a config file. Each tunnel takes only a name,
+    /// local host and port, remote port, server address, and env file, while the secret and the
+    /// connection options below apply to all of them. Other options of `bore local`, such as
+    /// `--shared`, `--expires`, `--subdomain`, visitor filters, rate limits, hooks, TLS
+    /// termination, and the inspector, are not supported here.
+    Tunnels {
+        /// Tunnels to expose, such as `3000`, `3000:8080`, or `web=3000`.
+        #[clap(value_name = "TUNNEL")]
+        tunnels: Vec<TunnelSpec>,
+
+        /// TOML file listing additional tunnels to expose.
+        #[clap(short, long, value_name = "PATH")]
+        config: Option<PathBuf>,
+
+        /// The local host to expose, unless a tunnel specifies its own [default: localhost].
+        #[clap(short, long, value_name = "HOST")]
+        local_host: Option<String>,
+
+        /// Address of the remote server, unless a tunnel specifies its own.
+        #[clap(short, long, env = "BORE_SERVER")]
+        to: Option<String>,
+
+        /// Optional secret for authentication.
+        #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
+        secret: Option<String>,
+
+        /// Profile in the config file providing defaults for these options.
+        #[clap(long, value_name = "NAME", env = "BORE_PROFILE")]
+        profile: Option<String>,
+
+        #[clap(flatten)]
+        transport: Transport,
+    },
+
+    /// Runs a daemon that manages tunnels added with `bore add`.
+    #[cfg(unix)]
+    Daemon {
+        #[clap(flatten)]
+        socket: DaemonSocket,
+    },
+
+    /// Adds a tunnel to a running daemon.
+    #[cfg(unix)]
+    Add {
         /// The local port to expose.
-        #[clap(env = "BORE_LOCAL_PORT")]
         local_port: u16,
 
-        /// The local host to expose.
-        #[clap(short, long, value_name = "HOST", default_value = "localhost")]
-        local_host: String,
+        /// The local host to expose [default: localhost].
+        #[clap(short, long, value_name = "HOST")]
+        local_host: Option<String>,
 
         /// Address of the remote server to expose local ports to.
         #[clap(short, long, env = "BORE_SERVER")]
-        to: String,
+        to: Option<String>,
 
-        /// Optional port on the remote server to select.
-        #[clap(short, long, default_value_t = 0)]
-        port: u16,
+        /// Optional port on the remote server to select [default: 0].
+        #[clap(short, long)]
+        port: Option<u16>,
 
         /// Optional secret for authentication.
         #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
         secret: Option<String>,
+
+        /// Name for the tunnel, used to remove it [default: HOST:LOCAL_PORT].
+        #[clap(short, long)]
+        name: Option<String>,
+
+        /// Profile in the config file providing defaults for these options.
+        #[clap(long, value_name = "NAME", env = "BORE_PROFILE")]
+        profile: Option<String>,
+
+        #[clap(flatten)]
+        transport: Transport,
+
+        #[clap(flatten)]
+        socket: DaemonSocket,
+    },
+
+    /// Lists the tunnels of a running daemon.
+    #[cfg(unix)]
+    List {
+        #[clap(flatten)]
+        socket: DaemonSocket,
+    },
+
+    /// Shows the state, uptime, and recent errors of the tunnels of a running daemon.
+    #[cfg(unix)]
+    Status {
+        /// Print the status of each tunnel to stdout as JSON.
+        #[clap(long)]
+        json: bool,
+
+        #[clap(flatten)]
+        socket: DaemonSocket,
+    },
+
+    /// Removes a tunnel from a running daemon.
+    #[cfg(unix)]
+    Remove {
+        /// Name of the tunnel, as shown by `bore list`.
+        name: String,
+
+        #[clap(flatten)]
+        socket: DaemonSocket,
     },
 
     /// Runs the remote proxy server.
+    #[clap(group(ArgGroup::new("tls_source").args(["tls_cert", "acme_domain"])))]
     Server {
         /// Minimum accepted TCP port number.
         #[clap(long, default_value_t = 1024, env = "BORE_MIN_PORT")]
@@ -44,10 +385,1109 @@ enum Command {
         #[clap(long, default_value_t = 65535, env = "BORE_MAX_PORT")]
         max_port: u16,
 
+        /// Accepted TCP port ranges, such as `2000-2999`, overriding the minimum and maximum.
+        #[clap(long, value_name = "PORTS")]
+        port_range: Vec<PortList>,
+
+        /// Ports that are never assigned, such as `8080,9090,30000-30100`.
+        #[clap(long, value_name = "PORTS", env = "BORE_EXCLUDE_PORTS")]
+        exclude_ports: Option<PortList>,
+
+        /// Address to bind the control listeners on.
+        #[clap(
+            long,
+            value_name = "IP",
+            default_value = "0.0.0.0",
+            env = "BORE_CONTROL_BIND_ADDR"
+        )]
+        control_bind_addr: IpAddr,
+
+        /// Address to bind tunnel listeners on, unless a client asks for another.
+        #[clap(
+            long,
+            value_name = "IP",
+            default_value = "0.0.0.0",
+            env = "BORE_TUNNEL_BIND_ADDR"
+        )]
+        tunnel_bind_addr: IpAddr,
+
+        /// Domain with wildcard DNS records for the server, under which tunnels get subdomains.
+        #[clap(long, value_name = "DOMAIN", env = "BORE_DOMAIN")]
+        domain: Option<String>,
+
+        /// Addresses clients may bind their public ports on: `loopback`, `any`, or `none`.
+        #[clap(long, value_name = "POLICY", default_value = "loopback")]
+        client_bind: BindPolicy,
+
+        /// Optional secret for authentication.
+        #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
+        secret: Option<String>,
+
+        /// PEM file with the certificate chain for TLS control connections.
+        #[clap(long, value_name = "PATH", requires = "tls_key", env = "BORE_TLS_CERT")]
+        tls_cert: Option<PathBuf>,
+
+        /// PEM file with the private key for TLS control connections.
+        #[clap(long, value_name = "PATH", requires = "tls_cert", env = "BORE_TLS_KEY")]
+        tls_key: Option<PathBuf>,
+
+        /// Port accepting TLS control connections.
+        #[clap(long, value_name = "PORT", default_value_t = TLS_CONTROL_PORT)]
+        tls_port: u16,
+
+        /// Only accept TLS control connections, disabling the plaintext control port.
+        #[clap(long, requires = "tls_source", conflicts_with = "cluster_peer")]
+        tls_only: bool,
+
+        /// Public ports whose visitors connect over TLS, which the server decrypts with its
+        /// certificate before forwarding plaintext to the client.
+        #[clap(long, value_name = "PORTS", requires = "tls_source")]
+        tls_tunnel_ports: Option<PortList>,
+
+        /// Obtain the TLS certificate for this domain from an ACME authority, such as Let's
+        /// Encrypt, and renew it automatically. May be repeated for more domains.
+        #[clap(long, value_name = "DOMAIN", conflicts_with = "tls_cert")]
+        acme_domain: Vec<String>,
+
+        /// Email address registered with the ACME account, for notices about certificates.
+        #[clap(
+            long,
+            value_name = "EMAIL",
+            requires = "acme_domain",
+            env = "BORE_ACME_EMAIL"
+        )]
+        acme_email: Option<String>,
+
+        /// Directory URL of the ACME authority [default: Let's Encrypt].
+        #[clap(long, value_name = "URL", requires = "acme_domain")]
+        acme_url: Option<String>,
+
+        /// Directory keeping the ACME account key and certificate across restarts.
+        #[clap(
+            long,
+            value_name = "PATH",
+            default_value = "acme",
+            env = "BORE_ACME_CACHE"
+        )]
+        acme_cache: PathBuf,
+
+        /// Port answering ACME HTTP-01 challenges, which must be reachable as port 80.
+        #[clap(long, value_name = "PORT", default_value_t = acme::ACME_HTTP_PORT)]
+        acme_http_port: u16,
+
+        /// Unix socket that also accepts control connections, such as from a local reverse proxy.
+        #[clap(long, value_name = "PATH", env = "BORE_CONTROL_SOCKET")]
+        control_socket: Option<PathBuf>,
+
+        /// Only accept control connections on the Unix socket, disabling the TCP control port.
+        #[clap(long, requires = "control_socket", conflicts_with_all = ["tls_source", "cluster_peer"])]
+        socket_only: bool,
+
+        /// File in which ports of named tunnels are persisted across restarts.
+        #[clap(long, value_name = "PATH", env = "BORE_STATE_FILE")]
+        state_file: Option<PathBuf>,
+
+        /// Port serving an HTTP health check at `/healthz`, a status page at `/status`, and a listing of public tunnels at `/tunnels`.
+        #[clap(long, value_name = "PORT", env = "BORE_HEALTH_PORT")]
+        health_port: Option<u16>,
+
+        /// Seconds an incoming connection waits for the client to accept it.
+        #[clap(long, value_name = "SECS", default_value_t = 10)]
+        pending_expiry: u64,
+
+        /// Seconds to hold the port of a disconnected tunnel for its client.
+        #[clap(long, value_name = "SECS", default_value_t = 0)]
+        reconnect_grace: u64,
+
+        /// Maximum number of visitors queued while a client reconnects.
+        #[clap(long, value_name = "N", default_value_t = 32)]
+        reconnect_queue: usize,
+
+        /// Reason given to clients when the server shuts down, such as planned maintenance.
+        #[clap(long, value_name = "TEXT", env = "BORE_SHUTDOWN_REASON")]
+        shutdown_reason: Option<String>,
+
+        /// Tell clients to reconnect after this many seconds when the server shuts down.
+        #[clap(long, value_name = "SECS")]
+        shutdown_retry_after: Option<u64>,
+
+        /// URL that receives a JSON POST request when a tunnel opens or closes.
+        #[clap(long, value_name = "URL")]
+        webhook: Vec<String>,
+
+        /// Domain under which each named tunnel gets a DNS record while it is open.
+        #[clap(
+            long,
+            value_name = "DOMAIN",
+            requires_all = ["dns_target", "dns_provider"],
+            env = "BORE_DNS_DOMAIN"
+        )]
+        dns_domain: Option<String>,
+
+        /// IP address or host name of the server, which the DNS records of tunnels point at.
+        #[clap(
+            long,
+            value_name = "TARGET",
+            requires = "dns_domain",
+            env = "BORE_DNS_TARGET"
+        )]
+        dns_target: Option<DnsTarget>,
+
+        /// Cloudflare zone in which DNS records are kept, with an API token allowed to edit it.
+        #[clap(
+            long,
+            value_name = "ZONE_ID",
+            group = "dns_provider",
+            requires_all = ["dns_domain", "dns_cloudflare_token"],
+            env = "BORE_DNS_CLOUDFLARE_ZONE"
+        )]
+        dns_cloudflare_zone: Option<String>,
+
+        /// API token for the Cloudflare zone.
+        #[clap(
+            long,
+            value_name = "TOKEN",
+            requires = "dns_cloudflare_zone",
+            env = "BORE_DNS_CLOUDFLARE_TOKEN",
+            hide_env_values = true
+        )]
+        dns_cloudflare_token: Option<String>,
+
+        /// Shell command that creates or removes DNS records, such as a script around `nsupdate`.
+        #[clap(
+            long,
+            value_name = "CMD",
+            group = "dns_provider",
+            requires = "dns_domain"
+        )]
+        dns_command: Option<String>,
+
+        /// File to which a JSON line is appended for each tunnel session and failed login.
+        #[clap(long, value_name = "PATH", env = "BORE_AUDIT_LOG")]
+        audit_log: Option<PathBuf>,
+
+        /// Address of another server node to share tunnels with, in cluster mode.
+        #[clap(long, value_name = "HOST", requires_all = ["secret", "cluster_node"])]
+        cluster_peer: Vec<String>,
+
+        /// Address at which other cluster nodes can reach this server.
+        #[clap(long, value_name = "HOST", env = "BORE_CLUSTER_NODE")]
+        cluster_node: Option<String>,
+
+        /// Path to a MaxMind country database used to filter visitors.
+        #[clap(long, value_name = "PATH", env = "BORE_GEOIP_DB")]
+        geoip_db: Option<PathBuf>,
+
+        /// Country policy for visitors, such as `allow:US,DE` or `deny:CN`.
+        #[clap(long, value_name = "POLICY", requires = "geoip_db")]
+        geoip_policy: Option<CountryPolicy>,
+
+        /// Country policy for a single tunnel port, such as `8080=deny:CN`.
+        #[clap(long, value_name = "PORT=POLICY", requires = "geoip_db", value_parser = parse_port_policy)]
+        geoip_port_policy: Vec<(u16, CountryPolicy)>,
+
+        /// Disable Nagle's algorithm on control and tunnel connections.
+        #[clap(long)]
+        tcp_nodelay: bool,
+
+        /// Enable TCP keepalive after this many seconds of idle time.
+        #[clap(long, value_name = "SECS")]
+        tcp_keepalive: Option<u64>,
+
+        /// Seconds between TCP keepalive probes.
+        #[clap(long, value_name = "SECS", requires = "tcp_keepalive")]
+        tcp_keepalive_interval: Option<u64>,
+
+        /// Maximum number of pending connections on each listener.
+        #[clap(long, value_name = "N")]
+        listen_backlog: Option<u32>,
+
+        /// Kernel send buffer size for listeners, in bytes.
+        #[clap(long, value_name = "BYTES")]
+        send_buffer_size: Option<u32>,
+
+        /// Kernel receive buffer size for listeners, in bytes.
+        #[clap(long, value_name = "BYTES")]
+        recv_buffer_size: Option<u32>,
+
+        /// Bind each listener this many times with SO_REUSEPORT, accepting on separate tasks.
+        #[clap(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+        accept_shards: Option<u16>,
+
+        /// Size of the buffer copying each direction of a visitor connection, such as `256K` [default: 8K].
+        #[clap(long, value_name = "SIZE", value_parser = parse_buffer_size)]
+        proxy_buffer_size: Option<usize>,
+
+        /// Maximum bandwidth of each visitor connection in each direction, such as `2M`.
+        #[clap(long, value_name = "RATE")]
+        connection_rate: Option<ByteRate>,
+
+        /// Maximum bandwidth of each visitor connection of an identity, such as `secret=10M`.
+        #[clap(long, value_name = "IDENTITY=RATE", requires = "secret", value_parser = parse_identity_rate)]
+        identity_connection_rate: Vec<(String, ByteRate)>,
+
+        /// Bytes that each tunnel may transfer before the server closes it, such as `1G`.
+        #[clap(long, value_name = "SIZE", value_parser = parse_transfer_size)]
+        max_transfer: Option<u64>,
+
+        /// Refuse clients older than this version, such as `0.5.0`.
+        #[clap(long, value_name = "VERSION")]
+        min_client_version: Option<Version>,
+
+        /// Maximum rate of new visitor connections per tunnel, such as `50/s`.
+        #[clap(long, value_name = "RATE")]
+        rate_limit_port: Option<Rate>,
+
+        /// Maximum rate of new visitor connections per source IP, such as `300/m`.
+        #[clap(long, value_name = "RATE")]
+        rate_limit_ip: Option<Rate>,
+
+        /// Ban source IPs after this many failed authentications within the ban window.
+        #[clap(long, value_name = "N", requires = "secret")]
+        ban_threshold: Option<u32>,
+
+        /// Seconds in which failed authentications are counted towards a ban.
+        #[clap(long, value_name = "SECS", default_value_t = 60)]
+        ban_window: u64,
+
+        /// Seconds that a banned source IP is refused.
+        #[clap(long, value_name = "SECS", default_value_t = 600)]
+        ban_duration: u64,
+
+        /// File in which bans are persisted across restarts.
+        #[clap(long, value_name = "PATH", requires = "secret", env = "BORE_BAN_FILE")]
+        ban_file: Option<PathBuf>,
+
+        /// Bytes that the tunnels of each client identity may transfer per month, such as `50G`.
+        #[clap(long, value_name = "SIZE", requires = "secret", value_parser = parse_transfer_size)]
+        quota: Option<u64>,
+
+        /// File in which transfer quota usage is persisted across restarts.
+        #[clap(long, value_name = "PATH", requires = "quota", env = "BORE_QUOTA_FILE")]
+        quota_file: Option<PathBuf>,
+
+        /// Window in which an identity may hold tunnels, such as `secret=mon-fri 09:00-17:00`.
+        #[clap(long, value_name = "IDENTITY=WINDOW", requires = "secret", value_parser = parse_schedule)]
+        schedule: Vec<(String, Window)>,
+
+        /// Secret required for tunnels on some ports or names, such as `9000-9099=s3cret`.
+        #[clap(long, value_name = "SCOPE=SECRET", requires = "secret", value_parser = parse_scoped_secret)]
+        scoped_secret: Vec<(SecretScope, String)>,
+
+        /// Ports on which clients may open tunnels without a secret, such as `30000-65535`.
+        #[clap(long, value_name = "PORTS", requires = "secret")]
+        open_ports: Option<PortList>,
+
+        #[clap(flatten)]
+        logging: Logging,
+
+        #[clap(flatten)]
+        detach: Detach,
+    },
+
+    /// Administers a remote server that requires a secret.
+    Admin {
+        #[clap(subcommand)]
+        action: AdminAction,
+    },
+
+    /// Checks that a server is reachable, and measures its response time.
+    Ping {
+        /// Address of the remote server.
+        #[clap(short, long, env = "BORE_SERVER")]
+        to: String,
+
         /// Optional secret for authentication.
         #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
         secret: Option<String>,
+
+        #[clap(flatten)]
+        transport: Transport,
+    },
+
+    /// Measures the throughput and latency of a tunnel, through a local or remote server.
+    Bench {
+        /// Address of a remote server to measure [default: a server started on loopback].
+        #[clap(short, long, env = "BORE_SERVER")]
+        to: Option<String>,
+
+        /// Optional secret for authentication.
+        #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
+        secret: Option<String>,
+
+        /// Number of connections opened to measure latency.
+        #[clap(
+            short = 'n',
+            long,
+            value_name = "N",
+            default_value_t = 20,
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        connections: u32,
+
+        /// Amount of data sent in each direction to measure throughput.
+        #[clap(long, value_name = "SIZE", default_value = "64M", value_parser = parse_transfer_size)]
+        size: u64,
+
+        /// Size of the buffer used to copy each connection, such as `256K` [default: 8K].
+        #[clap(long, value_name = "SIZE", value_parser = parse_buffer_size)]
+        proxy_buffer_size: Option<usize>,
+
+        /// Print the results as JSON.
+        #[clap(long)]
+        json: bool,
+
+        #[clap(flatten)]
+        transport: Transport,
+    },
+
+    /// Checks that a server is serving, exiting with an error if it does not respond.
+    Healthcheck {
+        /// Address of the server.
+        #[clap(short, long, env = "BORE_SERVER", default_value = "localhost")]
+        to: String,
+
+        /// Optional secret for authentication.
+        #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
+        secret: Option<String>,
+
+        /// Seconds to wait for the server to respond.
+        #[clap(long, value_name = "SECS", default_value_t = 5)]
+        timeout: u64,
+
+        #[clap(flatten)]
+        transport: Transport,
+    },
+
+    /// Forwards clients to an upstream server, so tunnels are chained through this host.
+    Relay {
+        /// Address of the upstream server, which may be another relay.
+        #[clap(short, long, env = "BORE_UPSTREAM")]
+        upstream: String,
+
+        /// Port that clients connect to, as if to a server.
+        #[clap(long, value_name = "PORT", default_value_t = CONTROL_PORT)]
+        port: u16,
+
+        /// Address to listen for clients on.
+        #[clap(long, value_name = "IP", default_value = "0.0.0.0")]
+        listen_addr: IpAddr,
+
+        #[clap(flatten)]
+        transport: Transport,
+    },
+
+    /// Prints a script that completes commands and options in a shell.
+    Completions {
+        /// Shell to generate completions for.
+        shell: Shell,
+    },
+
+    /// Installs, removes, or runs `bore` as a Windows service.
+    #[cfg(windows)]
+    Service {
+        #[clap(subcommand)]
+        action: ServiceAction,
+    },
+}
+
+#[cfg(windows)]
+#[derive(Subcommand, Debug)]
+enum ServiceAction {
+    /// Installs a service that starts at boot and runs the command after `--`.
+    Install {
+        /// Name of the service.
+        #[clap(long, default_value = "bore")]
+        name: String,
+
+        /// Command to run, such as `local 8000 --to example.com`.
+        #[clap(last = true, required = true)]
+        args: Vec<OsString>,
+    },
+
+    /// Stops and removes a service.
+    Uninstall {
+        /// Name of the service.
+        #[clap(long, default_value = "bore")]
+        name: String,
+    },
+
+    /// Runs a command as a service, when started by the service control manager.
+    #[clap(hide = true)]
+    Run {
+        /// Name of the service.
+        #[clap(long)]
+        name: String,
+
+        /// Command to run.
+        #[clap(last = true, required = true)]
+        args: Vec<OsString>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AdminAction {
+    /// Lists banned source IPs.
+    Bans {
+        #[clap(flatten)]
+        server: AdminServer,
+    },
+
+    /// Bans a source IP from the control port and tunnels, closing its clients' tunnels.
+    Ban {
+        /// The source IP to ban.
+        ip: IpAddr,
+
+        /// How long the ban lasts, such as `90m`, `24h`, or `7d`.
+        #[clap(long, value_name = "DURATION", default_value = "24h", value_parser = parse_duration)]
+        duration: Duration,
+
+        #[clap(flatten)]
+        server: AdminServer,
+    },
+
+    /// Lifts the ban on a source IP.
+    Unban {
+        /// The banned source IP.
+        ip: IpAddr,
+
+        #[clap(flatten)]
+        server: AdminServer,
+    },
+
+    /// Lifts all bans.
+    ClearBans {
+        #[clap(flatten)]
+        server: AdminServer,
+    },
+
+    /// Lists the tunnels open on the server, with their connections and traffic.
+    List {
+        /// Print the tunnels as a JSON array instead of a table.
+        #[clap(long)]
+        json: bool,
+
+        #[clap(flatten)]
+        server: AdminServer,
+    },
+
+    /// Shows a summary of the tunnels and traffic of the server.
+    Stats {
+        /// Print the summary as a JSON object.
+        #[clap(long)]
+        json: bool,
+
+        #[clap(flatten)]
+        server: AdminServer,
+    },
+
+    /// Lists the transfer quota usage of client identities this month.
+    Usage {
+        /// Print the usage as a JSON array instead of a table.
+        #[clap(long)]
+        json: bool,
+
+        #[clap(flatten)]
+        server: AdminServer,
     },
+
+    /// Closes a tunnel, releasing its port and dropping its waiting visitors.
+    #[clap(group(ArgGroup::new("target").required(true).args(["port", "name"])))]
+    Kill {
+        /// Public port of the tunnel.
+        #[clap(short, long)]
+        port: Option<u16>,
+
+        /// Name of the tunnel.
+        #[clap(short, long)]
+        name: Option<String>,
+
+        #[clap(flatten)]
+        server: AdminServer,
+    },
+}
+
+#[derive(ClapArgs, Debug)]
+struct AdminServer {
+    /// Address of the remote server to administer.
+    #[clap(short, long, env = "BORE_SERVER")]
+    to: String,
+
+    /// Secret of the remote server.
+    #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
+    secret: String,
+
+    #[clap(flatten)]
+    transport: Transport,
+}
+
+#[derive(ClapArgs, Debug)]
+struct Transport {
+    /// Encrypt control connections to the server with TLS.
+    #[clap(long, env = "BORE_TLS")]
+    tls: bool,
+
+    /// PEM file with extra certificates to trust, such as a self-signed server certificate.
+    #[clap(long, value_name = "PATH", requires = "tls", env = "BORE_TLS_CA")]
+    tls_ca: Option<PathBuf>,
+
+    /// Control port of the server, if it is not the default.
+    #[clap(long, value_name = "PORT")]
+    control_port: Option<u16>,
+
+    /// Local source address for connections to the server, to pick a network interface.
+    #[clap(long, value_name = "IP", env = "BORE_BIND_ADDR")]
+    bind_addr: Option<IpAddr>,
+
+    /// Treat the server as dead if it sends no heartbeat for this many seconds.
+    #[clap(long, value_name = "SECS")]
+    heartbeat_timeout: Option<u64>,
+
+    /// Resolve the server's host name again this often, reconnecting once its addresses change.
+    #[clap(long, value_name = "SECS")]
+    resolve_interval: Option<u64>,
+
+    /// Answer heartbeats, so the round-trip time to the server is measured on both ends.
+    #[clap(long)]
+    measure_rtt: bool,
+}
+
+impl Transport {
+    fn client_options(&self, profile: &Profile) -> Result<ClientOptions> {
+        let tls = match self.tls || profile.tls.unwrap_or(false) {
+            true => {
+                let ca = self.tls_ca.as_deref().or(profile.tls_ca.as_deref());
+                Some(tls::client_config(ca)?)
+            }
+            false => None,
+        };
+        Ok(ClientOptions {
+            tls,
+            control_port: self.control_port.or(profile.control_port),
+            bind_addr: self.bind_addr.or(profile.bind_addr),
+            heartbeat_timeout: self
+                .heartbeat_timeout
+                .or(profile.heartbeat_timeout)
+                .map(Duration::from_secs),
+            resolve_interval: self.resolve_interval.map(Duration::from_secs),
+            measure_rtt: self.measure_rtt || profile.measure_rtt.unwrap_or(false),
+            ..Default::default()
+        })
+    }
+}
+
+/// Options for running a command in the background.
+#[derive(ClapArgs, Debug)]
+struct Detach {
+    /// Detach from the terminal and run in the background.
+    #[clap(long)]
+    daemon: bool,
+
+    /// File to write the process ID to, when running in the background.
+    #[clap(long, value_name = "PATH", requires = "daemon")]
+    pid_file: Option<PathBuf>,
+}
+
+impl Detach {
+    /// Fork into the background if requested, which must happen before the runtime starts.
+    fn start(&self) -> Result<()> {
+        if !self.daemon {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        {
+            // Keep the working directory, so relative paths in other options still resolve.
+            let mut daemon = daemonize::Daemonize::new().working_directory(env::current_dir()?);
+            if let Some(path) = &self.pid_file {
+                daemon = daemon.pid_file(path);
+            }
+            daemon.start().context("could not run in the background")
+        }
+        #[cfg(not(unix))]
+        bail!("--daemon is not supported on this platform");
+    }
+}
+
+/// Location of the socket of a client daemon.
+#[cfg(unix)]
+#[derive(ClapArgs, Debug)]
+struct DaemonSocket {
+    /// Path of the daemon's Unix socket [default: $XDG_RUNTIME_DIR/bore.sock].
+    #[clap(long = "socket", value_name = "PATH", env = "BORE_DAEMON_SOCKET")]
+    path: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+impl DaemonSocket {
+    fn path(self) -> PathBuf {
+        self.path.unwrap_or_else(daemon::default_socket_path)
+    }
+}
+
+/// Load a profile from the config file, or the default profile if no name is given.
+fn load_profile(name: Option<&str>) -> Result<Profile> {
+    match Config::default_path() {
+        Some(path) => Config::load(path)?.profile(name),
+        None if name.is_some() => bail!("could not locate the config file"),
+        None => Ok(Profile::default()),
+    }
+}
+
+impl Command {
+    /// Returns the options for running in the background, for commands that support them.
+    fn detach(&self) -> Option<&Detach> {
+        match self {
+            Command::Local { detach, .. } | Command::Server { detach, .. } => Some(detach),
+            _ => None,
+        }
+    }
+
+    /// Returns the level to log at without `-v` or `-q`.
+    ///
+    /// Benchmarks only log warnings, since every connection they open would otherwise be logged
+    /// alongside their results.
+    fn default_log_level(&self) -> LevelFilter {
+        match self {
+            Command::Bench { .. } => LevelFilter::WARN,
+            _ => LevelFilter::INFO,
+        }
+    }
+
+    /// Returns the logging options, for commands that support them.
+    fn logging(&self) -> Option<&Logging> {
+        match self {
+            Command::Local { logging, .. } | Command::Server { logging, .. } => Some(logging),
+            _ => None,
+        }
+    }
+}
+
+/// Format a number of seconds as hours, minutes, and seconds, such as `2h05m09s`.
+fn format_uptime(secs: u64) -> String {
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m{s:02}s"),
+        (h, m, s) => format!("{h}h{m:02}m{s:02}s"),
+    }
+}
+
+/// Format a number of bytes with a binary unit, such as `1.5 MiB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+/// Print the results of a benchmark, with latencies in milliseconds.
+fn print_bench(to: &str, report: &BenchReport, connections: u32) {
+    let latency = |p: &Percentiles| {
+        let ms = |us: u64| us as f64 / 1000.0;
+        format!(
+            "p50 {:.2}ms  p90 {:.2}ms  max {:.2}ms",
+            ms(p.p50_us),
+            ms(p.p90_us),
+            ms(p.max_us)
+        )
+    };
+    let throughput = |secs: f64, rate: f64| {
+        format!(
+            "{} in {secs:.2}s, {}/s",
+            format_bytes(report.bytes),
+            format_bytes(rate as u64)
+        )
+    };
+    println!("tunnel through {to}, port {}", report.port);
+    println!(
+        "connect     {}  ({connections} connections)",
+        latency(&report.connect)
+    );
+    println!("first byte  {}", latency(&report.first_byte));
+    println!(
+        "upload      {}",
+        throughput(report.upload_secs, report.upload_rate())
+    );
+    println!(
+        "download    {}",
+        throughput(report.download_secs, report.download_rate())
+    );
+}
+
+/// Print rows as a table, with each column as wide as its longest cell.
+fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let header = header.map(String::from);
+    for row in iter::once(&header).chain(rows) {
+        let line: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+fn print_admin_tunnels(tunnels: &[AdminTunnel]) {
+    let rows: Vec<_> = tunnels
+        .iter()
+        .map(|tunnel| {
+            [
+                tunnel.port.to_string(),
+                tunnel.name.clone().unwrap_or_else(|| "-".into()),
+                tunnel.client.to_string(),
+                tunnel.client_host.clone().unwrap_or_else(|| "-".into()),
+                tunnel.client_version.clone().unwrap_or_else(|| "-".into()),
+                tunnel.identity.clone().unwrap_or_else(|| "-".into()),
+                format_uptime(tunnel.uptime_secs),
+                tunnel.connections.to_string(),
+                tunnel.active.to_string(),
+                format_bytes(tunnel.received),
+                format_bytes(tunnel.sent),
+                match tunnel.rtt_us {
+                    Some(rtt_us) => format!("{:.1}ms", rtt_us as f64 / 1000.0),
+                    None => "-".into(),
+                },
+                match tunnel.expires_in_secs {
+                    Some(secs) => format_uptime(secs),
+                    None => "-".into(),
+                },
+                tunnel.description.clone().unwrap_or_else(|| "-".into()),
+            ]
+        })
+        .collect();
+    let header = [
+        "PORT",
+        "NAME",
+        "CLIENT",
+        "HOST",
+        "VERSION",
+        "IDENTITY",
+        "UPTIME",
+        "CONNS",
+        "ACTIVE",
+        "RECEIVED",
+        "SENT",
+        "RTT",
+        "EXPIRES",
+        "DESCRIPTION",
+    ];
+    print_table(header, &rows);
+}
+
+fn print_admin_usage(usage: &[AdminUsage]) {
+    let rows: Vec<_> = usage
+        .iter()
+        .map(|entry| {
+            let percent = match entry.limit {
+                0 => 100.0,
+                limit => 100.0 * entry.used as f64 / limit as f64,
+            };
+            [
+                entry.identity.clone(),
+                entry.period.clone(),
+                format_bytes(entry.used),
+                format_bytes(entry.limit),
+                format!("{percent:.1}%"),
+            ]
+        })
+        .collect();
+    print_table(["IDENTITY", "MONTH", "USED", "LIMIT", "PERCENT"], &rows);
+}
+
+fn print_admin_stats(stats: &AdminStats) {
+    println!(
+        "server version {}, up {}",
+        stats.version,
+        format_uptime(stats.uptime_secs)
+    );
+    println!(
+        "{} open tunnels, {} active connections, {} forwarded",
+        stats.tunnels, stats.active, stats.connections
+    );
+    println!(
+        "received {}, sent {}",
+        format_bytes(stats.received),
+        format_bytes(stats.sent)
+    );
+    println!("{} banned addresses", stats.bans);
+    if !stats.geoip_blocked.is_empty() {
+        let blocked: Vec<_> = (stats.geoip_blocked.iter())
+            .map(|(country, count)| format!("{country} {count}"))
+            .collect();
+        println!("blocked by country: {}", blocked.join(", "));
+    }
+}
+
+/// Exit with a usage error if the remote server address is missing.
+fn require_to(to: Option<String>) -> String {
+    to.unwrap_or_else(|| {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the server address must be given with --to or in a profile",
+            )
+            .exit()
+    })
+}
+
+fn copy_to_clipboard(text: String) -> Option<Clipboard> {
+    let result = Clipboard::new().and_then(|mut clipboard| {
+        clipboard.set_text(text)?;
+        Ok(clipboard)
+    });
+    match result {
+        Ok(clipboard) => {
+            info!("copied public URL to the clipboard");
+            Some(clipboard)
+        }
+        Err(err) => {
+            warn!(%err, "could not copy to the clipboard");
+            None
+        }
+    }
+}
+
+fn require_single_port<T>(value: Option<T>, flag: &str) {
+    if value.is_some() {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!("{flag} cannot be used when exposing several local ports"),
+            )
+            .exit()
+    }
+}
+
+/// Reports the tunnels of `bore local` for several ports as they connect and disconnect.
+#[derive(Clone)]
+struct Announcer {
+    json: bool,
+    scheme: Option<Scheme>,
+    on_connect: Option<String>,
+    on_disconnect: Option<String>,
+    shutdown: CancellationToken,
+}
+
+impl Announcer {
+    /// Print the address of a tunnel and run the hooks each time it connects or disconnects,
+    /// until the client shuts down, sending its public URL on for the clipboard.
+    async fn watch(
+        self,
+        config: &TunnelConfig,
+        mut events: broadcast::Receiver<ClientEvent>,
+        urls: mpsc::UnboundedSender<(u16, String)>,
+    ) {
+        let mut connected = None;
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = self.shutdown.cancelled() => break,
+            };
+            match event {
+                Ok(ClientEvent::Connected {
+                    remote_host,
+                    remote_port,
+                }) => {
+                    let url = self
+                        .scheme
+                        .map(|scheme| scheme.url(&remote_host, remote_port));
+                    if self.json {
+                        let mut output = serde_json::json!({
+                            "remote_host": remote_host,
+                            "remote_port": remote_port,
+                            "local_port": config.local_port,
+                        });
+                        if let Some(url) = &url {
+                            output["url"] = url.as_str().into();
+                        }
+                        println!("{output}");
+                    } else if let Some(url) = &url {
+                        println!("{url}");
+                    }
+                    if let Some(url) = url {
+                        let _ = urls.send((config.local_port, url));
+                    }
+                    let hook_vars = [
+                        ("BORE_REMOTE_HOST", remote_host),
+                        ("BORE_REMOTE_PORT", remote_port.to_string()),
+                        ("BORE_LOCAL_HOST", config.local_host.clone()),
+                        ("BORE_LOCAL_PORT", config.local_port.to_string()),
+                        ("BORE_NAME", config.options.name.clone().unwrap_or_default()),
+                    ];
+                    if let Some(command) = self.on_connect.clone() {
+                        let hook_vars = hook_vars.clone();
+                        tokio::spawn(async move { run_hook(&command, &hook_vars).await });
+                    }
+                    connected = Some(hook_vars);
+                }
+                Ok(ClientEvent::Reconnecting { .. }) => {
+                    if let (Some(command), Some(hook_vars)) = (&self.on_disconnect, &connected) {
+                        run_hook(command, hook_vars).await;
+                    }
+                    connected = None;
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            }
+        }
+        if let (Some(command), Some(hook_vars)) = (&self.on_disconnect, &connected) {
+            run_hook(command, hook_vars).await;
+        }
+    }
+}
+
+/// Run the tunnels of `bore local` for several ports under a supervisor, which reconnects each
+/// of them on its own, as for `bore tunnels`.
+async fn supervise_local(
+    configs: Vec<TunnelConfig>,
+    announcer: Announcer,
+    copy: bool,
+) -> Result<()> {
+    let (urls_tx, mut urls_rx) = mpsc::unbounded_channel();
+    let mut watchers = Vec::new();
+    let configs: Vec<_> = configs
+        .into_iter()
+        .map(|config| {
+            let (events, receiver) = event::channel();
+            let config = TunnelConfig {
+                options: ClientOptions {
+                    events: Some(events),
+                    ..config.options
+                },
+                ..config
+            };
+            let (announcer, watched, urls_tx) =
+                (announcer.clone(), config.clone(), urls_tx.clone());
+            watchers.push(tokio::spawn(async move {
+                announcer.watch(&watched, receiver, urls_tx).await
+            }));
+            config
+        })
+        .collect();
+    let count = configs.len();
+    let supervisor = Arc::new(Supervisor::new(configs));
+    // The supervisor starts the tunnels when it first runs, and they stop only once they expire.
+    let stopped = async {
+        tokio::select! {
+            biased;
+            _ = Arc::clone(&supervisor).run() => {}
+            _ = supervisor.stopped() => {}
+        }
+    };
+    tokio::pin!(stopped);
+
+    // The URLs are copied together once every tunnel has connected. On some platforms, the
+    // clipboard is only served while it stays open.
+    let mut urls = BTreeMap::new();
+    let mut _clipboard = None;
+    loop {
+        tokio::select! {
+            _ = &mut stopped => break,
+            Some((local_port, url)) = urls_rx.recv() => {
+                let first = urls.len() < count;
+                urls.insert(local_port, url);
+                if copy && first && urls.len() == count {
+                    let text: Vec<_> = urls.values().cloned().collect();
+                    _clipboard = copy_to_clipboard(text.join("\n"));
+                }
+            }
+            _ = shutdown_signal() => break,
+        }
+    }
+
+    // Give the tunnels a moment to say goodbye, so the server frees their ports.
+    announcer.shutdown.cancel();
+    let _ = tokio::time::timeout(Duration::from_secs(2), supervisor.stopped()).await;
+    join_all(watchers).await;
+    Ok(())
+}
+
+fn parse_port_policy(s: &str) -> Result<(u16, CountryPolicy)> {
+    let (port, policy) = s.split_once('=').context("expected PORT=POLICY")?;
+    Ok((port.parse().context("invalid port")?, policy.parse()?))
+}
+
+fn parse_schedule(s: &str) -> Result<(String, Window)> {
+    let (identity, window) = s.split_once('=').context("expected IDENTITY=WINDOW")?;
+    Ok((identity.into(), window.parse()?))
+}
+
+fn parse_identity_rate(s: &str) -> Result<(String, ByteRate)> {
+    let (identity, rate) = s.split_once('=').context("expected IDENTITY=RATE")?;
+    Ok((identity.into(), rate.parse()?))
+}
+
+fn parse_scoped_secret(s: &str) -> Result<(SecretScope, String)> {
+    let (scope, secret) = s.split_once('=').context("expected SCOPE=SECRET")?;
+    Ok((scope.parse()?, secret.into()))
+}
+
+/// Parse a number of bytes, with an optional `K`, `M`, or `G` suffix in powers of 1024, which
+/// may be written as `KiB`, `MiB`, or `GiB`.
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.strip_suffix("iB").unwrap_or(s);
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Parse the size of a copy buffer, up to 64M.
+fn parse_buffer_size(s: &str) -> Result<usize> {
+    const MAX_BUFFER_SIZE: u64 = 64 << 20;
+    match parse_size(s) {
+        Some(size) if (1..=MAX_BUFFER_SIZE).contains(&size) => Ok(size as usize),
+        _ => bail!("expected a size like `64K` or `1M`, up to 64M"),
+    }
+}
+
+/// Parse a nonzero amount of data to transfer.
+fn parse_transfer_size(s: &str) -> Result<u64> {
+    match parse_size(s) {
+        Some(size) if size > 0 => Ok(size),
+        _ => bail!("expected a size like `64M` or `1G`"),
+    }
+}
+
+/// Parse a nonzero duration in seconds, with an optional `s`, `m`, `h`, or `d` suffix.
+fn parse_duration(s: &str) -> Result<Duration> {
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 3600),
+        Some((i, 'd')) => (&s[..i], 86400),
+        _ => (s, 1),
+    };
+    match number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+    {
+        Some(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => bail!("expected a duration like `90m`, `24h`, or `7d`"),
+    }
+}
+
+fn parse_ip_net(s: &str) -> Result<IpNet> {
+    match s.parse::<IpAddr>() {
+        Ok(ip) => Ok(ip.into()),
+        Err(_) => Ok(s.parse()?),
+    }
 }
 
 #[tokio::main]
@@ -55,33 +1495,888 @@ async fn run(command: Command) -> Result<()> {
     match command {
         Command::Local {
             local_host,
+            local_ports,
+            to,
+            port,
+            secret,
+            name,
+            shared,
+            description,
+            client_host,
+            public_listing,
+            subdomain,
+            expires,
+            max_transfer,
+            profile,
+            inspect,
+            inspect_body_limit,
+            terminate_tls,
+            terminate_tls_cert,
+            terminate_tls_key,
+            local_tls,
+            local_tls_insecure,
+            allow_ip,
+            deny_ip,
+            basic_auth,
+            forwarded_headers,
+            host_header,
+            proxy_protocol,
+            port_range,
+            remote_bind,
+            fallback_random,
+            pool_size,
+            stats_interval,
+            proxy_buffer_size,
+            max_up,
+            max_down,
+            on_connect,
+            on_disconnect,
+            json,
+            scheme,
+            copy,
+            write_env_to,
+            status_file,
+            transport,
+            logging: _,
+            detach: _,
+        } => {
+            let profile = load_profile(profile.as_deref())?;
+            let to = require_to(to.or_else(|| profile.to.clone()));
+            let local_host = local_host
+                .or_else(|| profile.local_host.clone())
+                .unwrap_or_else(|| "localhost".into());
+            let secret = secret.or_else(|| profile.secret.clone());
+            let name = name.or_else(|| profile.name.clone());
+            if shared && name.is_none() {
+                Args::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "--shared requires a tunnel name, given with --name",
+                    )
+                    .exit()
+            }
+            if local_ports.len() > 1 {
+                require_single_port(port.or(profile.port).filter(|&p| p != 0), "--port");
+                require_single_port(name.as_ref(), "--name");
+                require_single_port(inspect, "--inspect");
+                require_single_port(write_env_to.as_ref(), "--write-env-to");
+                require_single_port(status_file.as_ref(), "--status-file");
+            }
+            let shutdown = CancellationToken::new();
+            let mut options = ClientOptions {
+                name: name.clone(),
+                shared,
+                description,
+                client_host,
+                public_listing,
+                subdomain,
+                expires,
+                transfer_limit: max_transfer,
+                ip_filter: IpFilter {
+                    allow: allow_ip,
+                    deny: deny_ip,
+                },
+                basic_auth,
+                forwarded_headers,
+                host_header,
+                proxy_protocol,
+                fallback_random,
+                port_range,
+                remote_bind,
+                pool_size,
+                stats_interval: stats_interval.map(Duration::from_secs),
+                proxy_buffer_size,
+                max_up,
+                max_down,
+                shutdown: shutdown.clone(),
+                env_file: write_env_to.map(|path| EnvFile {
+                    path,
+                    scheme: scheme.unwrap_or(Scheme::Tcp),
+                }),
+                status_file,
+                ..transport.client_options(&profile)?
+            };
+            if local_tls {
+                options.local_tls = Some(match local_tls_insecure {
+                    true => tls::insecure_client_config(),
+                    false => tls::client_config(None)?,
+                });
+            }
+            if let Some(addr) = inspect {
+                let inspector = Arc::new(Inspector::new(DEFAULT_CAPACITY, inspect_body_limit));
+                let listener = TcpListener::bind(addr).await?;
+                let server = Arc::clone(&inspector).serve(
+                    listener,
+                    local_host.clone(),
+                    local_ports[0],
+                    options.local_tls.clone(),
+                );
+                tokio::spawn(server);
+                options.inspector = Some(inspector);
+            }
+            if terminate_tls {
+                options.terminate_tls = Some(match (terminate_tls_cert, terminate_tls_key) {
+                    (Some(cert), Some(key)) => tls::server_config(cert, key)?,
+                    _ => tls::self_signed_config(vec![to.clone(), "localhost".into()])?,
+                });
+            }
+            let port = port.or(profile.port).unwrap_or(0);
+            if local_ports.len() > 1 {
+                let configs = local_ports
+                    .iter()
+                    .map(|&local_port| TunnelConfig {
+                        local_host: local_host.clone(),
+                        local_port,
+                        to: to.clone(),
+                        port,
+                        secret: secret.clone(),
+                        options: options.clone(),
+                    })
+                    .collect();
+                let announcer = Announcer {
+                    json,
+                    scheme,
+                    on_connect,
+                    on_disconnect,
+                    shutdown,
+                };
+                return supervise_local(configs, announcer, copy).await;
+            }
+
+            let local_port = local_ports[0];
+            // The tunnel has its own span, which labels its logs and is the root of its traces.
+            let span = info_span!("tunnel", local_port, port = field::Empty);
+            let client = Client::with_options(
+                &local_host,
+                local_port,
+                &to,
+                port,
+                secret.as_deref(),
+                options,
+            )
+            .instrument(span.clone())
+            .await?;
+            span.record("port", client.remote_port());
+            let url = scheme.map(|scheme| client.public_url(scheme));
+            if json {
+                let mut output = serde_json::json!({
+                    "remote_host": client.public_host(),
+                    "remote_port": client.remote_port(),
+                    "local_port": local_port,
+                });
+                if let Some(url) = &url {
+                    output["url"] = url.as_str().into();
+                }
+                println!("{output}");
+            } else if let Some(url) = &url {
+                println!("{url}");
+            }
+            let hook_vars = [
+                ("BORE_REMOTE_HOST", client.public_host().to_string()),
+                ("BORE_REMOTE_PORT", client.remote_port().to_string()),
+                ("BORE_LOCAL_HOST", local_host.clone()),
+                ("BORE_LOCAL_PORT", local_port.to_string()),
+                ("BORE_NAME", name.clone().unwrap_or_default()),
+            ];
+            if let Some(command) = on_connect {
+                let hook_vars = hook_vars.clone();
+                tokio::spawn(async move { run_hook(&command, &hook_vars).await });
+            }
+            // On some platforms, the clipboard is only served while it stays open.
+            let _clipboard = match (copy, url) {
+                (true, Some(url)) => copy_to_clipboard(url),
+                _ => None,
+            };
+
+            // Tunnels log their summary when their listener is dropped.
+            let listener = client.listen().instrument(span);
+            tokio::pin!(listener);
+            let result = tokio::select! {
+                result = &mut listener => result,
+                _ = shutdown_signal() => {
+                    shutdown.cancel();
+                    // Give the tunnel a moment to say goodbye, so the server frees its port.
+                    let _ = tokio::time::timeout(Duration::from_secs(2), listener).await;
+                    Ok(())
+                }
+            };
+            if let Some(command) = on_disconnect {
+                run_hook(&command, &hook_vars).await;
+            }
+            result?;
+        }
+        Command::Tunnels {
+            mut tunnels,
+            config,
+            local_host,
+            to,
+            secret,
+            profile,
+            transport,
+        } => {
+            let profile = load_profile(profile.as_deref())?;
+            let file = match config {
+                Some(path) => TunnelsFile::load(path)?,
+                None => TunnelsFile::default(),
+            };
+            tunnels.extend(file.tunnels);
+            if tunnels.is_empty() {
+                Args::command()
+                    .error(ErrorKind::MissingRequiredArgument, "no tunnels to expose")
+                    .exit();
+            }
+            let to = to.or(file.to).or_else(|| profile.to.clone());
+            let secret = secret.or(file.secret).or_else(|| profile.secret.clone());
+            let local_host = local_host
+                .or_else(|| profile.local_host.clone())
+                .unwrap_or_else(|| "localhost".into());
+            let options = transport.client_options(&profile)?;
+            let configs = tunnels
+                .into_iter()
+                .map(|spec| {
+                    let to = spec.to.or_else(|| to.clone()).with_context(|| {
+                        format!("no server address for tunnel on port {}", spec.local_port)
+                    })?;
+                    Ok(TunnelConfig {
+                        local_host: spec.local_host.unwrap_or_else(|| local_host.clone()),
+                        local_port: spec.local_port,
+                        to,
+                        port: spec.port,
+                        secret: secret.clone(),
+                        options: ClientOptions {
+                            name: spec.name,
+                            env_file: spec.env_file.map(EnvFile::new),
+                            ..options.clone()
+                        },
+                    })
+                })
+                .collect::<Result<_>>()?;
+            tokio::select! {
+                _ = Arc::new(Supervisor::new(configs)).run() => {}
+                _ = shutdown_signal() => {}
+            }
+        }
+        #[cfg(unix)]
+        Command::Daemon { socket } => {
+            Daemon::bind(socket.path()).await?.listen().await?;
+        }
+        #[cfg(unix)]
+        Command::Add {
             local_port,
+            local_host,
             to,
             port,
             secret,
+            name,
+            profile,
+            transport,
+            socket,
         } => {
-            let client = Client::new(&local_host, local_port, &to, port, secret.as_deref()).await?;
-            client.listen().await?;
+            let profile = load_profile(profile.as_deref())?;
+            let tls_ca = match transport.tls_ca.or(profile.tls_ca) {
+                Some(path) => Some(std::path::absolute(path)?),
+                None => None,
+            };
+            let tunnel = NewTunnel {
+                local_host: local_host
+                    .or(profile.local_host)
+                    .unwrap_or_else(|| "localhost".into()),
+                local_port,
+                to: require_to(to.or(profile.to)),
+                port: port.or(profile.port).unwrap_or(0),
+                secret: secret.or(profile.secret),
+                name: name.or(profile.name),
+                tls: transport.tls || profile.tls.unwrap_or(false),
+                tls_ca,
+                control_port: transport.control_port.or(profile.control_port),
+                bind_addr: transport.bind_addr.or(profile.bind_addr),
+                heartbeat_timeout: transport.heartbeat_timeout.or(profile.heartbeat_timeout),
+                resolve_interval: transport.resolve_interval,
+                measure_rtt: transport.measure_rtt || profile.measure_rtt.unwrap_or(false),
+            };
+            daemon::request(socket.path(), DaemonRequest::Add(tunnel)).await?;
+        }
+        #[cfg(unix)]
+        Command::List { socket } => {
+            if let DaemonResponse::Tunnels(tunnels) =
+                daemon::request(socket.path(), DaemonRequest::List).await?
+            {
+                for (label, state) in tunnels {
+                    println!("{label}\t{state}");
+                }
+            }
+        }
+        #[cfg(unix)]
+        Command::Status { json, socket } => {
+            if let DaemonResponse::Status(tunnels) =
+                daemon::request(socket.path(), DaemonRequest::Status).await?
+            {
+                if json {
+                    println!("{}", serde_json::to_string(&tunnels)?);
+                } else {
+                    for tunnel in tunnels {
+                        let uptime = match tunnel.uptime_secs {
+                            Some(secs) => format!("up {}", format_uptime(secs)),
+                            None => "down".into(),
+                        };
+                        println!(
+                            "{}\t{}\t{}\t{uptime}",
+                            tunnel.label, tunnel.to, tunnel.state
+                        );
+                        for err in tunnel.recent_errors {
+                            println!("  error: {err}");
+                        }
+                    }
+                }
+            }
+        }
+        #[cfg(unix)]
+        Command::Remove { name, socket } => {
+            daemon::request(socket.path(), DaemonRequest::Remove(name)).await?;
         }
         Command::Server {
             min_port,
             max_port,
+            port_range,
+            exclude_ports,
+            control_bind_addr,
+            tunnel_bind_addr,
+            domain,
+            client_bind,
             secret,
+            tls_cert,
+            tls_key,
+            tls_port,
+            tls_only,
+            tls_tunnel_ports,
+            acme_domain,
+            acme_email,
+            acme_url,
+            acme_cache,
+            acme_http_port,
+            control_socket,
+            socket_only,
+            state_file,
+            health_port,
+            pending_expiry,
+            reconnect_grace,
+            reconnect_queue,
+            shutdown_reason,
+            shutdown_retry_after,
+            webhook,
+            dns_domain,
+            dns_target,
+            dns_cloudflare_zone,
+            dns_cloudflare_token,
+            dns_command,
+            audit_log,
+            cluster_peer,
+            cluster_node,
+            geoip_db,
+            geoip_policy,
+            geoip_port_policy,
+            tcp_nodelay,
+            tcp_keepalive,
+            tcp_keepalive_interval,
+            listen_backlog,
+            send_buffer_size,
+            recv_buffer_size,
+            accept_shards,
+            proxy_buffer_size,
+            connection_rate,
+            identity_connection_rate,
+            max_transfer,
+            min_client_version,
+            rate_limit_port,
+            rate_limit_ip,
+            ban_threshold,
+            ban_window,
+            ban_duration,
+            ban_file,
+            quota,
+            quota_file,
+            schedule,
+            scoped_secret,
+            open_ports,
+            logging: _,
+            detach: _,
         } => {
-            let port_range = min_port..=max_port;
-            if port_range.is_empty() {
+            let port_ranges: PortList = if port_range.is_empty() {
+                std::iter::once(min_port..=max_port).collect()
+            } else {
+                port_range
+                    .iter()
+                    .flat_map(|list| list.ranges().iter().cloned())
+                    .collect()
+            };
+            if port_ranges.is_empty() {
                 Args::command()
                     .error(ErrorKind::InvalidValue, "port range is empty")
                     .exit();
             }
-            Server::new(port_range, secret.as_deref()).listen().await?;
+            let mut server = Server::new(port_ranges, secret.as_deref());
+            let tls_config = if let (Some(cert), Some(key)) = (tls_cert, tls_key) {
+                Some(tls::server_config(cert, key)?)
+            } else if !acme_domain.is_empty() {
+                let mut acme = Acme::new(acme_domain, acme_cache)?;
+                if let Some(email) = acme_email {
+                    acme.set_contact(email);
+                }
+                if let Some(url) = acme_url {
+                    acme.set_directory(url);
+                }
+                acme.set_challenge_port(acme_http_port);
+                let config = acme.server_config()?;
+                server.set_acme(acme);
+                Some(config)
+            } else {
+                None
+            };
+            if let (Some(config), Some(ports)) = (&tls_config, tls_tunnel_ports) {
+                server.set_visitor_tls(ports, Arc::clone(config));
+            }
+            if let Some(config) = tls_config {
+                let tls = ControlListener::tls(tls_port, config);
+                let mut listeners = vec![tls];
+                if !tls_only {
+                    listeners.insert(0, ControlListener::plain(CONTROL_PORT));
+                }
+                server.set_control_listeners(listeners);
+            }
+            if let Some(path) = control_socket {
+                #[cfg(unix)]
+                server.add_acceptor(UnixAcceptor::bind(path).await?);
+                #[cfg(not(unix))]
+                bail!(
+                    "control sockets are not supported on this platform: {}",
+                    path.display()
+                );
+            }
+            if socket_only {
+                server.set_control_listeners(Vec::new());
+            }
+            if let Some(exclude_ports) = exclude_ports {
+                server.set_excluded_ports(exclude_ports);
+            }
+            server.set_control_bind_addr(control_bind_addr);
+            server.set_tunnel_bind_addr(tunnel_bind_addr);
+            if let Some(domain) = domain {
+                server.set_domain(domain);
+            }
+            server.set_bind_policy(client_bind);
+            if let Some(path) = state_file {
+                server.set_port_store(PortStore::open(path)?);
+            }
+            if let Some(limit) = quota {
+                server.set_quotas(match quota_file {
+                    Some(path) => Quotas::open(path, limit)?,
+                    None => Quotas::new(limit),
+                });
+            }
+            if !schedule.is_empty() {
+                let mut schedules = Schedules::new();
+                for (identity, window) in schedule {
+                    schedules.add(identity, window);
+                }
+                server.set_schedules(schedules);
+            }
+            if !scoped_secret.is_empty() {
+                let mut secrets = ScopedSecrets::new();
+                for (scope, secret) in scoped_secret {
+                    secrets.add(scope, &secret);
+                }
+                server.set_scoped_secrets(secrets);
+            }
+            if let Some(open_ports) = open_ports {
+                server.set_open_ports(open_ports);
+            }
+            if let Some(port) = health_port {
+                server.set_health_port(port);
+            }
+            server.set_pending_expiry(Duration::from_secs(pending_expiry));
+            server.set_reconnect_grace(Duration::from_secs(reconnect_grace), reconnect_queue);
+            if !webhook.is_empty() {
+                server.set_webhooks(Webhooks::new(webhook));
+            }
+            if let (Some(domain), Some(target)) = (dns_domain, dns_target) {
+                let provider: Arc<dyn DnsProvider> = match (dns_cloudflare_zone, dns_command) {
+                    (Some(zone), _) => Arc::new(Cloudflare::new(
+                        zone,
+                        dns_cloudflare_token.unwrap_or_default(),
+                    )),
+                    (None, Some(command)) => Arc::new(DnsCommand::new(command)),
+                    (None, None) => unreachable!("clap requires a DNS provider"),
+                };
+                server.set_dns_updater(DnsUpdater::new(provider, &domain, target));
+            }
+            if let Some(path) = audit_log {
+                server.set_audit_log(AuditLog::open(path)?);
+            }
+            if let (Some(node), Some(secret)) = (cluster_node, &secret) {
+                server.set_cluster(Cluster::new(&node, cluster_peer, secret));
+            }
+            if let Some(path) = geoip_db {
+                let mut geoip = GeoFilter::open(path)?;
+                if let Some(policy) = geoip_policy {
+                    geoip.set_policy(policy);
+                }
+                for (port, policy) in geoip_port_policy {
+                    geoip.set_port_policy(port, policy);
+                }
+                server.set_geoip(geoip);
+            }
+            #[cfg(not(unix))]
+            if accept_shards.is_some_and(|shards| shards > 1) {
+                bail!("--accept-shards is not supported on this platform");
+            }
+            server.set_socket_options(SocketOptions {
+                nodelay: tcp_nodelay,
+                keepalive: tcp_keepalive.map(Duration::from_secs),
+                keepalive_interval: tcp_keepalive_interval.map(Duration::from_secs),
+                backlog: listen_backlog,
+                send_buffer_size,
+                recv_buffer_size,
+                accept_shards: accept_shards.map(usize::from),
+            });
+            if let Some(size) = proxy_buffer_size {
+                server.set_proxy_buffer_size(size);
+            }
+            if let Some(rate) = connection_rate {
+                server.set_connection_rate(rate);
+            }
+            for (identity, rate) in identity_connection_rate {
+                server.set_identity_connection_rate(identity, rate);
+            }
+            if let Some(bytes) = max_transfer {
+                server.set_transfer_limit(bytes);
+            }
+            if let Some(version) = min_client_version {
+                server.set_min_client_version(version);
+            }
+            server.set_accept_policy(AcceptPolicy {
+                per_port: rate_limit_port,
+                per_ip: rate_limit_ip,
+            });
+            if let Some(threshold) = ban_threshold {
+                server.set_ban_policy(BanPolicy {
+                    threshold,
+                    window: Duration::from_secs(ban_window),
+                    duration: Duration::from_secs(ban_duration),
+                });
+            }
+            if let Some(path) = ban_file {
+                server.set_ban_file(path)?;
+            }
+            let shutdown = CancellationToken::new();
+            server.set_shutdown(shutdown.clone());
+            if shutdown_reason.is_some() || shutdown_retry_after.is_some() {
+                server.set_shutdown_notice(
+                    shutdown_reason.unwrap_or_else(|| "server is shutting down".into()),
+                    shutdown_retry_after.map(Duration::from_secs),
+                );
+            }
+            server.set_systemd_notify(true);
+            tokio::spawn(async move {
+                shutdown_signal().await;
+                info!("shutting down");
+                shutdown.cancel();
+            });
+            #[cfg(unix)]
+            if let Some(listener) = systemd::listener()? {
+                info!("using control socket from systemd");
+                server.listen_on(listener).await?;
+                return Ok(());
+            }
+            server.listen().await?;
+        }
+        Command::Admin { action } => {
+            let (server, request, json) = match action {
+                AdminAction::Bans { server } => (server, AdminRequest::ListBans, false),
+                AdminAction::Ban {
+                    ip,
+                    duration,
+                    server,
+                } => {
+                    let duration_secs = duration.as_secs();
+                    (server, AdminRequest::Ban { ip, duration_secs }, false)
+                }
+                AdminAction::Unban { ip, server } => (server, AdminRequest::Unban(ip), false),
+                AdminAction::ClearBans { server } => (server, AdminRequest::ClearBans, false),
+                AdminAction::List { json, server } => (server, AdminRequest::ListTunnels, json),
+                AdminAction::Stats { json, server } => (server, AdminRequest::Stats, json),
+                AdminAction::Usage { json, server } => (server, AdminRequest::Usage, json),
+                AdminAction::Kill { port, name, server } => {
+                    let target = match (port, name) {
+                        (Some(port), _) => TunnelTarget::Port(port),
+                        (None, Some(name)) => TunnelTarget::Name(name),
+                        (None, None) => unreachable!("clap requires a port or name"),
+                    };
+                    (server, AdminRequest::Close(target), false)
+                }
+            };
+            let list = matches!(request, AdminRequest::ListTunnels);
+            let usage_list = matches!(request, AdminRequest::Usage);
+            let closed = match &request {
+                AdminRequest::Close(target) => Some(target.clone()),
+                _ => None,
+            };
+            let options = server.transport.client_options(&Profile::default())?;
+            let admin =
+                AdminClient::connect_with_options(&server.to, &server.secret, &options).await?;
+            let mut tunnels = Vec::new();
+            let mut usage = Vec::new();
+            for response in admin.request(request).await? {
+                match response {
+                    AdminResponse::Ban { ip, remaining_secs } => {
+                        println!("{ip:<40} {remaining_secs}s remaining");
+                    }
+                    AdminResponse::Tunnel(tunnel) => tunnels.push(tunnel),
+                    AdminResponse::Stats(stats) if json => {
+                        println!("{}", serde_json::to_string(&stats)?);
+                    }
+                    AdminResponse::Stats(stats) => print_admin_stats(&stats),
+                    AdminResponse::Usage(entry) => usage.push(entry),
+                    AdminResponse::Done => {}
+                }
+            }
+            if list && json {
+                println!("{}", serde_json::to_string(&tunnels)?);
+            } else if list {
+                print_admin_tunnels(&tunnels);
+            }
+            if usage_list && json {
+                println!("{}", serde_json::to_string(&usage)?);
+            } else if usage_list {
+                print_admin_usage(&usage);
+            }
+            if let Some(target) = closed {
+                println!("closed tunnel with {target}");
+            }
+        }
+        Command::Ping {
+            to,
+            secret,
+            transport,
+        } => {
+            let options = transport.client_options(&Profile::default())?;
+            let report = client::ping(&to, secret.as_deref(), &options).await?;
+            println!(
+                "connected to {to} in {:.1?}, ping time {:.1?}",
+                report.connect, report.rtt
+            );
+            let info = report.info;
+            println!(
+                "server version {}, ports {}, {} open tunnels",
+                info.version, info.ports, info.tunnels
+            );
+            if !info.features.is_empty() {
+                let auth = match info.auth {
+                    true => "required",
+                    false => "not required",
+                };
+                println!(
+                    "authentication {auth}, features {}",
+                    info.features.join(", ")
+                );
+            }
+        }
+        Command::Bench {
+            to,
+            secret,
+            connections,
+            size,
+            proxy_buffer_size,
+            json,
+            transport,
+        } => {
+            let mut options = transport.client_options(&Profile::default())?;
+            options.proxy_buffer_size = proxy_buffer_size;
+            let to = match to {
+                Some(to) => to,
+                None => {
+                    if options.tls.is_some() {
+                        bail!("--tls needs a remote server, given with --to");
+                    }
+                    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+                    options.control_port = Some(listener.local_addr()?.port());
+                    let mut server = Server::new(1024..=65535, secret.as_deref());
+                    server.set_tunnel_bind_addr(Ipv4Addr::LOCALHOST.into());
+                    if let Some(size) = proxy_buffer_size {
+                        server.set_proxy_buffer_size(size);
+                    }
+                    tokio::spawn(server.listen_on(listener));
+                    Ipv4Addr::LOCALHOST.to_string()
+                }
+            };
+            let bench = BenchOptions {
+                connections: connections as usize,
+                size,
+            };
+            let report = bench::run(&to, secret.as_deref(), options, &bench).await?;
+            if json {
+                println!("{}", serde_json::to_string(&report)?);
+            } else {
+                print_bench(&to, &report, connections);
+            }
+        }
+        Command::Healthcheck {
+            to,
+            secret,
+            timeout,
+            transport,
+        } => {
+            let options = transport.client_options(&Profile::default())?;
+            let ping = client::ping(&to, secret.as_deref(), &options);
+            let report = tokio::time::timeout(Duration::from_secs(timeout), ping)
+                .await
+                .with_context(|| format!("{to} did not respond within {timeout}s"))??;
+            println!("ok, {} open tunnels", report.info.tunnels);
+        }
+        Command::Relay {
+            upstream,
+            port,
+            listen_addr,
+            transport,
+        } => {
+            let options = transport.client_options(&Profile::default())?;
+            let mut relay = Relay::new(&upstream, &options);
+            relay.set_bind_addr(SocketAddr::new(listen_addr, port));
+            let shutdown = CancellationToken::new();
+            relay.set_shutdown(shutdown.clone());
+            tokio::spawn(async move {
+                shutdown_signal().await;
+                info!("shutting down");
+                shutdown.cancel();
+            });
+            relay.listen().await?;
+        }
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Args::command(), "bore", &mut io::stdout());
         }
+        #[cfg(windows)]
+        Command::Service { action } => match action {
+            ServiceAction::Install { name, args } => {
+                service::install(&name, args)?;
+                println!("installed service {name}, start it with `sc start {name}`");
+            }
+            ServiceAction::Uninstall { name } => {
+                service::uninstall(&name)?;
+                println!("removed service {name}");
+            }
+            ServiceAction::Run { .. } => unreachable!("services are run before the runtime starts"),
+        },
     }
 
     Ok(())
 }
 
+/// Wait for Ctrl-C, or a request to stop from a service manager.
+///
+/// Service managers send SIGTERM on Unix, while Windows services are stopped by the service
+/// control manager.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut sigterm) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = signal::ctrl_c() => {}
+                _ = sigterm.recv() => {}
+            }
+            return;
+        }
+    }
+    #[cfg(windows)]
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = service::stop_token().cancelled() => {}
+    }
+    #[cfg(not(windows))]
+    let _ = signal::ctrl_c().await;
+}
+
 fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    run(Args::parse().command)
+    // Logs go to stderr, keeping stdout for output meant for scripts, like `--json`.
+    let args = Args::parse();
+    let rust_log = env::var("RUST_LOG").ok();
+    let targets = args
+        .verbosity
+        .targets(rust_log.as_deref(), args.command.default_log_level());
+    #[cfg(windows)]
+    if let Command::Service {
+        action: ServiceAction::Run { name, args },
+    } = args.command
+    {
+        // Services have no console, so their logs go to the event log instead.
+        tracing_subscriber::fmt()
+            .with_writer(service::EventLog::new(&name)?)
+            .with_ansi(false)
+            .without_time()
+            .finish()
+            .with(targets)
+            .init();
+        return service::run(name, args);
+    }
+    if let Some(detach) = args.command.detach() {
+        detach.start()?;
+    }
+    let logging = args.command.logging();
+    let provider = match logging {
+        Some(logging) => logging.tracer_provider()?,
+        None => None,
+    };
+    let writer = match logging {
+        Some(logging) => logging.writer()?,
+        None => BoxMakeWriter::new(io::stderr),
+    };
+    // Failed writes are ignored rather than reported on stderr, which panics once the reader
+    // of a piped stderr has exited, such as `head`.
+    match logging.map(Logging::format).unwrap_or_default() {
+        LogFormat::Text => tracing_subscriber::fmt()
+            .with_writer(writer)
+            .with_ansi(!logging.is_some_and(Logging::is_file))
+            .log_internal_errors(false)
+            .finish()
+            .with(targets)
+            .with(logging::otel_layer(provider.as_ref()))
+            .init(),
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .flatten_event(true)
+            .with_writer(writer)
+            .log_internal_errors(false)
+            .finish()
+            .with(targets)
+            .with(logging::otel_layer(provider.as_ref()))
+            .init(),
+    }
+    let result = run(args.command);
+    // Export the spans still in the batch before exiting.
+    if let Some(provider) = provider {
+        if let Err(err) = provider.shutdown() {
+            warn!(%err, "failed to export traces");
+        }
+    }
+    if let Err(err) = result {
+        eprintln!("Error: {err:?}");
+        process::exit(exit_status(&err));
+    }
+    Ok(())
+}
+
+/// Returns the exit status of a failed command, so scripts can tell the causes apart.
+fn exit_status(err: &anyhow::Error) -> i32 {
+    let Some(err) = err.chain().find_map(|err| err.downcast_ref::<Error>()) else {
+        return 1;
+    };
+    match err {
+        Error::Handshake(_) => 3,
+        Error::Refused {
+            code: ErrorCode::QuotaExceeded,
+            ..
+        } => 5,
+        Error::Bind(_) | Error::Refused { .. } => 4,
+        Error::Connect { .. } | Error::Timeout(_) => 6,
+        _ => 1,
+    }
 }
@@ -0,0 +1,41 @@
+//! Hooks that screen each visitor connection before the server hands it to a client.
+//!
+//! Middleware sees visitors that passed the server's own checks, such as bans, rate limits, and
+//! country filters. It can drop a connection, hold it up to slow down an abusive visitor, or
+//! wrap the stream, such as to log or sniff the traffic.
+
+use std::net::SocketAddr;
+
+use futures_util::future::BoxFuture;
+
+use crate::auth::Identity;
+use crate::shared::BoxedStream;
+
+/// A visitor connecting to a tunnel.
+#[derive(Clone, Debug)]
+pub struct VisitorInfo {
+    /// Address of the visitor.
+    pub addr: SocketAddr,
+
+    /// Public port of the tunnel.
+    pub port: u16,
+
+    /// Name of the tunnel, if the client provided one.
+    pub name: Option<String>,
+
+    /// Identity of the tunnel's client, if it authenticated.
+    pub identity: Option<Identity>,
+}
+
+/// Screens visitor connections before they are queued for the tunnel's client.
+///
+/// Each connection is screened on its own task, so a slow hook delays only its own visitor.
+pub trait VisitorMiddleware: Send + Sync {
+    /// Returns the stream to forward, which may wrap the one given, or `None` to drop the
+    /// connection.
+    fn on_visitor<'a>(
+        &'a self,
+        visitor: &'a VisitorInfo,
+        stream: BoxedStream,
+    ) -> BoxFuture<'a, Option<BoxedStream>>;
+}
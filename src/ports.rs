@@ -0,0 +1,188 @@
+//! Parsing and membership checks for lists of TCP ports.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// A list of ports and port ranges, such as `8080,9090,30000-30100`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PortList(Vec<RangeInclusive<u16>>);
+
+impl PortList {
+    /// Returns whether the list contains a port.
+    pub fn contains(&self, port: u16) -> bool {
+        self.0.iter().any(|range| range.contains(&port))
+    }
+
+    /// Returns whether the list contains no ports.
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|range| range.is_empty())
+    }
+
+    /// Returns the number of ports in the list, counting overlaps repeatedly.
+    pub fn len(&self) -> u32 {
+        self.0
+            .iter()
+            .filter(|range| !range.is_empty())
+            .map(|range| (range.end() - range.start()) as u32 + 1)
+            .sum()
+    }
+
+    /// Choose a port uniformly at random from the list.
+    ///
+    /// ```
+    /// use bore_cli::ports::PortList;
+    ///
+    /// let ports: PortList = "2000-2999,40000-49999".parse().unwrap();
+    /// let port = ports.random().unwrap();
+    /// assert!(ports.contains(port));
+    /// ```
+    pub fn random(&self) -> Option<u16> {
+        let len = self.len();
+        if len == 0 {
+            return None;
+        }
+        let mut index = fastrand::u32(..len);
+        for range in self.0.iter().filter(|range| !range.is_empty()) {
+            let size = (range.end() - range.start()) as u32 + 1;
+            if index < size {
+                return Some(range.start() + index as u16);
+            }
+            index -= size;
+        }
+        unreachable!("index is always within the total length")
+    }
+
+    /// Returns the ports contained in both this list and another.
+    ///
+    /// ```
+    /// use bore_cli::ports::PortList;
+    ///
+    /// let server: PortList = "1024-32767,40000-49999".parse().unwrap();
+    /// let client: PortList = "30000-30100".parse().unwrap();
+    /// assert_eq!(server.intersect(&client).to_string(), "30000-30100");
+    /// ```
+    pub fn intersect(&self, other: &PortList) -> PortList {
+        let mut ranges = Vec::new();
+        for a in &self.0 {
+            for b in &other.0 {
+                let range = *a.start().max(b.start())..=*a.end().min(b.end());
+                if !range.is_empty() {
+                    ranges.push(range);
+                }
+            }
+        }
+        PortList(ranges)
+    }
+
+    /// Returns the ports in this list that are not in another.
+    ///
+    /// ```
+    /// use bore_cli::ports::PortList;
+    ///
+    /// let server: PortList = "1024-65535".parse().unwrap();
+    /// let reserved: PortList = "2222,8000-8999".parse().unwrap();
+    /// assert_eq!(server.without(&reserved).to_string(), "1024-2221,2223-7999,9000-65535");
+    /// ```
+    pub fn without(&self, other: &PortList) -> PortList {
+        let mut ranges: Vec<_> = self.0.iter().filter(|a| !a.is_empty()).cloned().collect();
+        for b in other.0.iter().filter(|b| !b.is_empty()) {
+            ranges = ranges
+                .into_iter()
+                .flat_map(|a| {
+                    let below = (a.start() < b.start())
+                        .then(|| *a.start()..=*a.end().min(&(b.start() - 1)));
+                    let above =
+                        (a.end() > b.end()).then(|| *a.start().max(&(b.end() + 1))..=*a.end());
+                    below.into_iter().chain(above)
+                })
+                .collect();
+        }
+        PortList(ranges)
+    }
+
+    /// Returns the ranges making up this list.
+    pub fn ranges(&self) -> &[RangeInclusive<u16>] {
+        &self.0
+    }
+}
+
+impl From<RangeInclusive<u16>> for PortList {
+    fn from(range: RangeInclusive<u16>) -> Self {
+        PortList(vec![range])
+    }
+}
+
+impl FromIterator<RangeInclusive<u16>> for PortList {
+    fn from_iter<I: IntoIterator<Item = RangeInclusive<u16>>>(iter: I) -> Self {
+        PortList(iter.into_iter().collect())
+    }
+}
+
+impl FromStr for PortList {
+    type Err = anyhow::Error;
+
+    /// Parse a comma-separated list of ports and inclusive ranges.
+    ///
+    /// ```
+    /// use bore_cli::ports::PortList;
+    ///
+    /// let ports: PortList = "8080, 9090,30000-30100".parse().unwrap();
+    /// assert!(ports.contains(8080));
+    /// assert!(ports.contains(30050));
+    /// assert!(!ports.contains(30101));
+    /// ```
+    fn from_str(s: &str) -> Result<Self> {
+        let mut ranges = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let range = match part.split_once('-') {
+                Some((start, end)) => {
+                    let start = start.trim().parse().context("invalid port in range")?;
+                    let end = end.trim().parse().context("invalid port in range")?;
+                    start..=end
+                }
+                None => {
+                    let port = part.parse().context("invalid port")?;
+                    port..=port
+                }
+            };
+            if range.is_empty() {
+                bail!("port range {part} is empty");
+            }
+            ranges.push(range);
+        }
+        Ok(PortList(ranges))
+    }
+}
+
+impl fmt::Display for PortList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, range) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            if range.start() == range.end() {
+                write!(f, "{}", range.start())?;
+            } else {
+                write!(f, "{}-{}", range.start(), range.end())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for PortList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
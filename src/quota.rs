@@ -0,0 +1,183 @@
+//! Monthly transfer quotas for the identities of authenticated clients.
+//!
+//! Bytes forwarded to and from visitors count towards the identity of the client whose tunnel
+//! they pass through, and the counts start over at the beginning of each calendar month in UTC.
+//! Once an identity has used up its quota, the server refuses its new tunnels and new visitor
+//! connections until the month ends. Connections that are already open are left to finish.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// Limit on the bytes each identity may transfer per calendar month.
+pub struct Quotas {
+    limit: u64,
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+/// Usage counters of the current month.
+struct State {
+    period: String,
+    usage: HashMap<String, Arc<AtomicU64>>,
+}
+
+/// Usage as written to disk.
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    period: String,
+    usage: BTreeMap<String, u64>,
+}
+
+impl Quotas {
+    /// Limit each identity to a number of bytes per month, keeping usage only in memory.
+    pub fn new(limit: u64) -> Self {
+        Quotas {
+            limit,
+            path: None,
+            state: Mutex::new(State {
+                period: current_period(),
+                usage: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Limit each identity to a number of bytes per month, persisting usage in a JSON file.
+    ///
+    /// Usage saved by a previous run is loaded, unless it was counted in an earlier month.
+    pub fn open(path: impl AsRef<Path>, limit: u64) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut quotas = Quotas::new(limit);
+        match fs::read(&path) {
+            Ok(data) => {
+                let saved: Saved = serde_json::from_slice(&data)
+                    .with_context(|| format!("invalid quota file {}", path.display()))?;
+                let state = quotas.state.get_mut().unwrap();
+                if saved.period == state.period {
+                    state.usage = saved
+                        .usage
+                        .into_iter()
+                        .map(|(identity, used)| (identity, Arc::new(AtomicU64::new(used))))
+                        .collect();
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("could not read {}", path.display()))
+            }
+        }
+        quotas.path = Some(path);
+        Ok(quotas)
+    }
+
+    /// Returns the number of bytes each identity may transfer per month.
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Returns the month that usage is counted for, such as `2026-10`.
+    pub fn period(&self) -> String {
+        self.state.lock().unwrap().period.clone()
+    }
+
+    /// Returns the number of bytes an identity has transferred this month.
+    pub fn used(&self, identity: &str) -> u64 {
+        let state = self.state.lock().unwrap();
+        match state.usage.get(identity) {
+            Some(used) => used.load(Ordering::Relaxed),
+            None => 0,
+        }
+    }
+
+    /// Returns whether an identity has used up its quota for this month.
+    pub fn exceeded(&self, identity: &str) -> bool {
+        self.used(identity) >= self.limit
+    }
+
+    /// Returns the usage of every identity that transferred data this month, by identity.
+    pub fn usage(&self) -> Vec<(String, u64)> {
+        let state = self.state.lock().unwrap();
+        let mut usage: Vec<_> = state
+            .usage
+            .iter()
+            .map(|(identity, used)| (identity.clone(), used.load(Ordering::Relaxed)))
+            .collect();
+        usage.sort();
+        usage
+    }
+
+    /// Returns the counter of an identity, which the tunnels of its clients add to.
+    pub(crate) fn counter(&self, identity: &str) -> Arc<AtomicU64> {
+        let mut state = self.state.lock().unwrap();
+        Arc::clone(state.usage.entry(identity.to_string()).or_default())
+    }
+
+    /// Start counting anew if a new month has begun, and write usage to the quota file.
+    pub fn save(&self) -> Result<()> {
+        let saved = {
+            let mut state = self.state.lock().unwrap();
+            let period = current_period();
+            if state.period != period {
+                info!(%period, "transfer quotas reset for the new month");
+                // Open tunnels hold on to their counters, so those are reset in place.
+                for used in state.usage.values() {
+                    used.store(0, Ordering::Relaxed);
+                }
+                state.period = period;
+            }
+            Saved {
+                period: state.period.clone(),
+                usage: state
+                    .usage
+                    .iter()
+                    .map(|(identity, used)| (identity.clone(), used.load(Ordering::Relaxed)))
+                    .collect(),
+            }
+        };
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        // Write to a temporary file first, so that a crash never leaves a truncated file.
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&saved)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Returns the current month in UTC, formatted like `2026-10`.
+fn current_period() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month) = year_month_from_days((secs / 86400) as i64);
+    format!("{year:04}-{month:02}")
+}
+
+/// Find the year and month of a number of days since 1970-01-01, in the proleptic Gregorian
+/// calendar.
+fn year_month_from_days(days: i64) -> (i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month)
+}
@@ -0,0 +1,102 @@
+//! Relays that chain tunnels through intermediate hosts.
+//!
+//! Every connection of the protocol, control and data alike, is opened by the client to the
+//! control port of the server. A relay accepts these connections and forwards each one unchanged
+//! to an upstream server, which may itself be another relay. Clients still authenticate with the
+//! upstream server, so a relay needs no secret, and the visitors of its tunnels connect to the
+//! upstream server directly.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::client::ClientOptions;
+use crate::error::{Error, Result};
+use crate::shared::{proxy, CONTROL_PORT, DEFAULT_BUFFER_SIZE};
+use crate::transport::Connector;
+
+/// Forwards the connections of clients to an upstream server.
+pub struct Relay {
+    /// Address of the upstream server.
+    upstream: String,
+
+    /// Control port of the upstream server.
+    upstream_port: u16,
+
+    /// Transport for connections to the upstream server.
+    connector: Arc<dyn Connector>,
+
+    /// Address that clients connect to.
+    bind_addr: SocketAddr,
+
+    /// Token that stops the relay when cancelled.
+    shutdown: CancellationToken,
+}
+
+impl Relay {
+    /// Create a relay to an upstream server, which it reaches with the transport, TLS, and
+    /// control port settings of `options`.
+    pub fn new(upstream: &str, options: &ClientOptions) -> Self {
+        Relay {
+            upstream: upstream.into(),
+            upstream_port: options.control_port(),
+            connector: options.connector(),
+            bind_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), CONTROL_PORT),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// Accept clients on this address, which defaults to [`CONTROL_PORT`] on all interfaces.
+    pub fn set_bind_addr(&mut self, addr: SocketAddr) {
+        self.bind_addr = addr;
+    }
+
+    /// Stop accepting clients when the token is cancelled.
+    ///
+    /// Connections that are being forwarded are left to finish.
+    pub fn set_shutdown(&mut self, shutdown: CancellationToken) {
+        self.shutdown = shutdown;
+    }
+
+    /// Start the relay, forwarding each client connection to the upstream server.
+    pub async fn listen(self) -> Result<()> {
+        let listener = TcpListener::bind(self.bind_addr)
+            .await
+            .map_err(|err| Error::Bind(format!("could not listen on {}: {err}", self.bind_addr)))?;
+        info!(
+            addr = ?self.bind_addr,
+            upstream = %self.upstream,
+            "relay listening"
+        );
+        let this = Arc::new(self);
+        loop {
+            let (stream, addr) = tokio::select! {
+                result = listener.accept() => result?,
+                _ = this.shutdown.cancelled() => return Ok(()),
+            };
+            let this = Arc::clone(&this);
+            tokio::spawn(
+                async move {
+                    if let Err(err) = this.forward(stream).await {
+                        warn!(%err, "relayed connection exited with error");
+                    }
+                }
+                .instrument(info_span!("relay", client_addr = %addr)),
+            );
+        }
+    }
+
+    /// Copy data between a client and a new connection to the upstream server.
+    async fn forward(&self, stream: TcpStream) -> Result<()> {
+        let upstream = self
+            .connector
+            .connect(&self.upstream, self.upstream_port)
+            .await?;
+        let (sent, received) = proxy(stream, upstream, DEFAULT_BUFFER_SIZE).await?;
+        debug!(sent, received, "relayed connection closed");
+        Ok(())
+    }
+}
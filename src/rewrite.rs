@@ -0,0 +1,391 @@
+//! Rewriting of the HTTP requests of visitors on their way to the local service.
+//!
+//! Each request head on a connection is parsed and edited, such as to describe the visitor in
+//! forwarding headers, while request bodies pass through unchanged. Streams that do not look
+//! like HTTP/1.x, and connections that switch to another protocol, are passed through as is.
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+
+use anyhow::bail;
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+
+/// Largest request head that is parsed, beyond which rewriting of a stream stops.
+const MAX_HEAD_LENGTH: usize = 64 * 1024;
+
+/// Maximum number of headers parsed in a request.
+const MAX_HEADERS: usize = 100;
+
+/// Size of the chunks read from the visitor.
+const READ_SIZE: usize = 8 * 1024;
+
+/// What the `Host` header of requests is set to before they reach the local service.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum HostHeader {
+    /// Keep the host that the visitor asked for, which is the server's.
+    #[default]
+    Preserve,
+
+    /// Replace it with the local host and port, which development servers often expect.
+    Rewrite,
+
+    /// Replace it with a fixed value.
+    Custom(String),
+}
+
+impl HostHeader {
+    /// Returns the value that replaces the header, for a local service at a host and port.
+    ///
+    /// ```
+    /// use bore_cli::rewrite::HostHeader;
+    ///
+    /// assert_eq!(HostHeader::Preserve.value("localhost", 8000), None);
+    /// assert_eq!(HostHeader::Rewrite.value("localhost", 8000).unwrap(), "localhost:8000");
+    /// assert_eq!(HostHeader::Rewrite.value("::1", 3000).unwrap(), "[::1]:3000");
+    /// let custom = HostHeader::Custom("app.test".into());
+    /// assert_eq!(custom.value("localhost", 8000).unwrap(), "app.test");
+    /// ```
+    pub fn value(&self, local_host: &str, local_port: u16) -> Option<String> {
+        match self {
+            HostHeader::Preserve => None,
+            HostHeader::Rewrite if local_host.contains(':') => {
+                Some(format!("[{local_host}]:{local_port}"))
+            }
+            HostHeader::Rewrite => Some(format!("{local_host}:{local_port}")),
+            HostHeader::Custom(value) => Some(value.clone()),
+        }
+    }
+}
+
+impl FromStr for HostHeader {
+    type Err = anyhow::Error;
+
+    /// Parse `preserve`, `rewrite`, or `custom:<value>`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "preserve" => Ok(HostHeader::Preserve),
+            "rewrite" => Ok(HostHeader::Rewrite),
+            _ => match s.strip_prefix("custom:") {
+                Some(value) if !value.is_empty() && !value.contains(['\r', '\n']) => {
+                    Ok(HostHeader::Custom(value.into()))
+                }
+                _ => bail!("expected `preserve`, `rewrite`, or `custom:<value>`"),
+            },
+        }
+    }
+}
+
+/// Changes made to the head of each request.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Edits {
+    /// Visitor described in forwarding headers, if they are added.
+    pub forwarded: Option<Forwarded>,
+
+    /// Value replacing the `Host` header, if it is changed.
+    pub host: Option<String>,
+}
+
+impl Edits {
+    /// Returns whether requests are left unchanged.
+    pub fn is_empty(&self) -> bool {
+        self.forwarded.is_none() && self.host.is_none()
+    }
+
+    fn apply(&self, headers: &mut Vec<(String, Vec<u8>)>) {
+        if let Some(forwarded) = &self.forwarded {
+            forwarded.apply(headers);
+        }
+        if let Some(host) = &self.host {
+            let original = match headers
+                .iter_mut()
+                .find(|(n, _)| n.eq_ignore_ascii_case("host"))
+            {
+                Some((_, value)) => Some(std::mem::replace(value, host.clone().into_bytes())),
+                None => {
+                    headers.push(("Host".into(), host.clone().into_bytes()));
+                    None
+                }
+            };
+            // The original host stays available to the service, as after any reverse proxy.
+            let forwarded_host = headers
+                .iter()
+                .any(|(n, _)| n.eq_ignore_ascii_case("x-forwarded-host"));
+            if let (Some(original), false) = (original, forwarded_host) {
+                headers.push(("X-Forwarded-Host".into(), original));
+            }
+        }
+    }
+}
+
+/// The visitor and scheme of a connection, for the `Forwarded` and `X-Forwarded-*` headers.
+#[derive(Clone, Debug)]
+pub(crate) struct Forwarded {
+    /// Address of the visitor, if the server sent it.
+    pub addr: Option<SocketAddr>,
+
+    /// Scheme that the visitor used, `http` or `https`.
+    pub proto: &'static str,
+}
+
+impl Forwarded {
+    fn apply(&self, headers: &mut Vec<(String, Vec<u8>)>) {
+        let mut element = format!("proto={}", self.proto);
+        if let Some(addr) = self.addr {
+            let ip = addr.ip().to_canonical();
+            append(headers, "X-Forwarded-For", &ip.to_string());
+            // IPv6 addresses contain colons, so they are quoted in brackets.
+            element = match ip.is_ipv6() {
+                true => format!("for=\"[{ip}]\";{element}"),
+                false => format!("for={ip};{element}"),
+            };
+        }
+        headers.retain(|(name, _)| !name.eq_ignore_ascii_case("x-forwarded-proto"));
+        headers.push(("X-Forwarded-Proto".into(), self.proto.into()));
+        append(headers, "Forwarded", &element);
+    }
+}
+
+/// Add a value to the end of a list header, after any values that proxies before us added.
+fn append(headers: &mut Vec<(String, Vec<u8>)>, name: &str, value: &str) {
+    match headers
+        .iter_mut()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+    {
+        Some((_, existing)) => {
+            existing.extend_from_slice(b", ");
+            existing.extend_from_slice(value.as_bytes());
+        }
+        None => headers.push((name.into(), value.into())),
+    }
+}
+
+/// Position of the rewriter within a stream of requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// Waiting for a complete request head.
+    Head,
+    /// Passing a body with a known number of bytes remaining.
+    Body(u64),
+    /// Waiting for the size line of a chunk.
+    ChunkSize,
+    /// Passing chunk data with a number of bytes remaining.
+    ChunkData(u64),
+    /// Waiting for the line break after chunk data.
+    ChunkEnd,
+    /// Passing trailer lines after the last chunk.
+    Trailer,
+    /// The stream is not HTTP, or has switched protocols, and is passed through.
+    Stopped,
+}
+
+/// A visitor stream whose requests are edited as they are read.
+pub(crate) struct RewriteRequests<S> {
+    inner: S,
+    edits: Edits,
+    state: State,
+    /// Data read from the visitor that is not yet processed.
+    input: Vec<u8>,
+    /// Processed data waiting to be read, starting at `pos`.
+    output: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl<S> RewriteRequests<S> {
+    pub fn new(inner: S, edits: Edits) -> Self {
+        RewriteRequests {
+            inner,
+            edits,
+            state: State::Head,
+            input: Vec::new(),
+            output: Vec::new(),
+            pos: 0,
+            eof: false,
+        }
+    }
+
+    /// Move processed input to the output, as far as the data read so far allows.
+    fn process(&mut self) {
+        loop {
+            match self.state {
+                State::Head => match self.rewrite_head() {
+                    Some(state) => self.state = state,
+                    None => break,
+                },
+                State::Body(remaining) | State::ChunkData(remaining) => {
+                    if self.input.is_empty() {
+                        break;
+                    }
+                    let len = remaining.min(self.input.len() as u64);
+                    self.pass(len as usize);
+                    self.state = match (self.state, remaining - len) {
+                        (State::Body(_), 0) => State::Head,
+                        (State::Body(_), remaining) => State::Body(remaining),
+                        (_, 0) => State::ChunkEnd,
+                        (_, remaining) => State::ChunkData(remaining),
+                    };
+                }
+                State::ChunkSize => {
+                    let Some(line) = self.pass_line() else { break };
+                    let size = line.split(';').next().unwrap_or_default().trim();
+                    self.state = match u64::from_str_radix(size, 16) {
+                        Ok(0) => State::Trailer,
+                        Ok(size) => State::ChunkData(size),
+                        Err(_) => State::Stopped,
+                    };
+                }
+                State::ChunkEnd => {
+                    if self.input.len() < 2 {
+                        break;
+                    }
+                    self.pass(2);
+                    self.state = State::ChunkSize;
+                }
+                State::Trailer => {
+                    let Some(line) = self.pass_line() else { break };
+                    if line.is_empty() {
+                        self.state = State::Head;
+                    }
+                }
+                State::Stopped => {
+                    self.pass(self.input.len());
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Rewrite a complete request head, returning the state for its body, or `None` if more
+    /// data is needed.
+    fn rewrite_head(&mut self) -> Option<State> {
+        if self.input.is_empty() {
+            return None;
+        }
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        let len = match request.parse(&self.input) {
+            Ok(httparse::Status::Complete(len)) => len,
+            Ok(httparse::Status::Partial) if self.input.len() <= MAX_HEAD_LENGTH => return None,
+            _ => return Some(State::Stopped),
+        };
+        let method = request.method.unwrap_or_default();
+        let mut state = State::Body(0);
+        let mut upgrade = method.eq_ignore_ascii_case("CONNECT");
+        for header in request.headers.iter() {
+            let value = String::from_utf8_lossy(header.value);
+            if header.name.eq_ignore_ascii_case("transfer-encoding")
+                && value.to_ascii_lowercase().contains("chunked")
+            {
+                state = State::ChunkSize;
+            } else if header.name.eq_ignore_ascii_case("content-length")
+                && state != State::ChunkSize
+            {
+                match value.trim().parse() {
+                    Ok(len) => state = State::Body(len),
+                    Err(_) => return Some(State::Stopped),
+                }
+            } else if header.name.eq_ignore_ascii_case("upgrade") {
+                upgrade = true;
+            }
+        }
+        let mut head = format!(
+            "{method} {} HTTP/1.{}\r\n",
+            request.path.unwrap_or_default(),
+            request.version.unwrap_or(1)
+        )
+        .into_bytes();
+        let mut headers: Vec<_> = (request.headers.iter())
+            .map(|h| (h.name.to_string(), h.value.to_vec()))
+            .collect();
+        self.edits.apply(&mut headers);
+        for (name, value) in headers {
+            head.extend_from_slice(name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(&value);
+            head.extend_from_slice(b"\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+        self.output.extend_from_slice(&head);
+        self.input.drain(..len);
+        // The protocol may change after this request, so the rest of the stream is left alone.
+        Some(match upgrade {
+            true => State::Stopped,
+            false if state == State::Body(0) => State::Head,
+            false => state,
+        })
+    }
+
+    /// Move bytes from the input to the output unchanged.
+    fn pass(&mut self, len: usize) {
+        self.output.extend(self.input.drain(..len));
+    }
+
+    /// Move a line from the input to the output, returning it without its line break.
+    fn pass_line(&mut self) -> Option<String> {
+        let Some(end) = self.input.windows(2).position(|w| w == b"\r\n") else {
+            if self.input.len() > MAX_HEAD_LENGTH {
+                self.state = State::Stopped;
+            }
+            return None;
+        };
+        let line = String::from_utf8_lossy(&self.input[..end]).into_owned();
+        self.pass(end + 2);
+        Some(line)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RewriteRequests<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.pos < this.output.len() {
+                let len = (this.output.len() - this.pos).min(buf.remaining());
+                buf.put_slice(&this.output[this.pos..this.pos + len]);
+                this.pos += len;
+                if this.pos == this.output.len() {
+                    this.output.clear();
+                    this.pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0u8; READ_SIZE];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                // An incomplete request at the end of the stream is passed on as it is.
+                this.eof = true;
+                this.pass(this.input.len());
+                continue;
+            }
+            this.input.extend_from_slice(read.filled());
+            this.process();
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RewriteRequests<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,212 @@
+//! Weekly time windows in which the identities of authenticated clients may hold tunnels.
+//!
+//! Identities with a schedule can only open tunnels within one of their windows, and the server
+//! closes their open tunnels once the window ends. Identities without a schedule, and clients that
+//! did not authenticate, are not restricted.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+
+/// Names of the days of the week, starting on Monday.
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Full names of the days of the week, which are accepted as well as their abbreviations.
+const DAY_NAMES: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+
+/// Window of time that recurs on some days of each week.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Window {
+    /// Days on which the window starts, with Monday as the lowest bit.
+    days: u8,
+
+    /// Minutes after midnight at which the window starts.
+    start: u16,
+
+    /// Minutes after midnight at which the window ends, on the next day if before `start`.
+    end: u16,
+
+    /// Offset of the window's time zone from UTC, in minutes.
+    offset: i32,
+}
+
+impl Window {
+    /// Returns whether a time falls within the window.
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs() as i64),
+        };
+        let local = secs + i64::from(self.offset) * 60;
+        // The first of January 1970 was a Thursday.
+        let day = (local.div_euclid(86400) + 3).rem_euclid(7) as u32;
+        let minute = (local.rem_euclid(86400) / 60) as u16;
+        let starts_on = |day: u32| self.days & (1 << day) != 0;
+        if self.start < self.end {
+            starts_on(day) && (self.start..self.end).contains(&minute)
+        } else {
+            // The window runs past midnight, into the day after it starts.
+            (starts_on(day) && minute >= self.start)
+                || (starts_on((day + 6) % 7) && minute < self.end)
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = anyhow::Error;
+
+    /// Parse a window like `mon-fri 09:00-17:00`, with an optional time zone like `UTC+2`.
+    ///
+    /// Days are given as a list of names or ranges, and every day is included if they are left
+    /// out. Windows that end before they start run past midnight, and times are in UTC unless a
+    /// time zone is given.
+    ///
+    /// ```
+    /// use std::time::{Duration, UNIX_EPOCH};
+    /// use bore_cli::schedule::Window;
+    ///
+    /// let window: Window = "mon-fri 09:00-17:00 UTC+1".parse().unwrap();
+    /// // Monday, 5 January 1970, at 8:30 and 16:30 in UTC.
+    /// let monday = UNIX_EPOCH + Duration::from_secs(4 * 86400);
+    /// assert!(window.contains(monday + Duration::from_secs(8 * 3600 + 1800)));
+    /// assert!(!window.contains(monday + Duration::from_secs(16 * 3600 + 1800)));
+    /// ```
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts: Vec<_> = s.split_whitespace().collect();
+        let offset = match parts.last() {
+            Some(zone) if zone.to_ascii_uppercase().starts_with("UTC") => {
+                let offset = parse_offset(&zone[3..])?;
+                parts.pop();
+                offset
+            }
+            _ => 0,
+        };
+        let (days, times) = match parts[..] {
+            [times] => (0x7f, times),
+            [days, times] => (parse_days(days)?, times),
+            _ => bail!("expected a window like `mon-fri 09:00-17:00`"),
+        };
+        let (start, end) = times
+            .split_once('-')
+            .context("expected times like `09:00-17:00`")?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == 24 * 60 {
+            bail!("window must start before 24:00");
+        }
+        if start == end {
+            bail!("window must not start and end at the same time");
+        }
+        let end = end % (24 * 60);
+        Ok(Window {
+            days,
+            start,
+            end,
+            offset,
+        })
+    }
+}
+
+/// Parse a list of days like `mon-fri` or `sat,sun` into a set of bits.
+fn parse_days(s: &str) -> Result<u8> {
+    let day = |name: &str| {
+        let name = name.to_ascii_lowercase();
+        DAYS.iter()
+            .zip(DAY_NAMES)
+            .position(|(short, long)| name == *short || name == long)
+            .with_context(|| format!("unknown day `{name}`"))
+    };
+    let mut days = 0;
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (day(first)?, day(last)?);
+                // Ranges may wrap around the end of the week, like `fri-mon`.
+                let len = (last + 7 - first) % 7;
+                for i in 0..=len {
+                    days |= 1 << ((first + i) % 7);
+                }
+            }
+            None => days |= 1 << day(part)?,
+        }
+    }
+    Ok(days)
+}
+
+/// Parse a time of day like `09:00` into minutes after midnight, allowing `24:00`.
+fn parse_time(s: &str) -> Result<u16> {
+    let (hours, minutes) = s
+        .split_once(':')
+        .with_context(|| format!("expected a time like `09:00`, found `{s}`"))?;
+    let hours: u16 = hours
+        .parse()
+        .with_context(|| format!("invalid time `{s}`"))?;
+    let minutes: u16 = minutes
+        .parse()
+        .with_context(|| format!("invalid time `{s}`"))?;
+    if minutes >= 60 || hours > 24 || (hours == 24 && minutes > 0) {
+        bail!("invalid time `{s}`");
+    }
+    Ok(hours * 60 + minutes)
+}
+
+/// Parse an offset from UTC like `+2` or `-05:30` into minutes.
+fn parse_offset(s: &str) -> Result<i32> {
+    if s.is_empty() {
+        return Ok(0);
+    }
+    let (sign, rest) = match s.split_at(1) {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => bail!("invalid time zone `UTC{s}`"),
+    };
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+    let hours: i32 = hours
+        .parse()
+        .with_context(|| format!("invalid time zone `UTC{s}`"))?;
+    let minutes: i32 = minutes
+        .parse()
+        .with_context(|| format!("invalid time zone `UTC{s}`"))?;
+    if hours > 14 || minutes >= 60 {
+        bail!("invalid time zone `UTC{s}`");
+    }
+    Ok(sign * (hours * 60 + minutes))
+}
+
+/// Windows in which each identity may hold tunnels.
+#[derive(Clone, Debug, Default)]
+pub struct Schedules {
+    windows: HashMap<String, Vec<Window>>,
+}
+
+impl Schedules {
+    /// Create schedules that do not restrict any identity.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow an identity to hold tunnels within a window, in addition to its other windows.
+    pub fn add(&mut self, identity: impl Into<String>, window: Window) {
+        self.windows
+            .entry(identity.into())
+            .or_default()
+            .push(window);
+    }
+
+    /// Returns whether an identity may hold tunnels at a time.
+    pub fn allows(&self, identity: &str, time: SystemTime) -> bool {
+        match self.windows.get(identity) {
+            Some(windows) => windows.iter().any(|window| window.contains(time)),
+            None => true,
+        }
+    }
+}
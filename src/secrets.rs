@@ -0,0 +1,184 @@
+//! Secrets that tunnels on some ports or with some names require, beyond the server's secret.
+//!
+//! Each rule pairs a scope, which is a list of ports or a pattern of tunnel names, with a
+//! secret. Tunnels within the scope of a rule can only be opened by clients that authenticate
+//! with its secret, for which the server's own secret does not suffice. Clients that know only
+//! scoped secrets can open tunnels within their scopes and nowhere else.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use uuid::Uuid;
+
+use crate::auth::{Authenticator, Identity};
+use crate::ports::PortList;
+
+/// Tunnels that a scoped secret applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SecretScope {
+    /// Tunnels on any of these public ports.
+    Ports(PortList),
+
+    /// Tunnels with a name matching this pattern, in which `*` matches any text.
+    Names(String),
+}
+
+impl SecretScope {
+    /// Returns whether a tunnel on a port, with an optional name, is within the scope.
+    pub fn covers(&self, port: u16, name: Option<&str>) -> bool {
+        match self {
+            SecretScope::Ports(ports) => ports.contains(port),
+            SecretScope::Names(pattern) => name.is_some_and(|name| glob(pattern, name)),
+        }
+    }
+}
+
+impl FromStr for SecretScope {
+    type Err = anyhow::Error;
+
+    /// Parse a list of ports like `8000-8999`, or a pattern of names like `name:web-*`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix("name:") {
+            Some("") => bail!("expected a pattern of tunnel names after `name:`"),
+            Some(pattern) => Ok(SecretScope::Names(pattern.into())),
+            None => {
+                let ports: PortList = s.parse()?;
+                if ports.is_empty() {
+                    bail!("expected a list of ports or `name:<pattern>`");
+                }
+                Ok(SecretScope::Ports(ports))
+            }
+        }
+    }
+}
+
+impl fmt::Display for SecretScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretScope::Ports(ports) => write!(f, "{ports}"),
+            SecretScope::Names(pattern) => write!(f, "name:{pattern}"),
+        }
+    }
+}
+
+/// Returns whether a name matches a pattern, in which `*` matches any text.
+fn glob(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<_> = parts.collect();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Secrets required for tunnels within their scopes.
+#[derive(Default)]
+pub struct ScopedSecrets {
+    rules: Vec<(SecretScope, Authenticator)>,
+}
+
+/// What a client showed that it knows during its handshake.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Credentials {
+    /// Whether the client knows the server's secret, which all clients do if it has none.
+    pub main: bool,
+
+    /// Indices of the rules whose secrets the client knows.
+    pub keys: Vec<usize>,
+}
+
+impl ScopedSecrets {
+    /// Create an empty set of rules, which leaves every tunnel to the server's secret.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require a secret for tunnels within a scope, in addition to the other rules.
+    pub fn add(&mut self, scope: SecretScope, secret: &str) {
+        self.rules.push((scope, Authenticator::new(secret)));
+    }
+
+    /// Returns whether there are no rules.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns the indices of the rules whose secret a client used to answer a challenge.
+    pub(crate) fn verify(&self, challenge: &Uuid, answer: &str) -> Vec<usize> {
+        (self.rules.iter().enumerate())
+            .filter(|(_, (_, auth))| auth.validate(challenge, answer))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Identity of a client that knows only scoped secrets, named after the first scope.
+    pub(crate) fn identity(&self, keys: &[usize]) -> Option<Identity> {
+        let (scope, _) = self.rules.get(*keys.first()?)?;
+        Some(Identity(scope.to_string()))
+    }
+
+    /// Check that a client may open a tunnel on a port, with an optional name, where ports that
+    /// are open need no secret unless a rule covers them.
+    pub(crate) fn check(
+        &self,
+        credentials: &Credentials,
+        open: &PortList,
+        port: u16,
+        name: Option<&str>,
+    ) -> bool {
+        let mut covered = false;
+        for (index, (scope, _)) in self.rules.iter().enumerate() {
+            if scope.covers(port, name) {
+                if !credentials.keys.contains(&index) {
+                    return false;
+                }
+                covered = true;
+            }
+        }
+        covered || credentials.main || open.contains(port)
+    }
+
+    /// Check that a client may open a tunnel with a name on some port, returning the ports
+    /// that it may be assigned among those given.
+    pub(crate) fn ports(
+        &self,
+        credentials: &Credentials,
+        open: &PortList,
+        name: Option<&str>,
+        ports: &PortList,
+    ) -> Option<PortList> {
+        let mut named = false;
+        let mut denied = Vec::new();
+        let mut granted = open.ranges().to_vec();
+        for (index, (scope, _)) in self.rules.iter().enumerate() {
+            let held = credentials.keys.contains(&index);
+            match scope {
+                SecretScope::Names(_) if scope.covers(0, name) => {
+                    if !held {
+                        return None;
+                    }
+                    named = true;
+                }
+                SecretScope::Names(_) => {}
+                SecretScope::Ports(list) if held => granted.extend(list.ranges().iter().cloned()),
+                SecretScope::Ports(list) => denied.extend(list.ranges().iter().cloned()),
+            }
+        }
+        let ports = ports.without(&denied.into_iter().collect());
+        match credentials.main || named {
+            true => Some(ports),
+            false => Some(ports.intersect(&granted.into_iter().collect())),
+        }
+    }
+}
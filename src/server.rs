@@ -1,168 +1,2717 @@
 //! Server implementation for the `bore` service.
 
-use std::{io, net::SocketAddr, ops::RangeInclusive, sync::Arc, time::Duration};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::{pending, Future};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::Deref;
+use std::path::Path;
+use std::pin::pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use std::{io, iter, mem, sync::Arc};
 
-use anyhow::Result;
-use dashmap::DashMap;
+use anyhow::{anyhow, bail};
+use dashmap::{DashMap, DashSet};
+use futures_util::future::{try_join_all, FutureExt};
+use rustls::ServerConfig;
 use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::time::{sleep, timeout};
-use tracing::{info, info_span, warn, Instrument};
+use tokio::sync::{broadcast, mpsc, oneshot, Notify};
+use tokio::task::JoinSet;
+use tokio::time::{interval, sleep, sleep_until, timeout, MissedTickBehavior};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
 use uuid::Uuid;
 
-use crate::auth::Authenticator;
-use crate::shared::{proxy, ClientMessage, Delimited, ServerMessage, CONTROL_PORT};
+use crate::acme::Acme;
+use crate::allocator::{bind_error, PortAllocator, PortRequest, RandomAllocator};
+use crate::audit::{AuditEvent, AuditLog};
+use crate::auth::{Authenticate, Authenticator, Identity};
+use crate::ban::{BanList, BanPolicy};
+use crate::cluster::{ClaimGuard, Cluster};
+use crate::dns::DnsUpdater;
+use crate::error::{Error, ErrorCode, Result};
+use crate::event::{self, ServerEvent, TunnelObserver};
+use crate::geoip::GeoFilter;
+use crate::health::{self, HealthReport, ListedTunnel};
+use crate::limit::{AcceptLimiter, AcceptPolicy, TokenBucket};
+use crate::middleware::{VisitorInfo, VisitorMiddleware};
+use crate::ports::PortList;
+use crate::quota::Quotas;
+use crate::schedule::Schedules;
+use crate::secrets::{Credentials, ScopedSecrets};
+use crate::shared::{
+    proxy, AdminRequest, AdminResponse, AdminStats, AdminTunnel, AdminUsage, BoxedStream,
+    ClientMessage, ClusterMessage, Delimited, Prefixed, ServerInfo, ServerMessage, SocketOptions,
+    TunnelRequest, TunnelTarget, AUTH_FAILED, CONTROL_PORT, DEFAULT_BUFFER_SIZE,
+    MAX_METADATA_LENGTH, MAX_NAME_LENGTH, MAX_POOL_SIZE, NETWORK_TIMEOUT, VERSION_REQUIRED,
+};
+use crate::stats::Stats;
+use crate::store::PortStore;
+use crate::subdomain::subdomain;
+use crate::throttle::{Bandwidth, ByteRate, Throttled};
+use crate::transport::Acceptor;
+use crate::version::Version;
+use crate::webhook::{TunnelEvent, Webhooks};
+
+/// Interval between heartbeats on each control connection.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Number of unanswered heartbeat probes remembered, beyond which the oldest are forgotten.
+const MAX_PENDING_PROBES: usize = 16;
+
+/// Number of accepted visitors waiting to be announced to their client, beyond which accepting
+/// pauses.
+const ACCEPT_QUEUE_SIZE: usize = 64;
 
 /// State structure for the server.
 pub struct Server {
-    /// Range of TCP ports that can be forwarded.
-    port_range: RangeInclusive<u16>,
+    /// Ranges of TCP ports that can be forwarded.
+    port_ranges: PortList,
+
+    /// Ports within the range that are never assigned to clients.
+    excluded_ports: PortList,
+
+    /// Optional scheme used to authenticate clients, by default with a secret.
+    auth: Option<Arc<dyn Authenticate>>,
+
+    /// Concurrent map of IDs to incoming connections.
+    conns: Arc<DashMap<Uuid, VisitorConn>>,
+
+    /// How long incoming connections wait for the client to accept them.
+    pending_expiry: Duration,
+
+    /// Optional GeoIP filter applied to visitor connections.
+    geoip: Option<GeoFilter>,
+
+    /// TCP tuning for the control listener, tunnel listeners, and their streams.
+    socket_options: SocketOptions,
+
+    /// Public ports bound with `SO_REUSEPORT` for accept shards, which the kernel would otherwise
+    /// let two tunnels of this server share.
+    shared_ports: Arc<DashSet<u16>>,
+
+    /// Channels to the tunnels of this server, by public port, for visitors that other nodes of
+    /// the cluster relay.
+    relays: Arc<Relays>,
+
+    /// Size of the buffer copying each direction of a visitor connection.
+    proxy_buffer_size: usize,
+
+    /// Throughput limit of each visitor connection in each direction, if any.
+    connection_rate: Option<ByteRate>,
+
+    /// Throughput limits of the visitor connections of some client identities.
+    identity_connection_rates: HashMap<String, ByteRate>,
+
+    /// Bytes that the visitors of each tunnel may transfer before the server closes it, if any.
+    transfer_limit: Option<u64>,
+
+    /// Oldest client version that may open tunnels, if clients must report their version.
+    min_client_version: Option<Version>,
+
+    /// Rate limiter for new visitor connections.
+    limiter: AcceptLimiter,
+
+    /// Optional tunnel state shared with other nodes in a cluster.
+    cluster: Option<Arc<Cluster>>,
+
+    /// Optional persistent record of ports assigned to named tunnels.
+    store: Option<PortStore>,
+
+    /// Optional monthly transfer quotas of client identities.
+    quotas: Option<Quotas>,
+
+    /// Optional weekly windows in which client identities may hold tunnels.
+    schedules: Option<Schedules>,
+
+    /// Secrets that tunnels on some ports or with some names require.
+    scoped_secrets: ScopedSecrets,
+
+    /// Public ports on which clients may open tunnels without a secret.
+    open_ports: PortList,
+
+    /// Public ports on which visitors connect over TLS, which the server decrypts.
+    visitor_tls: Option<(PortList, TlsAcceptor)>,
+
+    /// How long the port of a disconnected named tunnel is held for its client.
+    reconnect_grace: Duration,
+
+    /// Maximum number of visitors queued while a client reconnects.
+    reconnect_queue: usize,
+
+    /// Tunnels waiting for their clients to reconnect, by name or resumption token, with their
+    /// public ports and client identities.
+    parked: DashMap<ParkKey, ParkedEntry>,
+
+    /// Tunnels shared by several clients, by name.
+    groups: DashMap<String, Arc<Group>>,
+
+    /// Optional webhooks notified when tunnels open and close.
+    webhooks: Option<Webhooks>,
+
+    /// Optional DNS records kept for named tunnels while they are open.
+    dns: Option<DnsUpdater>,
+
+    /// Optional audit log of authentication failures and tunnel sessions.
+    audit: Option<AuditLog>,
+
+    /// Source IPs refused after repeated authentication failures.
+    bans: BanList,
+
+    /// Ports accepting control connections, each with its own transport.
+    control_listeners: Vec<ControlListener>,
+
+    /// Queues of idle pooled data connections, by the secret pool token of their tunnel.
+    pools: DashMap<Uuid, mpsc::Sender<Delimited<BoxedStream>>>,
+
+    /// Which addresses clients may ask to bind their public ports on.
+    bind_policy: BindPolicy,
+
+    /// Token that stops the server when cancelled.
+    shutdown: CancellationToken,
+
+    /// Reason given to clients when the server shuts down.
+    shutdown_reason: String,
+
+    /// Time after which clients are told to reconnect when the server shuts down, if any.
+    retry_after: Option<Duration>,
+
+    /// Optional channel receiving lifecycle events.
+    events: Option<broadcast::Sender<ServerEvent>>,
+
+    /// Custom transports accepting control connections, besides the control listeners.
+    acceptors: Vec<Arc<dyn Acceptor>>,
+
+    /// Optional callbacks run when tunnels open and close.
+    observer: Option<Arc<dyn TunnelObserver>>,
+
+    /// Optional hook screening visitor connections before they are queued for clients.
+    middleware: Option<Arc<dyn VisitorMiddleware>>,
+
+    /// Tunnels whose clients are currently connected.
+    tunnels: ActiveTunnels,
+
+    /// Strategy that chooses and binds the public ports of tunnels.
+    allocator: Arc<dyn PortAllocator>,
+
+    /// Address that the control listeners are bound on.
+    control_bind_addr: IpAddr,
+
+    /// Address that tunnel listeners are bound on, unless a client asks for another.
+    tunnel_bind_addr: IpAddr,
+
+    /// Domain with wildcard DNS records, under which each tunnel is given a subdomain.
+    domain: Option<String>,
+
+    /// Whether to report readiness and watchdog pings to systemd.
+    systemd_notify: bool,
+
+    /// When the server was created, for reporting its uptime.
+    started: Instant,
+
+    /// Port of the HTTP health endpoint, if it is enabled.
+    health_port: Option<u16>,
+
+    /// Certificate obtained from an ACME authority, if TLS uses one.
+    acme: Option<Arc<Acme>>,
+}
+
+/// Description of a tunnel whose client is connected to the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TunnelInfo {
+    /// Public port of the tunnel.
+    pub port: u16,
+
+    /// Name of the tunnel, if the client provided one.
+    pub name: Option<String>,
+
+    /// Description of the tunnel, if the client provided one.
+    pub description: Option<String>,
+
+    /// Address of the client's control connection.
+    pub client: SocketAddr,
+
+    /// Host name of the client's machine, if the client provided one.
+    pub client_host: Option<String>,
+
+    /// Version of the client, if it reported one.
+    pub client_version: Option<String>,
+
+    /// Whether the client asked for the tunnel to be shown in the server's public listing.
+    pub public_listing: bool,
+
+    /// Identity of the client, if it authenticated.
+    pub identity: Option<Identity>,
+
+    /// Time since the client opened the tunnel.
+    pub uptime: Duration,
+
+    /// Number of visitor connections forwarded so far.
+    pub connections: u64,
+
+    /// Number of visitor connections currently open.
+    pub active: u64,
+
+    /// Bytes received from visitors.
+    pub received: u64,
+
+    /// Bytes sent to visitors.
+    pub sent: u64,
+
+    /// Round-trip time of the control connection, if the client answers heartbeats.
+    pub rtt: Option<Duration>,
+
+    /// Variation of the round-trip time, if it is measured.
+    pub jitter: Option<Duration>,
+
+    /// Time until the tunnel expires, if the client asked for it to expire.
+    pub expires_in: Option<Duration>,
+}
+
+/// Cloneable handle to the tunnels open on a server, which stays valid while it listens.
+#[derive(Clone, Default)]
+pub struct ActiveTunnels(Arc<DashMap<Uuid, ActiveTunnel>>);
+
+struct ActiveTunnel {
+    port: u16,
+    name: Option<String>,
+    description: Option<String>,
+    client: SocketAddr,
+    client_host: Option<String>,
+    client_version: Option<String>,
+    public_listing: bool,
+    identity: Option<Identity>,
+    opened: Instant,
+    expires: Option<Instant>,
+    stats: Arc<Stats>,
+    /// Cancelled to close the tunnel, such as when its identity's window ends.
+    stop: CancellationToken,
+}
+
+impl ActiveTunnels {
+    /// Returns a description of each open tunnel, ordered by port.
+    pub fn list(&self) -> Vec<TunnelInfo> {
+        let mut tunnels: Vec<_> = self
+            .0
+            .iter()
+            .map(|entry| TunnelInfo {
+                port: entry.port,
+                name: entry.name.clone(),
+                description: entry.description.clone(),
+                client: entry.client,
+                client_host: entry.client_host.clone(),
+                client_version: entry.client_version.clone(),
+                public_listing: entry.public_listing,
+                identity: entry.identity.clone(),
+                uptime: entry.opened.elapsed(),
+                connections: entry.stats.connections(),
+                active: entry.stats.active(),
+                received: entry.stats.received(),
+                sent: entry.stats.sent(),
+                rtt: entry.stats.rtt(),
+                jitter: entry.stats.jitter(),
+                expires_in: entry
+                    .expires
+                    .map(|expires| expires.saturating_duration_since(Instant::now())),
+            })
+            .collect();
+        tunnels.sort_by_key(|tunnel| tunnel.port);
+        tunnels
+    }
+
+    /// Close the open tunnel that a target refers to, returning its port.
+    ///
+    /// The port is released rather than held for the client to reconnect, and visitors waiting
+    /// for the client are dropped.
+    pub fn close(&self, target: &TunnelTarget) -> Option<u16> {
+        let tunnel = self.0.iter().find(|entry| match target {
+            TunnelTarget::Port(port) => entry.port == *port,
+            TunnelTarget::Name(name) => entry.name.as_ref() == Some(name),
+        })?;
+        tunnel.stop.cancel();
+        Some(tunnel.port)
+    }
+
+    /// Close the open tunnels of clients connected from an IP, returning how many there were.
+    fn close_from(&self, ip: IpAddr) -> usize {
+        let tunnels = self.0.iter().filter(|entry| entry.client.ip() == ip);
+        tunnels.map(|entry| entry.stop.cancel()).count()
+    }
+
+    /// Record an open tunnel until the returned guard is dropped.
+    fn register(&self, tunnel: ActiveTunnel) -> TunnelGuard<'_> {
+        let id = Uuid::new_v4();
+        self.0.insert(id, tunnel);
+        TunnelGuard(self, id)
+    }
+}
+
+/// Send times of the heartbeat probes that a client has not answered yet.
+#[derive(Default)]
+struct Probes {
+    seq: u64,
+    pending: VecDeque<(u64, Instant)>,
+}
+
+impl Probes {
+    /// Returns the sequence number of a new probe, sent now.
+    fn send(&mut self) -> u64 {
+        self.seq += 1;
+        if self.pending.len() == MAX_PENDING_PROBES {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((self.seq, Instant::now()));
+        self.seq
+    }
+
+    /// Returns the round-trip time of an answered probe, forgetting older ones.
+    fn echoed(&mut self, seq: u64) -> Option<Duration> {
+        while let Some(&(pending, sent)) = self.pending.front() {
+            if pending > seq {
+                break;
+            }
+            self.pending.pop_front();
+            if pending == seq {
+                return Some(sent.elapsed());
+            }
+        }
+        None
+    }
+}
+
+/// Removes a tunnel from [`ActiveTunnels`] when its control connection ends, logging its
+/// summary.
+struct TunnelGuard<'a>(&'a ActiveTunnels, Uuid);
+
+impl Drop for TunnelGuard<'_> {
+    fn drop(&mut self) {
+        if let Some((_, tunnel)) = self.0 .0.remove(&self.1) {
+            let identity = tunnel.identity.as_ref().map(|identity| identity.0.as_str());
+            let uptime = tunnel.opened.elapsed();
+            tunnel.stats.log_closed(tunnel.port, identity, uptime);
+        }
+    }
+}
+
+/// Policy on the addresses that clients may ask to bind their public ports on.
+///
+/// Public ports are bound on all interfaces unless a client asks otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BindPolicy {
+    /// Always bind on all interfaces.
+    Deny,
+
+    /// Allow loopback addresses, so tunnels can be reached only from the server host.
+    #[default]
+    Loopback,
+
+    /// Allow any address of the server.
+    Any,
+}
+
+impl BindPolicy {
+    /// Check whether clients may bind public ports on an address.
+    pub fn allows(self, ip: IpAddr) -> bool {
+        match self {
+            BindPolicy::Deny => false,
+            BindPolicy::Loopback => ip.is_loopback(),
+            BindPolicy::Any => true,
+        }
+    }
+}
+
+impl FromStr for BindPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "none" => Ok(BindPolicy::Deny),
+            "loopback" => Ok(BindPolicy::Loopback),
+            "any" => Ok(BindPolicy::Any),
+            _ => bail!("unknown bind policy `{s}`, expected `none`, `loopback`, or `any`"),
+        }
+    }
+}
+
+/// A port accepting control connections from clients.
+#[derive(Clone, Debug)]
+pub struct ControlListener {
+    /// TCP port to listen on.
+    pub port: u16,
+
+    /// TLS configuration, if connections on this port are encrypted.
+    pub tls: Option<Arc<ServerConfig>>,
+}
+
+impl ControlListener {
+    /// A listener for plaintext control connections.
+    pub fn plain(port: u16) -> Self {
+        ControlListener { port, tls: None }
+    }
+
+    /// A listener for control connections encrypted with TLS.
+    pub fn tls(port: u16, config: Arc<ServerConfig>) -> Self {
+        ControlListener {
+            port,
+            tls: Some(config),
+        }
+    }
+}
+
+/// What a reconnecting client presents to take over a parked tunnel.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum ParkKey {
+    /// The name of a named tunnel.
+    Name(String),
+
+    /// The secret resumption token of an unnamed tunnel.
+    Token(Uuid),
+}
+
+/// How a client got past the server's challenge.
+enum Handshake {
+    /// It answered with a secret.
+    Authenticated(Identity, Credentials),
+
+    /// It has no secret, and sent this message instead, which the server allows if some ports
+    /// are open.
+    Anonymous(ClientMessage),
+}
+
+/// Public port and client identity of a parked tunnel, and the channel to take it over.
+type ParkedEntry = (
+    u16,
+    Option<Identity>,
+    oneshot::Sender<oneshot::Sender<Parked>>,
+);
+
+/// Listener and queued visitors of a tunnel whose client has disconnected.
+struct Parked {
+    listener: TunnelListener,
+    queue: VecDeque<VisitorConn>,
+    /// Visitors still being screened by middleware, who join the queue once they pass.
+    screening: JoinSet<Option<VisitorConn>>,
+    claim: Option<ClaimGuard>,
+    stats: Arc<Stats>,
+    expires: Option<Instant>,
+    identity: Option<Identity>,
+    /// Rate limit of new visitors on the port, kept so that reconnecting does not reset it.
+    port_bucket: Option<TokenBucket>,
+}
+
+/// A visitor connection waiting for its client, which middleware may have wrapped.
+struct VisitorConn {
+    stream: BoxedStream,
+    addr: SocketAddr,
+    port: u16,
+}
+
+/// Clients sharing the visitors of a named tunnel, which are handed to each in turn.
+///
+/// The client that opened the tunnel keeps its listener, and holds it open after leaving until
+/// the other members have left too.
+struct Group {
+    name: String,
+    port: u16,
+    bound: IpAddr,
+    identity: Option<Identity>,
+    members: Mutex<Members>,
+    next: AtomicUsize,
+    /// Notified when a member leaves.
+    left: Notify,
+    /// Cancelled to close the tunnel for every member.
+    stop: CancellationToken,
+}
+
+/// Channels to the control connections of a group's members.
+#[derive(Default)]
+struct Members {
+    senders: Vec<mpsc::Sender<VisitorConn>>,
+    /// Visitors that arrived while the group had no members.
+    pending: VecDeque<VisitorConn>,
+    closed: bool,
+}
+
+impl Group {
+    /// Add a member, unless the tunnel is closing.
+    fn join(&self, sender: mpsc::Sender<VisitorConn>) -> bool {
+        let mut members = self.members.lock().unwrap();
+        if !members.closed {
+            members.senders.push(sender);
+        }
+        !members.closed
+    }
+
+    /// Hand a visitor to the next member in turn, skipping members that have left.
+    async fn dispatch(&self, mut conn: VisitorConn) {
+        loop {
+            let sender = {
+                let mut members = self.members.lock().unwrap();
+                members.senders.retain(|sender| !sender.is_closed());
+                if members.senders.is_empty() {
+                    members.pending.push_back(conn);
+                    return;
+                }
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                members.senders[next % members.senders.len()].clone()
+            };
+            match sender.send(conn).await {
+                Ok(()) => return,
+                Err(err) => conn = err.0,
+            }
+        }
+    }
+
+    /// Stop taking members if none are left, returning the visitors that no member took.
+    fn close(&self) -> Option<VecDeque<VisitorConn>> {
+        let mut members = self.members.lock().unwrap();
+        members.senders.retain(|sender| !sender.is_closed());
+        if !members.senders.is_empty() {
+            return None;
+        }
+        members.closed = true;
+        Some(mem::take(&mut members.pending))
+    }
+}
+
+/// Listener on the public port of a tunnel.
+///
+/// Ports bound for accept shards are reserved until the listener is dropped, since binding them
+/// with `SO_REUSEPORT` would succeed for a second tunnel as well.
+struct TunnelListener {
+    listener: TcpListener,
+    reserved: Option<Arc<DashSet<u16>>>,
+    /// Visitors relayed by other nodes of the cluster, if the server is in one.
+    relayed: Option<(Arc<Relays>, mpsc::Receiver<Relayed>)>,
+}
+
+/// Connection and address of a visitor that another node of the cluster relayed.
+type Relayed = (BoxedStream, SocketAddr);
+
+/// Senders of the visitors that other nodes of the cluster relay, by public port.
+type Relays = DashMap<u16, mpsc::Sender<Relayed>>;
+
+/// A visitor connection as it arrived, before it is counted.
+enum Incoming {
+    /// Accepted on the public port of the tunnel.
+    Direct(TcpStream),
+
+    /// Relayed by another node of the cluster, on its control connection.
+    Relayed(BoxedStream),
+}
+
+impl TunnelListener {
+    /// Accept a visitor on the public port, or one that another node of the cluster relayed.
+    async fn accept_visitor(&mut self) -> io::Result<(Incoming, SocketAddr)> {
+        let relayed = async {
+            match &mut self.relayed {
+                Some((_, relayed)) => relayed.recv().await,
+                None => pending().await,
+            }
+        };
+        tokio::select! {
+            result = self.listener.accept() => {
+                result.map(|(conn, addr)| (Incoming::Direct(conn), addr))
+            }
+            Some((stream, addr)) = relayed => Ok((Incoming::Relayed(stream), addr)),
+        }
+    }
+}
+
+impl Deref for TunnelListener {
+    type Target = TcpListener;
+
+    fn deref(&self) -> &TcpListener {
+        &self.listener
+    }
+}
+
+impl Drop for TunnelListener {
+    fn drop(&mut self) {
+        if let (Some(ports), Ok(addr)) = (&self.reserved, self.listener.local_addr()) {
+            ports.remove(&addr.port());
+        }
+        if let (Some((relays, _)), Ok(addr)) = (&self.relayed, self.listener.local_addr()) {
+            relays.remove(&addr.port());
+        }
+    }
+}
+
+/// Unregisters the connection pool of a tunnel when its control connection ends.
+struct PoolGuard<'a>(
+    &'a DashMap<Uuid, mpsc::Sender<Delimited<BoxedStream>>>,
+    Uuid,
+);
+
+impl Drop for PoolGuard<'_> {
+    fn drop(&mut self) {
+        self.0.remove(&self.1);
+    }
+}
+
+impl Server {
+    /// Create a new server with a specified range, or list of ranges, of ports.
+    pub fn new(port_ranges: impl Into<PortList>, secret: Option<&str>) -> Self {
+        let port_ranges = port_ranges.into();
+        assert!(!port_ranges.is_empty(), "must provide at least one port");
+        Server {
+            port_ranges,
+            excluded_ports: PortList::default(),
+            conns: Arc::new(DashMap::new()),
+            pending_expiry: Duration::from_secs(10),
+            auth: secret.map(|secret| Arc::new(Authenticator::new(secret)) as _),
+            geoip: None,
+            socket_options: SocketOptions::default(),
+            shared_ports: Arc::default(),
+            relays: Arc::default(),
+            proxy_buffer_size: DEFAULT_BUFFER_SIZE,
+            connection_rate: None,
+            identity_connection_rates: HashMap::new(),
+            transfer_limit: None,
+            min_client_version: None,
+            limiter: AcceptLimiter::default(),
+            cluster: None,
+            store: None,
+            quotas: None,
+            schedules: None,
+            scoped_secrets: ScopedSecrets::new(),
+            open_ports: PortList::default(),
+            visitor_tls: None,
+            reconnect_grace: Duration::ZERO,
+            reconnect_queue: 32,
+            parked: DashMap::new(),
+            groups: DashMap::new(),
+            webhooks: None,
+            dns: None,
+            audit: None,
+            bans: BanList::default(),
+            control_listeners: vec![ControlListener::plain(CONTROL_PORT)],
+            pools: DashMap::new(),
+            bind_policy: BindPolicy::default(),
+            shutdown: CancellationToken::new(),
+            shutdown_reason: "server is shutting down".into(),
+            retry_after: None,
+            events: None,
+            acceptors: Vec::new(),
+            observer: None,
+            middleware: None,
+            tunnels: ActiveTunnels::default(),
+            allocator: Arc::new(RandomAllocator),
+            control_bind_addr: Ipv4Addr::UNSPECIFIED.into(),
+            tunnel_bind_addr: Ipv4Addr::UNSPECIFIED.into(),
+            domain: None,
+            systemd_notify: false,
+            started: Instant::now(),
+            health_port: None,
+            acme: None,
+        }
+    }
+
+    /// Returns a description of each tunnel whose client is connected.
+    pub fn active_tunnels(&self) -> Vec<TunnelInfo> {
+        self.tunnels.list()
+    }
+
+    /// Returns a handle to the open tunnels, which can be kept after [`Server::listen`] is called.
+    pub fn tunnels(&self) -> ActiveTunnels {
+        self.tunnels.clone()
+    }
+
+    /// Bind the control listeners on an address, instead of all IPv4 interfaces.
+    pub fn set_control_bind_addr(&mut self, addr: IpAddr) {
+        self.control_bind_addr = addr;
+    }
+
+    /// Bind tunnel listeners on an address, instead of all IPv4 interfaces.
+    ///
+    /// Clients may still ask for another address, if the bind policy allows it.
+    pub fn set_tunnel_bind_addr(&mut self, addr: IpAddr) {
+        self.tunnel_bind_addr = addr;
+    }
+
+    /// Give each tunnel a memorable subdomain of a domain whose wildcard DNS records point at
+    /// the server, and tell clients that ask for it.
+    ///
+    /// Subdomains follow from the public port, so a tunnel keeps its subdomain while it keeps its
+    /// port. Visitors still connect to the port, since the server does not route by host name.
+    pub fn set_domain(&mut self, domain: impl Into<String>) {
+        let domain = domain.into();
+        self.domain = Some(domain.trim_matches('.').to_ascii_lowercase());
+    }
+
+    /// Set which addresses clients may ask to bind their public ports on.
+    pub fn set_bind_policy(&mut self, policy: BindPolicy) {
+        self.bind_policy = policy;
+    }
+
+    /// Stop the server when a token is cancelled.
+    ///
+    /// The server then stops accepting connections and closes the control connections of its
+    /// tunnels, so [`Server::listen`] returns. Visitor connections in progress are unaffected.
+    pub fn set_shutdown(&mut self, token: CancellationToken) {
+        self.shutdown = token;
+    }
+
+    /// Set the notice that clients receive when the server shuts down, with how long they should
+    /// wait before reconnecting.
+    ///
+    /// Only clients that ask for the notice receive it, and others see their connection drop.
+    pub fn set_shutdown_notice(
+        &mut self,
+        reason: impl Into<String>,
+        retry_after: Option<Duration>,
+    ) {
+        self.shutdown_reason = reason.into();
+        self.retry_after = retry_after;
+    }
+
+    /// Notify systemd when the server is ready and stopping, and ping its watchdog if enabled.
+    ///
+    /// This has no effect unless the server runs as a systemd service on Unix.
+    pub fn set_systemd_notify(&mut self, notify: bool) {
+        self.systemd_notify = notify;
+    }
+
+    /// Persist the ports of named tunnels, so they are reassigned after a restart.
+    pub fn set_port_store(&mut self, store: PortStore) {
+        self.store = Some(store);
+    }
+
+    /// Limit the bytes that the tunnels of each client identity may transfer per month.
+    ///
+    /// Clients that have used up their quota cannot open tunnels, and their tunnels refuse new
+    /// visitors, until the month ends. This has no effect on a server without authentication.
+    pub fn set_quotas(&mut self, quotas: Quotas) {
+        self.quotas = Some(quotas);
+    }
+
+    /// Restrict client identities to hold tunnels only within their scheduled windows.
+    ///
+    /// Clients outside their windows cannot open tunnels, and their open tunnels are closed
+    /// within a minute of the window ending. This has no effect on a server without
+    /// authentication.
+    pub fn set_schedules(&mut self, schedules: Schedules) {
+        self.schedules = Some(schedules);
+    }
+
+    /// Require other secrets than the server's for tunnels on some ports or with some names.
+    ///
+    /// Clients authenticate with any of the secrets, and can only open tunnels within the scopes
+    /// of the secrets they know, or outside of every scope if they know the server's secret.
+    /// This has no effect on a server without authentication.
+    pub fn set_scoped_secrets(&mut self, secrets: ScopedSecrets) {
+        self.scoped_secrets = secrets;
+    }
+
+    /// Let clients without a secret open tunnels on some public ports.
+    ///
+    /// Such clients have no identity, so quotas and schedules do not apply to them, and ports
+    /// that a scoped secret covers still require it. This has no effect on a server without
+    /// authentication, which lets anyone open tunnels on any port.
+    pub fn set_open_ports(&mut self, ports: PortList) {
+        self.open_ports = ports;
+    }
+
+    /// Decrypt TLS from visitors on some public ports, passing plaintext into their tunnels.
+    ///
+    /// This serves HTTPS for a plain HTTP app without the certificate's key leaving the server.
+    pub fn set_visitor_tls(&mut self, ports: PortList, config: Arc<ServerConfig>) {
+        self.visitor_tls = Some((ports, TlsAcceptor::from(config)));
+    }
+
+    /// Join a cluster of server nodes, sharing tunnels between them.
+    ///
+    /// Cluster announcements are only accepted from peers that authenticate with the
+    /// server secret, so this has no effect on a server without one.
+    pub fn set_cluster(&mut self, cluster: Cluster) {
+        self.cluster = Some(Arc::new(cluster));
+    }
+
+    /// Limit the rate of new visitor connections per public port and source IP.
+    pub fn set_accept_policy(&mut self, policy: AcceptPolicy) {
+        self.limiter = AcceptLimiter::new(policy);
+    }
+
+    /// Set TCP tuning options for all listeners and accepted connections.
+    ///
+    /// With [`SocketOptions::accept_shards`], the control listeners and the listener of each
+    /// tunnel are bound several times with `SO_REUSEPORT`, and accepted on separate tasks.
+    pub fn set_socket_options(&mut self, socket_options: SocketOptions) {
+        self.socket_options = socket_options;
+    }
+
+    /// Set the size of the buffer copying each direction of a visitor connection, which defaults
+    /// to [`DEFAULT_BUFFER_SIZE`].
+    ///
+    /// Larger buffers raise throughput on links with a high bandwidth-delay product, at the cost
+    /// of memory for every open connection.
+    pub fn set_proxy_buffer_size(&mut self, size: usize) {
+        assert!(size > 0, "proxy buffer size must be positive");
+        self.proxy_buffer_size = size;
+    }
+
+    /// Limit the throughput of each visitor connection in each direction.
+    ///
+    /// Unlike a limit on a whole tunnel, this keeps one bulk transfer from starving the other
+    /// connections of the same tunnel.
+    pub fn set_connection_rate(&mut self, rate: ByteRate) {
+        self.connection_rate = Some(rate);
+    }
+
+    /// Limit the visitor connections of a client identity to another rate than the default.
+    pub fn set_identity_connection_rate(&mut self, identity: impl Into<String>, rate: ByteRate) {
+        self.identity_connection_rates.insert(identity.into(), rate);
+    }
+
+    /// Close each tunnel once its visitors transferred this many bytes in both directions.
+    ///
+    /// Clients may ask for a lower limit of their own. Connections are cut off at the limit, and
+    /// clients that understand it are told why their tunnel closed.
+    pub fn set_transfer_limit(&mut self, bytes: u64) {
+        self.transfer_limit = Some(bytes);
+    }
+
+    /// Refuse tunnels from clients older than this version.
+    ///
+    /// Clients too old to report their version are refused as well, while newer clients that
+    /// sent a plain request ask again with their version.
+    pub fn set_min_client_version(&mut self, version: Version) {
+        self.min_client_version = Some(version);
+    }
+
+    /// Prevent ports within the range from being selected or requested.
+    pub fn set_excluded_ports(&mut self, excluded_ports: PortList) {
+        self.excluded_ports = excluded_ports;
+    }
+
+    /// Filter visitor connections on public ports by their country of origin.
+    pub fn set_geoip(&mut self, geoip: GeoFilter) {
+        self.geoip = Some(geoip);
+    }
+
+    /// Hold the ports of disconnected tunnels, queueing up to `queue` visitors until their
+    /// clients reconnect or the grace period expires.
+    ///
+    /// Named tunnels are held for any client with the same name. Unnamed tunnels are only held
+    /// if their client asked for a resumption token, and only a client presenting it may take
+    /// them over.
+    pub fn set_reconnect_grace(&mut self, grace: Duration, queue: usize) {
+        self.reconnect_grace = grace;
+        self.reconnect_queue = queue;
+    }
+
+    /// Set how long incoming connections wait for the client to accept them.
+    pub fn set_pending_expiry(&mut self, expiry: Duration) {
+        self.pending_expiry = expiry;
+    }
+
+    /// Also accept control connections from a custom transport.
+    ///
+    /// To only use custom transports, remove the TCP control port by passing an empty list to
+    /// [`Server::set_control_listeners`].
+    pub fn add_acceptor(&mut self, acceptor: impl Acceptor + 'static) {
+        self.acceptors.push(Arc::new(acceptor));
+    }
+
+    /// Authenticate clients with a custom scheme, instead of the secret given to [`Server::new`].
+    ///
+    /// Any authenticated client may also send cluster and administrative requests.
+    pub fn set_authenticator(&mut self, auth: impl Authenticate + 'static) {
+        self.auth = Some(Arc::new(auth));
+    }
+
+    /// Choose and bind the public ports of tunnels with a custom strategy, instead of probing
+    /// random ports.
+    pub fn set_port_allocator(&mut self, allocator: impl PortAllocator + 'static) {
+        self.allocator = Arc::new(allocator);
+    }
+
+    /// Run callbacks when tunnels open and close.
+    pub fn set_observer(&mut self, observer: impl TunnelObserver + 'static) {
+        self.observer = Some(Arc::new(observer));
+    }
+
+    /// Screen each visitor connection with middleware, which may drop, delay, or wrap it.
+    pub fn set_middleware(&mut self, middleware: impl VisitorMiddleware + 'static) {
+        self.middleware = Some(Arc::new(middleware));
+    }
+
+    /// Send tunnel lifecycle events to a channel, such as one created by [`event::channel`].
+    pub fn set_events(&mut self, events: broadcast::Sender<ServerEvent>) {
+        self.events = Some(events);
+    }
+
+    /// Post tunnel lifecycle events to webhooks.
+    pub fn set_webhooks(&mut self, webhooks: Webhooks) {
+        self.webhooks = Some(webhooks);
+    }
+
+    /// Create a DNS record for each named tunnel when it opens, and remove it when it closes.
+    ///
+    /// Tunnels held for their client to reconnect keep their records.
+    pub fn set_dns_updater(&mut self, updater: DnsUpdater) {
+        self.dns = Some(updater);
+    }
+
+    /// Record authentication failures and the opening and closing of tunnels in an audit log.
+    pub fn set_audit_log(&mut self, audit: AuditLog) {
+        self.audit = Some(audit);
+    }
+
+    /// Temporarily ban source IPs that repeatedly fail to authenticate.
+    pub fn set_ban_policy(&mut self, policy: BanPolicy) {
+        self.bans.set_policy(policy);
+    }
+
+    /// Persist bans in a JSON file, so that they last across restarts.
+    pub fn set_ban_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        Ok(self.bans.open(path)?)
+    }
+
+    /// Accept control connections on these ports, instead of plaintext on [`CONTROL_PORT`].
+    ///
+    /// Cluster peers always connect to the plaintext control port. The list may only be empty
+    /// if custom transports are added with [`Server::add_acceptor`].
+    pub fn set_control_listeners(&mut self, control_listeners: Vec<ControlListener>) {
+        self.control_listeners = control_listeners;
+    }
+
+    /// Serve an HTTP health endpoint at `/healthz`, a status page at `/status`, and a listing of
+    /// the tunnels whose clients opted into it at `/tunnels`, on this port of the control bind
+    /// address.
+    ///
+    /// See the [`health`](crate::health) module for the report it returns.
+    pub fn set_health_port(&mut self, port: u16) {
+        self.health_port = Some(port);
+    }
+
+    /// Obtain and renew the certificate of TLS control listeners from an ACME authority.
+    ///
+    /// The server answers its challenges on [`Acme::challenge_port`] of the control bind
+    /// address. Listeners present the certificate when built with [`Acme::server_config`].
+    pub fn set_acme(&mut self, acme: Acme) {
+        self.acme = Some(Arc::new(acme));
+    }
+
+    /// Start the server, listening for new connections.
+    ///
+    /// Runs until the token given to [`Server::set_shutdown`] is cancelled, if any.
+    pub async fn listen(self) -> Result<()> {
+        let mut listeners = Vec::new();
+        for control in &self.control_listeners {
+            let addr = SocketAddr::new(self.control_bind_addr, control.port);
+            let listener = self.socket_options.bind(addr).map_err(|err| {
+                Error::Bind(format!(
+                    "could not listen on control port {}: {err}",
+                    control.port
+                ))
+            })?;
+            let shards = self.socket_options.bind_shards(&listener).map_err(|err| {
+                Error::Bind(format!(
+                    "could not add accept shards on control port {}: {err}",
+                    control.port
+                ))
+            })?;
+            info!(?addr, tls = control.tls.is_some(), "server listening");
+            let tls = control.tls.clone().map(TlsAcceptor::from);
+            for listener in iter::once(listener).chain(shards) {
+                listeners.push((listener, tls.clone()));
+            }
+        }
+        self.serve(listeners).await
+    }
+
+    /// Start the server, accepting plaintext control connections on a listener that is already
+    /// bound, such as one passed in by a service manager.
+    ///
+    /// The listener replaces the control listeners, while custom transports are still used.
+    pub async fn listen_on(self, listener: TcpListener) -> Result<()> {
+        info!(addr = ?listener.local_addr()?, "server listening");
+        self.serve(vec![(listener, None)]).await
+    }
+
+    /// Accept control connections on bound listeners and custom transports.
+    async fn serve(self, listeners: Vec<(TcpListener, Option<TlsAcceptor>)>) -> Result<()> {
+        if listeners.is_empty() && self.acceptors.is_empty() {
+            return Err(anyhow!("server must have at least one control listener").into());
+        }
+        let health = match self.health_port {
+            Some(port) => {
+                let addr = SocketAddr::new(self.control_bind_addr, port);
+                let listener = TcpListener::bind(addr).await.map_err(|err| {
+                    Error::Bind(format!("could not listen on health port {port}: {err}"))
+                })?;
+                info!(?addr, "health endpoint listening");
+                Some(listener)
+            }
+            None => None,
+        };
+        let acme = match &self.acme {
+            Some(acme) => {
+                let port = acme.challenge_port();
+                let addr = SocketAddr::new(self.control_bind_addr, port);
+                let listener = TcpListener::bind(addr).await.map_err(|err| {
+                    Error::Bind(format!(
+                        "could not listen on ACME challenge port {port}: {err}"
+                    ))
+                })?;
+                info!(?addr, "ACME challenges listening");
+                Some((Arc::clone(acme), listener))
+            }
+            None => None,
+        };
+        let this = Arc::new(self);
+        if let Some((acme, listener)) = acme {
+            tokio::spawn(
+                this.shutdown
+                    .clone()
+                    .run_until_cancelled_owned(acme.run(listener)),
+            );
+        }
+        if let Some(listener) = health {
+            let this2 = Arc::clone(&this);
+            let this3 = Arc::clone(&this);
+            let run = health::serve(listener, move || this2.health(), move || this3.listing());
+            tokio::spawn(this.shutdown.clone().run_until_cancelled_owned(run));
+        }
+        let this2 = Arc::clone(&this);
+        tokio::spawn(this.shutdown.clone().run_until_cancelled_owned(async move {
+            loop {
+                sleep(Duration::from_secs(60)).await;
+                this2.limiter.purge();
+                this2.bans.purge();
+                this2.sweep_schedules();
+                if let Some(quotas) = &this2.quotas {
+                    if let Err(err) = quotas.save() {
+                        warn!(%err, "failed to save transfer quotas");
+                    }
+                }
+            }
+        }));
+        if let Some(cluster) = &this.cluster {
+            cluster.bind_on(this.tunnel_bind_addr, this.socket_options.clone());
+            let run = Arc::clone(cluster).run();
+            tokio::spawn(this.shutdown.clone().run_until_cancelled_owned(run));
+        }
+
+        let accept_loops = listeners
+            .into_iter()
+            .map(|(listener, tls)| Arc::clone(&this).accept_loop(listener, tls).boxed())
+            .chain(this.acceptors.iter().map(|acceptor| {
+                Arc::clone(&this)
+                    .custom_accept_loop(Arc::clone(acceptor))
+                    .boxed()
+            }));
+        #[cfg(unix)]
+        if this.systemd_notify {
+            crate::systemd::notify_ready();
+            tokio::spawn(crate::systemd::watchdog(this.shutdown.clone()));
+        }
+        let result = try_join_all(accept_loops).await;
+        #[cfg(unix)]
+        if this.systemd_notify {
+            crate::systemd::notify_stopping();
+        }
+        if let Some(quotas) = &this.quotas {
+            if let Err(err) = quotas.save() {
+                warn!(%err, "failed to save transfer quotas");
+            }
+        }
+        result?;
+        info!("server shut down");
+        Ok(())
+    }
+
+    /// Accept control connections on a listener, completing TLS handshakes if required.
+    async fn accept_loop(
+        self: Arc<Self>,
+        listener: TcpListener,
+        tls: Option<TlsAcceptor>,
+    ) -> Result<()> {
+        loop {
+            let (stream, addr) = tokio::select! {
+                result = listener.accept() => result?,
+                _ = self.shutdown.cancelled() => return Ok(()),
+            };
+            if self.bans.is_banned(addr.ip()) {
+                debug!(client_addr = %addr, "refused connection from banned address");
+                continue;
+            }
+            if let Err(err) = self.socket_options.apply(&stream) {
+                warn!(%err, client_addr = %addr, "failed to apply socket options");
+            }
+            let tls = tls.clone();
+            self.spawn_connection(addr, async move {
+                let stream: BoxedStream = match tls {
+                    Some(tls) => Box::new(
+                        timeout(NETWORK_TIMEOUT, tls.accept(stream))
+                            .await
+                            .map_err(|_| {
+                                Error::Timeout("timed out waiting for TLS handshake".into())
+                            })??,
+                    ),
+                    None => Box::new(stream),
+                };
+                Ok(stream)
+            });
+        }
+    }
+
+    /// Accept control connections from a custom transport.
+    async fn custom_accept_loop(self: Arc<Self>, acceptor: Arc<dyn Acceptor>) -> Result<()> {
+        loop {
+            let (stream, addr) = tokio::select! {
+                result = acceptor.accept() => result?,
+                _ = self.shutdown.cancelled() => return Ok(()),
+            };
+            if self.bans.is_banned(addr.ip()) {
+                debug!(client_addr = %addr, "refused connection from banned address");
+                continue;
+            }
+            self.spawn_connection(addr, async move { Ok(stream) });
+        }
+    }
+
+    /// Handle a control connection in the background, once its stream is established.
+    fn spawn_connection(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        stream: impl Future<Output = Result<BoxedStream>> + Send + 'static,
+    ) {
+        let this = Arc::clone(self);
+        tokio::spawn(
+            async move {
+                info!("incoming connection");
+                let result = async { this.handle_connection(stream.await?, addr).await };
+                // Tunnels log a summary when they close, so a clean exit is only worth a debug line.
+                if let Err(err) = result.await {
+                    warn!(%err, "connection exited with error");
+                } else {
+                    debug!("connection exited");
+                }
+            }
+            .instrument(info_span!("control", client_addr = %addr, port = field::Empty)),
+        );
+    }
+
+    /// Bind a listener on a port, or on any available port in `ranges` if the port is zero.
+    ///
+    /// Listeners from the port allocator are checked against the allowed and excluded ports.
+    /// With accept shards, ports that other tunnels bound for their shards are excluded too.
+    async fn create_listener(
+        &self,
+        ip: IpAddr,
+        port: u16,
+        ranges: &PortList,
+        identity: Option<&Identity>,
+        name: Option<&str>,
+    ) -> Result<TunnelListener> {
+        let sharded = self.socket_options.shards() > 1;
+        if sharded && self.shared_ports.contains(&port) {
+            return Err(bind_error(ErrorCode::PortInUse, "port already in use"));
+        }
+        let excluded = match sharded {
+            true => Cow::Owned(
+                (self.excluded_ports.ranges().iter().cloned())
+                    .chain(self.shared_ports.iter().map(|port| *port..=*port))
+                    .collect(),
+            ),
+            false => Cow::Borrowed(&self.excluded_ports),
+        };
+        let request = PortRequest {
+            port,
+            ip,
+            ranges,
+            excluded: &excluded,
+            identity,
+            name,
+            socket_options: &self.socket_options,
+        };
+        let listener = self.allocator.allocate(request).await?;
+        let assigned = listener.local_addr()?.port();
+        if !ranges.contains(assigned) || self.excluded_ports.contains(assigned) {
+            warn!(
+                port = assigned,
+                "port allocator returned a port that is not allowed"
+            );
+            return Err(bind_error(
+                ErrorCode::NoPortAvailable,
+                "failed to find an available port",
+            ));
+        }
+        // Another tunnel may have bound the same port since the ports were excluded.
+        if sharded && !self.shared_ports.insert(assigned) {
+            return Err(bind_error(ErrorCode::PortInUse, "port already in use"));
+        }
+        let relayed = self.cluster.is_some().then(|| {
+            let (relay_tx, relayed) = mpsc::channel(ACCEPT_QUEUE_SIZE);
+            self.relays.insert(assigned, relay_tx);
+            (Arc::clone(&self.relays), relayed)
+        });
+        Ok(TunnelListener {
+            listener,
+            reserved: sharded.then(|| Arc::clone(&self.shared_ports)),
+            relayed,
+        })
+    }
+
+    async fn handle_connection(&self, stream: BoxedStream, addr: SocketAddr) -> Result<()> {
+        let mut stream = Delimited::new(stream);
+        let mut identity = None;
+        let mut credentials = Credentials {
+            main: true,
+            keys: Vec::new(),
+        };
+        let mut message = None;
+        if let Some(auth) = &self.auth {
+            let handshake = self.authenticate(auth.as_ref(), &mut stream);
+            match handshake.instrument(info_span!("handshake")).await {
+                Ok(Handshake::Authenticated(id, proven)) => {
+                    debug!(identity = id.0, "client authenticated");
+                    identity = Some(id);
+                    credentials = proven;
+                }
+                Ok(Handshake::Anonymous(request)) => {
+                    debug!("client without a secret");
+                    credentials.main = false;
+                    message = Some(request);
+                }
+                Err(err) => {
+                    warn!(%err, "server handshake failed");
+                    self.audit(AuditEvent::AuthFailed {
+                        client: addr,
+                        reason: err.to_string(),
+                    });
+                    if self.bans.record_failure(addr.ip()) {
+                        warn!(ip = %addr.ip(), "banned after repeated authentication failures");
+                    }
+                    let message = format!("{AUTH_FAILED}: {err}");
+                    stream.send(ServerMessage::Error(message)).await?;
+                    return Ok(());
+                }
+            }
+        }
+
+        let message = match message {
+            Some(message) => Some(message),
+            None => stream.recv_timeout().await?,
+        };
+        match message {
+            Some(ClientMessage::Authenticate(_)) => {
+                warn!("unexpected authenticate");
+                Ok(())
+            }
+            Some(ClientMessage::Echo(_)) => {
+                warn!("unexpected echo");
+                Ok(())
+            }
+            Some(ClientMessage::Goodbye) => {
+                warn!("unexpected goodbye");
+                Ok(())
+            }
+            Some(ClientMessage::Hello(port)) => {
+                let request = TunnelRequest {
+                    port,
+                    ..Default::default()
+                };
+                self.handle_tunnel(stream, request, addr, identity, credentials)
+                    .await
+            }
+            Some(ClientMessage::Open(request)) => {
+                self.handle_tunnel(stream, request, addr, identity, credentials)
+                    .await
+            }
+            Some(ClientMessage::Accept(id)) => {
+                info!(%id, "forwarding connection");
+                match self.conns.remove(&id) {
+                    Some((_, stream2)) => {
+                        let tls = self.visitor_tls(&stream2);
+                        let events = self.events.as_ref();
+                        let buffer_size = self.proxy_buffer_size;
+                        forward_visitor(id, stream, stream2, tls, buffer_size, events).await?
+                    }
+                    None => warn!(%id, "missing connection"),
+                }
+                Ok(())
+            }
+            Some(ClientMessage::Pool(token)) => {
+                let pool = self.pools.get(&token).map(|pool| pool.clone());
+                match pool {
+                    Some(pool) => {
+                        if pool.try_send(stream).is_err() {
+                            warn!("connection pool is full");
+                        }
+                    }
+                    None => warn!("unknown connection pool"),
+                }
+                Ok(())
+            }
+            Some(ClientMessage::Cluster(message)) => {
+                match (&self.cluster, message) {
+                    (Some(_), ClusterMessage::Visitor { port, addr })
+                        if self.auth.is_some() && credentials.main =>
+                    {
+                        self.relay(stream, port, addr).await;
+                    }
+                    (Some(cluster), message) if self.auth.is_some() && credentials.main => {
+                        cluster.handle(&mut stream, message).await?;
+                    }
+                    _ => warn!("unexpected cluster message"),
+                }
+                Ok(())
+            }
+            Some(ClientMessage::Admin(request)) => {
+                if self.auth.is_none() {
+                    let message = "administration requires the server to have a secret";
+                    stream.send(ServerMessage::Error(message.into())).await?;
+                    return Ok(());
+                }
+                if !credentials.main {
+                    let message = "administration requires the server's secret";
+                    stream.send(ServerMessage::Error(message.into())).await?;
+                    return Ok(());
+                }
+                info!(?request, "admin request");
+                self.handle_admin(&mut stream, request).await
+            }
+            Some(ClientMessage::Ping) => {
+                stream.send(ServerMessage::Pong(self.info())).await?;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
 
-    /// Optional secret used to authenticate clients.
-    auth: Option<Authenticator>,
+    /// Challenge a client, checking its answer against the server's secret and scoped secrets.
+    ///
+    /// If some ports are open, clients may skip the answer and send their request right away.
+    async fn authenticate(
+        &self,
+        auth: &dyn Authenticate,
+        stream: &mut Delimited<BoxedStream>,
+    ) -> Result<Handshake> {
+        let challenge = Uuid::new_v4();
+        stream.send(ServerMessage::Challenge(challenge)).await?;
+        let answer = match stream.recv_timeout().await? {
+            Some(ClientMessage::Authenticate(answer)) => answer,
+            Some(message) if !self.open_ports.is_empty() => {
+                return Ok(Handshake::Anonymous(message));
+            }
+            _ => {
+                return Err(Error::Handshake(
+                    "server requires secret, but no secret was provided".into(),
+                ))
+            }
+        };
+        let keys = self.scoped_secrets.verify(&challenge, &answer);
+        match auth.verify(&challenge, &answer).await {
+            Ok(identity) => Ok(Handshake::Authenticated(
+                identity,
+                Credentials { main: true, keys },
+            )),
+            Err(err) => match self.scoped_secrets.identity(&keys) {
+                Some(identity) => Ok(Handshake::Authenticated(
+                    identity,
+                    Credentials { main: false, keys },
+                )),
+                None => Err(err),
+            },
+        }
+    }
 
-    /// Concurrent map of IDs to incoming connections.
-    conns: Arc<DashMap<Uuid, TcpStream>>,
-}
+    /// Returns the host name of the tunnel on a public port, if the server has a domain.
+    fn hostname(&self, port: u16) -> Option<String> {
+        let domain = self.domain.as_ref()?;
+        Some(format!("{}.{domain}", subdomain(port)))
+    }
 
-impl Server {
-    /// Create a new server with a specified minimum port number.
-    pub fn new(port_range: RangeInclusive<u16>, secret: Option<&str>) -> Self {
-        assert!(!port_range.is_empty(), "must provide at least one port");
-        Server {
-            port_range,
-            conns: Arc::new(DashMap::new()),
-            auth: secret.map(Authenticator::new),
+    /// Describe the server to a client.
+    fn info(&self) -> ServerInfo {
+        let mut features: Vec<String> = [
+            "open",
+            "pool",
+            "visitor-addr",
+            "fallback-random",
+            "port-range",
+            "bind-addr",
+            "measure-rtt",
+            "ping",
+            "goodbye",
+            "hostname",
+            "expires",
+            "transfer-limit",
+        ]
+        .map(String::from)
+        .into();
+        if self.auth.is_some() {
+            features.push("admin".into());
+            features.push("shared".into());
+        }
+        if !self.reconnect_grace.is_zero() {
+            features.push("resume".into());
+        }
+        if self.quotas.is_some() {
+            features.push("quota".into());
+        }
+        if self.control_listeners.iter().any(|l| l.tls.is_some()) {
+            features.push("tls".into());
+        }
+        if self.health_port.is_some() {
+            features.push("public-listing".into());
+        }
+        ServerInfo {
+            version: env!("CARGO_PKG_VERSION").into(),
+            ports: self.port_ranges.clone(),
+            tunnels: self.tunnels.0.len(),
+            auth: self.auth.is_some(),
+            features,
         }
     }
 
-    /// Start the server, listening for new connections.
-    pub async fn listen(self) -> Result<()> {
-        let this = Arc::new(self);
-        let addr = SocketAddr::from(([0, 0, 0, 0], CONTROL_PORT));
-        let listener = TcpListener::bind(&addr).await?;
-        info!(?addr, "server listening");
+    /// Describe the readiness and load of the server for its health endpoint.
+    fn health(&self) -> HealthReport {
+        let tunnels = self.tunnels.list();
+        let usable = self.port_ranges.without(&self.excluded_ports);
+        let used: HashSet<_> = (tunnels.iter())
+            .map(|tunnel| tunnel.port)
+            .filter(|port| usable.contains(*port))
+            .collect();
+        HealthReport {
+            ready: !self.shutdown.is_cancelled(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            tunnels: tunnels.len(),
+            active_connections: tunnels.iter().map(|tunnel| tunnel.active).sum(),
+            pending_connections: self.conns.len(),
+            ports_available: usable.len().saturating_sub(used.len() as u32),
+            ports_total: usable.len(),
+        }
+    }
 
-        loop {
-            let (stream, addr) = listener.accept().await?;
-            let this = Arc::clone(&this);
-            tokio::spawn(
-                async move {
-                    info!("incoming connection");
-                    if let Err(err) = this.handle_connection(stream).await {
-                        warn!(%err, "connection exited with error");
-                    } else {
-                        info!("connection exited");
-                    }
+    /// List the tunnels whose clients asked to be shown publicly, ordered by port.
+    fn listing(&self) -> Vec<ListedTunnel> {
+        let tunnels = self.tunnels.list().into_iter();
+        tunnels
+            .filter(|tunnel| tunnel.public_listing)
+            .map(|tunnel| ListedTunnel {
+                port: tunnel.port,
+                name: tunnel.name,
+                description: tunnel.description,
+                hostname: self.hostname(tunnel.port),
+            })
+            .collect()
+    }
+
+    async fn handle_admin(
+        &self,
+        stream: &mut Delimited<BoxedStream>,
+        request: AdminRequest,
+    ) -> Result<()> {
+        match request {
+            AdminRequest::ListBans => {
+                for (ip, remaining) in self.bans.bans() {
+                    let remaining_secs = remaining.as_secs();
+                    let response = AdminResponse::Ban { ip, remaining_secs };
+                    stream.send(ServerMessage::Admin(response)).await?;
                 }
-                .instrument(info_span!("control", ?addr)),
-            );
+            }
+            AdminRequest::Unban(ip) => {
+                if !self.bans.unban(ip) {
+                    stream
+                        .send(ServerMessage::Error(format!("{ip} is not banned")))
+                        .await?;
+                    return Ok(());
+                }
+                info!(%ip, "lifted ban");
+            }
+            AdminRequest::Ban { ip, duration_secs } => {
+                self.bans.ban(ip, Duration::from_secs(duration_secs));
+                let closed = self.tunnels.close_from(ip);
+                info!(%ip, duration_secs, closed, "banned by admin request");
+            }
+            AdminRequest::ClearBans => {
+                self.bans.clear();
+                info!("lifted all bans");
+            }
+            AdminRequest::Close(target) => match self.tunnels.close(&target) {
+                Some(port) => info!(port, "closed tunnel by admin request"),
+                None => {
+                    let message = format!("no open tunnel with {target}");
+                    stream.send(ServerMessage::Error(message)).await?;
+                    return Ok(());
+                }
+            },
+            AdminRequest::ListTunnels => {
+                for tunnel in self.tunnels.list() {
+                    let response = AdminResponse::Tunnel(AdminTunnel {
+                        port: tunnel.port,
+                        name: tunnel.name,
+                        description: tunnel.description,
+                        client: tunnel.client,
+                        client_host: tunnel.client_host,
+                        client_version: tunnel.client_version,
+                        identity: tunnel.identity.map(|identity| identity.0),
+                        uptime_secs: tunnel.uptime.as_secs(),
+                        connections: tunnel.connections,
+                        active: tunnel.active,
+                        received: tunnel.received,
+                        sent: tunnel.sent,
+                        rtt_us: tunnel.rtt.map(|rtt| rtt.as_micros() as u64),
+                        jitter_us: tunnel.jitter.map(|jitter| jitter.as_micros() as u64),
+                        expires_in_secs: tunnel.expires_in.map(|expires_in| expires_in.as_secs()),
+                    });
+                    stream.send(ServerMessage::Admin(response)).await?;
+                }
+            }
+            AdminRequest::Stats => {
+                let tunnels = self.tunnels.list();
+                let stats = AdminStats {
+                    version: env!("CARGO_PKG_VERSION").into(),
+                    uptime_secs: self.started.elapsed().as_secs(),
+                    tunnels: tunnels.len(),
+                    connections: tunnels.iter().map(|tunnel| tunnel.connections).sum(),
+                    active: tunnels.iter().map(|tunnel| tunnel.active).sum(),
+                    received: tunnels.iter().map(|tunnel| tunnel.received).sum(),
+                    sent: tunnels.iter().map(|tunnel| tunnel.sent).sum(),
+                    bans: self.bans.bans().len(),
+                    geoip_blocked: (self.geoip.as_ref())
+                        .map(|geoip| geoip.blocked_counts().into_iter().collect())
+                        .unwrap_or_default(),
+                };
+                let response = AdminResponse::Stats(stats);
+                stream.send(ServerMessage::Admin(response)).await?;
+            }
+            AdminRequest::Usage => {
+                let Some(quotas) = &self.quotas else {
+                    let message = "transfer quotas are not enabled".to_string();
+                    stream.send(ServerMessage::Error(message)).await?;
+                    return Ok(());
+                };
+                let period = quotas.period();
+                for (identity, used) in quotas.usage() {
+                    let response = AdminResponse::Usage(AdminUsage {
+                        identity,
+                        period: period.clone(),
+                        used,
+                        limit: quotas.limit(),
+                    });
+                    stream.send(ServerMessage::Admin(response)).await?;
+                }
+            }
         }
+        stream.send(ServerMessage::Admin(AdminResponse::Done)).await
     }
 
-    async fn create_listener(&self, port: u16) -> Result<TcpListener, &'static str> {
-        let try_bind = |port: u16| async move {
-            TcpListener::bind(("0.0.0.0", port))
-                .await
-                .map_err(|err| match err.kind() {
-                    io::ErrorKind::AddrInUse => "port already in use",
-                    io::ErrorKind::PermissionDenied => "permission denied",
-                    _ => "failed to bind to port",
-                })
-        };
-        if port > 0 {
-            // Client requests a specific port number.
-            if !self.port_range.contains(&port) {
-                return Err("client port number not in allowed range");
+    /// Bind the listener for a tunnel, preferring the port previously assigned to its name.
+    ///
+    /// Ports are restricted to the client's range, if any. If the client allows it, a random port
+    /// is assigned when the requested one is unavailable. The listener is bound on the tunnel bind
+    /// address, or on the address requested by the client if the bind policy allows it.
+    async fn assign_listener(
+        &self,
+        request: &TunnelRequest,
+        identity: Option<&Identity>,
+        credentials: &Credentials,
+    ) -> Result<TunnelListener> {
+        if let Some(name) = &request.name {
+            if name.len() > MAX_NAME_LENGTH {
+                return Err(Error::Protocol("tunnel name is too long".into()));
             }
-            try_bind(port).await
-        } else {
-            // Client requests any available port in range.
-            //
-            // In this case, we bind to 150 random port numbers. We choose this value because in
-            // order to find a free port with probability at least 1-δ, when ε proportion of the
-            // ports are currently available, it suffices to check approximately -2 ln(δ) / ε
-            // independently and uniformly chosen ports (up to a second-order term in ε).
-            //
-            // Checking 150 times gives us 99.999% success at utilizing 85% of ports under these
-            // conditions, when ε=0.15 and δ=0.00001.
-            for _ in 0..150 {
-                let port = fastrand::u16(self.port_range.clone());
-                match try_bind(port).await {
-                    Ok(listener) => return Ok(listener),
-                    Err(_) => continue,
+        }
+        let ip = match request.bind_addr {
+            Some(ip) if !self.bind_policy.allows(ip) => {
+                return Err(bind_error(
+                    ErrorCode::AddressNotAllowed,
+                    "server does not allow binding to this address",
+                ));
+            }
+            Some(ip) => ip,
+            None => self.tunnel_bind_addr,
+        };
+        let ranges = match &request.port_range {
+            Some(range) => Cow::Owned(self.port_ranges.intersect(range)),
+            None => Cow::Borrowed(&self.port_ranges),
+        };
+        let name = request.name.as_deref();
+        // Clients can only be assigned ports that their secrets cover.
+        let denied = |message: String| Error::Refused {
+            code: ErrorCode::PermissionDenied,
+            message,
+        };
+        let open = &self.open_ports;
+        if request.port > 0
+            && !self
+                .scoped_secrets
+                .check(credentials, open, request.port, name)
+        {
+            let port = request.port;
+            return Err(denied(format!("port {port} requires a different secret")));
+        }
+        let Some(ranges) = self.scoped_secrets.ports(credentials, open, name, &ranges) else {
+            return Err(denied("tunnel name requires a different secret".into()));
+        };
+        if request.port == 0 {
+            let saved = match (&self.store, &request.name) {
+                (Some(store), Some(name)) => store.get(name),
+                _ => None,
+            };
+            if let Some(port) = saved {
+                if let Ok(listener) = self
+                    .create_listener(ip, port, &ranges, identity, name)
+                    .await
+                {
+                    return Ok(listener);
                 }
             }
-            Err("failed to find an available port")
+        }
+        match self
+            .create_listener(ip, request.port, &ranges, identity, name)
+            .await
+        {
+            Err(err) if request.port > 0 && request.fallback_random => {
+                info!(port = request.port, %err, "requested port unavailable, assigning another");
+                self.create_listener(ip, 0, &ranges, identity, name).await
+            }
+            result => result,
         }
     }
 
-    async fn handle_connection(&self, stream: TcpStream) -> Result<()> {
-        let mut stream = Delimited::new(stream);
-        if let Some(auth) = &self.auth {
-            if let Err(err) = auth.server_handshake(&mut stream).await {
-                warn!(%err, "server handshake failed");
-                stream.send(ServerMessage::Error(err.to_string())).await?;
-                return Ok(());
+    async fn handle_tunnel(
+        &self,
+        mut stream: Delimited<BoxedStream>,
+        request: TunnelRequest,
+        addr: SocketAddr,
+        identity: Option<Identity>,
+        credentials: Credentials,
+    ) -> Result<()> {
+        if request.server_info {
+            stream.send(ServerMessage::Info(self.info())).await?;
+        }
+        if let Err(message) = self.check_version(&request) {
+            warn!(client_version = ?request.client_version, "refused outdated client");
+            let err = Error::Refused {
+                code: ErrorCode::ClientOutdated,
+                message,
+            };
+            return refuse(&mut stream, &request, err).await;
+        }
+        if self.over_quota(identity.as_ref()) {
+            warn!("refused tunnel over transfer quota");
+            let err = Error::Refused {
+                code: ErrorCode::QuotaExceeded,
+                message: "transfer quota exceeded for this month".into(),
+            };
+            return refuse(&mut stream, &request, err).await;
+        }
+        if let Err(message) = check_metadata(&request) {
+            let err = Error::Refused {
+                code: ErrorCode::InvalidRequest,
+                message,
+            };
+            return refuse(&mut stream, &request, err).await;
+        }
+        if !self.in_schedule(identity.as_ref()) {
+            warn!("refused tunnel outside of scheduled window");
+            let err = Error::Refused {
+                code: ErrorCode::PermissionDenied,
+                message: "tunnels are not allowed at this time".into(),
+            };
+            return refuse(&mut stream, &request, err).await;
+        }
+        // Without authentication, every client has the same identity, so anyone who guesses the
+        // name of a shared tunnel could join it and be handed its visitors.
+        if request.shared && self.auth.is_none() {
+            let err = Error::Refused {
+                code: ErrorCode::InvalidRequest,
+                message: "shared tunnels require a server with authentication".into(),
+            };
+            return refuse(&mut stream, &request, err).await;
+        }
+        let group = match (&request.name, request.shared) {
+            (Some(name), true) => self.groups.get(name).map(|group| Arc::clone(&group)),
+            _ => None,
+        };
+        if let Some(group) = group {
+            if group.identity != identity {
+                let err = Error::Refused {
+                    code: ErrorCode::PortInUse,
+                    message: "tunnel name is held by another client".into(),
+                };
+                return refuse(&mut stream, &request, err).await;
+            }
+            let (accepted_tx, accepted) = mpsc::channel(ACCEPT_QUEUE_SIZE);
+            // A tunnel that is closing no longer takes members, and is opened anew below.
+            if group.join(accepted_tx) {
+                return self
+                    .join_tunnel(stream, request, addr, group, accepted)
+                    .await;
+            }
+        }
+        let key = match (&request.name, request.resume_token) {
+            (Some(name), _) => Some(ParkKey::Name(name.clone())),
+            (None, Some(token)) => Some(ParkKey::Token(token)),
+            (None, None) => None,
+        };
+        // Parked tunnels belong to their identity, so another one cannot hold the same name and
+        // replace it.
+        let owner = key
+            .as_ref()
+            .and_then(|key| Some(self.parked.get(key)?.1.clone()));
+        if owner.is_some_and(|owner| owner != identity) {
+            let err = Error::Refused {
+                code: ErrorCode::PortInUse,
+                message: "tunnel name is held by another client".into(),
+            };
+            return refuse(&mut stream, &request, err).await;
+        }
+        // Clients cannot take over a tunnel of another identity, or on a port that their secret
+        // does not cover.
+        let allowed = |port, owner: &Option<Identity>| {
+            let name = request.name.as_deref();
+            *owner == identity
+                && (self.scoped_secrets).check(&credentials, &self.open_ports, port, name)
+        };
+        let reclaimed = match &key {
+            Some(key) => self.reclaim(key, allowed).await,
+            None => None,
+        };
+        let resumed = reclaimed.is_some();
+        let requested_expiry = request
+            .expires_secs
+            .map(|secs| Instant::now() + Duration::from_secs(secs));
+        // A resumed tunnel keeps the lifetime it was opened with.
+        let (mut listener, mut queue, mut screening, claim, stats, expires, mut port_bucket) =
+            match reclaimed {
+                Some(parked) => (
+                    parked.listener,
+                    parked.queue,
+                    parked.screening,
+                    parked.claim,
+                    parked.stats,
+                    parked.expires.or(requested_expiry),
+                    parked.port_bucket,
+                ),
+                None => match self
+                    .assign_listener(&request, identity.as_ref(), &credentials)
+                    .instrument(info_span!("assign_port", requested = request.port))
+                    .await
+                {
+                    Ok(listener) => {
+                        let stats = self.new_stats(identity.as_ref(), request.transfer_limit);
+                        let screening = JoinSet::new();
+                        (
+                            listener,
+                            VecDeque::new(),
+                            screening,
+                            None,
+                            stats,
+                            requested_expiry,
+                            self.limiter.port_bucket(),
+                        )
+                    }
+                    Err(err) => return refuse(&mut stream, &request, err).await,
+                },
+            };
+        let port = listener.local_addr()?.port();
+        Span::current().record("port", port);
+        info!(
+            name = ?request.name,
+            description = ?request.description,
+            client_host = ?request.client_host,
+            client_version = ?request.client_version,
+            "new client"
+        );
+        if let (Some(store), Some(name)) = (&self.store, &request.name) {
+            if let Err(err) = store.set(name, port) {
+                warn!(%err, "failed to persist port assignment");
+            }
+        }
+        stream.send(ServerMessage::Hello(port)).await?;
+        if request.hostname {
+            stream
+                .send(ServerMessage::Hostname(self.hostname(port)))
+                .await?;
+        }
+        let (pool_tx, mut pool) = mpsc::channel(MAX_POOL_SIZE);
+        let _pool_guard = match request.pool {
+            true => {
+                let token = Uuid::new_v4();
+                self.pools.insert(token, pool_tx);
+                stream.send(ServerMessage::Pool(token)).await?;
+                Some(PoolGuard(&self.pools, token))
             }
+            false => None,
+        };
+        if request.bind_addr.is_some() {
+            let ip = listener.local_addr()?.ip();
+            stream.send(ServerMessage::Bound(ip)).await?;
+        }
+        // Named tunnels are resumed by their name, so they need no token.
+        let token =
+            (request.resumable && request.name.is_none() && !self.reconnect_grace.is_zero())
+                .then(Uuid::new_v4);
+        if request.resumable {
+            stream.send(ServerMessage::Resume(token)).await?;
+        }
+        let opened = Instant::now();
+        let stop = CancellationToken::new();
+        let registration = self.tunnels.register(ActiveTunnel {
+            port,
+            name: request.name.clone(),
+            description: request.description.clone(),
+            client: addr,
+            client_host: request.client_host.clone(),
+            client_version: request.client_version.clone(),
+            public_listing: request.public_listing,
+            identity: identity.clone(),
+            opened,
+            expires,
+            stats: Arc::clone(&stats),
+            stop: stop.clone(),
+        });
+        let opened_event = ServerEvent::TunnelOpened {
+            port,
+            name: request.name.clone(),
+            client: addr,
+        };
+        event::emit(self.events.as_ref(), opened_event);
+        if let Some(observer) = &self.observer {
+            observer.on_tunnel_open(identity.as_ref(), port);
         }
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.notify(TunnelEvent::Opened {
+                port,
+                name: request.name.clone(),
+                client: addr,
+                authenticated: self.auth.is_some(),
+            });
+        }
+        if let (Some(dns), Some(name)) = (&self.dns, &request.name) {
+            dns.publish(name);
+        }
+        self.audit(AuditEvent::Opened {
+            port,
+            name: request.name.clone(),
+            client: addr,
+            identity: identity.as_ref().map(|identity| identity.0.clone()),
+            resumed,
+        });
+        // Tunnels bound on another address, such as loopback for a private tunnel, are not
+        // forwarded by other nodes.
+        let public = listener.local_addr()?.ip() == self.tunnel_bind_addr;
+        let cluster = self.cluster.as_ref().filter(|_| public);
+        let claim = claim.or_else(|| cluster.map(|cluster| cluster.claim(port)));
 
-        match stream.recv_timeout().await? {
-            Some(ClientMessage::Authenticate(_)) => {
-                warn!("unexpected authenticate");
-                Ok(())
+        let (accepted_tx, mut accepted) = mpsc::channel(ACCEPT_QUEUE_SIZE);
+        let group = match (&request.name, request.shared) {
+            (Some(name), true) => {
+                let group = Arc::new(Group {
+                    name: name.clone(),
+                    port,
+                    bound: listener.local_addr()?.ip(),
+                    identity: identity.clone(),
+                    members: Mutex::new(Members {
+                        senders: vec![accepted_tx.clone()],
+                        ..Default::default()
+                    }),
+                    next: AtomicUsize::new(0),
+                    left: Notify::new(),
+                    stop: stop.clone(),
+                });
+                self.groups.insert(name.clone(), Arc::clone(&group));
+                Some(group)
             }
-            Some(ClientMessage::Hello(port)) => {
-                let listener = match self.create_listener(port).await {
-                    Ok(listener) => listener,
-                    Err(err) => {
-                        stream.send(ServerMessage::Error(err.into())).await?;
-                        return Ok(());
+            _ => None,
+        };
+        let (sharded_tx, mut sharded) = mpsc::channel(ACCEPT_QUEUE_SIZE);
+        // Shards only accept, leaving the checks on each visitor to the loop below. They are
+        // bound anew for each control connection, and closed when it ends.
+        let mut shards = JoinSet::new();
+        match self.socket_options.bind_shards(&listener) {
+            Ok(listeners) => {
+                for shard in listeners {
+                    let sharded_tx = sharded_tx.clone();
+                    shards.spawn(async move {
+                        loop {
+                            let result = shard.accept().await;
+                            let failed = result.is_err();
+                            if sharded_tx.send(result).await.is_err() || failed {
+                                return;
+                            }
+                        }
+                    });
+                }
+            }
+            Err(err) => warn!(%err, "failed to bind accept shards"),
+        }
+        drop(sharded_tx);
+        // Visitors are accepted on their own, so that neither heartbeats nor slow announcements
+        // to the client delay them.
+        let dispatch = |conn| async {
+            match &group {
+                Some(group) => {
+                    group.dispatch(conn).await;
+                    true
+                }
+                None => accepted_tx.send(conn).await.is_ok(),
+            }
+        };
+        let accept = async {
+            loop {
+                let (stream2, addr) = tokio::select! {
+                    result = listener.accept_visitor() => result?,
+                    Some(result) = sharded.recv() => {
+                        let (conn, addr) = result?;
+                        (Incoming::Direct(conn), addr)
+                    }
+                    Some(Ok(Some(conn))) = screening.join_next() => {
+                        if !dispatch(conn).await {
+                            return Ok::<_, io::Error>(());
+                        }
+                        continue;
                     }
                 };
-                let port = listener.local_addr()?.port();
-                info!(?port, "new client");
-                stream.send(ServerMessage::Hello(port)).await?;
-
+                if !self.admit(port, addr, identity.as_ref(), &mut port_bucket) {
+                    continue;
+                }
+                let conn = self.visitor_conn(stream2, addr, port, &stats, identity.as_ref());
+                info!(visitor_addr = %addr, "new connection");
+                event::emit(
+                    self.events.as_ref(),
+                    ServerEvent::VisitorOpened { port, addr },
+                );
+                let name = request.name.as_deref();
+                let Some(conn) = self.screen(conn, name, identity.as_ref(), &mut screening) else {
+                    continue;
+                };
+                if !dispatch(conn).await {
+                    return Ok(());
+                }
+            }
+        };
+        // Visitors are accepted until no client is left to serve them.
+        let departed;
+        {
+            let control = self.control(
+                &mut stream,
+                &request,
+                &mut accepted,
+                &mut pool,
+                &mut queue,
+                &stats,
+            );
+            let expiry = async {
+                match expires {
+                    Some(expires) => sleep_until(expires.into()).await,
+                    None => pending().await,
+                }
+            };
+            let mut accept = pin!(accept);
+            departed = tokio::select! {
+                result = &mut accept => {
+                    result?;
+                    false
+                }
+                departed = control => departed,
+                _ = stop.cancelled() => false,
+                _ = expiry => {
+                    info!("tunnel expired");
+                    stop.cancel();
+                    false
+                }
+                _ = stats.exhausted() => {
+                    info!("tunnel reached its transfer limit");
+                    stop.cancel();
+                    if request.close_notice {
+                        let notice = ServerMessage::Closed {
+                            code: ErrorCode::TransferLimit,
+                            message: "tunnel reached its transfer limit".into(),
+                        };
+                        let _ = stream.send(notice).await;
+                    }
+                    false
+                }
+            };
+            // Keep visitors that were accepted but not announced, in case the client reconnects.
+            accepted.close();
+            while let Ok(conn) = accepted.try_recv() {
+                queue.push_back(conn);
+            }
+            if let Some(group) = &group {
+                // Other clients sharing the tunnel take over its visitors, until the last leaves.
+                for conn in queue.drain(..) {
+                    group.dispatch(conn).await;
+                }
                 loop {
-                    if stream.send(ServerMessage::Heartbeat).await.is_err() {
+                    if let Some(pending) = group.close() {
+                        queue.extend(pending);
+                        break;
+                    }
+                    tokio::select! {
+                        result = &mut accept => result?,
+                        _ = group.left.notified() => {}
+                    }
+                }
+                self.groups
+                    .remove_if(&group.name, |_, current| Arc::ptr_eq(current, group));
+            }
+        }
+        // Only the main listener is kept while the client reconnects.
+        drop(shards);
+        while let Ok(Ok((conn, addr))) = sharded.try_recv() {
+            if self.admit(port, addr, identity.as_ref(), &mut port_bucket) {
+                let conn = Incoming::Direct(conn);
+                let conn = self.visitor_conn(conn, addr, port, &stats, identity.as_ref());
+                let name = request.name.as_deref();
+                queue.extend(self.screen(conn, name, identity.as_ref(), &mut screening));
+            }
+        }
+        drop(registration);
+
+        let key = match (&request.name, token) {
+            (Some(name), _) => Some(ParkKey::Name(name.clone())),
+            (None, Some(token)) => Some(ParkKey::Token(token)),
+            (None, None) => None,
+        };
+        if let Some(key) = key {
+            // Tunnels that were closed on purpose, by either side, are not kept for the client to
+            // resume.
+            let closing = self.shutdown.is_cancelled() || stop.is_cancelled() || departed;
+            if !self.reconnect_grace.is_zero() && !closing {
+                let parked = Parked {
+                    listener,
+                    queue,
+                    screening,
+                    claim,
+                    stats: Arc::clone(&stats),
+                    expires,
+                    identity: identity.clone(),
+                    port_bucket,
+                };
+                if self.park(key, parked).await? {
+                    return Ok(());
+                }
+            }
+        }
+        let closed_event = ServerEvent::TunnelClosed {
+            port,
+            name: request.name.clone(),
+            client: addr,
+        };
+        event::emit(self.events.as_ref(), closed_event);
+        if let Some(observer) = &self.observer {
+            observer.on_tunnel_close(port, &stats);
+        }
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.notify(TunnelEvent::Closed {
+                port,
+                name: request.name.clone(),
+                client: addr,
+                duration_secs: opened.elapsed().as_secs(),
+            });
+        }
+        if let (Some(dns), Some(name)) = (&self.dns, &request.name) {
+            dns.withdraw(name);
+        }
+        self.audit(AuditEvent::Closed {
+            port,
+            name: request.name,
+            client: addr,
+            identity: identity.map(|identity| identity.0),
+            duration_secs: opened.elapsed().as_secs(),
+            connections: stats.connections(),
+            received: stats.received(),
+            sent: stats.sent(),
+        });
+        Ok(())
+    }
+
+    /// Serve a client that joined a shared tunnel, announcing the visitors it is handed in turn.
+    async fn join_tunnel(
+        &self,
+        mut stream: Delimited<BoxedStream>,
+        request: TunnelRequest,
+        addr: SocketAddr,
+        group: Arc<Group>,
+        mut accepted: mpsc::Receiver<VisitorConn>,
+    ) -> Result<()> {
+        Span::current().record("port", group.port);
+        info!(name = group.name, "client joined shared tunnel");
+        self.audit(AuditEvent::Joined {
+            port: group.port,
+            name: group.name.clone(),
+            client: addr,
+            identity: group.identity.as_ref().map(|identity| identity.0.clone()),
+        });
+        stream.send(ServerMessage::Hello(group.port)).await?;
+        if request.hostname {
+            stream
+                .send(ServerMessage::Hostname(self.hostname(group.port)))
+                .await?;
+        }
+        let (pool_tx, mut pool) = mpsc::channel(MAX_POOL_SIZE);
+        let _pool_guard = match request.pool {
+            true => {
+                let token = Uuid::new_v4();
+                self.pools.insert(token, pool_tx);
+                stream.send(ServerMessage::Pool(token)).await?;
+                Some(PoolGuard(&self.pools, token))
+            }
+            false => None,
+        };
+        if request.bind_addr.is_some() {
+            stream.send(ServerMessage::Bound(group.bound)).await?;
+        }
+        // Shared tunnels are named, so they are resumed by their name.
+        if request.resumable {
+            stream.send(ServerMessage::Resume(None)).await?;
+        }
+        // Traffic counts towards the tunnel, but each member measures its own round trips.
+        let stats = Stats::default();
+        let mut queue = VecDeque::new();
+        let control = self.control(
+            &mut stream,
+            &request,
+            &mut accepted,
+            &mut pool,
+            &mut queue,
+            &stats,
+        );
+        tokio::select! {
+            _ = control => {}
+            _ = group.stop.cancelled() => {}
+        }
+        accepted.close();
+        while let Ok(conn) = accepted.try_recv() {
+            queue.push_back(conn);
+        }
+        for conn in queue {
+            group.dispatch(conn).await;
+        }
+        group.left.notify_one();
+        info!(name = group.name, "client left shared tunnel");
+        self.audit(AuditEvent::Left {
+            port: group.port,
+            name: group.name.clone(),
+            client: addr,
+        });
+        Ok(())
+    }
+
+    /// Announce visitors on the control connection of a tunnel and send heartbeats, until the
+    /// client disconnects or the server shuts down.
+    ///
+    /// Returns whether the client said goodbye. Visitors that could not be announced are left in
+    /// `queue`.
+    async fn control(
+        &self,
+        stream: &mut Delimited<BoxedStream>,
+        request: &TunnelRequest,
+        accepted: &mut mpsc::Receiver<VisitorConn>,
+        pool: &mut mpsc::Receiver<Delimited<BoxedStream>>,
+        queue: &mut VecDeque<VisitorConn>,
+        stats: &Stats,
+    ) -> bool {
+        // Hand over visitors that arrived while the client was reconnecting.
+        while let Some(conn) = queue.pop_front() {
+            if let Err(conn) = self.announce(stream, conn, request.visitor_addr).await {
+                queue.push_front(conn);
+                return false;
+            }
+        }
+        let mut heartbeats = interval(HEARTBEAT_INTERVAL);
+        heartbeats.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut probes = Probes::default();
+        loop {
+            tokio::select! {
+                _ = heartbeats.tick() => {
+                    let heartbeat = match request.measure_rtt {
+                        true => ServerMessage::Probe {
+                            seq: probes.send(),
+                            rtt_us: stats.rtt().map(|rtt| rtt.as_micros() as u64),
+                        },
+                        false => ServerMessage::Heartbeat,
+                    };
+                    if stream.send(heartbeat).await.is_err() {
                         // Assume that the TCP connection has been dropped.
-                        return Ok(());
-                    }
-                    const TIMEOUT: Duration = Duration::from_millis(500);
-                    if let Ok(result) = timeout(TIMEOUT, listener.accept()).await {
-                        let (stream2, addr) = result?;
-                        info!(?addr, ?port, "new connection");
-
-                        let id = Uuid::new_v4();
-                        let conns = Arc::clone(&self.conns);
-
-                        conns.insert(id, stream2);
-                        tokio::spawn(async move {
-                            // Remove stale entries to avoid memory leaks.
-                            sleep(Duration::from_secs(10)).await;
-                            if conns.remove(&id).is_some() {
-                                warn!(%id, "removed stale connection");
-                            }
-                        });
-                        stream.send(ServerMessage::Connection(id)).await?;
+                        return false;
+                    }
+                }
+                message = stream.recv() => match message {
+                    Ok(Some(ClientMessage::Echo(seq))) => {
+                        if let Some(rtt) = probes.echoed(seq) {
+                            stats.record_rtt(rtt);
+                        }
                     }
+                    Ok(Some(ClientMessage::Goodbye)) => {
+                        info!("client said goodbye");
+                        return true;
+                    }
+                    Ok(Some(_)) => warn!("unexpected message on control connection"),
+                    Ok(None) | Err(_) => return false,
+                },
+                Some(stream2) = accepted.recv() => {
+                    let with_addr = request.visitor_addr;
+                    let dispatched = self.dispatch_pooled(pool, stream2, with_addr);
+                    let Err(stream2) = dispatched.await else {
+                        continue;
+                    };
+                    if let Err(conn) = self.announce(stream, stream2, with_addr).await {
+                        queue.push_back(conn);
+                        return false;
+                    }
+                }
+                _ = self.shutdown.cancelled() => {
+                    if request.shutdown_notice {
+                        let notice = ServerMessage::Shutdown {
+                            reason: self.shutdown_reason.clone(),
+                            retry_after: self.retry_after.map(|after| after.as_secs()),
+                        };
+                        let _ = stream.send(notice).await;
+                    }
+                    return false;
                 }
             }
-            Some(ClientMessage::Accept(id)) => {
-                info!(%id, "forwarding connection");
-                match self.conns.remove(&id) {
-                    Some((_, mut stream2)) => {
-                        let parts = stream.into_parts();
-                        debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
-                        stream2.write_all(&parts.read_buf).await?;
-                        proxy(parts.io, stream2).await?
+        }
+    }
+
+    /// Check whether a visitor passes the rate limits and GeoIP filter of a tunnel.
+    fn admit(
+        &self,
+        port: u16,
+        addr: SocketAddr,
+        identity: Option<&Identity>,
+        port_bucket: &mut Option<TokenBucket>,
+    ) -> bool {
+        if self.bans.is_banned(addr.ip()) {
+            debug!(visitor_addr = %addr, "refused visitor connection from banned address");
+            return false;
+        }
+        if self.over_quota(identity) {
+            debug!(visitor_addr = %addr, "refused visitor connection over transfer quota");
+            return false;
+        }
+        if !self.limiter.check_ip(addr.ip())
+            || !port_bucket.as_mut().is_none_or(TokenBucket::try_acquire)
+        {
+            debug!(visitor_addr = %addr, "rate limited visitor connection");
+            return false;
+        }
+        if let Some(geoip) = &self.geoip {
+            if let Err(country) = geoip.check(port, addr.ip()) {
+                let blocked = geoip.blocked_total();
+                debug!(visitor_addr = %addr, %country, blocked, "blocked visitor by country");
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Check the version that the client reported against the minimum, if there is one.
+    fn check_version(&self, request: &TunnelRequest) -> Result<(), String> {
+        let Some(min) = self.min_client_version else {
+            return Ok(());
+        };
+        let Some(reported) = &request.client_version else {
+            return Err(format!(
+                "{VERSION_REQUIRED}, server requires bore {min} or newer"
+            ));
+        };
+        match reported.parse::<Version>() {
+            Ok(version) if version >= min => Ok(()),
+            Ok(_) => Err(format!(
+                "client version {reported} is outdated, server requires bore {min} or newer"
+            )),
+            Err(_) => Err(format!(
+                "client version {reported} is not recognized, server requires bore {min} or newer"
+            )),
+        }
+    }
+
+    /// Returns whether a client identity has used up its transfer quota.
+    fn over_quota(&self, identity: Option<&Identity>) -> bool {
+        match (&self.quotas, identity) {
+            (Some(quotas), Some(identity)) => quotas.exceeded(&identity.0),
+            _ => false,
+        }
+    }
+
+    /// Append an event to the audit log, if there is one.
+    fn audit(&self, event: AuditEvent) {
+        if let Some(audit) = &self.audit {
+            audit.record(event);
+        }
+    }
+
+    /// Returns whether a client identity may hold tunnels now.
+    fn in_schedule(&self, identity: Option<&Identity>) -> bool {
+        match (&self.schedules, identity) {
+            (Some(schedules), Some(identity)) => schedules.allows(&identity.0, SystemTime::now()),
+            _ => true,
+        }
+    }
+
+    /// Close the tunnels of client identities whose scheduled windows have ended.
+    fn sweep_schedules(&self) {
+        if self.schedules.is_none() {
+            return;
+        }
+        for tunnel in self.tunnels.0.iter() {
+            if !tunnel.stop.is_cancelled() && !self.in_schedule(tunnel.identity.as_ref()) {
+                info!(
+                    port = tunnel.port,
+                    "closing tunnel outside of scheduled window"
+                );
+                tunnel.stop.cancel();
+            }
+        }
+    }
+
+    /// Create the transfer counters of a new tunnel, counting towards its client's quota.
+    fn new_stats(&self, identity: Option<&Identity>, transfer_limit: Option<u64>) -> Arc<Stats> {
+        let quota = match (&self.quotas, identity) {
+            (Some(quotas), Some(identity)) => Some(quotas.counter(&identity.0)),
+            _ => None,
+        };
+        let limit = match (transfer_limit, self.transfer_limit) {
+            (Some(requested), Some(limit)) => Some(requested.min(limit)),
+            (requested, limit) => requested.or(limit),
+        };
+        Arc::new(Stats::with_limits(quota, limit))
+    }
+
+    /// Returns the acceptor to decrypt a visitor connection with, if its port terminates TLS.
+    fn visitor_tls(&self, conn: &VisitorConn) -> Option<TlsAcceptor> {
+        let (ports, acceptor) = self.visitor_tls.as_ref()?;
+        ports.contains(conn.port).then(|| acceptor.clone())
+    }
+
+    /// Prepare a visitor connection accepted on a tunnel's port, counting its traffic.
+    fn visitor_conn(
+        &self,
+        conn: Incoming,
+        addr: SocketAddr,
+        port: u16,
+        stats: &Arc<Stats>,
+        identity: Option<&Identity>,
+    ) -> VisitorConn {
+        let conn: BoxedStream = match conn {
+            Incoming::Direct(conn) => {
+                if let Err(err) = self.socket_options.apply(&conn) {
+                    warn!(%err, visitor_addr = %addr, "failed to apply socket options");
+                }
+                Box::new(conn)
+            }
+            Incoming::Relayed(stream) => stream,
+        };
+        let identity_rate = identity.and_then(|id| self.identity_connection_rates.get(&id.0));
+        let stream: BoxedStream = match identity_rate.or(self.connection_rate.as_ref()) {
+            Some(&rate) => {
+                let limit = || Some(Arc::new(Bandwidth::new(rate)));
+                Box::new(Throttled::new(stats.track(conn), limit(), limit()))
+            }
+            None => Box::new(stats.track(conn)),
+        };
+        VisitorConn { stream, addr, port }
+    }
+
+    /// Pass a visitor connection through the middleware, if there is any.
+    ///
+    /// Returns the connection at once without middleware. Otherwise it is screened on a task in
+    /// `screening`, which yields it if it passes.
+    fn screen(
+        &self,
+        conn: VisitorConn,
+        name: Option<&str>,
+        identity: Option<&Identity>,
+        screening: &mut JoinSet<Option<VisitorConn>>,
+    ) -> Option<VisitorConn> {
+        let Some(middleware) = &self.middleware else {
+            return Some(conn);
+        };
+        let middleware = Arc::clone(middleware);
+        let visitor = VisitorInfo {
+            addr: conn.addr,
+            port: conn.port,
+            name: name.map(String::from),
+            identity: identity.cloned(),
+        };
+        screening.spawn(
+            async move {
+                let stream = middleware.on_visitor(&visitor, conn.stream).await;
+                if stream.is_none() {
+                    debug!(visitor_addr = %visitor.addr, "middleware dropped visitor connection");
+                }
+                stream.map(|stream| VisitorConn { stream, ..conn })
+            }
+            .in_current_span(),
+        );
+        None
+    }
+
+    /// Hand a visitor that another node of the cluster relayed to the tunnel on its port.
+    async fn relay(&self, stream: Delimited<BoxedStream>, port: u16, addr: SocketAddr) {
+        let Some(relay_tx) = self.relays.get(&port).map(|relay_tx| relay_tx.clone()) else {
+            debug!(port, visitor_addr = %addr, "no tunnel for relayed visitor");
+            return;
+        };
+        let parts = stream.into_parts();
+        let stream = Box::new(Prefixed::new(parts.read_buf.to_vec(), parts.io));
+        if relay_tx.send((stream, addr)).await.is_err() {
+            debug!(port, visitor_addr = %addr, "tunnel closed before relayed visitor arrived");
+        }
+    }
+
+    /// Queue a visitor of a parked tunnel for its client, unless the queue is full.
+    fn hold(&self, queue: &mut VecDeque<VisitorConn>, conn: VisitorConn) {
+        let addr = conn.addr;
+        if queue.len() < self.reconnect_queue {
+            info!(visitor_addr = %addr, "queued connection until client reconnects");
+            queue.push_back(conn);
+        } else {
+            warn!(visitor_addr = %addr, "reconnect queue full, dropping connection");
+        }
+    }
+
+    /// Hand a visitor to an idle pooled data connection of its tunnel.
+    ///
+    /// Returns the visitor connection if no pooled connection could take it.
+    async fn dispatch_pooled(
+        &self,
+        pool: &mut mpsc::Receiver<Delimited<BoxedStream>>,
+        conn: VisitorConn,
+        with_addr: bool,
+    ) -> Result<(), VisitorConn> {
+        while let Ok(mut pooled) = pool.try_recv() {
+            let id = Uuid::new_v4();
+            let message = self.connection_message(id, &conn, with_addr);
+            if pooled.send(message).await.is_err() {
+                // The client closed this connection while it was idle.
+                continue;
+            }
+            info!(%id, "forwarding connection to pooled stream");
+            let tls = self.visitor_tls(&conn);
+            let events = self.events.clone();
+            let buffer_size = self.proxy_buffer_size;
+            tokio::spawn(
+                async move {
+                    let events = events.as_ref();
+                    let forward = forward_visitor(id, pooled, conn, tls, buffer_size, events);
+                    if let Err(err) = forward.await {
+                        warn!(%id, %err, "pooled connection exited with error");
                     }
-                    None => warn!(%id, "missing connection"),
                 }
-                Ok(())
+                .in_current_span(),
+            );
+            return Ok(());
+        }
+        Err(conn)
+    }
+
+    /// Returns the message that asks the client to accept a visitor connection, with the
+    /// visitor's address if `with_addr` is set.
+    fn connection_message(&self, id: Uuid, conn: &VisitorConn, with_addr: bool) -> ServerMessage {
+        match with_addr {
+            true => ServerMessage::Visitor {
+                id,
+                addr: conn.addr,
+                port: Some(conn.port),
+            },
+            false => ServerMessage::Connection(id),
+        }
+    }
+
+    /// Store a visitor connection and ask the client to accept it, including the visitor's
+    /// address if `with_addr` is set.
+    ///
+    /// Returns the connection if the control connection has been dropped.
+    async fn announce(
+        &self,
+        stream: &mut Delimited<BoxedStream>,
+        conn: VisitorConn,
+        with_addr: bool,
+    ) -> Result<(), VisitorConn> {
+        let id = Uuid::new_v4();
+        let message = self.connection_message(id, &conn, with_addr);
+        let conns = Arc::clone(&self.conns);
+        let expiry = self.pending_expiry;
+
+        conns.insert(id, conn);
+        tokio::spawn(
+            async move {
+                // Remove stale entries to avoid memory leaks.
+                sleep(expiry).await;
+                if conns.remove(&id).is_some() {
+                    warn!(%id, "removed stale connection");
+                }
             }
-            None => Ok(()),
+            .in_current_span(),
+        );
+        if stream.send(message).await.is_err() {
+            if let Some((_, conn)) = self.conns.remove(&id) {
+                return Err(conn);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep a tunnel's port and queue new visitors while its client reconnects.
+    ///
+    /// Returns whether the tunnel was handed over to a reconnected client.
+    async fn park(&self, key: ParkKey, mut parked: Parked) -> Result<bool> {
+        let port = parked.listener.local_addr()?.port();
+        let (reclaim_tx, mut reclaim_rx) = oneshot::channel::<oneshot::Sender<Parked>>();
+        let name = match &key {
+            ParkKey::Name(name) => Some(name.clone()),
+            ParkKey::Token(_) => None,
+        };
+        let owner = parked.identity.clone();
+        self.parked.insert(key.clone(), (port, owner, reclaim_tx));
+        info!(?name, "holding tunnel for reconnect");
+
+        let deadline = sleep(self.reconnect_grace);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                reply = &mut reclaim_rx => {
+                    if let Ok(reply) = reply {
+                        let _ = reply.send(parked);
+                    }
+                    return Ok(true);
+                }
+                result = parked.listener.accept_visitor() => {
+                    let (conn, addr) = result?;
+                    let identity = parked.identity.as_ref();
+                    if !self.admit(port, addr, identity, &mut parked.port_bucket) {
+                        continue;
+                    }
+                    let conn = self.visitor_conn(conn, addr, port, &parked.stats, identity);
+                    let screening = &mut parked.screening;
+                    if let Some(conn) = self.screen(conn, name.as_deref(), identity, screening) {
+                        self.hold(&mut parked.queue, conn);
+                    }
+                }
+                Some(Ok(Some(conn))) = parked.screening.join_next() => {
+                    self.hold(&mut parked.queue, conn);
+                }
+            }
+        }
+
+        self.parked.remove(&key);
+        info!(
+            ?name,
+            dropped = parked.queue.len(),
+            "client did not reconnect in time"
+        );
+        Ok(false)
+    }
+
+    /// Take over the listener and queued visitors of a parked tunnel, if its port and owner are
+    /// allowed.
+    async fn reclaim(
+        &self,
+        key: &ParkKey,
+        allowed: impl Fn(u16, &Option<Identity>) -> bool,
+    ) -> Option<Parked> {
+        let (_, (_, _, reclaim_tx)) = self
+            .parked
+            .remove_if(key, |_, (port, owner, _)| allowed(*port, owner))?;
+        let (reply_tx, reply_rx) = oneshot::channel();
+        reclaim_tx.send(reply_tx).ok()?;
+        reply_rx.await.ok()
+    }
+}
+
+/// Check that the description and host name of a tunnel request are fit to show operators.
+fn check_metadata(request: &TunnelRequest) -> Result<(), String> {
+    let fields = [
+        ("description", &request.description),
+        ("client host name", &request.client_host),
+    ];
+    for (field, value) in fields {
+        let Some(value) = value else { continue };
+        if value.len() > MAX_METADATA_LENGTH || value.chars().any(char::is_control) {
+            return Err(format!(
+                "{field} must be at most {MAX_METADATA_LENGTH} bytes, without control characters"
+            ));
         }
     }
+    Ok(())
+}
+
+/// Refuse a tunnel request, with the code of the error if the client understands codes.
+async fn refuse(
+    stream: &mut Delimited<BoxedStream>,
+    request: &TunnelRequest,
+    err: Error,
+) -> Result<()> {
+    let message = err.to_string();
+    let reply = match (request.error_codes, err.code()) {
+        (true, Some(code)) => ServerMessage::Failure { code, message },
+        _ => ServerMessage::Error(message),
+    };
+    stream.send(reply).await
+}
+
+/// Copy data between a visitor and the data connection that the client opened for it, then
+/// report the traffic of the visitor.
+///
+/// With a TLS acceptor, the visitor's TLS is decrypted first and only plaintext is forwarded.
+async fn forward_visitor(
+    id: Uuid,
+    data: Delimited<BoxedStream>,
+    visitor: VisitorConn,
+    tls: Option<TlsAcceptor>,
+    buffer_size: usize,
+    events: Option<&broadcast::Sender<ServerEvent>>,
+) -> io::Result<()> {
+    let VisitorConn {
+        stream: visitor,
+        addr,
+        port,
+    } = visitor;
+    let span = info_span!(
+        "proxy",
+        %id,
+        port,
+        visitor_addr = %addr,
+        received = field::Empty,
+        sent = field::Empty,
+    );
+    let parts = data.into_parts();
+    debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
+    let copy = async {
+        let mut visitor: BoxedStream = match tls {
+            Some(tls) => Box::new(
+                timeout(NETWORK_TIMEOUT, tls.accept(visitor))
+                    .await
+                    .map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "timed out waiting for TLS handshake",
+                        )
+                    })??,
+            ),
+            None => visitor,
+        };
+        visitor.write_all(&parts.read_buf).await?;
+        proxy(parts.io, visitor, buffer_size).await
+    };
+    let (sent, received) = copy.instrument(span.clone()).await?;
+    let sent = sent + parts.read_buf.len() as u64;
+    span.in_scope(|| info!(received, sent, "connection closed"));
+    span.record("received", received).record("sent", sent);
+    let closed = ServerEvent::VisitorClosed {
+        port,
+        addr,
+        received,
+        sent,
+    };
+    event::emit(events, closed);
+    Ok(())
 }
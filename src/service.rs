@@ -0,0 +1,257 @@
+//! Running `bore` as a native Windows service.
+//!
+//! A service wraps an ordinary `bore` command line, such as `local` or `server`. Stopping the
+//! service shuts the command down as if it received Ctrl-C, and its logs go to the Windows
+//! event log under the name of the service.
+//!
+//! `windows-service` has no wrapper for the event log, so this module calls the Win32 event log
+//! functions itself. It is the only unsafe code in `bore`, which is why it lives in the binary
+//! rather than the library.
+
+use std::ffi::{OsStr, OsString};
+use std::os::windows::ffi::OsStrExt;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use std::{env, io, iter, ptr};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::System::EventLog::{
+    DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+    EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE, REPORT_EVENT_TYPE,
+};
+
+use crate::{Args, Command};
+
+/// Name and command of the service run by this process, set before the dispatcher starts.
+static SERVICE: OnceLock<(String, Mutex<Option<Command>>)> = OnceLock::new();
+
+/// Token cancelled when the service control manager asks the service to stop.
+static STOP: OnceLock<CancellationToken> = OnceLock::new();
+
+/// Returns the token cancelled when the service is stopped.
+pub fn stop_token() -> &'static CancellationToken {
+    STOP.get_or_init(CancellationToken::new)
+}
+
+/// Parse the command line that a service runs, as if it were given to `bore`.
+fn parse_command(args: &[OsString]) -> Result<Command> {
+    let args = iter::once(OsString::from("bore")).chain(args.iter().cloned());
+    Ok(Args::try_parse_from(args)?.command)
+}
+
+/// Register a service that starts automatically and runs a `bore` command.
+pub fn install(name: &str, args: Vec<OsString>) -> Result<()> {
+    if let Command::Service { .. } = parse_command(&args)? {
+        bail!("a service cannot run another service command");
+    }
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+    let info = ServiceInfo {
+        name: name.into(),
+        display_name: format!("bore ({name})").into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: env::current_exe()?,
+        launch_arguments: ["service", "run", "--name", name, "--"]
+            .into_iter()
+            .map(OsString::from)
+            .chain(args)
+            .collect(),
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .with_context(|| format!("could not install service {name}"))?;
+    service.set_description("Exposes local ports to a remote server, or serves tunnels.")?;
+    Ok(())
+}
+
+/// Stop a service if it is running, and remove it.
+pub fn uninstall(name: &str) -> Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager
+        .open_service(
+            name,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .with_context(|| format!("could not open service {name}"))?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    service.delete()?;
+    Ok(())
+}
+
+/// Run a command under the service control manager, returning once the service stops.
+pub fn run(name: String, args: Vec<OsString>) -> Result<()> {
+    let command = parse_command(&args)?;
+    let _ = SERVICE.set((name.clone(), Mutex::new(Some(command))));
+    service_dispatcher::start(&name, ffi_service_main)
+        .context("could not start service, it must be started by the service control manager")
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(err) = run_service() {
+        error!("service failed: {err:#}");
+    }
+}
+
+fn run_service() -> Result<()> {
+    let (name, command) = SERVICE.get().context("service was not configured")?;
+    let command = command
+        .lock()
+        .unwrap()
+        .take()
+        .context("service already ran")?;
+    let handle = service_control_handler::register(name, |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            stop_token().cancel();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })?;
+    let status = |state: ServiceState, exit_code: u32| ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::ZERO,
+        process_id: None,
+    };
+
+    handle.set_service_status(status(ServiceState::Running, 0))?;
+    let result = crate::run(command);
+    if let Err(err) = &result {
+        error!("{err:#}");
+    }
+    let exit_code = if result.is_ok() { 0 } else { 1 };
+    handle.set_service_status(status(ServiceState::Stopped, exit_code))?;
+    Ok(())
+}
+
+/// Writes log lines to the Windows event log, with the event type matching their level.
+pub struct EventLog(HANDLE);
+
+// SAFETY: the handle is only passed to `ReportEventW` and `DeregisterEventSource`, which may be
+// called from any thread, and Windows does not tie event source handles to the thread that
+// registered them.
+unsafe impl Send for EventLog {}
+
+// SAFETY: `ReportEventW` may be called concurrently with the same handle, and the handle is only
+// closed in `drop`, which has exclusive access.
+unsafe impl Sync for EventLog {}
+
+impl EventLog {
+    /// Register an event source with the name of a service.
+    pub fn new(name: &str) -> Result<Self> {
+        let name = wide(OsStr::new(name));
+        // SAFETY: the source name is a valid null-terminated wide string.
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), name.as_ptr()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error()).context("could not open the event log");
+        }
+        Ok(EventLog(handle))
+    }
+
+    fn writer(&self, kind: REPORT_EVENT_TYPE) -> EventWriter<'_> {
+        EventWriter {
+            log: self,
+            kind,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        // SAFETY: the handle was returned by `RegisterEventSourceW`, and no writer borrowing the
+        // log outlives it.
+        unsafe { DeregisterEventSource(self.0) };
+    }
+}
+
+impl<'a> MakeWriter<'a> for EventLog {
+    type Writer = EventWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.writer(EVENTLOG_INFORMATION_TYPE)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.writer(match *meta.level() {
+            Level::ERROR => EVENTLOG_ERROR_TYPE,
+            Level::WARN => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        })
+    }
+}
+
+/// Buffers one log line, and reports it to the event log when dropped.
+pub struct EventWriter<'a> {
+    log: &'a EventLog,
+    kind: REPORT_EVENT_TYPE,
+    buf: Vec<u8>,
+}
+
+impl io::Write for EventWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventWriter<'_> {
+    fn drop(&mut self) {
+        let message = String::from_utf8_lossy(&self.buf);
+        let message = wide(OsStr::new(message.trim_end()));
+        let strings = [message.as_ptr()];
+        // SAFETY: the handle is a registered event source, and the single string is a valid
+        // null-terminated wide string that outlives the call.
+        unsafe {
+            ReportEventW(
+                self.log.0,
+                self.kind,
+                0,
+                0,
+                ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                ptr::null(),
+            );
+        }
+    }
+}
+
+/// Encode a string as a null-terminated wide string for Windows APIs.
+fn wide(s: &OsStr) -> Vec<u16> {
+    s.encode_wide().chain(iter::once(0)).collect()
+}
@@ -1,25 +1,160 @@
 //! Shared data structures, utilities, and protocol definitions.
 
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
 use std::time::Duration;
 
-use anyhow::{Context, Result};
 use futures_util::{SinkExt, StreamExt};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::io::{self, AsyncRead, AsyncWrite};
+#[cfg(feature = "server")]
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+#[cfg(feature = "server")]
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tokio::time::timeout;
-use tokio_util::codec::{AnyDelimiterCodec, Framed, FramedParts};
+use tokio_util::codec::{AnyDelimiterCodec, AnyDelimiterCodecError, Framed, FramedParts};
 use tracing::trace;
 use uuid::Uuid;
 
+use crate::error::{Error, ErrorCode, Result};
+use crate::ports::PortList;
+
 /// TCP port used for control connections with the server.
 pub const CONTROL_PORT: u16 = 7835;
 
+/// TCP port used for control connections over TLS, by default.
+pub const TLS_CONTROL_PORT: u16 = 7836;
+
+/// Prefix of the error sent by the server when a client fails to authenticate.
+pub const AUTH_FAILED: &str = "authentication failed";
+
+/// Prefix of the error sent by the server when it requires a client version and the client did
+/// not report one.
+pub const VERSION_REQUIRED: &str = "client version required";
+
 /// Maximum byte length for a JSON frame in the stream.
-pub const MAX_FRAME_LENGTH: usize = 256;
+///
+/// This fits a tunnel request whose name, description, and client host name are at their
+/// longest, even if every character is escaped as `\uXXXX`.
+pub const MAX_FRAME_LENGTH: usize = 8192;
+
+/// Maximum byte length of a tunnel name.
+pub const MAX_NAME_LENGTH: usize = 64;
+
+/// Maximum byte length of the description and client host name attached to a tunnel.
+pub const MAX_METADATA_LENGTH: usize = 256;
 
 /// Timeout for network connections and initial protocol messages.
 pub const NETWORK_TIMEOUT: Duration = Duration::from_secs(3);
 
+/// Maximum number of idle pooled data connections kept for a tunnel.
+pub const MAX_POOL_SIZE: usize = 16;
+
+/// Default size of the buffer copying each direction of a proxied connection.
+pub const DEFAULT_BUFFER_SIZE: usize = 8192;
+
+/// Default backlog of pending connections for listening sockets.
+pub const DEFAULT_BACKLOG: u32 = 1024;
+
+/// Time to wait after failing to accept a connection, such as when out of file descriptors,
+/// before trying again.
+pub const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// A bidirectional byte stream, such as a plain or TLS-wrapped TCP connection.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> AsyncStream for T {}
+
+/// Control connection whose transport is chosen at runtime.
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+/// Tuning options applied to TCP listeners and the streams they accept.
+#[cfg(feature = "server")]
+#[derive(Clone, Debug, Default)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm on accepted streams.
+    pub nodelay: bool,
+
+    /// Enable TCP keepalive, sending probes after this much idle time.
+    pub keepalive: Option<Duration>,
+
+    /// Interval between keepalive probes, if keepalive is enabled.
+    pub keepalive_interval: Option<Duration>,
+
+    /// Maximum number of pending connections, defaulting to [`DEFAULT_BACKLOG`].
+    pub backlog: Option<u32>,
+
+    /// Size of the kernel send buffer, in bytes.
+    pub send_buffer_size: Option<u32>,
+
+    /// Size of the kernel receive buffer, in bytes.
+    pub recv_buffer_size: Option<u32>,
+
+    /// Number of listeners bound to each address with `SO_REUSEPORT`, each accepting on its own
+    /// task, so that the kernel spreads new connections across them. Only supported on Unix.
+    pub accept_shards: Option<usize>,
+}
+
+#[cfg(feature = "server")]
+impl SocketOptions {
+    /// Bind a TCP listener with these options.
+    ///
+    /// Buffer sizes are set on the listening socket so that accepted streams inherit them.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        #[cfg(not(windows))]
+        socket.set_reuseaddr(true)?;
+        #[cfg(unix)]
+        if self.shards() > 1 {
+            socket.set_reuseport(true)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        socket.bind(addr)?;
+        socket.listen(self.backlog.unwrap_or(DEFAULT_BACKLOG))
+    }
+
+    /// Returns the number of listeners bound to each address, which is at least one.
+    pub fn shards(&self) -> usize {
+        match cfg!(unix) {
+            true => self.accept_shards.unwrap_or(1).max(1),
+            false => 1,
+        }
+    }
+
+    /// Bind the extra listeners that share the address of a listener bound with these options,
+    /// one fewer than [`SocketOptions::shards`].
+    pub fn bind_shards(&self, listener: &TcpListener) -> io::Result<Vec<TcpListener>> {
+        let addr = listener.local_addr()?;
+        (1..self.shards()).map(|_| self.bind(addr)).collect()
+    }
+
+    /// Apply per-stream options to a newly accepted or connected stream.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            let mut keepalive = TcpKeepalive::new().with_time(time);
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
 /// A message from the client on the control connection.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
@@ -29,8 +164,215 @@ pub enum ClientMessage {
     /// Initial client message specifying a port to forward.
     Hello(u16),
 
+    /// Initial client message with extended tunnel parameters, used in place of `Hello`.
+    Open(TunnelRequest),
+
     /// Accepts an incoming TCP connection, using this stream as a proxy.
     Accept(Uuid),
+
+    /// Offers this stream as an idle data connection for the tunnel with this pool token.
+    Pool(Uuid),
+
+    /// Announcement from another server node in the same cluster.
+    Cluster(ClusterMessage),
+
+    /// Request to administer the server, sent after authenticating.
+    Admin(AdminRequest),
+
+    /// Request for a description of the server, used to check that it is reachable.
+    Ping,
+
+    /// Reply to a [`ServerMessage::Probe`] on the control connection, with its sequence number.
+    Echo(u64),
+
+    /// Sent on the control connection by a client that is shutting down, so the server releases
+    /// the tunnel at once rather than holding it for a reconnect.
+    Goodbye,
+}
+
+/// Parameters of a tunnel requested by the client.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TunnelRequest {
+    /// Port on the remote server to select, or 0 for any available port.
+    pub port: u16,
+
+    /// Stable name for the tunnel, used to reassign the same port on reconnect.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// Whether to announce visitors with their address, as [`ServerMessage::Visitor`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub visitor_addr: bool,
+
+    /// Whether to assign a random port if the requested one is unavailable.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub fallback_random: bool,
+
+    /// Whether the client keeps a pool of idle data connections, as [`ServerMessage::Pool`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pool: bool,
+
+    /// Ports the client accepts when any available port is requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port_range: Option<PortList>,
+
+    /// Server address to bind the public port on, such as loopback for a private tunnel.
+    ///
+    /// The server confirms it with [`ServerMessage::Bound`], so clients can tell when it was
+    /// ignored by an older server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind_addr: Option<IpAddr>,
+
+    /// Whether the client answers heartbeats, so both sides can measure the round-trip time
+    /// of the control connection, as [`ServerMessage::Probe`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub measure_rtt: bool,
+
+    /// Whether the client asks for a token to resume the tunnel after a disconnect, as
+    /// [`ServerMessage::Resume`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub resumable: bool,
+
+    /// Token from an earlier control connection, to take over the port of its tunnel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume_token: Option<Uuid>,
+
+    /// Whether other clients may join the named tunnel, sharing its visitors in turn, or to
+    /// join it if another client already holds it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shared: bool,
+
+    /// Whether the client asks for a description of the server, as [`ServerMessage::Info`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub server_info: bool,
+
+    /// Whether the client understands error codes, so that the server refuses the request with
+    /// [`ServerMessage::Failure`] rather than [`ServerMessage::Error`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub error_codes: bool,
+
+    /// Whether the client understands [`ServerMessage::Shutdown`], sent before the server closes
+    /// the control connection to shut down.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shutdown_notice: bool,
+
+    /// Whether the client asks for the host name of the tunnel, as [`ServerMessage::Hostname`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hostname: bool,
+
+    /// Seconds after which the server closes the tunnel and releases its port, if it should not
+    /// stay open indefinitely.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_secs: Option<u64>,
+
+    /// Bytes that visitors of the tunnel may transfer in both directions, after which the server
+    /// closes it, if it should not transfer indefinitely.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transfer_limit: Option<u64>,
+
+    /// Whether the client understands [`ServerMessage::Closed`], sent before the server closes
+    /// the tunnel for a reason of its own.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub close_notice: bool,
+
+    /// Description of the tunnel, shown to the server's operators.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// Host name of the client's machine, shown to the server's operators.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_host: Option<String>,
+
+    /// Whether the server may show the tunnel's name, port, and description to anyone, in its
+    /// public listing of tunnels.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub public_listing: bool,
+
+    /// Version of the client, shown to the server's operators and checked against its minimum.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_version: Option<String>,
+}
+
+impl TunnelRequest {
+    /// Returns whether the request only specifies a port, so a plain `Hello` suffices.
+    ///
+    /// The client version alone does not call for an extended request, since older servers
+    /// would not understand one.
+    pub fn is_plain(&self) -> bool {
+        let TunnelRequest {
+            port: _,
+            name,
+            visitor_addr,
+            fallback_random,
+            pool,
+            port_range,
+            bind_addr,
+            measure_rtt,
+            resumable,
+            resume_token,
+            shared,
+            server_info,
+            error_codes,
+            shutdown_notice,
+            hostname,
+            expires_secs,
+            transfer_limit,
+            close_notice,
+            description,
+            client_host,
+            public_listing,
+            client_version: _,
+        } = self;
+        name.is_none()
+            && !visitor_addr
+            && !fallback_random
+            && !pool
+            && port_range.is_none()
+            && bind_addr.is_none()
+            && !measure_rtt
+            && !resumable
+            && resume_token.is_none()
+            && !shared
+            && !server_info
+            && !error_codes
+            && !shutdown_notice
+            && !hostname
+            && expires_secs.is_none()
+            && transfer_limit.is_none()
+            && !close_notice
+            && description.is_none()
+            && client_host.is_none()
+            && !public_listing
+    }
+}
+
+/// A message exchanged between server nodes in a cluster.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ClusterMessage {
+    /// The node hosts a tunnel on this public port.
+    Claim {
+        /// Address at which peers can reach the node.
+        node: String,
+        /// Public port of the tunnel.
+        port: u16,
+    },
+
+    /// The node no longer hosts a tunnel on this public port.
+    Release {
+        /// Address at which peers can reach the node.
+        node: String,
+        /// Public port of the tunnel.
+        port: u16,
+    },
+
+    /// A visitor of a tunnel hosted by the receiving node, accepted by the sending node, whose
+    /// connection continues on this stream.
+    Visitor {
+        /// Public port of the tunnel.
+        port: u16,
+        /// Address of the visitor.
+        addr: SocketAddr,
+    },
 }
 
 /// A message from the server on the control connection.
@@ -42,14 +384,305 @@ pub enum ServerMessage {
     /// Response to a client's initial message, with actual public port.
     Hello(u16),
 
+    /// Host name under which visitors reach the tunnel, sent right after `Hello` if requested.
+    ///
+    /// This is `None` if the server has no domain to assign subdomains of.
+    Hostname(Option<String>),
+
+    /// Secret token for offering pooled data connections, sent after `Hello` if requested.
+    Pool(Uuid),
+
+    /// Address the public port is bound on, sent after `Hello` and `Pool` if requested.
+    Bound(IpAddr),
+
+    /// Secret token for resuming the tunnel after a disconnect, sent last of the replies to
+    /// `Open` if requested.
+    ///
+    /// This is `None` if the server does not hold the ports of disconnected tunnels, or if the
+    /// tunnel is named, since named tunnels are resumed by their name.
+    Resume(Option<Uuid>),
+
     /// No-op used to test if the client is still reachable.
     Heartbeat,
 
+    /// Heartbeat that the client answers with [`ClientMessage::Echo`], sent in place of
+    /// `Heartbeat` if requested.
+    Probe {
+        /// Sequence number of the probe, increasing by one each time.
+        seq: u64,
+        /// Round-trip time last measured by the server, in microseconds.
+        rtt_us: Option<u64>,
+    },
+
     /// Asks the client to accept a forwarded TCP connection.
     Connection(Uuid),
 
+    /// Asks the client to accept a forwarded TCP connection from a visitor's address.
+    Visitor {
+        /// Identifier of the pending connection.
+        id: Uuid,
+        /// Address of the visitor.
+        addr: SocketAddr,
+        /// Public port the visitor connected to, which older servers leave out.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        port: Option<u16>,
+    },
+
     /// Indicates a server error that terminates the connection.
     Error(String),
+
+    /// Refusal of a tunnel request with a code for the reason, sent in place of `Error` if
+    /// requested.
+    Failure {
+        /// Reason for the refusal.
+        code: ErrorCode,
+        /// Description of the reason, for people.
+        message: String,
+    },
+
+    /// Reply to an administrative request.
+    Admin(AdminResponse),
+
+    /// Reply to a ping, describing the server.
+    Pong(ServerInfo),
+
+    /// Description of the server, sent before the reply to `Open` if requested.
+    Info(ServerInfo),
+
+    /// Notice that the server is shutting down and about to close the control connection, sent
+    /// if requested.
+    Shutdown {
+        /// Why the server is shutting down, for people.
+        reason: String,
+        /// Seconds after which the server expects to accept tunnels again, if it said.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+    },
+
+    /// Notice that the server is about to close the tunnel for a reason of its own, such as its
+    /// transfer limit, sent if requested.
+    Closed {
+        /// Reason for closing the tunnel.
+        code: ErrorCode,
+        /// Description of the reason, for people.
+        message: String,
+    },
+}
+
+/// Description of a server, sent in reply to a ping, or before the reply to a tunnel request
+/// if the client asks for it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// Version of the server.
+    pub version: String,
+
+    /// Ports the server may assign to tunnels.
+    pub ports: PortList,
+
+    /// Number of tunnels currently open.
+    pub tunnels: usize,
+
+    /// Whether clients must authenticate. Older servers leave this out.
+    #[serde(default)]
+    pub auth: bool,
+
+    /// Protocol extensions and transports that the server supports, such as `pool` or `tls`.
+    ///
+    /// Older servers leave this out, so an empty list means that nothing is known.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+}
+
+impl ServerInfo {
+    /// Returns whether the server supports a feature, assuming it does if the server is too old
+    /// to list its features.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.is_empty() || self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// An administrative request from the operator of a server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum AdminRequest {
+    /// List the source IPs that are currently banned.
+    ListBans,
+
+    /// Lift the ban on a source IP.
+    Unban(IpAddr),
+
+    /// Ban a source IP, closing the tunnels of its clients.
+    Ban {
+        /// The address to ban.
+        ip: IpAddr,
+        /// Seconds until the ban expires.
+        duration_secs: u64,
+    },
+
+    /// Lift all bans.
+    ClearBans,
+
+    /// List the tunnels whose clients are connected.
+    ListTunnels,
+
+    /// Summarize the tunnels and traffic of the server.
+    Stats,
+
+    /// List the transfer quota usage of client identities this month.
+    Usage,
+
+    /// Close a tunnel, releasing its port and dropping the visitors waiting for it.
+    Close(TunnelTarget),
+}
+
+/// The open tunnel that an administrative request refers to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TunnelTarget {
+    /// The tunnel on a public port.
+    Port(u16),
+
+    /// The tunnel with a name.
+    Name(String),
+}
+
+impl fmt::Display for TunnelTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelTarget::Port(port) => write!(f, "port {port}"),
+            TunnelTarget::Name(name) => write!(f, "name {name}"),
+        }
+    }
+}
+
+/// A reply to an administrative request.
+///
+/// Lists are sent as one message per entry, and every reply ends with `Done`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum AdminResponse {
+    /// A banned source IP.
+    Ban {
+        /// The banned address.
+        ip: IpAddr,
+        /// Seconds until the ban expires.
+        remaining_secs: u64,
+    },
+
+    /// An open tunnel.
+    Tunnel(AdminTunnel),
+
+    /// Summary of the server.
+    Stats(AdminStats),
+
+    /// Transfer quota usage of a client identity.
+    Usage(AdminUsage),
+
+    /// The request has been completed.
+    Done,
+}
+
+/// Description of an open tunnel, in reply to [`AdminRequest::ListTunnels`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdminTunnel {
+    /// Public port of the tunnel.
+    pub port: u16,
+
+    /// Name of the tunnel, if the client provided one.
+    pub name: Option<String>,
+
+    /// Description of the tunnel, if the client provided one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// Address of the client's control connection.
+    pub client: SocketAddr,
+
+    /// Host name of the client's machine, if the client provided one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_host: Option<String>,
+
+    /// Version of the client, if it reported one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_version: Option<String>,
+
+    /// Identity of the client, if it authenticated.
+    pub identity: Option<String>,
+
+    /// Seconds since the client opened the tunnel.
+    pub uptime_secs: u64,
+
+    /// Number of visitor connections forwarded so far.
+    pub connections: u64,
+
+    /// Number of visitor connections currently open.
+    pub active: u64,
+
+    /// Bytes received from visitors.
+    pub received: u64,
+
+    /// Bytes sent to visitors.
+    pub sent: u64,
+
+    /// Round-trip time of the control connection, in microseconds, if the client answers
+    /// heartbeats.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_us: Option<u64>,
+
+    /// Variation of the round-trip time, in microseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter_us: Option<u64>,
+
+    /// Seconds until the tunnel expires, if the client asked for it to expire.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in_secs: Option<u64>,
+}
+
+/// Summary of a server, in reply to [`AdminRequest::Stats`].
+///
+/// Connection and traffic counters cover the tunnels that are currently open.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdminStats {
+    /// Version of the server.
+    pub version: String,
+
+    /// Seconds since the server started.
+    pub uptime_secs: u64,
+
+    /// Number of tunnels currently open.
+    pub tunnels: usize,
+
+    /// Number of visitor connections forwarded so far.
+    pub connections: u64,
+
+    /// Number of visitor connections currently open.
+    pub active: u64,
+
+    /// Bytes received from visitors.
+    pub received: u64,
+
+    /// Bytes sent to visitors.
+    pub sent: u64,
+
+    /// Number of source IPs currently banned.
+    pub bans: usize,
+
+    /// Number of visitor connections blocked by GeoIP filtering, keyed by country code.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub geoip_blocked: BTreeMap<String, u64>,
+}
+
+/// Transfer quota usage of a client identity, in reply to [`AdminRequest::Usage`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdminUsage {
+    /// Identity of the client.
+    pub identity: String,
+
+    /// Month that usage is counted for, such as `2026-10`.
+    pub period: String,
+
+    /// Bytes transferred by the tunnels of the identity this month.
+    pub used: u64,
+
+    /// Bytes the identity may transfer per month.
+    pub limit: u64,
 }
 
 /// Transport stream with JSON frames delimited by null characters.
@@ -58,7 +691,12 @@ pub struct Delimited<U>(Framed<U, AnyDelimiterCodec>);
 impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
     /// Construct a new delimited stream.
     pub fn new(stream: U) -> Self {
-        let codec = AnyDelimiterCodec::new_with_max_length(vec![0], vec![0], MAX_FRAME_LENGTH);
+        Self::with_max_length(stream, MAX_FRAME_LENGTH)
+    }
+
+    /// Construct a new delimited stream, allowing frames of up to `max_length` bytes.
+    pub fn with_max_length(stream: U, max_length: usize) -> Self {
+        let codec = AnyDelimiterCodec::new_with_max_length(vec![0], vec![0], max_length);
         Self(Framed::new(stream, codec))
     }
 
@@ -66,9 +704,9 @@ impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
     pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
         trace!("waiting to receive json message");
         if let Some(next_message) = self.0.next().await {
-            let byte_message = next_message.context("frame error, invalid byte length")?;
-            let serialized_obj =
-                serde_json::from_slice(&byte_message).context("unable to parse message")?;
+            let byte_message = next_message.map_err(frame_error)?;
+            let serialized_obj = serde_json::from_slice(&byte_message)
+                .map_err(|err| Error::Protocol(format!("unable to parse message: {err}")))?;
             Ok(serialized_obj)
         } else {
             Ok(None)
@@ -82,14 +720,14 @@ impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
     pub async fn recv_timeout<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
         timeout(NETWORK_TIMEOUT, self.recv())
             .await
-            .context("timed out waiting for initial message")?
+            .map_err(|_| Error::Timeout("timed out waiting for initial message".into()))?
     }
 
     /// Send a null-terminated JSON instruction on a stream.
     pub async fn send<T: Serialize>(&mut self, msg: T) -> Result<()> {
         trace!("sending json message");
-        self.0.send(serde_json::to_string(&msg)?).await?;
-        Ok(())
+        let message = serde_json::to_string(&msg).map_err(anyhow::Error::from)?;
+        self.0.send(message).await.map_err(frame_error)
     }
 
     /// Consume this object, returning current buffers and the inner transport.
@@ -98,17 +736,113 @@ impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
     }
 }
 
-/// Copy data mutually between two read/write streams.
-pub async fn proxy<S1, S2>(stream1: S1, stream2: S2) -> io::Result<()>
+/// Classify an error of the frame codec, which is either I/O or an oversized frame.
+fn frame_error(err: AnyDelimiterCodecError) -> Error {
+    match err {
+        AnyDelimiterCodecError::Io(err) => Error::Io(err),
+        _ => Error::Protocol("frame error, invalid byte length".into()),
+    }
+}
+
+/// Stream that yields buffered bytes before reading from an inner stream.
+///
+/// This is used to hand over data that was read past the end of a protocol message.
+pub struct Prefixed<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    /// Construct a stream that reads `prefix` before the data of `inner`.
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Prefixed {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let len = (self.prefix.len() - self.pos).min(buf.remaining());
+            buf.put_slice(&self.prefix[self.pos..self.pos + len]);
+            self.pos += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Copy data mutually between two read/write streams, until both directions end.
+///
+/// When one stream reaches EOF, the write side of the other is shut down while data keeps
+/// flowing the other way, so protocols that rely on TCP half-close work. An error in either
+/// direction ends both.
+///
+/// Each direction is copied through a buffer of `buffer_size` bytes, such as
+/// [`DEFAULT_BUFFER_SIZE`]. Larger buffers help on links with a high bandwidth-delay product.
+///
+/// Returns the number of bytes copied from `stream1` to `stream2`, and from `stream2` to
+/// `stream1`.
+pub async fn proxy<S1, S2>(stream1: S1, stream2: S2, buffer_size: usize) -> io::Result<(u64, u64)>
 where
     S1: AsyncRead + AsyncWrite + Unpin,
     S2: AsyncRead + AsyncWrite + Unpin,
 {
     let (mut s1_read, mut s1_write) = io::split(stream1);
     let (mut s2_read, mut s2_write) = io::split(stream2);
-    tokio::select! {
-        res = io::copy(&mut s1_read, &mut s2_write) => res,
-        res = io::copy(&mut s2_read, &mut s1_write) => res,
-    }?;
-    Ok(())
+    let (mut forward, mut backward) = (0, 0);
+    tokio::try_join!(
+        copy_counted(&mut s1_read, &mut s2_write, &mut forward, buffer_size),
+        copy_counted(&mut s2_read, &mut s1_write, &mut backward, buffer_size),
+    )?;
+    Ok((forward, backward))
+}
+
+/// Copy data from a reader to a writer until EOF, then shut the writer down, adding the bytes
+/// copied to `total`.
+async fn copy_counted<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    total: &mut u64,
+    buffer_size: usize,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; buffer_size];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return writer.shutdown().await;
+        }
+        writer.write_all(&buf[..n]).await?;
+        *total += n as u64;
+    }
 }
@@ -0,0 +1,275 @@
+//! Transfer statistics of the connections forwarded through a tunnel.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{broadcast, Notify};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::event::{self, ClientEvent};
+
+/// Aggregate counters for the connections of a tunnel.
+///
+/// Bytes are counted on the visitor's side of each connection, so "received" is traffic from
+/// visitors and "sent" is traffic to them.
+#[derive(Debug, Default)]
+pub struct Stats {
+    connections: AtomicU64,
+    active: AtomicU64,
+    received: AtomicU64,
+    sent: AtomicU64,
+    last_heartbeat: Mutex<Option<Instant>>,
+    latency: Mutex<Latency>,
+    quota: Option<Arc<AtomicU64>>,
+    limit: Option<u64>,
+    /// Notified when the connections reach the transfer limit.
+    exhausted: Notify,
+}
+
+/// Round-trip time of a control connection and its variation.
+#[derive(Debug, Default)]
+struct Latency {
+    rtt: Option<Duration>,
+    jitter: Duration,
+}
+
+impl Stats {
+    /// Create counters that also add the bytes of each connection to a transfer quota, and stop
+    /// connections once they transferred as many bytes as the limit.
+    #[cfg(feature = "server")]
+    pub(crate) fn with_limits(quota: Option<Arc<AtomicU64>>, limit: Option<u64>) -> Self {
+        Stats {
+            quota,
+            limit,
+            ..Default::default()
+        }
+    }
+
+    /// Returns whether the connections transferred as many bytes as the limit, if there is one.
+    pub fn limit_reached(&self) -> bool {
+        (self.limit).is_some_and(|limit| self.received() + self.sent() >= limit)
+    }
+
+    /// Wait until the connections reach the transfer limit, which never happens without one.
+    #[cfg(feature = "server")]
+    pub(crate) async fn exhausted(&self) {
+        if self.limit.is_none() {
+            std::future::pending::<()>().await;
+        }
+        while !self.limit_reached() {
+            self.exhausted.notified().await;
+        }
+    }
+
+    /// Count bytes transferred by a connection in either direction.
+    fn count(&self, n: u64) {
+        if let Some(quota) = &self.quota {
+            quota.fetch_add(n, Ordering::Relaxed);
+        }
+        if n > 0 && self.limit_reached() {
+            self.exhausted.notify_one();
+        }
+    }
+
+    /// Returns the number of connections forwarded so far.
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of connections currently open.
+    pub fn active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of bytes received from visitors.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of bytes sent to visitors.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Returns when the client last received a heartbeat from the server, if it has.
+    ///
+    /// This is only recorded for the tunnel of a client.
+    pub fn last_heartbeat(&self) -> Option<Instant> {
+        *self.last_heartbeat.lock().unwrap()
+    }
+
+    /// Record that a heartbeat was received just now.
+    #[cfg(feature = "client")]
+    pub(crate) fn record_heartbeat(&self) {
+        *self.last_heartbeat.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Returns the last round-trip time measured on the control connection, if any.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.latency.lock().unwrap().rtt
+    }
+
+    /// Returns the jitter of the control connection, if its round-trip time was measured.
+    ///
+    /// This is the smoothed difference between consecutive round-trip times, as in RFC 3550.
+    pub fn jitter(&self) -> Option<Duration> {
+        let latency = self.latency.lock().unwrap();
+        latency.rtt.map(|_| latency.jitter)
+    }
+
+    /// Record a round-trip time measured on the control connection.
+    #[cfg(any(feature = "client", feature = "server"))]
+    pub(crate) fn record_rtt(&self, rtt: Duration) {
+        let mut latency = self.latency.lock().unwrap();
+        if let Some(last) = latency.rtt {
+            let diff = rtt.abs_diff(last).as_secs_f64();
+            let jitter = latency.jitter.as_secs_f64();
+            latency.jitter = Duration::from_secs_f64(jitter + (diff - jitter) / 16.0);
+        }
+        latency.rtt = Some(rtt);
+    }
+
+    /// Log a summary of the counters.
+    pub fn log_summary(&self) {
+        info!(
+            connections = self.connections(),
+            active = self.active(),
+            received = self.received(),
+            sent = self.sent(),
+            last_heartbeat = ?self.last_heartbeat().map(|at| at.elapsed()),
+            rtt = ?self.rtt(),
+            jitter = ?self.jitter(),
+            "transfer statistics"
+        );
+    }
+
+    /// Log a summary of a tunnel that closed, with the counters of all its connections.
+    pub fn log_closed(&self, port: u16, identity: Option<&str>, uptime: Duration) {
+        info!(
+            port,
+            identity,
+            uptime_secs = uptime.as_secs(),
+            connections = self.connections(),
+            received = self.received(),
+            sent = self.sent(),
+            "tunnel closed"
+        );
+    }
+
+    /// Log a summary of the counters at a fixed interval, indefinitely.
+    pub async fn report(self: Arc<Self>, period: Duration) {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            self.log_summary();
+        }
+    }
+
+    /// Count the traffic of a new connection, which is logged when the stream is dropped.
+    pub fn track<S>(self: &Arc<Self>, stream: S) -> Counted<S> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Counted {
+            inner: stream,
+            stats: Arc::clone(self),
+            received: 0,
+            sent: 0,
+            closed: None,
+        }
+    }
+}
+
+/// Stream that adds the bytes read and written through it to [`Stats`].
+pub struct Counted<S> {
+    inner: S,
+    stats: Arc<Stats>,
+    received: u64,
+    sent: u64,
+    closed: Option<(Uuid, broadcast::Sender<ClientEvent>)>,
+}
+
+impl<S> Counted<S> {
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Send a [`ClientEvent::VisitorClosed`] event with the byte counts when dropped.
+    pub fn notify_closed(mut self, id: Uuid, events: broadcast::Sender<ClientEvent>) -> Self {
+        self.closed = Some((id, events));
+        self
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.stats.limit_reached() {
+            return Poll::Ready(Err(limit_error()));
+        }
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = (buf.filled().len() - before) as u64;
+        self.received += n;
+        self.stats.received.fetch_add(n, Ordering::Relaxed);
+        self.stats.count(n);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.stats.limit_reached() {
+            return Poll::Ready(Err(limit_error()));
+        }
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.sent += n as u64;
+            self.stats.sent.fetch_add(n as u64, Ordering::Relaxed);
+            self.stats.count(n as u64);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Error of a connection whose tunnel reached its transfer limit.
+fn limit_error() -> io::Error {
+    io::Error::other("tunnel reached its transfer limit")
+}
+
+impl<S> Drop for Counted<S> {
+    fn drop(&mut self) {
+        self.stats.active.fetch_sub(1, Ordering::Relaxed);
+        if let Some((id, events)) = &self.closed {
+            let event = ClientEvent::VisitorClosed {
+                id: *id,
+                received: self.received,
+                sent: self.sent,
+            };
+            event::emit(Some(events), event);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Status of a client's tunnel, kept in a file for scripts and CI jobs to poll.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+/// Whether a client is connected to the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    /// Connected, with visitors forwarded through the tunnel.
+    Connected,
+
+    /// The connection to the server ended.
+    Disconnected,
+}
+
+/// Status document of a client's tunnel, written as JSON.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientStatus {
+    /// Whether the client is connected to the server.
+    pub state: ConnectionState,
+
+    /// Host at which visitors reach the tunnel.
+    pub remote_host: String,
+
+    /// Port publicly available on the remote.
+    pub remote_port: u16,
+
+    /// Local port that is forwarded.
+    pub local_port: u16,
+
+    /// Seconds since the last heartbeat from the server, if there was one.
+    pub last_heartbeat_secs: Option<u64>,
+
+    /// Number of visitor connections forwarded so far.
+    pub connections: u64,
+
+    /// Number of visitor connections currently open.
+    pub active: u64,
+
+    /// Total number of bytes received from visitors.
+    pub received: u64,
+
+    /// Total number of bytes sent to visitors.
+    pub sent: u64,
+
+    /// Error that ended the connection, if it ended with one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// Time at which the status was written, in seconds since the Unix epoch.
+    pub updated_at: u64,
+}
+
+impl ClientStatus {
+    /// Returns the current time in seconds since the Unix epoch, for `updated_at`.
+    pub fn now() -> u64 {
+        let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+        since_epoch.unwrap_or_default().as_secs()
+    }
+
+    /// Read a status file.
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// Write the status to a file, replacing it in one step.
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        write_atomically(path.as_ref(), &serde_json::to_vec_pretty(self)?)
+    }
+}
+
+/// Replace the contents of a file through a temporary file, so that readers never see it half
+/// written.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp = path.to_path_buf().into_os_string();
+    temp.push(".tmp");
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, path)
+}
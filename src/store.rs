@@ -0,0 +1,52 @@
+//! Persistent storage of port assignments for named tunnels.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+
+/// JSON file mapping tunnel names to the public ports they were last assigned.
+pub struct PortStore {
+    path: PathBuf,
+    ports: Mutex<HashMap<String, u16>>,
+}
+
+impl PortStore {
+    /// Open a store at a path, loading any assignments saved by a previous run.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let ports = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("invalid state file {}", path.display()))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("could not read {}", path.display()))
+            }
+        };
+        Ok(PortStore {
+            path,
+            ports: Mutex::new(ports),
+        })
+    }
+
+    /// Returns the port last assigned to a tunnel name.
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.ports.lock().unwrap().get(name).copied()
+    }
+
+    /// Record the port assigned to a tunnel name, writing the store to disk.
+    pub fn set(&self, name: &str, port: u16) -> Result<()> {
+        let mut ports = self.ports.lock().unwrap();
+        if ports.insert(name.to_string(), port) == Some(port) {
+            return Ok(());
+        }
+        // Write to a temporary file first, so that a crash never leaves a truncated store.
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&*ports)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+//! Memorable subdomains for tunnels on servers with wildcard DNS records.
+//!
+//! Each public port maps to its own three-word label, like `fuzzy-amber-otter`, so a tunnel keeps
+//! its subdomain for as long as it keeps its port, including after reconnecting.
+
+/// Adjectives that start each label, one for each of 64 values.
+const ADJECTIVES: [&str; 64] = [
+    "bold", "brave", "bright", "brisk", "calm", "clever", "cosy", "crisp", "curly", "dapper",
+    "eager", "fancy", "fluffy", "fond", "fuzzy", "gentle", "giddy", "glad", "grand", "happy",
+    "hardy", "hasty", "jolly", "keen", "kind", "lively", "lucky", "mellow", "merry", "mighty",
+    "misty", "modest", "nimble", "noble", "odd", "plucky", "polite", "proud", "quick", "quiet",
+    "rapid", "rosy", "rustic", "shiny", "shy", "silky", "sleepy", "smooth", "snappy", "snug",
+    "spry", "steady", "sturdy", "sunny", "swift", "tidy", "tiny", "vivid", "wary", "warm", "wise",
+    "witty", "zany", "zesty",
+];
+
+/// Colors in the middle of each label, one for each of 16 values.
+const COLORS: [&str; 16] = [
+    "amber", "azure", "coral", "cream", "cyan", "gold", "green", "grey", "indigo", "ivory", "jade",
+    "lilac", "olive", "pink", "ruby", "teal",
+];
+
+/// Animals that end each label, one for each of 64 values.
+const ANIMALS: [&str; 64] = [
+    "badger", "beaver", "bison", "bobcat", "camel", "cheetah", "cobra", "condor", "coyote",
+    "crane", "dingo", "dolphin", "donkey", "eagle", "falcon", "ferret", "finch", "gecko", "gibbon",
+    "gopher", "heron", "hippo", "ibex", "iguana", "jackal", "jaguar", "koala", "lemur", "leopard",
+    "llama", "lynx", "magpie", "marmot", "meerkat", "mink", "moose", "newt", "ocelot", "orca",
+    "osprey", "otter", "owl", "panda", "parrot", "pelican", "penguin", "puffin", "python", "quail",
+    "rabbit", "raven", "salmon", "seal", "shark", "sloth", "stork", "tapir", "tiger", "toucan",
+    "turtle", "walrus", "weasel", "wombat", "yak",
+];
+
+/// Returns the subdomain label of a public port, which differs for every port.
+///
+/// ```
+/// use bore_cli::subdomain::subdomain;
+///
+/// assert_eq!(subdomain(13592), "fuzzy-amber-otter");
+/// assert_ne!(subdomain(13592), subdomain(13593));
+/// ```
+pub fn subdomain(port: u16) -> String {
+    // Multiplying by an odd number permutes the ports, so neighbouring ports get unrelated
+    // labels while each port still gets its own.
+    let value = port.wrapping_mul(0x9e37) as usize;
+    let adjective = ADJECTIVES[value >> 10];
+    let color = COLORS[(value >> 6) & 0xf];
+    let animal = ANIMALS[value & 0x3f];
+    format!("{adjective}-{color}-{animal}")
+}
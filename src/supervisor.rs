@@ -0,0 +1,429 @@
+//! Supervision of several tunnels from a single client process.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Context, Result};
+use futures_util::future::pending;
+use serde::{Deserialize, Serialize};
+use tokio::task::AbortHandle;
+use tokio::time::sleep;
+use tracing::{field, info, info_span, warn, Instrument, Span};
+
+use crate::client::{Client, ClientOptions};
+use crate::error::Error;
+use crate::event::{self, ClientEvent};
+
+/// Delay before the first attempt to reconnect a tunnel, doubled after each failure.
+pub const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest delay between attempts to reconnect a tunnel.
+pub const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Number of recent errors kept for each tunnel, for status reports.
+pub const MAX_RECENT_ERRORS: usize = 5;
+
+/// A tunnel to expose, as given on the command line or in a tunnels file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelSpec {
+    /// Stable name for the tunnel.
+    pub name: Option<String>,
+
+    /// Local host to expose, if not the default.
+    pub local_host: Option<String>,
+
+    /// Local port to expose.
+    pub local_port: u16,
+
+    /// Port on the remote server to select, or 0 for any available port.
+    #[serde(default)]
+    pub port: u16,
+
+    /// Address of the remote server, if not the default.
+    pub to: Option<String>,
+
+    /// File in which to keep the remote host, port, and URL of the tunnel.
+    pub env_file: Option<PathBuf>,
+}
+
+impl FromStr for TunnelSpec {
+    type Err = anyhow::Error;
+
+    /// Parse a tunnel of the form `[NAME=]LOCAL_PORT[:REMOTE_PORT]`.
+    ///
+    /// ```
+    /// use bore_cli::supervisor::TunnelSpec;
+    ///
+    /// let spec: TunnelSpec = "web=3000:8080".parse().unwrap();
+    /// assert_eq!(spec.name.as_deref(), Some("web"));
+    /// assert_eq!((spec.local_port, spec.port), (3000, 8080));
+    /// ```
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, ports) = match s.split_once('=') {
+            Some((name, ports)) => (Some(name.to_string()), ports),
+            None => (None, s),
+        };
+        let (local_port, port) = match ports.split_once(':') {
+            Some((local, remote)) => (local, remote.parse().context("invalid remote port")?),
+            None => (ports, 0),
+        };
+        Ok(TunnelSpec {
+            name,
+            local_port: local_port.parse().context("invalid local port")?,
+            port,
+            ..Default::default()
+        })
+    }
+}
+
+/// A TOML file listing the tunnels of a client process.
+///
+/// ```toml
+/// to = "bore.example.com"
+///
+/// [[tunnel]]
+/// name = "web"
+/// local_port = 3000
+///
+/// [[tunnel]]
+/// local_port = 5432
+/// port = 15432
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelsFile {
+    /// Default address of the remote server.
+    pub to: Option<String>,
+
+    /// Secret for authentication.
+    pub secret: Option<String>,
+
+    /// Tunnels to expose.
+    #[serde(default, rename = "tunnel")]
+    pub tunnels: Vec<TunnelSpec>,
+}
+
+impl TunnelsFile {
+    /// Read and parse a tunnels file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        toml::from_str(&data).with_context(|| format!("invalid tunnels file {}", path.display()))
+    }
+}
+
+/// Connection parameters of a supervised tunnel.
+#[derive(Clone, Debug)]
+pub struct TunnelConfig {
+    /// Local host that is forwarded.
+    pub local_host: String,
+
+    /// Local port that is forwarded.
+    pub local_port: u16,
+
+    /// Address of the remote server.
+    pub to: String,
+
+    /// Port on the remote server to select, or 0 for any available port.
+    pub port: u16,
+
+    /// Optional secret for authentication.
+    pub secret: Option<String>,
+
+    /// Additional client options, such as the tunnel name.
+    pub options: ClientOptions,
+}
+
+impl TunnelConfig {
+    /// Returns a short label identifying the tunnel in logs and state reports.
+    pub fn label(&self) -> String {
+        match &self.options.name {
+            Some(name) => name.clone(),
+            None => format!("{}:{}", self.local_host, self.local_port),
+        }
+    }
+}
+
+/// Current state of a supervised tunnel.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TunnelState {
+    /// Connecting to the server.
+    Connecting,
+
+    /// Connected, with the port assigned on the remote server.
+    Connected(u16),
+
+    /// Disconnected after an error, waiting to reconnect.
+    Retrying(String),
+
+    /// Closed for good, because its lifetime ran out.
+    Expired,
+
+    /// Closed for good, because it was shut down.
+    Stopped,
+}
+
+impl fmt::Display for TunnelState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelState::Connecting => write!(f, "connecting"),
+            TunnelState::Connected(port) => write!(f, "connected on port {port}"),
+            TunnelState::Retrying(err) => write!(f, "retrying after error: {err}"),
+            TunnelState::Expired => write!(f, "expired"),
+            TunnelState::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+/// Detailed report on a supervised tunnel.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelStatus {
+    /// Label identifying the tunnel.
+    pub label: String,
+
+    /// Address of the remote server.
+    pub to: String,
+
+    /// Current state of the tunnel.
+    pub state: TunnelState,
+
+    /// Seconds since the tunnel last connected, while it is connected.
+    pub uptime_secs: Option<u64>,
+
+    /// Most recent errors that disconnected the tunnel, oldest first.
+    pub recent_errors: Vec<String>,
+}
+
+/// A tunnel with its current state, shared with the task that keeps it connected.
+struct Tunnel {
+    config: TunnelConfig,
+    state: Mutex<State>,
+}
+
+/// Mutable state of a tunnel.
+struct State {
+    current: TunnelState,
+    connected_at: Option<Instant>,
+    recent_errors: VecDeque<String>,
+}
+
+/// Keeps several tunnels connected, reconnecting each with backoff when it drops.
+///
+/// Tunnels can be added and removed while the supervisor is running.
+pub struct Supervisor {
+    tunnels: Mutex<Vec<(Arc<Tunnel>, Option<AbortHandle>)>>,
+}
+
+impl Supervisor {
+    /// Create a supervisor for a list of tunnels.
+    pub fn new(tunnels: Vec<TunnelConfig>) -> Self {
+        let tunnels = tunnels
+            .into_iter()
+            .map(|config| (Arc::new(Tunnel::new(config)), None))
+            .collect();
+        Supervisor {
+            tunnels: Mutex::new(tunnels),
+        }
+    }
+
+    /// Returns the label and current state of each tunnel.
+    pub fn states(&self) -> Vec<(String, TunnelState)> {
+        let tunnels = self.tunnels.lock().unwrap();
+        tunnels
+            .iter()
+            .map(|(tunnel, _)| (tunnel.config.label(), tunnel.state()))
+            .collect()
+    }
+
+    /// Returns a detailed report on each tunnel.
+    pub fn status(&self) -> Vec<TunnelStatus> {
+        let tunnels = self.tunnels.lock().unwrap();
+        tunnels.iter().map(|(tunnel, _)| tunnel.status()).collect()
+    }
+
+    /// Run all tunnels, reconnecting them indefinitely.
+    pub async fn run(self: Arc<Self>) {
+        for (tunnel, task) in self.tunnels.lock().unwrap().iter_mut() {
+            if task.is_none() {
+                *task = Some(Tunnel::spawn(Arc::clone(tunnel)));
+            }
+        }
+        pending().await
+    }
+
+    /// Add a tunnel and start connecting it, which requires a unique label.
+    pub fn add(&self, config: TunnelConfig) -> Result<()> {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let label = config.label();
+        ensure!(
+            tunnels
+                .iter()
+                .all(|(tunnel, _)| tunnel.config.label() != label),
+            "a tunnel named {label} already exists"
+        );
+        let tunnel = Arc::new(Tunnel::new(config));
+        let task = Tunnel::spawn(Arc::clone(&tunnel));
+        tunnels.push((tunnel, Some(task)));
+        Ok(())
+    }
+
+    /// Wait until every tunnel has stopped for good, because it expired or the shutdown token in
+    /// its options was cancelled.
+    pub async fn stopped(&self) {
+        let running = || {
+            let tunnels = self.tunnels.lock().unwrap();
+            (tunnels.iter()).any(|(_, task)| task.as_ref().is_some_and(|task| !task.is_finished()))
+        };
+        while running() {
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Stop and remove a tunnel by label, returning whether it existed.
+    pub fn remove(&self, label: &str) -> bool {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let Some(index) = tunnels.iter().position(|(t, _)| t.config.label() == label) else {
+            return false;
+        };
+        let (_, task) = tunnels.remove(index);
+        if let Some(task) = task {
+            task.abort();
+        }
+        info!(tunnel = label, "tunnel removed");
+        true
+    }
+}
+
+impl Tunnel {
+    fn new(config: TunnelConfig) -> Self {
+        Tunnel {
+            config,
+            state: Mutex::new(State {
+                current: TunnelState::Connecting,
+                connected_at: None,
+                recent_errors: VecDeque::new(),
+            }),
+        }
+    }
+
+    fn state(&self) -> TunnelState {
+        self.state.lock().unwrap().current.clone()
+    }
+
+    fn status(&self) -> TunnelStatus {
+        let state = self.state.lock().unwrap();
+        TunnelStatus {
+            label: self.config.label(),
+            to: self.config.to.clone(),
+            state: state.current.clone(),
+            uptime_secs: state.connected_at.map(|at| at.elapsed().as_secs()),
+            recent_errors: state.recent_errors.iter().cloned().collect(),
+        }
+    }
+
+    fn spawn(self: Arc<Self>) -> AbortHandle {
+        // Each tunnel has its own span, which labels its logs and is the root of its traces.
+        let local_port = self.config.local_port;
+        let span = info_span!("tunnel", local_port, port = field::Empty);
+        tokio::spawn(async move { self.supervise().await }.instrument(span)).abort_handle()
+    }
+
+    async fn supervise(&self) {
+        let tunnel = &self.config;
+        let mut backoff = RECONNECT_BACKOFF;
+        // Reconnecting with the token of the last connection keeps the same port, as long as
+        // the server still holds it.
+        let mut options = ClientOptions {
+            resumable: true,
+            ..tunnel.options.clone()
+        };
+        // Reconnecting does not extend the lifetime of the tunnel.
+        let expires_at = options.expires.map(|expires| Instant::now() + expires);
+        let expired = || expires_at.is_some_and(|expires_at| expires_at <= Instant::now());
+        loop {
+            if expired() {
+                self.set_state(TunnelState::Expired);
+                return;
+            }
+            options.expires =
+                expires_at.map(|expires_at| expires_at.saturating_duration_since(Instant::now()));
+            self.set_state(TunnelState::Connecting);
+            let client = Client::with_options(
+                &tunnel.local_host,
+                tunnel.local_port,
+                &tunnel.to,
+                tunnel.port,
+                tunnel.secret.as_deref(),
+                options.clone(),
+            )
+            .await;
+            let result = match client {
+                Ok(client) => {
+                    options.resume_token = client.resume_token();
+                    Span::current().record("port", client.remote_port());
+                    self.set_state(TunnelState::Connected(client.remote_port()));
+                    backoff = RECONNECT_BACKOFF;
+                    client.listen().await
+                }
+                Err(err) => Err(err),
+            };
+            // A tunnel that was shut down on purpose is not reconnected.
+            if tunnel.options.shutdown.is_cancelled() {
+                self.set_state(TunnelState::Stopped);
+                return;
+            }
+            if expired() {
+                continue;
+            }
+            let (error, retryable) = match &result {
+                Ok(()) => ("connection closed by server".into(), true),
+                Err(err) => (format!("{err:#}"), err.is_retryable()),
+            };
+            // Retrying soon cannot help with a refusal such as a port outside the server's
+            // range, which only a change on the server fixes.
+            let delay = match (result, retryable) {
+                // A server that shuts down on purpose may say when it will be back.
+                (Err(Error::ServerShutdown { retry_after, .. }), _) => {
+                    retry_after.unwrap_or(backoff)
+                }
+                (_, true) => backoff,
+                (_, false) => MAX_RECONNECT_BACKOFF,
+            };
+            let reconnecting = ClientEvent::Reconnecting {
+                delay,
+                error: error.clone(),
+            };
+            event::emit(tunnel.options.events.as_ref(), reconnecting);
+            self.set_state(TunnelState::Retrying(error));
+            sleep(delay).await;
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        }
+    }
+
+    fn set_state(&self, state: TunnelState) {
+        let tunnel = self.config.label();
+        match &state {
+            TunnelState::Retrying(_) => warn!(%tunnel, %state, "tunnel state changed"),
+            _ => info!(%tunnel, %state, "tunnel state changed"),
+        }
+        let mut current = self.state.lock().unwrap();
+        current.connected_at = match state {
+            TunnelState::Connected(_) => Some(Instant::now()),
+            _ => None,
+        };
+        if let TunnelState::Retrying(err) = &state {
+            if current.recent_errors.len() == MAX_RECENT_ERRORS {
+                current.recent_errors.pop_front();
+            }
+            current.recent_errors.push_back(err.clone());
+        }
+        current.current = state;
+    }
+}
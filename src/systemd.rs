@@ -0,0 +1,67 @@
+//! Integration with systemd, for running the server as a supervised service.
+//!
+//! All of these functions do nothing when the process was not started by systemd, so they
+//! are safe to call unconditionally.
+
+use std::io;
+use std::time::Duration;
+
+use listenfd::ListenFd;
+use sd_notify::NotifyState;
+use tokio::net::TcpListener;
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+/// Take the control listener passed in by systemd socket activation, if there is one.
+///
+/// Only the first socket in `LISTEN_FDS` is used, and it must be a TCP listener.
+pub fn listener() -> io::Result<Option<TcpListener>> {
+    let mut fds = ListenFd::from_env();
+    if fds.len() > 1 {
+        warn!(
+            count = fds.len(),
+            "ignoring extra sockets passed by systemd"
+        );
+    }
+    match fds.take_tcp_listener(0)? {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Tell systemd that the server is accepting connections.
+pub fn notify_ready() {
+    notify(&[NotifyState::Ready]);
+}
+
+/// Tell systemd that the server is shutting down.
+pub fn notify_stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Ping the systemd watchdog at half its timeout, until the token is cancelled.
+pub async fn watchdog(shutdown: CancellationToken) {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    let mut pings = interval(Duration::from_micros(usec) / 2);
+    shutdown
+        .run_until_cancelled(async {
+            loop {
+                pings.tick().await;
+                notify(&[NotifyState::Watchdog]);
+            }
+        })
+        .await;
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(err) = sd_notify::notify(false, state) {
+        debug!(%err, "failed to notify systemd");
+    }
+}
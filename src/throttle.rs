@@ -0,0 +1,203 @@
+//! Bandwidth limits on the traffic forwarded by a client, or on each visitor connection of a
+//! server.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Sleep};
+
+/// A bandwidth in bytes per second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRate {
+    /// Number of bytes allowed per second, also used as the burst size.
+    pub bytes_per_sec: u64,
+}
+
+impl FromStr for ByteRate {
+    type Err = anyhow::Error;
+
+    /// Parse a number of bytes per second, with an optional `K`, `M`, or `G` suffix in
+    /// powers of 1024.
+    ///
+    /// ```
+    /// use bore_cli::throttle::ByteRate;
+    ///
+    /// let rate: ByteRate = "512K".parse().unwrap();
+    /// assert_eq!(rate.bytes_per_sec, 512 * 1024);
+    /// assert_eq!("2M".parse::<ByteRate>().unwrap().bytes_per_sec, 2 * 1024 * 1024);
+    /// assert_eq!("1000".parse::<ByteRate>().unwrap().bytes_per_sec, 1000);
+    /// ```
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (number, multiplier) = match s.char_indices().last() {
+            Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+            Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+            Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+            _ => (s, 1),
+        };
+        let Ok(number) = number.parse::<u64>() else {
+            bail!("expected a bandwidth like `512K`, `2M`, or a number of bytes");
+        };
+        match number.checked_mul(multiplier) {
+            Some(0) => bail!("bandwidth must be at least one byte per second"),
+            Some(bytes_per_sec) => Ok(ByteRate { bytes_per_sec }),
+            None => bail!("bandwidth is too large"),
+        }
+    }
+}
+
+/// Token bucket of bytes shared by every connection of a tunnel.
+#[derive(Debug)]
+pub struct Bandwidth {
+    rate: ByteRate,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl Bandwidth {
+    /// Create a full bucket for the given rate.
+    pub fn new(rate: ByteRate) -> Self {
+        Self {
+            rate,
+            bucket: Mutex::new((rate.bytes_per_sec as f64, Instant::now())),
+        }
+    }
+
+    /// Take up to `want` bytes from the bucket, or return how long to wait for them.
+    ///
+    /// Transfers wait for a chunk of a twentieth of a second's worth of bytes to avoid many
+    /// tiny reads and writes when the bucket is nearly empty.
+    fn acquire(&self, want: usize) -> Result<usize, Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, last) = &mut *bucket;
+        let now = Instant::now();
+        let per_sec = self.rate.bytes_per_sec as f64;
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * per_sec).min(per_sec);
+        *last = now;
+        let chunk = (want as f64).min((per_sec / 20.0).max(1.0));
+        if *tokens >= chunk {
+            let granted = (want as f64).min(tokens.floor());
+            *tokens -= granted;
+            Ok(granted as usize)
+        } else {
+            Err(Duration::from_secs_f64((chunk - *tokens) / per_sec))
+        }
+    }
+
+    /// Return bytes that were acquired but not transferred.
+    fn release(&self, unused: usize) {
+        self.bucket.lock().unwrap().0 += unused as f64;
+    }
+}
+
+/// Stream whose reads and writes are limited by shared [`Bandwidth`] buckets.
+pub struct Throttled<S> {
+    inner: S,
+    read_limit: Option<Arc<Bandwidth>>,
+    write_limit: Option<Arc<Bandwidth>>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    /// Limit a stream, with optional buckets for each direction.
+    pub fn new(
+        inner: S,
+        read_limit: Option<Arc<Bandwidth>>,
+        write_limit: Option<Arc<Bandwidth>>,
+    ) -> Self {
+        Self {
+            inner,
+            read_limit,
+            write_limit,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+/// Wait until up to `want` bytes can be transferred, returning the allowed number of bytes
+/// and the bucket they were taken from.
+fn poll_budget<'a>(
+    limit: &'a Option<Arc<Bandwidth>>,
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    want: usize,
+    cx: &mut Context<'_>,
+) -> Poll<(usize, Option<&'a Bandwidth>)> {
+    let Some(limit) = limit else {
+        return Poll::Ready((want, None));
+    };
+    loop {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+        match limit.acquire(want) {
+            Ok(granted) => return Poll::Ready((granted, Some(limit))),
+            Err(wait) => *delay = Some(Box::pin(sleep(wait))),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let want = buf.remaining();
+        let (granted, limit) = ready!(poll_budget(
+            &this.read_limit,
+            &mut this.read_delay,
+            want,
+            cx
+        ));
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(granted));
+        let poll = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        let filled = limited.filled().len();
+        buf.advance(filled);
+        if let Some(limit) = limit {
+            limit.release(granted - filled);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let (granted, limit) = ready!(poll_budget(
+            &this.write_limit,
+            &mut this.write_delay,
+            buf.len(),
+            cx
+        ));
+        let poll = Pin::new(&mut this.inner).poll_write(cx, &buf[..granted]);
+        if let Some(limit) = limit {
+            let written = match poll {
+                Poll::Ready(Ok(n)) => n,
+                _ => 0,
+            };
+            limit.release(granted - written);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
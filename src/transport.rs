@@ -0,0 +1,119 @@
+//! Pluggable transports for connections between clients and the server.
+//!
+//! Clients open control and data connections with a [`Connector`], and servers can accept them
+//! from an [`Acceptor`] alongside their TCP control ports. Visitors always reach tunnels over
+//! plain TCP.
+
+use std::fmt;
+#[cfg(feature = "client")]
+use std::net::IpAddr;
+use std::net::SocketAddr;
+#[cfg(all(unix, any(feature = "client", feature = "server")))]
+use std::path::Path;
+#[cfg(feature = "client")]
+use std::sync::Arc;
+
+#[cfg(all(unix, any(feature = "client", feature = "server")))]
+use anyhow::{anyhow, Context};
+use futures_util::future::BoxFuture;
+#[cfg(feature = "client")]
+use rustls::ClientConfig;
+#[cfg(all(unix, any(feature = "client", feature = "server")))]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(all(unix, feature = "server"))]
+use tracing::info;
+#[cfg(all(unix, any(feature = "client", feature = "server")))]
+use tracing::warn;
+
+#[cfg(feature = "client")]
+use crate::client::connect_stream;
+use crate::error::Result;
+use crate::shared::BoxedStream;
+
+/// Opens connections from a client to the server.
+pub trait Connector: fmt::Debug + Send + Sync {
+    /// Connect to the server at a host and control port.
+    fn connect<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, Result<BoxedStream>>;
+}
+
+/// The default connector, over TCP with optional TLS.
+#[cfg(feature = "client")]
+#[derive(Clone, Debug, Default)]
+pub struct TcpConnector {
+    /// TLS configuration, if connections are encrypted.
+    pub tls: Option<Arc<ClientConfig>>,
+
+    /// Local source address for connections.
+    pub bind_addr: Option<IpAddr>,
+}
+
+#[cfg(feature = "client")]
+impl Connector for TcpConnector {
+    fn connect<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, Result<BoxedStream>> {
+        Box::pin(connect_stream(
+            host,
+            port,
+            self.tls.as_ref(),
+            self.bind_addr,
+        ))
+    }
+}
+
+/// Accepts connections from clients on the server.
+pub trait Acceptor: Send + Sync {
+    /// Wait for the next connection, returning it with the address of its client.
+    ///
+    /// Connections are accepted one at a time, so this should return as soon as a connection
+    /// arrives, without waiting on the client.
+    fn accept(&self) -> BoxFuture<'_, Result<(BoxedStream, SocketAddr)>>;
+}
+
+/// Accepts control connections on a Unix domain socket, such as from a reverse proxy on the
+/// same host.
+///
+/// Clients on the socket have no IP address, so they are all reported as `127.0.0.1`, and share
+/// the rate limits and bans of that address.
+#[cfg(all(unix, feature = "server"))]
+pub struct UnixAcceptor(UnixListener);
+
+#[cfg(all(unix, feature = "server"))]
+impl UnixAcceptor {
+    /// Listen on a socket path, replacing a stale socket file left by an earlier process.
+    pub async fn bind(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let listener = bind_unix(path).await?;
+        info!(path = %path.display(), "server listening on unix socket");
+        Ok(UnixAcceptor(listener))
+    }
+}
+
+#[cfg(all(unix, feature = "server"))]
+impl Acceptor for UnixAcceptor {
+    fn accept(&self) -> BoxFuture<'_, Result<(BoxedStream, SocketAddr)>> {
+        Box::pin(async move {
+            let (stream, _) = self.0.accept().await?;
+            let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+            Ok((Box::new(stream) as BoxedStream, addr))
+        })
+    }
+}
+
+/// Bind a Unix socket, removing a stale socket file if no process is listening on it.
+#[cfg(all(unix, any(feature = "client", feature = "server")))]
+pub(crate) async fn bind_unix(path: &Path) -> Result<UnixListener> {
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(anyhow!("another process is already listening on {}", path.display()).into());
+    }
+    match std::fs::remove_file(path) {
+        Ok(()) => warn!(path = %path.display(), "removed stale socket file"),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => {
+            return Err(anyhow!(err)
+                .context("could not remove stale socket file")
+                .into())
+        }
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("could not listen on {}", path.display()))?;
+    Ok(listener)
+}
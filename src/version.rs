@@ -0,0 +1,60 @@
+//! Versions that clients report to the server, compared against its minimum.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// Release version of bore, as `major.minor.patch`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    /// Major version number.
+    pub major: u64,
+
+    /// Minor version number.
+    pub minor: u64,
+
+    /// Patch version number.
+    pub patch: u64,
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    /// Parse a version like `0.5.0`, where missing minor and patch numbers are zero and any
+    /// pre-release or build suffix is ignored.
+    ///
+    /// ```
+    /// use bore_cli::version::Version;
+    ///
+    /// let version: Version = "0.6.1-beta.2".parse().unwrap();
+    /// assert_eq!((version.major, version.minor, version.patch), (0, 6, 1));
+    /// assert!(version < "0.7".parse().unwrap());
+    /// ```
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let s = s.strip_prefix('v').unwrap_or(s);
+        let release = s.split(['-', '+']).next().unwrap_or_default();
+        let mut parts = release.split('.');
+        let mut next = |required| match parts.next() {
+            Some(part) => part.parse().context("invalid number in version"),
+            None if required => Err(anyhow!("expected a version like `0.5.0`")),
+            None => Ok(0),
+        };
+        let version = Version {
+            major: next(true)?,
+            minor: next(false)?,
+            patch: next(false)?,
+        };
+        if parts.next().is_some() {
+            bail!("expected a version like `0.5.0`");
+        }
+        Ok(version)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
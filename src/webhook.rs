@@ -0,0 +1,108 @@
+//! Webhook notifications for tunnel lifecycle events.
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+/// Number of times a failed notification is retried.
+pub const WEBHOOK_RETRIES: u32 = 3;
+
+/// Delay before the first retry, doubled after each attempt.
+pub const WEBHOOK_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A change in the state of a tunnel, sent as the JSON body of a webhook.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TunnelEvent {
+    /// A client opened a tunnel on a public port.
+    Opened {
+        /// Public port of the tunnel.
+        port: u16,
+        /// Name of the tunnel, if the client provided one.
+        name: Option<String>,
+        /// Address of the client's control connection.
+        client: SocketAddr,
+        /// Whether the client authenticated with the server secret.
+        authenticated: bool,
+    },
+
+    /// A tunnel was closed and its public port released.
+    Closed {
+        /// Public port of the tunnel.
+        port: u16,
+        /// Name of the tunnel, if the client provided one.
+        name: Option<String>,
+        /// Address of the client's control connection.
+        client: SocketAddr,
+        /// How long the tunnel was open, in seconds.
+        duration_secs: u64,
+    },
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a TunnelEvent,
+    timestamp: u64,
+}
+
+/// Sends tunnel events to a list of HTTP endpoints.
+pub struct Webhooks {
+    urls: Vec<String>,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    /// Create a notifier that posts to each of the given URLs.
+    pub fn new(urls: Vec<String>) -> Self {
+        Webhooks {
+            urls,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Post an event to every URL in the background, retrying failures with backoff.
+    pub fn notify(&self, event: TunnelEvent) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let payload = Payload {
+            event: &event,
+            timestamp,
+        };
+        let body = serde_json::to_vec(&payload).expect("webhook payload is serializable");
+        for url in &self.urls {
+            let client = self.client.clone();
+            let url = url.clone();
+            let body = body.clone();
+            tokio::spawn(async move {
+                let mut backoff = WEBHOOK_BACKOFF;
+                for attempt in 0..=WEBHOOK_RETRIES {
+                    let result = client
+                        .post(&url)
+                        .header("content-type", "application/json")
+                        .body(body.clone())
+                        .send()
+                        .await
+                        .and_then(|resp| resp.error_for_status());
+                    match result {
+                        Ok(_) => {
+                            debug!(%url, "delivered webhook");
+                            return;
+                        }
+                        Err(err) if attempt < WEBHOOK_RETRIES => {
+                            debug!(%err, %url, attempt, "webhook failed, retrying");
+                            sleep(backoff).await;
+                            backoff *= 2;
+                        }
+                        Err(err) => warn!(%err, %url, "giving up on webhook"),
+                    }
+                }
+            });
+        }
+    }
+}
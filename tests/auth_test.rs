@@ -1,6 +1,10 @@
 use anyhow::Result;
-use bore_cli::{auth::Authenticator, shared::Delimited};
+use bore_cli::auth::{server_handshake, Authenticate, Authenticator, Identity};
+use bore_cli::error::{self, Error};
+use bore_cli::shared::Delimited;
+use futures_util::future::BoxFuture;
 use tokio::io::{self};
+use uuid::Uuid;
 
 #[tokio::test]
 async fn auth_handshake() -> Result<()> {
@@ -33,3 +37,45 @@ async fn auth_handshake_fail() {
     );
     assert!(result.is_err());
 }
+
+/// Authenticates clients that know any of several per-user secrets.
+struct UserSecrets(Vec<(&'static str, Authenticator)>);
+
+impl Authenticate for UserSecrets {
+    fn verify<'a>(
+        &'a self,
+        challenge: &'a Uuid,
+        answer: &'a str,
+    ) -> BoxFuture<'a, error::Result<Identity>> {
+        Box::pin(async move {
+            let user = self
+                .0
+                .iter()
+                .find(|(_, auth)| auth.validate(challenge, answer));
+            match user {
+                Some((name, _)) => Ok(Identity(name.to_string())),
+                None => Err(Error::Handshake("unknown user".into())),
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn custom_authenticator() -> Result<()> {
+    let users = UserSecrets(vec![
+        ("alice", Authenticator::new("alice secret")),
+        ("bob", Authenticator::new("bob secret")),
+    ]);
+
+    let (client, server) = io::duplex(8);
+    let mut client = Delimited::new(client);
+    let mut server = Delimited::new(server);
+
+    let bob = Authenticator::new("bob secret");
+    let ((), identity) = tokio::try_join!(
+        bob.client_handshake(&mut client),
+        server_handshake(&users, &mut server),
+    )?;
+    assert_eq!(identity, Identity("bob".into()));
+    Ok(())
+}
@@ -0,0 +1,39 @@
+use anyhow::Result;
+use bore_cli::config::Config;
+
+#[test]
+fn select_profiles() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("bore-config-{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        r#"
+[profiles.default]
+to = "bore.example.com"
+
+[profiles.work]
+to = "bore.work.internal"
+secret = "hunter2"
+port = 30000
+"#,
+    )?;
+    let config = Config::load(&path)?;
+    std::fs::remove_file(&path)?;
+
+    assert_eq!(
+        config.profile(None)?.to.as_deref(),
+        Some("bore.example.com")
+    );
+    let work = config.profile(Some("work"))?;
+    assert_eq!(work.secret.as_deref(), Some("hunter2"));
+    assert_eq!(work.port, Some(30000));
+    assert!(config.profile(Some("home")).is_err());
+    Ok(())
+}
+
+#[test]
+fn missing_config_file() -> Result<()> {
+    let config = Config::load("/nonexistent/bore/config.toml")?;
+    assert!(config.profile(None)?.to.is_none());
+    assert!(config.profile(Some("work")).is_err());
+    Ok(())
+}
@@ -1,16 +1,57 @@
 #![allow(clippy::items_after_test_module)]
 
-use std::net::SocketAddr;
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{anyhow, Result};
-use bore_cli::{client::Client, server::Server, shared::CONTROL_PORT};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bore_cli::acme::Acme;
+use bore_cli::admin::AdminClient;
+use bore_cli::allocator::{self, PortAllocator, PortRequest};
+use bore_cli::audit::AuditLog;
+use bore_cli::auth::{Authenticate, Authenticator, Identity};
+use bore_cli::ban::{BanList, BanPolicy};
+use bore_cli::bench::{self, BenchOptions};
+use bore_cli::client::{self, Client, ClientOptions, IpFilter, Scheme};
+use bore_cli::cluster::Cluster;
+use bore_cli::dns::{DnsProvider, DnsRecord, DnsUpdater};
+use bore_cli::error::{self, Error, ErrorCode};
+use bore_cli::event::{self, ClientEvent, ServerEvent, TunnelObserver};
+use bore_cli::geoip::GeoFilter;
+use bore_cli::handle::{ClientHandle, ClientState};
+use bore_cli::hook::EnvFile;
+use bore_cli::inspect::Inspector;
+use bore_cli::limit::AcceptPolicy;
+use bore_cli::middleware::{VisitorInfo, VisitorMiddleware};
+use bore_cli::ports::PortList;
+use bore_cli::quota::Quotas;
+use bore_cli::relay::Relay;
+use bore_cli::schedule::Schedules;
+use bore_cli::secrets::ScopedSecrets;
+use bore_cli::server::{ControlListener, Server};
+use bore_cli::shared::{
+    AdminRequest, AdminResponse, BoxedStream, SocketOptions, TunnelTarget, CONTROL_PORT,
+    TLS_CONTROL_PORT,
+};
+use bore_cli::stats::Stats;
+use bore_cli::status::{ClientStatus, ConnectionState};
+use bore_cli::subdomain::subdomain;
+use bore_cli::supervisor::{Supervisor, TunnelConfig, TunnelState};
+use bore_cli::transport::{Acceptor, Connector};
+use bore_cli::{store::PortStore, tls, webhook::Webhooks};
+use futures_util::future::BoxFuture;
 use lazy_static::lazy_static;
 use rstest::*;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use sha2::{Digest, Sha256};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{mpsc, Mutex};
 use tokio::time;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 
 lazy_static! {
     /// Guard to make sure that tests are run serially, not concurrently.
@@ -33,6 +74,16 @@ async fn spawn_client(secret: Option<&str>) -> Result<(TcpListener, SocketAddr)>
     Ok((listener, remote_addr))
 }
 
+/// Visit a tunnel from a loopback address, returning whether the visitor reached the local
+/// service behind it.
+async fn visit(local: &TcpListener, from: [u8; 4], port: u16) -> Result<bool> {
+    let socket = TcpSocket::new_v4()?;
+    socket.bind((from, 0).into())?;
+    let _stream = socket.connect(([127, 0, 0, 1], port).into()).await?;
+    let accepted = time::timeout(Duration::from_millis(500), local.accept()).await;
+    Ok(accepted.is_ok())
+}
+
 #[rstest]
 #[tokio::test]
 async fn basic_proxy(#[values(None, Some(""), Some("abc"))] secret: Option<&str>) -> Result<()> {
@@ -120,6 +171,3600 @@ async fn very_long_frame() -> Result<()> {
     panic!("did not exit after a 1 MB frame");
 }
 
+#[tokio::test]
+async fn excluded_port() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_excluded_ports("40000-40010".parse()?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    assert!(Client::new("localhost", 5000, "localhost", 40005, None)
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn mostly_excluded_ports() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(40000..=40999, None);
+    server.set_excluded_ports("40000-40989".parse()?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let mut clients = Vec::new();
+    for _ in 0..10 {
+        let client = Client::new("localhost", 5000, "localhost", 0, None).await?;
+        assert!(client.remote_port() >= 40990);
+        clients.push(client);
+    }
+    assert!(Client::new("localhost", 5000, "localhost", 0, None)
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn typed_errors() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let result = Client::new("localhost", 5000, "localhost", 0, None).await;
+    assert!(matches!(result, Err(Error::Connect { .. })));
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_excluded_ports("40000-40010".parse()?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let result = Client::new("localhost", 5000, "localhost", 0, Some("wrong")).await;
+    assert!(matches!(result, Err(Error::Handshake(_))));
+    let result = Client::new("localhost", 5000, "localhost", 0, None).await;
+    assert!(matches!(result, Err(Error::Handshake(_))));
+    let result = Client::new("localhost", 5000, "localhost", 40005, Some("secret")).await;
+    assert!(matches!(result, Err(Error::Bind(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn error_codes() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(40000..=40010, None);
+    server.set_excluded_ports("40005".parse()?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let open = |name: &str, port| {
+        let options = ClientOptions {
+            name: Some(name.into()),
+            ..Default::default()
+        };
+        Client::with_options("localhost", 5000, "localhost", port, None, options)
+    };
+    let code = |result: Result<Client, Error>| match result {
+        Err(err @ Error::Refused { .. }) => (err.code(), err.is_retryable()),
+        Err(err) => panic!("untyped error: {err}"),
+        Ok(_) => panic!("tunnel was opened"),
+    };
+    let _client = open("first", 40001).await?;
+    assert_eq!(
+        code(open("outside", 80).await),
+        (Some(ErrorCode::PortOutOfRange), false)
+    );
+    assert_eq!(
+        code(open("excluded", 40005).await),
+        (Some(ErrorCode::PortExcluded), false)
+    );
+    assert_eq!(
+        code(open("taken", 40001).await),
+        (Some(ErrorCode::PortInUse), true)
+    );
+
+    // Plain requests cannot ask for codes, so they get the message alone.
+    let result = Client::new("localhost", 5000, "localhost", 40001, None).await;
+    assert!(matches!(result, Err(Error::Bind(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn shared_tunnel() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(Some("secret")).await;
+    let first = TcpListener::bind("localhost:0").await?;
+    let second = TcpListener::bind("localhost:0").await?;
+    let shutdown = CancellationToken::new();
+    let options = ClientOptions {
+        name: Some("replicated".into()),
+        shared: true,
+        ..Default::default()
+    };
+    let connect = |listener: &TcpListener, options: ClientOptions| {
+        let local_port = listener.local_addr().unwrap().port();
+        Client::with_options(
+            "localhost",
+            local_port,
+            "localhost",
+            0,
+            Some("secret"),
+            options,
+        )
+    };
+    let leader = ClientOptions {
+        shutdown: shutdown.clone(),
+        ..options.clone()
+    };
+    let client = connect(&first, leader).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+    let client = connect(&second, options.clone()).await?;
+    assert_eq!(client.remote_port(), port);
+    tokio::spawn(client.listen());
+
+    // Visitors alternate between the clients.
+    for local in [&first, &second, &first, &second] {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        stream.write_all(b"hi").await?;
+        let (mut conn, _) = time::timeout(Duration::from_secs(1), local.accept()).await??;
+        let mut buf = [0u8; 2];
+        conn.read_exact(&mut buf).await?;
+    }
+
+    // Once the first client leaves, the port stays open for the other. The server notices the
+    // closed control connection when a heartbeat fails to send.
+    shutdown.cancel();
+    time::sleep(Duration::from_millis(1500)).await;
+    for _ in 0..2 {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        stream.write_all(b"hi").await?;
+        let (mut conn, _) = time::timeout(Duration::from_secs(1), second.accept()).await??;
+        let mut buf = [0u8; 2];
+        conn.read_exact(&mut buf).await?;
+    }
+
+    // Clients that do not ask to share are refused the port.
+    let local_port = first.local_addr()?.port();
+    let options = ClientOptions {
+        shared: false,
+        ..options
+    };
+    let secret = Some("secret");
+    let result = Client::with_options("localhost", local_port, "localhost", port, secret, options);
+    assert!(result.await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn shared_tunnel_unauthenticated() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let (listener, _addr) = spawn_client(None).await?;
+    let local_port = listener.local_addr()?.port();
+    let open = |shared| {
+        let options = ClientOptions {
+            name: Some("web".into()),
+            shared,
+            ..Default::default()
+        };
+        Client::with_options("localhost", local_port, "localhost", 0, None, options)
+    };
+    let client = open(false).await?;
+    assert!(!client.server_info().unwrap().supports("shared"));
+    tokio::spawn(client.listen());
+
+    // Every client of a server without authentication looks alike, so none may share a tunnel.
+    let err = open(true).await.err().expect("joined the tunnel");
+    assert_eq!(err.code(), Some(ErrorCode::InvalidRequest));
+    Ok(())
+}
+
+#[tokio::test]
+async fn relay_chain() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(Some("secret")).await;
+    let shutdown = CancellationToken::new();
+    for (port, upstream_port) in [(7900, CONTROL_PORT), (7901, 7900)] {
+        let options = ClientOptions {
+            control_port: Some(upstream_port),
+            ..Default::default()
+        };
+        let mut relay = Relay::new("localhost", &options);
+        relay.set_bind_addr(([127, 0, 0, 1], port).into());
+        relay.set_shutdown(shutdown.clone());
+        tokio::spawn(relay.listen());
+    }
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The client reaches the server through both relays, authenticating with it end to end.
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        control_port: Some(7901),
+        ..Default::default()
+    };
+    let result = Client::with_options(
+        "localhost",
+        local_port,
+        "localhost",
+        0,
+        None,
+        options.clone(),
+    );
+    assert!(matches!(result.await, Err(Error::Handshake(_))));
+    let client = Client::with_options(
+        "localhost",
+        local_port,
+        "localhost",
+        0,
+        Some("secret"),
+        options,
+    )
+    .await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut conn, _) = time::timeout(Duration::from_secs(1), listener.accept()).await??;
+    let mut buf = [0u8; 5];
+    conn.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    shutdown.cancel();
+    Ok(())
+}
+
+#[tokio::test]
+async fn ping_server() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let options = ClientOptions::default();
+    let result = client::ping("localhost", None, &options).await;
+    assert!(matches!(result, Err(Error::Connect { .. })));
+
+    spawn_server(Some("secret")).await;
+    let (_listener, _) = spawn_client(Some("secret")).await?;
+    let report = client::ping("localhost", Some("secret"), &options).await?;
+    assert_eq!(report.info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(report.info.ports.to_string(), "1024-65535");
+    assert_eq!(report.info.tunnels, 1);
+    assert!(report.info.auth && report.info.supports("admin"));
+
+    let result = client::ping("localhost", None, &options).await;
+    assert!(matches!(result, Err(Error::Handshake(_))));
+    let result = client::ping("localhost", Some("wrong"), &options).await;
+    assert!(matches!(result, Err(Error::Handshake(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_info() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    tokio::spawn(Server::new(2000..=3000, None).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let client = Client::new("localhost", 5000, "localhost", 0, None).await?;
+    assert!(client.server_info().is_none());
+
+    let options = ClientOptions {
+        name: Some("described".into()),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", 5000, "localhost", 0, None, options.clone()).await?;
+    let info = client.server_info().expect("no server info");
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert!(!info.auth);
+    assert!(info.supports("pool") && !info.supports("resume"));
+
+    let result = Client::with_options("localhost", 5000, "localhost", 80, None, options).await;
+    let err = result.err().expect("port outside the range was assigned");
+    assert!(
+        err.to_string().contains("server ports are 2000-3000"),
+        "{err}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn tunnel_subdomain() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_domain("Bore.Example.");
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Plain requests do not ask for a subdomain, unless the client wants one.
+    let client = Client::new("localhost", 5000, "localhost", 0, None).await?;
+    assert_eq!(client.hostname(), None);
+    assert_eq!(client.public_host(), "localhost");
+
+    let options = ClientOptions {
+        subdomain: true,
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 5000, "localhost", 0, None, options).await?;
+    let hostname = format!("{}.bore.example", subdomain(client.remote_port()));
+    assert_eq!(client.hostname(), Some(hostname.as_str()));
+    assert_eq!(
+        client.public_url(Scheme::Http),
+        format!("http://{hostname}:{}", client.remote_port())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn persistent_port() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let path = std::env::temp_dir().join(format!("bore-test-{}.json", std::process::id()));
+    let mut server = Server::new(1024..=65535, None);
+    server.set_port_store(PortStore::open(&path)?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let options = ClientOptions {
+        name: Some("my-app".into()),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", 5000, "localhost", 0, None, options.clone()).await?;
+    let port = client.remote_port();
+    drop(client);
+
+    // Wait for the server to notice the disconnect and release the port.
+    time::sleep(Duration::from_millis(1500)).await;
+    let client = Client::with_options("localhost", 5000, "localhost", 0, None, options).await?;
+    assert_eq!(client.remote_port(), port);
+    assert_eq!(PortStore::open(&path)?.get("my-app"), Some(port));
+
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn connect_fallback() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, port));
+    let v4 = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+
+    // Nothing listens on the IPv6 loopback, so the IPv4 address is tried next, ahead of the
+    // remaining IPv6 one, and connects.
+    let stream = client::connect_addrs(vec![v6, v6, v4], None).await?;
+    assert_eq!(stream.peer_addr()?, v4);
+    let (accepted, _) = listener.accept().await?;
+    assert_eq!(accepted.peer_addr()?, stream.local_addr()?);
+
+    let err = client::connect_addrs(vec![v6, v6], None).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    assert!(client::connect_addrs(Vec::new(), None).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnect_grace() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_reconnect_grace(Duration::from_secs(5), 4);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        name: Some("flaky".into()),
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        local_port,
+        "localhost",
+        0,
+        None,
+        options.clone(),
+    )
+    .await?;
+    let port = client.remote_port();
+    drop(client);
+    time::sleep(Duration::from_millis(1500)).await;
+
+    // This visitor arrives while no client is attached, so it is queued by the server.
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"queued").await?;
+
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    assert_eq!(client.remote_port(), port);
+    tokio::spawn(client.listen());
+
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 6];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"queued");
+    Ok(())
+}
+
+/// Authenticates clients that know any of several per-user secrets.
+struct UserSecrets(Vec<(&'static str, Authenticator)>);
+
+impl Authenticate for UserSecrets {
+    fn verify<'a>(
+        &'a self,
+        challenge: &'a Uuid,
+        answer: &'a str,
+    ) -> BoxFuture<'a, error::Result<Identity>> {
+        Box::pin(async move {
+            let user = self
+                .0
+                .iter()
+                .find(|(_, auth)| auth.validate(challenge, answer));
+            match user {
+                Some((name, _)) => Ok(Identity(name.to_string())),
+                None => Err(Error::Handshake("unknown user".into())),
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn accept_limits() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_accept_policy(AcceptPolicy {
+        per_port: Some("3/m".parse()?),
+        per_ip: Some("2/m".parse()?),
+    });
+    server.set_reconnect_grace(Duration::from_secs(5), 4);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let local = TcpListener::bind("localhost:0").await?;
+    let local_port = local.local_addr()?.port();
+    let options = ClientOptions {
+        name: Some("limited".into()),
+        ..Default::default()
+    };
+    let open = || {
+        let options = options.clone();
+        Client::with_options("localhost", local_port, "localhost", 0, None, options)
+    };
+    let client = open().await?;
+    let port = client.remote_port();
+    let handle = tokio::spawn(client.listen());
+    for from in 2..=4 {
+        assert!(visit(&local, [127, 0, 0, from], port).await?);
+    }
+    assert!(!visit(&local, [127, 0, 0, 5], port).await?);
+
+    // The port keeps its limit when the client reconnects.
+    handle.abort();
+    time::sleep(Duration::from_millis(500)).await;
+    let client = open().await?;
+    assert_eq!(client.remote_port(), port);
+    tokio::spawn(client.listen());
+    assert!(!visit(&local, [127, 0, 0, 6], port).await?);
+
+    // Each source address has a limit of its own, across ports.
+    let client = Client::new("localhost", local_port, "localhost", 0, None).await?;
+    let other = client.remote_port();
+    tokio::spawn(client.listen());
+    assert!(visit(&local, [127, 0, 0, 7], other).await?);
+    assert!(visit(&local, [127, 0, 0, 7], other).await?);
+    assert!(!visit(&local, [127, 0, 0, 7], other).await?);
+    assert!(visit(&local, [127, 0, 0, 8], other).await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnect_grace_identity() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_authenticator(UserSecrets(vec![
+        ("alice", Authenticator::new("alice secret")),
+        ("bob", Authenticator::new("bob secret")),
+    ]));
+    server.set_reconnect_grace(Duration::from_secs(5), 4);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let options = ClientOptions {
+        name: Some("web".into()),
+        ..Default::default()
+    };
+    let open = |secret| {
+        let options = options.clone();
+        Client::with_options("localhost", 5000, "localhost", 0, Some(secret), options)
+    };
+    let client = open("alice secret").await?;
+    let port = client.remote_port();
+    drop(client);
+    time::sleep(Duration::from_millis(500)).await;
+
+    // Another identity with the same name does not take over the parked tunnel.
+    let err = open("bob secret")
+        .await
+        .err()
+        .expect("tunnel was taken over");
+    assert_eq!(err.code(), Some(ErrorCode::PortInUse));
+
+    let client = open("alice secret").await?;
+    assert_eq!(client.remote_port(), port);
+    Ok(())
+}
+
+#[tokio::test]
+async fn lazy_connect() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // Nothing is sent until the client connects, so the server can start afterwards.
+    let lazy = Client::lazy(
+        "localhost",
+        5000,
+        "localhost",
+        0,
+        Some("abc"),
+        Default::default(),
+    );
+    spawn_server(Some("abc")).await;
+
+    let first = lazy.connect().await?;
+    let second = lazy.connect().await?;
+    assert_ne!(first.remote_port(), second.remote_port());
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_handle() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let lazy = Client::lazy("localhost", 5000, "localhost", 0, None, Default::default());
+    let handle = ClientHandle::new(lazy);
+    assert_eq!(handle.state(), ClientState::Disconnected(None));
+
+    let port = handle.reconnect().await?;
+    assert_eq!(handle.remote_port(), Some(port));
+    assert!(TcpStream::connect(("localhost", port)).await.is_ok());
+
+    // Clones share the connection, so another task can close it.
+    let mut states = handle.subscribe();
+    let other = handle.clone();
+    tokio::spawn(async move { other.disconnect() });
+    while matches!(*states.borrow_and_update(), ClientState::Connected(_)) {
+        states.changed().await?;
+    }
+    assert_eq!(handle.state(), ClientState::Disconnected(None));
+
+    // The handle outlives the connection, and connects again.
+    let port = handle.reconnect().await?;
+    assert_eq!(handle.state(), ClientState::Connected(port));
+    Ok(())
+}
+
+#[tokio::test]
+async fn goodbye_releases_port() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_reconnect_grace(Duration::from_secs(5), 4);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let shutdown = CancellationToken::new();
+    let options = ClientOptions {
+        name: Some("leaving".into()),
+        shutdown: shutdown.clone(),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 5000, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    let listen = tokio::spawn(client.listen());
+    time::sleep(Duration::from_millis(100)).await;
+
+    // The client says goodbye, so the server does not hold the named tunnel's port.
+    shutdown.cancel();
+    listen.await??;
+    time::sleep(Duration::from_millis(100)).await;
+    assert!(TcpStream::connect(("localhost", port)).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn tunnel_expires() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_reconnect_grace(Duration::from_secs(5), 4);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let options = ClientOptions {
+        name: Some("demo".into()),
+        expires: Some(Duration::from_secs(2)),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", 5000, "localhost", 0, Some("secret"), options).await?;
+    let port = client.remote_port();
+    assert!(client
+        .expires_in()
+        .is_some_and(|left| left <= Duration::from_secs(2)));
+    let listen = tokio::spawn(client.listen());
+
+    let admin = AdminClient::connect("localhost", "secret").await?;
+    let responses = admin.request(AdminRequest::ListTunnels).await?;
+    let [AdminResponse::Tunnel(tunnel)] = &responses[..] else {
+        panic!("expected one tunnel, got {responses:?}");
+    };
+    assert!(tunnel.expires_in_secs.is_some_and(|secs| secs <= 2));
+
+    // The tunnel closes on its own, and its port is not held for the client to reconnect.
+    time::timeout(Duration::from_secs(3), listen).await???;
+    time::sleep(Duration::from_millis(100)).await;
+    assert!(TcpStream::connect(("localhost", port)).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn transfer_limit() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_transfer_limit(1 << 20);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        transfer_limit: Some(16 << 10),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", local_port, "localhost", 0, None, options);
+    let client = client.await?;
+    let port = client.remote_port();
+    let listen = tokio::spawn(client.listen());
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        stream.write_all(&[0; 1 << 20]).await?;
+        anyhow::Ok(())
+    });
+
+    // The visitor is cut off soon after the limit, and the client learns why the tunnel closed.
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    let mut received = Vec::new();
+    let _ = stream.read_to_end(&mut received).await;
+    assert!(
+        received.len() < 1 << 20,
+        "received {} bytes",
+        received.len()
+    );
+    let err = time::timeout(Duration::from_secs(3), listen)
+        .await??
+        .unwrap_err();
+    assert_eq!(err.code(), Some(ErrorCode::TransferLimit));
+    assert!(!err.is_retryable());
+    Ok(())
+}
+
+#[tokio::test]
+async fn env_file() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let path = std::env::temp_dir().join(format!("bore-{}.env", std::process::id()));
+    let options = ClientOptions {
+        env_file: Some(EnvFile {
+            path: path.clone(),
+            scheme: Scheme::Http,
+        }),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 5000, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    let contents = std::fs::read_to_string(&path)?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(
+        contents,
+        format!(
+            "BORE_REMOTE_HOST=localhost\nBORE_REMOTE_PORT={port}\nBORE_URL=http://localhost:{port}\n"
+        )
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn status_file() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let path = std::env::temp_dir().join(format!("bore-status-{}.json", std::process::id()));
+    let shutdown = CancellationToken::new();
+    let options = ClientOptions {
+        status_file: Some(path.clone()),
+        shutdown: shutdown.clone(),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 5000, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    let listen = tokio::spawn(client.listen());
+    time::sleep(Duration::from_millis(100)).await;
+
+    let status = ClientStatus::read(&path)?;
+    assert_eq!(status.state, ConnectionState::Connected);
+    assert_eq!((status.remote_port, status.local_port), (port, 5000));
+    assert_eq!(status.error, None);
+
+    shutdown.cancel();
+    listen.await??;
+    let status = ClientStatus::read(&path)?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(status.state, ConnectionState::Disconnected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn shutdown_notice() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_shutdown_notice("planned maintenance", Some(Duration::from_secs(30)));
+    let shutdown = CancellationToken::new();
+    server.set_shutdown(shutdown.clone());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Only extended requests, like one for a named tunnel, ask for the notice.
+    let options = ClientOptions {
+        name: Some("notified".into()),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 5000, "localhost", 0, None, options).await?;
+    let listen = tokio::spawn(client.listen());
+    time::sleep(Duration::from_millis(100)).await;
+
+    shutdown.cancel();
+    match time::timeout(Duration::from_secs(2), listen).await?? {
+        Err(Error::ServerShutdown {
+            reason,
+            retry_after,
+        }) => {
+            assert_eq!(reason, "planned maintenance");
+            assert_eq!(retry_after, Some(Duration::from_secs(30)));
+        }
+        result => panic!("unexpected result: {result:?}"),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn resume_token() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_reconnect_grace(Duration::from_secs(5), 4);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let options = ClientOptions {
+        resumable: true,
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", 5000, "localhost", 0, None, options.clone()).await?;
+    let port = client.remote_port();
+    let token = client.resume_token().expect("no resumption token");
+    drop(client);
+    time::sleep(Duration::from_millis(1500)).await;
+
+    // Without the token, another client cannot take over the held port.
+    let other =
+        Client::with_options("localhost", 5000, "localhost", port, None, options.clone()).await;
+    assert!(other.is_err());
+
+    let options = ClientOptions {
+        resume_token: Some(token),
+        ..options
+    };
+    let client = Client::with_options("localhost", 5000, "localhost", 0, None, options).await?;
+    assert_eq!(client.remote_port(), port);
+    assert_ne!(client.resume_token(), Some(token));
+    Ok(())
+}
+
+#[tokio::test]
+async fn webhook_events() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let hook = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/hook", hook.local_addr()?);
+    let mut server = Server::new(1024..=65535, None);
+    server.set_webhooks(Webhooks::new(vec![url]));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (_listener, addr) = spawn_client(None).await?;
+    let (mut stream, _) = hook.accept().await?;
+    let mut request = Vec::new();
+    while !request.ends_with(b"}") {
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await?;
+        assert!(n > 0, "webhook request ended early");
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8(request)?;
+    assert!(request.starts_with("POST /hook"));
+    assert!(request.contains(r#""event":"opened""#));
+    assert!(request.contains(&format!(r#""port":{}"#, addr.port())));
+    Ok(())
+}
+
+#[tokio::test]
+async fn ban_after_failures() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_ban_policy(BanPolicy {
+        threshold: 2,
+        window: Duration::from_secs(60),
+        duration: Duration::from_secs(2),
+    });
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let admin = AdminClient::connect("localhost", "secret").await?;
+    assert!(admin.request(AdminRequest::ListBans).await?.is_empty());
+
+    assert!(spawn_client(Some("wrong")).await.is_err());
+    assert!(spawn_client(Some("wrong")).await.is_err());
+    assert!(spawn_client(Some("secret")).await.is_err());
+
+    time::sleep(Duration::from_secs(2)).await;
+    spawn_client(Some("secret")).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_ban() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let path = std::env::temp_dir().join(format!("bore-bans-{}.json", std::process::id()));
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_ban_file(&path)?;
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let (listener, addr) = spawn_client(Some("secret")).await?;
+
+    // Connect from another loopback address, so that the admin client is not banned too.
+    let banned_ip = "127.0.0.2".parse()?;
+    let connect_from = |ip, addr| async move {
+        let socket = tokio::net::TcpSocket::new_v4()?;
+        socket.bind(SocketAddr::new(ip, 0))?;
+        socket.connect(addr).await
+    };
+    let options = ClientOptions {
+        bind_addr: Some(banned_ip),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", 5000, "localhost", 0, Some("secret"), options).await?;
+    let banned_listen = tokio::spawn(client.listen());
+    let admin = AdminClient::connect("localhost", "secret").await?;
+    let request = AdminRequest::Ban {
+        ip: banned_ip,
+        duration_secs: 3600,
+    };
+    admin.request(request).await?;
+    time::timeout(Duration::from_secs(1), banned_listen).await???;
+
+    let mut buf = [0u8; 5];
+    let mut visitor = connect_from(banned_ip, addr).await?;
+    assert_eq!(visitor.read(&mut buf).await?, 0);
+    let mut visitor = TcpStream::connect(addr).await?;
+    visitor.write_all(b"hello").await?;
+    let (mut local, _) = time::timeout(Duration::from_secs(1), listener.accept()).await??;
+    local.read_exact(&mut buf).await?;
+    let mut control = connect_from(banned_ip, ([127, 0, 0, 1], CONTROL_PORT).into()).await?;
+    assert_eq!(control.read(&mut buf).await?, 0);
+
+    let mut saved = BanList::default();
+    saved.open(&path)?;
+    assert_eq!(saved.bans().len(), 1);
+    assert!(saved.is_banned(banned_ip));
+
+    let admin = AdminClient::connect("localhost", "secret").await?;
+    admin.request(AdminRequest::Unban(banned_ip)).await?;
+    let mut saved = BanList::default();
+    saved.open(&path)?;
+    assert!(saved.bans().is_empty());
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn geoip_filter() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let path = std::env::temp_dir().join(format!("bore-geoip-{}.mmdb", std::process::id()));
+    std::fs::write(
+        &path,
+        geoip_database(&[("127.0.0.1", "DE"), ("127.0.0.2", "US")]),
+    )?;
+    let mut geoip = GeoFilter::open(&path)?;
+    std::fs::remove_file(path)?;
+    geoip.set_policy("allow:DE".parse()?);
+    geoip.set_port_policy(40201, "deny:DE".parse()?);
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_geoip(geoip);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let local = TcpListener::bind("localhost:0").await?;
+    let local_port = local.local_addr()?.port();
+    for port in [40200, 40201] {
+        let client =
+            Client::new("localhost", local_port, "localhost", port, Some("secret")).await?;
+        tokio::spawn(client.listen());
+    }
+
+    assert!(visit(&local, [127, 0, 0, 1], 40200).await?);
+    assert!(!visit(&local, [127, 0, 0, 2], 40200).await?);
+    assert!(!visit(&local, [127, 0, 0, 3], 40200).await?);
+
+    // The override for the second port denies the country that the default allows.
+    assert!(!visit(&local, [127, 0, 0, 1], 40201).await?);
+    assert!(visit(&local, [127, 0, 0, 2], 40201).await?);
+    assert!(visit(&local, [127, 0, 0, 3], 40201).await?);
+
+    let admin = AdminClient::connect("localhost", "secret").await?;
+    let responses = admin.request(AdminRequest::Stats).await?;
+    let [AdminResponse::Stats(stats)] = &responses[..] else {
+        panic!("expected stats, got {responses:?}");
+    };
+    let blocked = [("DE", 1), ("US", 1), ("unknown", 1)];
+    let blocked = blocked.map(|(country, count)| (country.to_string(), count));
+    assert_eq!(stats.geoip_blocked, BTreeMap::from(blocked));
+    Ok(())
+}
+
+/// Build a MaxMind database that maps IPv4 addresses to their country codes.
+fn geoip_database(countries: &[(&str, &str)]) -> Vec<u8> {
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    fn push_str(buf: &mut Vec<u8>, s: &str) {
+        buf.push(0x40 | s.len() as u8);
+        buf.extend_from_slice(s.as_bytes());
+    }
+
+    let mut nodes = vec![[Record::Empty; 2]];
+    let mut data = Vec::new();
+    for (ip, country) in countries {
+        let offset = data.len();
+        data.push(0xe1);
+        push_str(&mut data, "country");
+        data.push(0xe1);
+        push_str(&mut data, "iso_code");
+        push_str(&mut data, country);
+
+        let bits = u32::from(ip.parse::<Ipv4Addr>().unwrap());
+        let mut node = 0;
+        for i in (1..32).rev() {
+            let bit = (bits >> i & 1) as usize;
+            node = match nodes[node][bit] {
+                Record::Node(next) => next,
+                _ => {
+                    nodes.push([Record::Empty; 2]);
+                    nodes[node][bit] = Record::Node(nodes.len() - 1);
+                    nodes.len() - 1
+                }
+            };
+        }
+        nodes[node][(bits & 1) as usize] = Record::Data(offset);
+    }
+
+    let node_count = nodes.len() as u32;
+    let mut db = Vec::new();
+    for record in nodes.into_iter().flatten() {
+        let value = match record {
+            Record::Empty => node_count,
+            Record::Node(next) => next as u32,
+            Record::Data(offset) => node_count + 16 + offset as u32,
+        };
+        db.extend_from_slice(&value.to_be_bytes()[1..]);
+    }
+    db.extend_from_slice(&[0; 16]);
+    db.extend_from_slice(&data);
+
+    db.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+    db.push(0xe9);
+    push_str(&mut db, "binary_format_major_version");
+    db.extend_from_slice(&[0xa1, 2]);
+    push_str(&mut db, "binary_format_minor_version");
+    db.push(0xa0);
+    push_str(&mut db, "build_epoch");
+    db.extend_from_slice(&[0x00, 0x02]);
+    push_str(&mut db, "database_type");
+    push_str(&mut db, "GeoIP2-Country");
+    push_str(&mut db, "description");
+    db.push(0xe0);
+    push_str(&mut db, "ip_version");
+    db.extend_from_slice(&[0xa1, 4]);
+    push_str(&mut db, "languages");
+    db.extend_from_slice(&[0x00, 0x04]);
+    push_str(&mut db, "node_count");
+    db.push(0xc4);
+    db.extend_from_slice(&node_count.to_be_bytes());
+    push_str(&mut db, "record_size");
+    db.extend_from_slice(&[0xa1, 24]);
+    db
+}
+
+#[tokio::test]
+async fn admin_list_tunnels() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(Some("secret")).await;
+    let (listener, addr) = spawn_client(Some("secret")).await?;
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        stream.write_all(b"hello").await?;
+        anyhow::Ok(())
+    });
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await?;
+
+    let admin = AdminClient::connect("localhost", "secret").await?;
+    let responses = admin.request(AdminRequest::ListTunnels).await?;
+    let [AdminResponse::Tunnel(tunnel)] = &responses[..] else {
+        panic!("expected one tunnel, got {responses:?}");
+    };
+    assert_eq!(tunnel.port, addr.port());
+    assert_eq!(tunnel.connections, 1);
+    assert_eq!(tunnel.sent, 5);
+
+    let admin = AdminClient::connect("localhost", "secret").await?;
+    let responses = admin.request(AdminRequest::Stats).await?;
+    let [AdminResponse::Stats(stats)] = &responses[..] else {
+        panic!("expected stats, got {responses:?}");
+    };
+    assert_eq!(stats.tunnels, 1);
+    assert_eq!(stats.connections, 1);
+    assert_eq!(stats.version, env!("CARGO_PKG_VERSION"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_close_tunnel() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_reconnect_grace(Duration::from_secs(30), 8);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let options = ClientOptions {
+        name: Some("web".into()),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", 5000, "localhost", 0, Some("secret"), options).await?;
+    let port = client.remote_port();
+    let listen = tokio::spawn(client.listen());
+
+    let admin = AdminClient::connect("localhost", "secret").await?;
+    let target = TunnelTarget::Name("web".into());
+    admin.request(AdminRequest::Close(target.clone())).await?;
+    time::timeout(Duration::from_secs(1), listen).await???;
+
+    // The port is released at once, rather than held for the client to reconnect.
+    time::sleep(Duration::from_millis(50)).await;
+    TcpListener::bind(("0.0.0.0", port)).await?;
+    let admin = AdminClient::connect("localhost", "secret").await?;
+    let err = admin
+        .request(AdminRequest::Close(target))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("no open tunnel"), "{err}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn tunnel_metadata() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(Some("secret")).await;
+    let options = ClientOptions {
+        description: Some("staging API".into()),
+        client_host: Some("ci-runner-3".into()),
+        ..Default::default()
+    };
+    let open =
+        |options| Client::with_options("localhost", 5000, "localhost", 0, Some("secret"), options);
+    let client = open(options.clone()).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let admin = AdminClient::connect("localhost", "secret").await?;
+    let responses = admin.request(AdminRequest::ListTunnels).await?;
+    let [AdminResponse::Tunnel(tunnel)] = &responses[..] else {
+        panic!("expected one tunnel, got {responses:?}");
+    };
+    assert_eq!(tunnel.port, port);
+    assert_eq!(tunnel.description.as_deref(), Some("staging API"));
+    assert_eq!(tunnel.client_host.as_deref(), Some("ci-runner-3"));
+
+    let long = ClientOptions {
+        description: Some("x".repeat(1000)),
+        ..options.clone()
+    };
+    let err = open(long).await.err().expect("tunnel was opened");
+    assert!(err.to_string().contains("too long"), "{err}");
+
+    // The longest request, with every character escaped, still reaches the server.
+    let escaped = ClientOptions {
+        name: Some("\"".repeat(64)),
+        description: Some("\u{1}".repeat(256)),
+        client_host: Some("\u{1}".repeat(256)),
+        ..options
+    };
+    let err = open(escaped).await.err().expect("tunnel was opened");
+    assert_eq!(err.code(), Some(ErrorCode::InvalidRequest));
+    Ok(())
+}
+
+#[tokio::test]
+async fn transfer_quota() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_quotas(Quotas::new(10));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(Some("secret")).await?;
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 11];
+    local.read_exact(&mut buf[..5]).await?;
+    local.write_all(b"hello world").await?;
+    stream.read_exact(&mut buf).await?;
+
+    let admin = AdminClient::connect("localhost", "secret").await?;
+    let responses = admin.request(AdminRequest::Usage).await?;
+    let [AdminResponse::Usage(usage)] = &responses[..] else {
+        panic!("expected usage of one identity, got {responses:?}");
+    };
+    assert_eq!(usage.identity, "secret");
+    assert_eq!((usage.used, usage.limit), (16, 10));
+
+    // The open connection is left alone, but new visitors and tunnels are refused.
+    local.write_all(b"!").await?;
+    stream.read_exact(&mut buf[..1]).await?;
+    let mut refused = TcpStream::connect(addr).await?;
+    assert_eq!(refused.read(&mut buf).await?, 0);
+    let err = spawn_client(Some("secret")).await.unwrap_err();
+    assert!(err.to_string().contains("quota"), "{err}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn parked_quota() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_quotas(Quotas::new(10));
+    server.set_reconnect_grace(Duration::from_secs(5), 4);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        name: Some("metered".into()),
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        local_port,
+        "localhost",
+        0,
+        Some("secret"),
+        options,
+    )
+    .await?;
+    let port = client.remote_port();
+    let handle = tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"hello world!").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 12];
+    local.read_exact(&mut buf).await?;
+    drop((stream, local));
+    handle.abort();
+    time::sleep(Duration::from_millis(500)).await;
+
+    // The parked tunnel still belongs to an identity over its quota, so visitors are refused.
+    let mut refused = TcpStream::connect(("localhost", port)).await?;
+    let read = time::timeout(Duration::from_secs(1), refused.read(&mut buf)).await;
+    assert!(matches!(read, Ok(Ok(0))), "visitor was not refused");
+    Ok(())
+}
+
+#[tokio::test]
+async fn persistent_quota() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let path = std::env::temp_dir().join(format!("bore-quota-{}.json", std::process::id()));
+    let period = Quotas::new(10).period();
+    let saved = format!(r#"{{"period": "{period}", "usage": {{"secret": 10}}}}"#);
+    std::fs::write(&path, saved)?;
+    let quotas = Quotas::open(&path, 10)?;
+    assert_eq!(quotas.used("secret"), 10);
+    assert!(quotas.exceeded("secret"));
+    quotas.save()?;
+    assert_eq!(
+        Quotas::open(&path, 20)?.usage(),
+        [("secret".to_string(), 10)]
+    );
+
+    // Usage from an earlier month no longer counts.
+    std::fs::write(&path, r#"{"period": "2000-01", "usage": {"secret": 10}}"#)?;
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_quotas(Quotas::open(&path, 10)?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    spawn_client(Some("secret")).await?;
+
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn access_schedule() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // Windows of an hour that starts now, and of one that starts twelve hours from now.
+    let hour = UNIX_EPOCH.elapsed()?.as_secs() / 3600 % 24;
+    let window = |start: u64| format!("{:02}:00-{:02}:00", start % 24, (start + 1) % 24);
+    let mut schedules = Schedules::new();
+    schedules.add("secret", window(hour).parse()?);
+    schedules.add("other", window(hour + 12).parse()?);
+    assert!(schedules.allows("secret", SystemTime::now()));
+    assert!(!schedules.allows("other", SystemTime::now()));
+    assert!(schedules.allows("unscheduled", SystemTime::now()));
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    let mut closed = Schedules::new();
+    closed.add("secret", window(hour + 12).parse()?);
+    server.set_schedules(closed);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let err = spawn_client(Some("secret")).await.unwrap_err();
+    assert!(err.to_string().contains("not allowed"), "{err}");
+    let options = ClientOptions {
+        name: Some("contractor".into()),
+        ..Default::default()
+    };
+    let result = Client::with_options("localhost", 5000, "localhost", 0, Some("secret"), options);
+    let err = result.await.err().expect("tunnel was opened");
+    assert_eq!(err.code(), Some(ErrorCode::PermissionDenied));
+    Ok(())
+}
+
+#[tokio::test]
+async fn scoped_secrets() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut secrets = ScopedSecrets::new();
+    secrets.add("40000-40009".parse()?, "team");
+    secrets.add("name:staging-*".parse()?, "staging");
+    let mut server = Server::new(1024..=65535, Some("main"));
+    server.set_scoped_secrets(secrets);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let client = Client::new("localhost", 5000, "localhost", 0, Some("team")).await?;
+    assert!((40000..=40009).contains(&client.remote_port()));
+    let client = Client::new("localhost", 5000, "localhost", 0, Some("main")).await?;
+    assert!(!(40000..=40009).contains(&client.remote_port()));
+
+    let options = ClientOptions {
+        name: Some("web".into()),
+        ..Default::default()
+    };
+    let result = Client::with_options("localhost", 5000, "localhost", 40005, Some("main"), options);
+    let err = result.await.err().expect("tunnel was opened");
+    assert_eq!(err.code(), Some(ErrorCode::PermissionDenied));
+    let options = ClientOptions {
+        name: Some("staging-web".into()),
+        ..Default::default()
+    };
+    let result = Client::with_options("localhost", 5000, "localhost", 0, Some("team"), options);
+    let err = result.await.err().expect("tunnel was opened");
+    assert_eq!(err.code(), Some(ErrorCode::PermissionDenied));
+    Ok(())
+}
+
+#[tokio::test]
+async fn open_ports() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_open_ports("40000-40009".parse()?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(None).await?;
+    assert!((40000..=40009).contains(&addr.port()));
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        stream.write_all(b"hello").await?;
+        anyhow::Ok(())
+    });
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    let options = ClientOptions {
+        name: Some("premium".into()),
+        ..Default::default()
+    };
+    let result = Client::with_options("localhost", 5000, "localhost", 5000, None, options);
+    let err = result.await.err().expect("tunnel was opened");
+    assert_eq!(err.code(), Some(ErrorCode::PermissionDenied));
+    let client = Client::new("localhost", 5000, "localhost", 5000, Some("secret")).await?;
+    assert_eq!(client.remote_port(), 5000);
+    Ok(())
+}
+
+#[tokio::test]
+async fn min_client_version() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_min_client_version(env!("CARGO_PKG_VERSION").parse()?);
+    let tunnels = server.tunnels();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A plain request carries no version, so the client asks again with one.
+    let client = Client::new("localhost", 5000, "localhost", 0, Some("secret")).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let [tunnel] = &tunnels.list()[..] else {
+        panic!("expected one tunnel");
+    };
+    assert_eq!(tunnel.port, port);
+    assert_eq!(
+        tunnel.client_version.as_deref(),
+        Some(env!("CARGO_PKG_VERSION"))
+    );
+
+    let control = TcpListener::bind("localhost:0").await?;
+    let control_port = control.local_addr()?.port();
+    let mut server = Server::new(1024..=65535, None);
+    server.set_min_client_version("999.0".parse()?);
+    tokio::spawn(server.listen_on(control));
+
+    let options = ClientOptions {
+        control_port: Some(control_port),
+        ..Default::default()
+    };
+    let result = Client::with_options("localhost", 5000, "localhost", 0, None, options);
+    let err = result.await.err().expect("tunnel was opened");
+    assert_eq!(err.code(), Some(ErrorCode::ClientOutdated));
+    assert!(!err.is_retryable());
+    assert!(err.to_string().contains("requires bore 999.0.0"), "{err}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn connection_rate() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_identity_connection_rate("secret", "16K".parse()?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(Some("secret")).await?;
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        stream.write_all(&[0; 48 << 10]).await?;
+        anyhow::Ok(())
+    });
+
+    // After a burst of a second's worth, the rest takes two more seconds.
+    let start = time::Instant::now();
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.read_exact(&mut [0; 48 << 10]).await?;
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    Ok(())
+}
+
+#[tokio::test]
+async fn audit_log() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let path = std::env::temp_dir().join(format!("bore-audit-{}.jsonl", std::process::id()));
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_audit_log(AuditLog::open(&path)?);
+    let shutdown = CancellationToken::new();
+    server.set_shutdown(shutdown.clone());
+    let server = tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    assert!(spawn_client(Some("wrong")).await.is_err());
+    let (listener, addr) = spawn_client(Some("secret")).await?;
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    drop((stream, local));
+    time::sleep(Duration::from_millis(50)).await;
+    shutdown.cancel();
+    server.await??;
+
+    let log = std::fs::read_to_string(&path)?;
+    std::fs::remove_file(&path)?;
+    let events: Vec<serde_json::Value> = log
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    let kinds: Vec<_> = events.iter().map(|event| event["event"].clone()).collect();
+    assert_eq!(kinds, ["auth_failed", "opened", "closed"]);
+    assert_eq!(events[1]["port"], addr.port());
+    assert_eq!(events[1]["identity"], "secret");
+    assert_eq!(events[2]["connections"], 1);
+    assert_eq!(events[2]["received"], 5);
+    assert!(events[2]["timestamp"].is_u64());
+    Ok(())
+}
+
+#[tokio::test]
+async fn health_endpoint() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_health_port(7839);
+    server.set_excluded_ports("40000-40999".parse()?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let (_listener, _addr) = spawn_client(None).await?;
+
+    let mut stream = TcpStream::connect(("localhost", 7839)).await?;
+    stream
+        .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains(r#""ready":true"#));
+    assert!(response.contains(r#""tunnels":1"#));
+
+    let mut stream = TcpStream::connect(("localhost", 7839)).await?;
+    stream.write_all(b"GET /status HTTP/1.1\r\n\r\n").await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(response.contains("Content-Type: text/plain"));
+    assert!(response.contains("tunnels:      1\n"));
+    assert!(response.contains("ports:        1 of 63512 in use"));
+
+    let mut stream = TcpStream::connect(("localhost", 7839)).await?;
+    stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(response.contains("Content-Type: text/plain"));
+
+    let mut stream = TcpStream::connect(("localhost", 7839)).await?;
+    stream.write_all(b"POST /healthz HTTP/1.1\r\n\r\n").await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    assert!(response.ends_with("method not allowed\n"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn public_listing() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_health_port(7839);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let (_listener, _addr) = spawn_client(None).await?;
+    let options = ClientOptions {
+        name: Some("blog".into()),
+        description: Some("my static blog".into()),
+        public_listing: true,
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 5000, "localhost", 0, None, options).await?;
+    assert!(client.server_info().unwrap().supports("public-listing"));
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", 7839)).await?;
+    stream.write_all(b"GET /tunnels HTTP/1.1\r\n\r\n").await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(response.contains("Content-Type: application/json"));
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    let listing: serde_json::Value = serde_json::from_str(body)?;
+    let expected = serde_json::json!([
+        {"port": port, "name": "blog", "description": "my static blog"},
+    ]);
+    assert_eq!(listing, expected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn plaintext_and_tls_listeners() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let dir = std::env::temp_dir();
+    let cert_path = dir.join(format!("bore-test-{}.crt", std::process::id()));
+    let key_path = dir.join(format!("bore-test-{}.key", std::process::id()));
+    std::fs::write(&cert_path, cert.cert.pem())?;
+    std::fs::write(&key_path, cert.key_pair.serialize_pem())?;
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_control_listeners(vec![
+        ControlListener::plain(CONTROL_PORT),
+        ControlListener::tls(TLS_CONTROL_PORT, tls::server_config(&cert_path, &key_path)?),
+    ]);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Old clients keep working over plaintext.
+    spawn_client(Some("secret")).await?;
+
+    // Without trusting the self-signed certificate, the TLS handshake fails.
+    let options = ClientOptions {
+        tls: Some(tls::client_config(None)?),
+        ..Default::default()
+    };
+    let result = Client::with_options("localhost", 5000, "localhost", 0, None, options).await;
+    assert!(result.is_err());
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        tls: Some(tls::client_config(Some(&cert_path))?),
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        local_port,
+        "localhost",
+        0,
+        Some("secret"),
+        options,
+    )
+    .await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"over tls").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 8];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"over tls");
+
+    std::fs::remove_file(cert_path)?;
+    std::fs::remove_file(key_path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn acme_cached_certificate() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // A certificate left by an earlier run is served without contacting the authority.
+    let mut params = rcgen::CertificateParams::new(vec!["localhost".into()])?;
+    // Before 2050, the expiry is encoded as UTCTime, with a two-digit year.
+    params.not_after = rcgen::date_time_ymd(2030, 1, 1);
+    let key = rcgen::KeyPair::generate()?;
+    let cert = params.self_signed(&key)?;
+    let dir = std::env::temp_dir().join(format!("bore-test-acme-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("cert.pem"), cert.pem())?;
+    std::fs::write(dir.join("key.pem"), key.serialize_pem())?;
+    std::fs::write(dir.join("domains"), "localhost")?;
+
+    let other = Acme::new(vec!["example.com".into()], &dir)?;
+    assert_eq!(other.expires(), None);
+    let mut acme = Acme::new(vec!["localhost".into()], &dir)?;
+    let expires = UNIX_EPOCH + Duration::from_secs(1893456000);
+    assert_eq!(acme.expires(), Some(expires));
+    acme.set_directory("http://localhost:1/directory");
+    acme.set_challenge_port(0);
+
+    let mut server = Server::new(1024..=65535, None);
+    let config = acme.server_config()?;
+    server.set_control_listeners(vec![ControlListener::tls(TLS_CONTROL_PORT, config)]);
+    server.set_acme(acme);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let cert_path = dir.join("cert.pem");
+    let options = ClientOptions {
+        tls: Some(tls::client_config(Some(&cert_path))?),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"acme").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 4];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"acme");
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+/// A minimal ACME authority, which checks the signature of every request, validates HTTP-01
+/// challenges on the challenge port, and signs certificates with its own CA.
+struct MockAcme {
+    base: String,
+    challenge_port: u16,
+    ca: rcgen::Certificate,
+    ca_key: rcgen::KeyPair,
+    state: std::sync::Mutex<MockAcmeState>,
+}
+
+#[derive(Default)]
+struct MockAcmeState {
+    nonce: u64,
+    jwk: Option<serde_json::Value>,
+    authorized: bool,
+    chain: Option<String>,
+    requests: Vec<String>,
+}
+
+impl MockAcme {
+    async fn serve(self: Arc<Self>, listener: TcpListener) {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let this = Arc::clone(&self);
+            tokio::spawn(async move {
+                let (head, body) = read_request(&mut stream).await?;
+                let mut line = head.split(' ');
+                let (method, path) = (
+                    line.next().unwrap_or_default(),
+                    line.next().unwrap_or_default(),
+                );
+                let (status, location, body) = match this.handle(method, path, &body).await {
+                    Ok(response) => response,
+                    Err(err) => {
+                        let problem =
+                            serde_json::json!({ "type": "malformed", "detail": err.to_string() });
+                        ("400 Bad Request", None, problem.to_string())
+                    }
+                };
+                let nonce = this.next_nonce();
+                let location = location
+                    .map(|url| format!("Location: {url}\r\n"))
+                    .unwrap_or_default();
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nReplay-Nonce: {nonce}\r\n{location}\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await?;
+                anyhow::Ok(())
+            });
+        }
+    }
+
+    fn next_nonce(&self) -> String {
+        let mut state = self.state.lock().unwrap();
+        state.nonce += 1;
+        format!("nonce-{}", state.nonce)
+    }
+
+    async fn handle(
+        &self,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> Result<(&'static str, Option<String>, String)> {
+        let base = &self.base;
+        let url = |path: &str| format!("{base}{path}");
+        match (method, path) {
+            ("GET", "/directory") => {
+                let directory = serde_json::json!({
+                    "newNonce": url("/nonce"),
+                    "newAccount": url("/account"),
+                    "newOrder": url("/order"),
+                });
+                return Ok(("200 OK", None, directory.to_string()));
+            }
+            ("HEAD", "/nonce") => return Ok(("200 OK", None, String::new())),
+            ("POST", _) => {}
+            _ => bail!("unexpected {method} {path}"),
+        }
+
+        let payload = self.verify(&url(path), body)?;
+        self.state.lock().unwrap().requests.push(path.into());
+        let order = |status: &str| {
+            serde_json::json!({
+                "status": status,
+                "authorizations": [url("/authz")],
+                "finalize": url("/finalize"),
+                "certificate": url("/cert"),
+            })
+        };
+        Ok(match path {
+            "/account" => ("201 Created", Some(url("/account/1")), "{}".into()),
+            "/order" => {
+                let identifiers = serde_json::json!([{ "type": "dns", "value": "localhost" }]);
+                ensure!(payload["identifiers"] == identifiers, "wrong identifiers");
+                (
+                    "201 Created",
+                    Some(url("/order/1")),
+                    order("pending").to_string(),
+                )
+            }
+            "/order/1" => {
+                let done = self.state.lock().unwrap().chain.is_some();
+                (
+                    "200 OK",
+                    None,
+                    order(if done { "valid" } else { "pending" }).to_string(),
+                )
+            }
+            "/authz" => {
+                let authorized = self.state.lock().unwrap().authorized;
+                let authorization = serde_json::json!({
+                    "status": if authorized { "valid" } else { "pending" },
+                    "identifier": { "type": "dns", "value": "localhost" },
+                    "challenges": [
+                        { "type": "dns-01", "url": url("/dns"), "token": "dns-token" },
+                        { "type": "http-01", "url": url("/challenge"), "token": "http-token" },
+                    ],
+                });
+                ("200 OK", None, authorization.to_string())
+            }
+            "/challenge" => {
+                let mut stream = TcpStream::connect(("localhost", self.challenge_port)).await?;
+                let request = "GET /.well-known/acme-challenge/http-token HTTP/1.1\r\n\r\n";
+                stream.write_all(request.as_bytes()).await?;
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await?;
+                let expected = format!("http-token.{}", self.thumbprint());
+                ensure!(response.ends_with(&expected), "wrong key authorization");
+                self.state.lock().unwrap().authorized = true;
+                ("200 OK", None, r#"{"status":"valid"}"#.into())
+            }
+            "/finalize" => {
+                let csr = payload["csr"].as_str().unwrap_or_default();
+                let csr = URL_SAFE_NO_PAD.decode(csr)?;
+                let mut csr = rcgen::CertificateSigningRequestParams::from_der(&csr.into())?;
+                // Past 2049, the expiry is encoded as GeneralizedTime rather than UTCTime.
+                csr.params.not_after = rcgen::date_time_ymd(2051, 1, 1);
+                let cert = csr.signed_by(&self.ca, &self.ca_key)?;
+                self.state.lock().unwrap().chain = Some(cert.pem() + &self.ca.pem());
+                ("200 OK", None, order("processing").to_string())
+            }
+            "/cert" => {
+                let chain = self.state.lock().unwrap().chain.clone();
+                ("200 OK", None, chain.unwrap_or_default())
+            }
+            _ => bail!("unexpected POST {path}"),
+        })
+    }
+
+    /// Check the nonce, URL, and signature of a request, returning its payload.
+    fn verify(&self, url: &str, body: &[u8]) -> Result<serde_json::Value> {
+        let decode = |field: &serde_json::Value| -> Result<Vec<u8>> {
+            Ok(URL_SAFE_NO_PAD.decode(field.as_str().unwrap_or_default())?)
+        };
+        let jws: serde_json::Value = serde_json::from_slice(body)?;
+        let protected: serde_json::Value = serde_json::from_slice(&decode(&jws["protected"])?)?;
+        ensure!(protected["url"] == url, "wrong url");
+        let nonce = format!("nonce-{}", self.state.lock().unwrap().nonce);
+        ensure!(protected["nonce"] == nonce.as_str(), "stale nonce");
+
+        let mut state = self.state.lock().unwrap();
+        let jwk = match &protected["kid"] {
+            serde_json::Value::Null => state.jwk.insert(protected["jwk"].clone()),
+            kid => {
+                ensure!(*kid == format!("{}/account/1", self.base), "wrong kid");
+                state.jwk.as_mut().context("no account")?
+            }
+        };
+        let point = [vec![4], decode(&jwk["x"])?, decode(&jwk["y"])?].concat();
+        let key = ring::signature::UnparsedPublicKey::new(
+            &ring::signature::ECDSA_P256_SHA256_FIXED,
+            point,
+        );
+        let signed = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap_or_default(),
+            jws["payload"].as_str().unwrap_or_default()
+        );
+        key.verify(signed.as_bytes(), &decode(&jws["signature"])?)
+            .map_err(|_| anyhow!("bad signature"))?;
+        let payload = decode(&jws["payload"])?;
+        Ok(serde_json::from_slice(&payload).unwrap_or_default())
+    }
+
+    fn thumbprint(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let jwk = state.jwk.as_ref().unwrap();
+        let jwk = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+            jwk["x"], jwk["y"]
+        );
+        URL_SAFE_NO_PAD.encode(Sha256::digest(jwk.as_bytes()))
+    }
+}
+
+/// Read an HTTP request, returning its head and body.
+async fn read_request(stream: &mut TcpStream) -> Result<(String, Vec<u8>)> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    let end = loop {
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        let n = stream.read(&mut buf).await?;
+        ensure!(n > 0, "connection closed");
+        data.extend_from_slice(&buf[..n]);
+    };
+    let head = String::from_utf8_lossy(&data[..end]).into_owned();
+    let length = (head.lines())
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse().ok())?
+        })
+        .unwrap_or(0);
+    let mut body = data.split_off(end);
+    while body.len() < length {
+        let n = stream.read(&mut buf).await?;
+        ensure!(n > 0, "connection closed");
+        body.extend_from_slice(&buf[..n]);
+    }
+    Ok((head, body))
+}
+
+#[tokio::test]
+async fn acme_issue_certificate() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let ca_key = rcgen::KeyPair::generate()?;
+    let mut params = rcgen::CertificateParams::new(Vec::new())?;
+    params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    let ca = params.self_signed(&ca_key)?;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let challenge_port = TcpListener::bind("localhost:0").await?.local_addr()?.port();
+    let authority = Arc::new(MockAcme {
+        base: format!("http://localhost:{}", listener.local_addr()?.port()),
+        challenge_port,
+        ca,
+        ca_key,
+        state: Default::default(),
+    });
+    tokio::spawn(Arc::clone(&authority).serve(listener));
+
+    let dir = std::env::temp_dir().join(format!("bore-test-acme-issue-{}", std::process::id()));
+    let mut acme = Acme::new(vec!["localhost".into()], &dir)?;
+    assert_eq!(acme.expires(), None);
+    acme.set_directory(format!("{}/directory", authority.base));
+    acme.set_contact("admin@example.com");
+    acme.set_challenge_port(challenge_port);
+    let mut server = Server::new(1024..=65535, None);
+    let config = acme.server_config()?;
+    server.set_control_listeners(vec![ControlListener::tls(TLS_CONTROL_PORT, config)]);
+    server.set_acme(acme);
+    tokio::spawn(server.listen());
+
+    // The account is created, the order placed, its challenge answered, and then finalized.
+    for _ in 0..50 {
+        if dir.join("domains").exists() {
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    let requests = authority.state.lock().unwrap().requests.clone();
+    let expected = [
+        "/account",
+        "/order",
+        "/authz",
+        "/challenge",
+        "/authz",
+        "/finalize",
+        "/order/1",
+        "/cert",
+    ];
+    assert_eq!(requests, expected);
+
+    // The new certificate is kept for the next run, which reads its expiry.
+    time::sleep(Duration::from_millis(100)).await;
+    let cached = Acme::new(vec!["localhost".into()], &dir)?;
+    let expires = UNIX_EPOCH + Duration::from_secs(2556144000);
+    assert_eq!(cached.expires(), Some(expires));
+
+    // Control connections are served with the certificate signed by the authority.
+    let ca_path = dir.join("ca.pem");
+    std::fs::write(&ca_path, authority.ca.pem())?;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        tls: Some(tls::client_config(Some(&ca_path))?),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"acme").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 4];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"acme");
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn supervised_tunnels() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let tunnel = |name: &str| TunnelConfig {
+        local_host: "localhost".into(),
+        local_port,
+        to: "localhost".into(),
+        port: 0,
+        secret: None,
+        options: ClientOptions {
+            name: Some(name.into()),
+            ..Default::default()
+        },
+    };
+    let supervisor = Arc::new(Supervisor::new(vec![tunnel("a"), tunnel("b")]));
+    tokio::spawn(Arc::clone(&supervisor).run());
+
+    // The server is not up yet, so both tunnels fail and retry.
+    time::sleep(Duration::from_millis(200)).await;
+    for (_, state) in supervisor.states() {
+        assert!(matches!(state, TunnelState::Retrying(_)));
+    }
+    for status in supervisor.status() {
+        assert_eq!(status.uptime_secs, None);
+        assert!(!status.recent_errors.is_empty());
+    }
+
+    spawn_server(None).await;
+    time::sleep(Duration::from_millis(1500)).await;
+    let ports: Vec<_> = supervisor
+        .states()
+        .into_iter()
+        .map(|(_, state)| match state {
+            TunnelState::Connected(port) => Ok(port),
+            state => Err(anyhow!("unexpected state: {state}")),
+        })
+        .collect::<Result<_>>()?;
+    assert_ne!(ports[0], ports[1]);
+    for status in supervisor.status() {
+        assert!(status.uptime_secs.is_some());
+        assert!(status.recent_errors[0].contains("could not connect"));
+    }
+
+    let mut stream = TcpStream::connect(("localhost", ports[1])).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[tokio::test]
+async fn supervised_tunnels_shutdown() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let (events, mut receiver) = event::channel();
+    let shutdown = CancellationToken::new();
+    let tunnel = |local_port| TunnelConfig {
+        local_host: "localhost".into(),
+        local_port,
+        to: "localhost".into(),
+        port: 0,
+        secret: None,
+        options: ClientOptions {
+            events: Some(events.clone()),
+            shutdown: shutdown.clone(),
+            ..Default::default()
+        },
+    };
+    let supervisor = Arc::new(Supervisor::new(vec![tunnel(5000), tunnel(5001)]));
+    tokio::spawn(Arc::clone(&supervisor).run());
+
+    for _ in 0..2 {
+        let event = time::timeout(Duration::from_secs(2), receiver.recv()).await??;
+        assert!(matches!(
+            event,
+            ClientEvent::Connected { remote_host, .. } if remote_host == "localhost"
+        ));
+    }
+
+    // Tunnels that were shut down on purpose stop for good instead of reconnecting.
+    shutdown.cancel();
+    time::timeout(Duration::from_secs(2), supervisor.stopped()).await?;
+    for (_, state) in supervisor.states() {
+        assert!(matches!(state, TunnelState::Stopped));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn daemon_tunnels() -> Result<()> {
+    use bore_cli::daemon::{self, Daemon, DaemonRequest, DaemonResponse, NewTunnel};
+
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let socket = std::env::temp_dir().join(format!("bore-test-{}.sock", std::process::id()));
+    tokio::spawn(Daemon::bind(&socket).await?.listen());
+    assert!(Daemon::bind(&socket).await.is_err());
+
+    let tunnel = NewTunnel {
+        local_host: "localhost".into(),
+        local_port,
+        to: "localhost".into(),
+        name: Some("web".into()),
+        ..Default::default()
+    };
+    daemon::request(&socket, DaemonRequest::Add(tunnel.clone())).await?;
+    assert!(daemon::request(&socket, DaemonRequest::Add(tunnel))
+        .await
+        .is_err());
+
+    time::sleep(Duration::from_millis(200)).await;
+    let port = match daemon::request(&socket, DaemonRequest::List).await? {
+        DaemonResponse::Tunnels(tunnels) => match &tunnels[..] {
+            [(name, TunnelState::Connected(port))] if name == "web" => *port,
+            tunnels => return Err(anyhow!("unexpected tunnels: {tunnels:?}")),
+        },
+        response => return Err(anyhow!("unexpected response: {response:?}")),
+    };
+    match daemon::request(&socket, DaemonRequest::Status).await? {
+        DaemonResponse::Status(tunnels) => {
+            assert_eq!(tunnels.len(), 1);
+            assert_eq!(tunnels[0].state, TunnelState::Connected(port));
+            assert!(tunnels[0].uptime_secs.is_some());
+        }
+        response => return Err(anyhow!("unexpected response: {response:?}")),
+    }
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    daemon::request(&socket, DaemonRequest::Remove("web".into())).await?;
+    assert!(matches!(
+        daemon::request(&socket, DaemonRequest::List).await?,
+        DaemonResponse::Tunnels(tunnels) if tunnels.is_empty()
+    ));
+    assert!(
+        daemon::request(&socket, DaemonRequest::Remove("web".into()))
+            .await
+            .is_err()
+    );
+
+    // The server releases the port once a heartbeat fails on the dropped connection.
+    time::sleep(Duration::from_millis(1500)).await;
+    assert!(TcpListener::bind(("0.0.0.0", port)).await.is_ok());
+    std::fs::remove_file(&socket)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn inspect_http() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let local = TcpListener::bind("localhost:0").await?;
+    let local_port = local.local_addr()?.port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = local.accept().await {
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await?;
+            assert!(n > 0);
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello")
+                .await?;
+        }
+        anyhow::Ok(())
+    });
+
+    let inspector = Arc::new(Inspector::default());
+    let options = ClientOptions {
+        inspector: Some(Arc::clone(&inspector)),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream
+        .write_all(b"POST /hook HTTP/1.1\r\nhost: example\r\ncontent-length: 4\r\n\r\nping")
+        .await?;
+    let mut buf = [0u8; 43];
+    stream.read_exact(&mut buf).await?;
+    time::sleep(Duration::from_millis(50)).await;
+
+    let exchanges = inspector.exchanges();
+    assert_eq!(exchanges.len(), 1);
+    let exchange = &exchanges[0];
+    assert_eq!(
+        (&exchange.method[..], &exchange.path[..]),
+        ("POST", "/hook")
+    );
+    assert_eq!(exchange.status, Some(200));
+    assert_eq!(exchange.request.body, "ping");
+    assert_eq!(exchange.response.as_ref().unwrap().body, "hello");
+
+    // Replay the request through the inspector's API.
+    let api = TcpListener::bind("localhost:0").await?;
+    let api_addr = api.local_addr()?;
+    tokio::spawn(Arc::clone(&inspector).serve(api, "localhost".into(), local_port, None));
+    let mut stream = TcpStream::connect(api_addr).await?;
+    let path = format!("/api/requests/{}/replay", exchange.id);
+    stream
+        .write_all(format!("POST {path} HTTP/1.1\r\n\r\n").as_bytes())
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains(&format!(r#""replay_of":{}"#, exchange.id)));
+    assert_eq!(inspector.exchanges().len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn terminate_tls() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let dir = std::env::temp_dir();
+    let cert_path = dir.join(format!("bore-visitor-{}.crt", std::process::id()));
+    let key_path = dir.join(format!("bore-visitor-{}.key", std::process::id()));
+    std::fs::write(&cert_path, cert.cert.pem())?;
+    std::fs::write(&key_path, cert.key_pair.serialize_pem())?;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        terminate_tls: Some(tls::server_config(&cert_path, &key_path)?),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    // Visitors speak TLS, while the local service receives plaintext.
+    let config = tls::client_config(Some(&cert_path))?;
+    let stream = TcpStream::connect(("localhost", port)).await?;
+    let mut stream = tls::connect(config, "localhost", stream).await?;
+    stream.write_all(b"secret").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 6];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"secret");
+
+    std::fs::remove_file(cert_path)?;
+    std::fs::remove_file(key_path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn basic_auth() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        basic_auth: Some("alice:secret".parse()?),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    // Visitors without the right credentials never reach the local service.
+    for auth in ["", "Authorization: Basic YWxpY2U6d3Jvbmc=\r\n"] {
+        let mut stream = TcpStream::connect(("localhost", port)).await?;
+        let request = format!("GET / HTTP/1.1\r\nHost: localhost\r\n{auth}\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(response.contains("WWW-Authenticate: Basic"));
+    }
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    let request = "GET / HTTP/1.1\r\nAuthorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n";
+    stream.write_all(request.as_bytes()).await?;
+    let (mut local, _) = time::timeout(Duration::from_secs(1), listener.accept()).await??;
+    let mut buf = vec![0u8; request.len()];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(buf, request.as_bytes());
+    Ok(())
+}
+
+#[tokio::test]
+async fn forwarded_headers() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        forwarded_headers: true,
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    // Every request on the connection gets the headers, and bodies pass through unchanged.
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream
+        .write_all(
+            b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              4\r\nGET \r\n0\r\n\r\n\
+              GET /b HTTP/1.1\r\nX-Forwarded-For: 203.0.113.7\r\nX-Forwarded-Proto: https\r\n\r\n",
+        )
+        .await?;
+    stream.shutdown().await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut received = String::new();
+    local.read_to_string(&mut received).await?;
+    assert_eq!(
+        received,
+        "POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\nX-Forwarded-For: 127.0.0.1\r\n\
+         X-Forwarded-Proto: http\r\nForwarded: for=127.0.0.1;proto=http\r\n\r\n\
+         4\r\nGET \r\n0\r\n\r\n\
+         GET /b HTTP/1.1\r\nX-Forwarded-For: 203.0.113.7, 127.0.0.1\r\n\
+         X-Forwarded-Proto: http\r\nForwarded: for=127.0.0.1;proto=http\r\n\r\n"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn proxy_protocol() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let (events, mut client_events) = event::channel();
+    let options = ClientOptions {
+        proxy_protocol: true,
+        events: Some(events),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let visitor_port = stream.local_addr()?.port();
+    stream.write_all(b"hello").await?;
+    stream.shutdown().await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut received = String::new();
+    local.read_to_string(&mut received).await?;
+    assert_eq!(
+        received,
+        format!("PROXY TCP4 127.0.0.1 0.0.0.0 {visitor_port} {port}\r\nhello")
+    );
+
+    assert!(matches!(
+        client_events.recv().await?,
+        ClientEvent::Connected { .. }
+    ));
+    let ClientEvent::VisitorOpened {
+        addr, port: opened, ..
+    } = client_events.recv().await?
+    else {
+        panic!("expected a visitor");
+    };
+    assert_eq!(addr.map(|addr| addr.port()), Some(visitor_port));
+    assert_eq!(opened, port);
+    Ok(())
+}
+
+#[tokio::test]
+async fn host_header() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        host_header: "rewrite".parse()?,
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: bore.pub:4000\r\nAccept: */*\r\n\r\n")
+        .await?;
+    stream.shutdown().await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut received = String::new();
+    local.read_to_string(&mut received).await?;
+    let expected = format!(
+        "GET / HTTP/1.1\r\nHost: localhost:{local_port}\r\nAccept: */*\r\n\
+         X-Forwarded-Host: bore.pub:4000\r\n\r\n"
+    );
+    assert_eq!(received, expected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_tls() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let acceptor =
+        tokio_rustls::TlsAcceptor::from(tls::self_signed_config(vec!["localhost".into()])?);
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut stream = acceptor.accept(stream).await?;
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await?;
+        stream.write_all(&buf).await?;
+        stream.flush().await?;
+        anyhow::Ok(())
+    });
+
+    let options = ClientOptions {
+        local_tls: Some(tls::insecure_client_config()),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[tokio::test]
+async fn visitor_tls() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(42000..=42001, None);
+    let config = tls::self_signed_config(vec!["localhost".into()])?;
+    server.set_visitor_tls("42000".parse()?, config);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                io::copy(&mut reader, &mut writer).await
+            });
+        }
+    });
+
+    let client = Client::new("localhost", local_port, "localhost", 42000, None).await?;
+    tokio::spawn(client.listen());
+    let plain = Client::new("localhost", local_port, "localhost", 42001, None).await?;
+    tokio::spawn(plain.listen());
+
+    // The local service sees plaintext, while visitors of the selected port speak TLS.
+    let stream = TcpStream::connect("localhost:42000").await?;
+    let config = tls::insecure_client_config();
+    let mut stream = tls::connect(config, "localhost", stream).await?;
+    stream.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    let mut stream = TcpStream::connect("localhost:42001").await?;
+    stream.write_all(b"plain").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"plain");
+    Ok(())
+}
+
+#[tokio::test]
+async fn visitor_ip_filter() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+
+    let spawn_filtered = |ip_filter: IpFilter| async move {
+        let options = ClientOptions {
+            ip_filter,
+            ..Default::default()
+        };
+        let client =
+            Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+        let port = client.remote_port();
+        tokio::spawn(client.listen());
+        anyhow::Ok(port)
+    };
+
+    // Rejected visitors are disconnected without reaching the local service.
+    let port = spawn_filtered(IpFilter {
+        allow: vec!["10.0.0.0/8".parse()?],
+        deny: vec![],
+    })
+    .await?;
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let mut buf = [0u8; 5];
+    assert_eq!(stream.read(&mut buf).await?, 0);
+    let accepted = time::timeout(Duration::from_millis(100), listener.accept()).await;
+    assert!(accepted.is_err());
+
+    let port = spawn_filtered(IpFilter {
+        allow: vec!["127.0.0.0/8".parse()?],
+        deny: vec!["127.0.0.2/32".parse()?],
+    })
+    .await?;
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_bind_addresses() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_control_bind_addr([127, 0, 0, 1].into());
+    server.set_tunnel_bind_addr([127, 0, 0, 1].into());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    assert!(TcpStream::connect(("127.0.0.2", CONTROL_PORT))
+        .await
+        .is_err());
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let client = Client::new("localhost", local_port, "127.0.0.1", 0, None).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+    assert!(TcpStream::connect(("127.0.0.2", port)).await.is_err());
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[tokio::test]
+async fn bind_address() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let connect = |bind_addr: [u8; 4]| {
+        let options = ClientOptions {
+            bind_addr: Some(bind_addr.into()),
+            ..Default::default()
+        };
+        Client::with_options("127.0.0.1", local_port, "127.0.0.1", 0, None, options)
+    };
+
+    // Binding to an address that is not assigned to this machine fails.
+    assert!(connect([192, 0, 2, 1]).await.is_err());
+
+    let client = connect([127, 0, 0, 1]).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[tokio::test]
+async fn fallback_random_port() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let taken = TcpListener::bind("0.0.0.0:0").await?;
+    let port = taken.local_addr()?.port();
+    assert!(Client::new("localhost", 5000, "localhost", port, None)
+        .await
+        .is_err());
+
+    let options = ClientOptions {
+        fallback_random: true,
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 5000, "localhost", port, None, options).await?;
+    assert_ne!(client.remote_port(), port);
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn pooled_connections(#[values(None, Some("abc"))] secret: Option<&str>) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(secret).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        pool_size: 2,
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, secret, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+    time::sleep(Duration::from_millis(100)).await;
+
+    // More visitors than pooled connections, so the pool has to be refilled.
+    for _ in 0..5 {
+        let mut stream = TcpStream::connect(("localhost", port)).await?;
+        stream.write_all(b"hello").await?;
+        let (mut local, _) = listener.accept().await?;
+        let mut buf = [0u8; 5];
+        local.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        local.write_all(b"world").await?;
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"world");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn transfer_stats() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let client = Client::new("localhost", local_port, "localhost", 0, None).await?;
+    let (port, stats) = (client.remote_port(), client.stats());
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    local.write_all(b"hi").await?;
+    stream.read_exact(&mut buf[..2]).await?;
+    assert_eq!((stats.connections(), stats.active()), (1, 1));
+
+    drop(local);
+    assert_eq!(stream.read(&mut buf).await?, 0);
+    drop(stream);
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!((stats.connections(), stats.active()), (1, 0));
+    assert_eq!((stats.received(), stats.sent()), (5, 2));
+    let last_heartbeat = stats.last_heartbeat().expect("no heartbeat recorded");
+    assert!(last_heartbeat.elapsed() < Duration::from_secs(1));
+    Ok(())
+}
+
+#[tokio::test]
+async fn proxy_buffer_size() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_proxy_buffer_size(7);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        proxy_buffer_size: Some(3),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    // Data arrives intact however small the buffers copying it are.
+    let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(&data).await?;
+    stream.shutdown().await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut received = Vec::new();
+    local.read_to_end(&mut received).await?;
+    assert_eq!(received, data);
+    Ok(())
+}
+
+#[tokio::test]
+async fn bench_report() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let options = BenchOptions {
+        connections: 5,
+        size: 1 << 20,
+    };
+    let report = bench::run("localhost", None, ClientOptions::default(), &options).await?;
+    assert_ne!(report.port, 0);
+    assert_eq!(report.bytes, 1 << 20);
+    assert!(report.connect.p50_us <= report.connect.max_us);
+    assert!(report.first_byte.p50_us <= report.first_byte.p90_us);
+    assert!(report.first_byte.p90_us <= report.first_byte.max_us);
+    assert!(report.upload_rate() > 0.0 && report.download_rate() > 0.0);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn accept_shards() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_socket_options(SocketOptions {
+        accept_shards: Some(4),
+        ..Default::default()
+    });
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(None).await?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                io::copy(&mut reader, &mut writer).await
+            });
+        }
+    });
+
+    // Visitors land on whichever shard the kernel picks, and all of them reach the client.
+    for i in 0..32u8 {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(&[i]).await?;
+        let mut buf = [0u8];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(buf[0], i);
+    }
+
+    // The port is shared by the shards alone, not with another tunnel.
+    let result = Client::new("localhost", 5000, "localhost", addr.port(), None).await;
+    assert!(matches!(result, Err(Error::Bind(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn half_close() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    // The visitor sends its whole request before reading the response, like `nc -N`.
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    stream.shutdown().await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut request = Vec::new();
+    local.read_to_end(&mut request).await?;
+    assert_eq!(request, b"hello");
+    local.write_all(b"world").await?;
+    drop(local);
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    assert_eq!(response, b"world");
+    Ok(())
+}
+
+#[tokio::test]
+async fn heartbeat_rtt() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = Server::new(1024..=65535, None);
+    let tunnels = server.tunnels();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let options = ClientOptions {
+        measure_rtt: true,
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 0, "localhost", 0, None, options).await?;
+    let stats = client.stats();
+    tokio::spawn(client.listen());
+    let plain = Client::new("localhost", 0, "localhost", 0, None).await?;
+    let plain_port = plain.remote_port();
+    tokio::spawn(plain.listen());
+
+    // The client learns the round-trip time from the heartbeat after the first one it answers.
+    time::sleep(Duration::from_millis(1200)).await;
+    let rtt = stats.rtt().expect("no round-trip time measured");
+    assert!(rtt < Duration::from_secs(1));
+    assert!(stats.jitter().is_some());
+    for tunnel in tunnels.list() {
+        assert_eq!(tunnel.rtt.is_some(), tunnel.port != plain_port);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn bandwidth_limit() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        max_up: Some("16K".parse()?),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"go").await?;
+    let (mut local, _) = listener.accept().await?;
+    let start = time::Instant::now();
+    tokio::spawn(async move { local.write_all(&[1u8; 32 * 1024]).await });
+
+    // The first 16 KiB are sent as a burst, and the rest at the limited rate.
+    let mut buf = vec![0u8; 32 * 1024];
+    stream.read_exact(&mut buf).await?;
+    assert!(
+        start.elapsed() >= Duration::from_millis(900),
+        "{:?}",
+        start.elapsed()
+    );
+    assert!(buf.iter().all(|&b| b == 1));
+    Ok(())
+}
+
+#[tokio::test]
+async fn remote_bind() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        remote_bind: Some([127, 0, 0, 1].into()),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    // By default, the server only allows loopback addresses.
+    let options = ClientOptions {
+        remote_bind: Some([0, 0, 0, 0].into()),
+        ..Default::default()
+    };
+    let result = Client::with_options("localhost", local_port, "localhost", 0, None, options).await;
+    assert!(result.is_err());
+    Ok(())
+}
+
+/// Allocator that assigns the lowest free port in range, recording who asked for it.
+#[derive(Clone, Default)]
+struct SequentialAllocator(Arc<std::sync::Mutex<Vec<Option<Identity>>>>);
+
+impl PortAllocator for SequentialAllocator {
+    fn allocate<'a>(
+        &'a self,
+        request: PortRequest<'a>,
+    ) -> BoxFuture<'a, error::Result<TcpListener>> {
+        Box::pin(async move {
+            self.0.lock().unwrap().push(request.identity.cloned());
+            let ports = request
+                .ranges
+                .ranges()
+                .iter()
+                .flat_map(|range| range.clone());
+            for port in ports.filter(|port| !request.excluded.contains(*port)) {
+                if let Ok(listener) = allocator::bind(&request, port) {
+                    return Ok(listener);
+                }
+            }
+            Err(Error::Bind("pool exhausted".into()))
+        })
+    }
+}
+
+#[tokio::test]
+async fn port_allocator() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let allocator = SequentialAllocator::default();
+    let mut server = Server::new(41000..=41002, Some("abc"));
+    server.set_excluded_ports("41000".parse()?);
+    server.set_port_allocator(allocator.clone());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let first = Client::new("localhost", 5000, "localhost", 0, Some("abc")).await?;
+    let second = Client::new("localhost", 5000, "localhost", 0, Some("abc")).await?;
+    assert_eq!((first.remote_port(), second.remote_port()), (41001, 41002));
+    let result = Client::new("localhost", 5000, "localhost", 0, Some("abc")).await;
+    assert!(matches!(result, Err(Error::Bind(_))));
+    let identities = allocator.0.lock().unwrap().clone();
+    assert_eq!(identities, vec![Some(Identity("secret".into())); 3]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn prebound_listener() -> Result<()> {
+    // No serial guard is needed, since the control port is chosen by the OS.
+    let control = TcpListener::bind("localhost:0").await?;
+    let control_port = control.local_addr()?.port();
+    tokio::spawn(Server::new(1024..=65535, None).listen_on(control));
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        control_port: Some(control_port),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[tokio::test]
+async fn graceful_shutdown() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server_token = CancellationToken::new();
+    let mut server = Server::new(1024..=65535, None);
+    server.set_shutdown(server_token.clone());
+    let server = tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let client_token = CancellationToken::new();
+    let options = ClientOptions {
+        shutdown: client_token.clone(),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 5000, "localhost", 0, None, options).await?;
+    let client = tokio::spawn(client.listen());
+    client_token.cancel();
+    time::timeout(Duration::from_secs(1), client).await???;
+
+    // A second client is disconnected when the server shuts down.
+    let client = Client::new("localhost", 5000, "localhost", 0, None).await?;
+    let client = tokio::spawn(client.listen());
+    server_token.cancel();
+    time::timeout(Duration::from_secs(1), server).await???;
+    time::timeout(Duration::from_secs(1), client).await???;
+    assert!(TcpStream::connect(("localhost", CONTROL_PORT))
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn cluster_forwarding() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // Both nodes run on this machine, so they bind tunnels on different loopback addresses.
+    let node = |control_port, peer_port, tunnel_ip: &str| -> Result<Server> {
+        let mut server = Server::new(1024..=65535, Some("secret"));
+        server.set_control_listeners(vec![ControlListener::plain(control_port)]);
+        server.set_tunnel_bind_addr(tunnel_ip.parse()?);
+        let (node, peer) = (
+            format!("127.0.0.1:{control_port}"),
+            format!("127.0.0.1:{peer_port}"),
+        );
+        server.set_cluster(Cluster::new(&node, vec![peer], "secret"));
+        Ok(server)
+    };
+    let (server_tx, mut server_events) = event::channel();
+    let mut owner = node(7845, 7846, "127.0.0.1")?;
+    owner.set_events(server_tx);
+    tokio::spawn(owner.listen());
+    tokio::spawn(node(7846, 7845, "127.0.0.2")?.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        control_port: Some(7845),
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        local_port,
+        "localhost",
+        0,
+        Some("secret"),
+        options,
+    );
+    let client = client.await?;
+    let port = client.remote_port();
+    let handle = tokio::spawn(client.listen());
+    time::sleep(Duration::from_millis(200)).await;
+
+    // The peer relays the visitor, and the owner sees the visitor's own address.
+    let mut stream = TcpStream::connect(("127.0.0.2", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    let visitor_addr = loop {
+        if let ServerEvent::VisitorOpened { addr, .. } = server_events.recv().await? {
+            break addr;
+        }
+    };
+    assert_eq!(visitor_addr, stream.local_addr()?);
+
+    // Closing the tunnel releases it on the peer too.
+    handle.abort();
+    time::sleep(Duration::from_millis(200)).await;
+    assert!(TcpStream::connect(("127.0.0.2", port)).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn lifecycle_events() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (server_tx, mut server_events) = event::channel();
+    let mut server = Server::new(1024..=65535, None);
+    server.set_events(server_tx);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let (client_tx, mut client_events) = event::channel();
+    let shutdown = CancellationToken::new();
+    let options = ClientOptions {
+        events: Some(client_tx),
+        shutdown: shutdown.clone(),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let remote_port = client.remote_port();
+    tokio::spawn(client.listen());
+    assert_eq!(
+        client_events.recv().await?,
+        ClientEvent::Connected {
+            remote_host: "localhost".into(),
+            remote_port
+        }
+    );
+    assert!(matches!(
+        server_events.recv().await?,
+        ServerEvent::TunnelOpened { port, .. } if port == remote_port
+    ));
+
+    let mut stream = TcpStream::connect(("localhost", remote_port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    drop(local);
+    assert_eq!(stream.read(&mut buf).await?, 0);
+    drop(stream);
+    assert!(matches!(
+        server_events.recv().await?,
+        ServerEvent::VisitorOpened { port, .. } if port == remote_port
+    ));
+    let ClientEvent::VisitorOpened { id, .. } = client_events.recv().await? else {
+        panic!("expected a visitor to open");
+    };
+    let closed = client_events.recv().await?;
+    assert_eq!(
+        closed,
+        ClientEvent::VisitorClosed {
+            id,
+            received: 5,
+            sent: 0
+        }
+    );
+    assert!(matches!(
+        server_events.recv().await?,
+        ServerEvent::VisitorClosed { port, received: 5, sent: 0, .. } if port == remote_port
+    ));
+
+    shutdown.cancel();
+    let closed = time::timeout(Duration::from_secs(2), server_events.recv()).await??;
+    assert!(matches!(closed, ServerEvent::TunnelClosed { port, .. } if port == remote_port));
+    Ok(())
+}
+
+/// Observer recording the tunnels that were opened and closed, with some of their statistics.
+#[derive(Clone, Default)]
+struct RecordingObserver(Arc<std::sync::Mutex<Vec<String>>>);
+
+impl TunnelObserver for RecordingObserver {
+    fn on_tunnel_open(&self, identity: Option<&Identity>, port: u16) {
+        let identity = identity.map(|identity| identity.0.as_str());
+        let entry = format!("open {port} {identity:?}");
+        self.0.lock().unwrap().push(entry);
+    }
+
+    fn on_tunnel_close(&self, port: u16, stats: &Stats) {
+        let entry = format!("close {port} {} {}", stats.connections(), stats.received());
+        self.0.lock().unwrap().push(entry);
+    }
+}
+
+#[tokio::test]
+async fn tunnel_observer() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let observer = RecordingObserver::default();
+    let mut server = Server::new(1024..=65535, Some("abc"));
+    server.set_observer(observer.clone());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let shutdown = CancellationToken::new();
+    let options = ClientOptions {
+        shutdown: shutdown.clone(),
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        local_port,
+        "localhost",
+        0,
+        Some("abc"),
+        options,
+    )
+    .await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    drop((local, stream));
+
+    // The server notices that the client is gone on its next heartbeat.
+    shutdown.cancel();
+    time::sleep(Duration::from_millis(1500)).await;
+    let entries = observer.0.lock().unwrap().clone();
+    assert_eq!(
+        entries,
+        [
+            format!("open {port} Some(\"secret\")"),
+            format!("close {port} 1 5")
+        ]
+    );
+    Ok(())
+}
+
+/// DNS provider recording the changes made to its records.
+#[derive(Clone, Default)]
+struct RecordingDns(Arc<std::sync::Mutex<Vec<String>>>);
+
+impl DnsProvider for RecordingDns {
+    fn upsert<'a>(&'a self, record: &'a DnsRecord) -> BoxFuture<'a, anyhow::Result<()>> {
+        let entry = format!(
+            "upsert {} {} {}",
+            record.name,
+            record.target.record_type(),
+            record.target
+        );
+        self.0.lock().unwrap().push(entry);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn remove<'a>(&'a self, record: &'a DnsRecord) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.0
+            .lock()
+            .unwrap()
+            .push(format!("remove {}", record.name));
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[tokio::test]
+async fn dns_records() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let dns = RecordingDns::default();
+    let target = "203.0.113.7".parse()?;
+    let mut server = Server::new(1024..=65535, None);
+    server.set_dns_updater(DnsUpdater::new(
+        Arc::new(dns.clone()),
+        "tunnels.example.",
+        target,
+    ));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Unnamed tunnels get no record.
+    spawn_client(None).await?;
+    let shutdown = CancellationToken::new();
+    let options = ClientOptions {
+        name: Some("Web".into()),
+        shutdown: shutdown.clone(),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 5000, "localhost", 0, None, options).await?;
+    let listen = tokio::spawn(client.listen());
+    time::sleep(Duration::from_millis(100)).await;
+    shutdown.cancel();
+    listen.await??;
+    time::sleep(Duration::from_millis(100)).await;
+
+    let entries = dns.0.lock().unwrap().clone();
+    assert_eq!(
+        entries,
+        [
+            "upsert web.tunnels.example A 203.0.113.7",
+            "remove web.tunnels.example",
+        ]
+    );
+    Ok(())
+}
+
+/// Middleware that drops visitors of tunnels named `blocked`, and greets the others.
+struct Greeter;
+
+impl VisitorMiddleware for Greeter {
+    fn on_visitor<'a>(
+        &'a self,
+        visitor: &'a VisitorInfo,
+        mut stream: BoxedStream,
+    ) -> BoxFuture<'a, Option<BoxedStream>> {
+        Box::pin(async move {
+            if visitor.name.as_deref() == Some("blocked") {
+                return None;
+            }
+            stream.write_all(b"hi ").await.ok()?;
+            Some(stream)
+        })
+    }
+}
+
+#[tokio::test]
+async fn visitor_middleware() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_middleware(Greeter);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let _ = stream.write_all(b"hello").await;
+        }
+    });
+    let mut ports = Vec::new();
+    for name in ["greeted", "blocked"] {
+        let options = ClientOptions {
+            name: Some(name.into()),
+            ..Default::default()
+        };
+        let client =
+            Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+        ports.push(client.remote_port());
+        tokio::spawn(client.listen());
+    }
+
+    // The middleware writes to the visitor before the service does.
+    let mut stream = TcpStream::connect(("localhost", ports[0])).await?;
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hi hello");
+
+    let mut stream = TcpStream::connect(("localhost", ports[1])).await?;
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await?;
+    assert!(buf.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn active_tunnels() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = Server::new(1024..=65535, Some("abc"));
+    let tunnels = server.tunnels();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    assert!(tunnels.list().is_empty());
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let shutdown = CancellationToken::new();
+    let options = ClientOptions {
+        name: Some("web".into()),
+        shutdown: shutdown.clone(),
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        local_port,
+        "localhost",
+        0,
+        Some("abc"),
+        options,
+    )
+    .await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+
+    let list = tunnels.list();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].port, port);
+    assert_eq!(list[0].name.as_deref(), Some("web"));
+    assert!(list[0].client.ip().is_loopback());
+    assert_eq!(list[0].identity, Some(Identity("secret".into())));
+    assert_eq!((list[0].connections, list[0].active), (1, 1));
+
+    drop((local, stream));
+    shutdown.cancel();
+    time::sleep(Duration::from_millis(1500)).await;
+    assert!(tunnels.list().is_empty());
+    Ok(())
+}
+
+/// Transport that connects clients to the server through in-memory pipes.
+#[derive(Debug)]
+struct MemoryConnector(mpsc::UnboundedSender<DuplexStream>);
+
+struct MemoryAcceptor(Mutex<mpsc::UnboundedReceiver<DuplexStream>>);
+
+impl Connector for MemoryConnector {
+    fn connect<'a>(
+        &'a self,
+        _host: &'a str,
+        _port: u16,
+    ) -> BoxFuture<'a, error::Result<BoxedStream>> {
+        Box::pin(async move {
+            let (client, server) = io::duplex(4096);
+            self.0
+                .send(server)
+                .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+            Ok(Box::new(client) as BoxedStream)
+        })
+    }
+}
+
+impl Acceptor for MemoryAcceptor {
+    fn accept(&self) -> BoxFuture<'_, error::Result<(BoxedStream, SocketAddr)>> {
+        Box::pin(async move {
+            let stream = self
+                .0
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            Ok((Box::new(stream) as BoxedStream, ([127, 0, 0, 1], 0).into()))
+        })
+    }
+}
+
+#[tokio::test]
+async fn custom_transport() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_control_listeners(Vec::new());
+    server.add_acceptor(MemoryAcceptor(Mutex::new(rx)));
+    tokio::spawn(server.listen());
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        connector: Some(Arc::new(MemoryConnector(tx))),
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        local_port,
+        "localhost",
+        0,
+        Some("secret"),
+        options,
+    )
+    .await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    // Visitors still connect over TCP, while the data connection uses the custom transport.
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    assert!(TcpStream::connect(("localhost", CONTROL_PORT))
+        .await
+        .is_err());
+    Ok(())
+}
+
+/// Transport that connects clients to the server's control socket.
+#[cfg(unix)]
+#[derive(Debug)]
+struct UnixConnector(std::path::PathBuf);
+
+#[cfg(unix)]
+impl Connector for UnixConnector {
+    fn connect<'a>(
+        &'a self,
+        _host: &'a str,
+        _port: u16,
+    ) -> BoxFuture<'a, error::Result<BoxedStream>> {
+        Box::pin(async move {
+            let stream = tokio::net::UnixStream::connect(&self.0).await?;
+            Ok(Box::new(stream) as BoxedStream)
+        })
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn control_socket() -> Result<()> {
+    use bore_cli::transport::UnixAcceptor;
+
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let socket = std::env::temp_dir().join(format!("bore-control-{}.sock", std::process::id()));
+    let mut server = Server::new(1024..=65535, None);
+    server.set_control_listeners(Vec::new());
+    server.add_acceptor(UnixAcceptor::bind(&socket).await?);
+    tokio::spawn(server.listen());
+    assert!(UnixAcceptor::bind(&socket).await.is_err());
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        connector: Some(Arc::new(UnixConnector(socket.clone()))),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    std::fs::remove_file(socket)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn systemd_notify() -> Result<()> {
+    use tokio::net::UnixDatagram;
+
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let socket = std::env::temp_dir().join(format!("bore-notify-{}.sock", std::process::id()));
+    let notify = UnixDatagram::bind(&socket)?;
+    std::env::set_var("NOTIFY_SOCKET", &socket);
+
+    let shutdown = CancellationToken::new();
+    let mut server = Server::new(1024..=65535, None);
+    server.set_shutdown(shutdown.clone());
+    server.set_systemd_notify(true);
+    let listener = TcpListener::bind("localhost:0").await?;
+    let task = tokio::spawn(server.listen_on(listener));
+
+    let mut buf = [0u8; 64];
+    let len = time::timeout(Duration::from_secs(1), notify.recv(&mut buf)).await??;
+    assert_eq!(&buf[..len], b"READY=1\n");
+
+    shutdown.cancel();
+    task.await??;
+    let len = time::timeout(Duration::from_secs(1), notify.recv(&mut buf)).await??;
+    assert_eq!(&buf[..len], b"STOPPING=1\n");
+
+    std::env::remove_var("NOTIFY_SOCKET");
+    std::fs::remove_file(socket)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_port_range() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let range: PortList = "40000-40010".parse()?;
+    let options = ClientOptions {
+        port_range: Some(range.clone()),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 5000, "localhost", 0, None, options).await?;
+    assert!(range.contains(client.remote_port()));
+
+    // The server's range starts at 1024, so there is no port to choose from.
+    let options = ClientOptions {
+        port_range: Some("80-90".parse()?),
+        ..Default::default()
+    };
+    let result = Client::with_options("localhost", 5000, "localhost", 0, None, options).await;
+    assert!(result.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn heartbeat_timeout() -> Result<()> {
+    // A fake server that assigns a port, then goes silent as if its host had crashed.
+    let control = TcpListener::bind("localhost:0").await?;
+    let control_port = control.local_addr()?.port();
+    tokio::spawn(async move {
+        let (mut stream, _) = control.accept().await?;
+        let mut buf = [0u8; 64];
+        let n = stream.read(&mut buf).await?;
+        assert!(n > 0);
+        stream.write_all(b"{\"Hello\":40000}\0").await?;
+        time::sleep(Duration::from_secs(10)).await;
+        anyhow::Ok(())
+    });
+
+    let options = ClientOptions {
+        control_port: Some(control_port),
+        heartbeat_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 5000, "localhost", 0, None, options).await?;
+    let result = time::timeout(Duration::from_secs(2), client.listen()).await?;
+    assert!(result.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn resolve_interval() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    // The server's address never changes, so lookups keep finding it and the tunnel stays up.
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        resolve_interval: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+    let client =
+        Client::with_options("localhost", local_port, "localhost", 0, None, options).await?;
+    let remote_port = client.remote_port();
+    let listen = tokio::spawn(client.listen());
+
+    time::sleep(Duration::from_millis(300)).await;
+    assert!(!listen.is_finished());
+    let mut stream = TcpStream::connect(("localhost", remote_port)).await?;
+    let (mut local, _) = listener.accept().await?;
+    stream.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    listen.abort();
+    Ok(())
+}
+
 #[test]
 #[should_panic]
 fn empty_port_range() {